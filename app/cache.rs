@@ -1,13 +1,39 @@
+use std::time::Duration;
 use thiserror::Error;
 
 use crate::domain::PersonId;
 use crate::dto::PersonDto;
+use crate::error_detail::Diagnose;
 
 #[derive(Debug, Error, Clone, PartialEq, Eq)]
 pub enum CaoError {
     #[error("cache unavailable: {0}")]
     Unavailable(String),
+    #[error("cache corrupted: {0}")]
+    Corrupted(String),
+    // rejected by the circuit breaker without trying
+    #[error("cache circuit open: {0}")]
+    CircuitOpen(String),
 }
+impl Diagnose for CaoError {
+    fn kind(&self) -> &'static str {
+        match self {
+            CaoError::Unavailable(_) => "CaoError::Unavailable",
+            CaoError::Corrupted(_) => "CaoError::Corrupted",
+            CaoError::CircuitOpen(_) => "CaoError::CircuitOpen",
+        }
+    }
+    // the corrupted entry has been evicted, so the next one is read from the db
+    fn retryable(&self) -> bool {
+        true
+    }
+    fn backend(&self) -> Option<&str> {
+        Some("cache")
+    }
+}
+
+/// Proof of holding the lock, which only its holder can release.
+pub type LockToken = String;
 
 pub trait PersonCao<Ctx> {
     fn get_conn(&self) -> Result<Ctx, CaoError>;
@@ -17,10 +43,61 @@ pub trait PersonCao<Ctx> {
         F: tx_rs::Tx<Ctx, Item = T, Err = CaoError>;
 
     fn find(&self, id: PersonId) -> impl tx_rs::Tx<Ctx, Item = Option<PersonDto>, Err = CaoError>;
+    // the same as find, but not counted as a hit or a miss, e.g. while waiting for the loader
+    fn peek(&self, id: PersonId) -> impl tx_rs::Tx<Ctx, Item = Option<PersonDto>, Err = CaoError> {
+        self.find(id)
+    }
     fn load(
         &self,
         id: PersonId,
         person: &PersonDto,
     ) -> impl tx_rs::Tx<Ctx, Item = (), Err = CaoError>;
     fn unload(&self, id: PersonId) -> impl tx_rs::Tx<Ctx, Item = (), Err = CaoError>;
+    // every person in the cache, for audit
+    fn ids(&self) -> impl tx_rs::Tx<Ctx, Item = Vec<PersonId>, Err = CaoError>;
+
+    // single-flight on cache miss: only the one who got the lock loads the person from db.
+    // caches which are not shared with the others can always get it.
+    fn lock(
+        &self,
+        _id: PersonId,
+        _ttl: Duration,
+    ) -> impl tx_rs::Tx<Ctx, Item = Option<LockToken>, Err = CaoError> {
+        tx_rs::with_tx(|_: &mut Ctx| Ok(Some(LockToken::new())))
+    }
+    // releases the lock only if it's still held with the token
+    fn unlock(
+        &self,
+        _id: PersonId,
+        _token: LockToken,
+    ) -> impl tx_rs::Tx<Ctx, Item = (), Err = CaoError> {
+        tx_rs::with_tx(|_: &mut Ctx| Ok(()))
+    }
+
+    // snapshot of list_all, valid only while the generation stays the same.
+    // every mutation bumps the generation, so older snapshots are never read again.
+    // caches which don't support snapshots never serve one.
+    fn generation(&self) -> impl tx_rs::Tx<Ctx, Item = u64, Err = CaoError> {
+        tx_rs::with_tx(|_: &mut Ctx| Ok(0))
+    }
+    fn bump_generation(&self) -> impl tx_rs::Tx<Ctx, Item = (), Err = CaoError> {
+        tx_rs::with_tx(|_: &mut Ctx| Ok(()))
+    }
+    fn find_all(
+        &self,
+        _generation: u64,
+    ) -> impl tx_rs::Tx<Ctx, Item = Option<Vec<(PersonId, PersonDto)>>, Err = CaoError> {
+        tx_rs::with_tx(|_: &mut Ctx| Ok(None))
+    }
+    fn load_all(
+        &self,
+        _generation: u64,
+        _persons: &[(PersonId, PersonDto)],
+    ) -> impl tx_rs::Tx<Ctx, Item = (), Err = CaoError> {
+        tx_rs::with_tx(|_: &mut Ctx| Ok(()))
+    }
+    // drops the snapshot, when the generation can't be bumped after a mutation
+    fn unload_all(&self, _generation: u64) -> impl tx_rs::Tx<Ctx, Item = (), Err = CaoError> {
+        tx_rs::with_tx(|_: &mut Ctx| Ok(()))
+    }
 }
@@ -0,0 +1,344 @@
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+use crate::cache::CaoError;
+use crate::domain::PersonId;
+use crate::dto::PersonDto;
+
+// bump this whenever PersonDto changes incompatibly
+const CACHE_FORMAT_VERSION: u32 = 1;
+
+// what is actually serialized by every codec.
+// `person` holds the whole list of persons for a snapshot.
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope<P> {
+    v: u32,
+    person: P,
+}
+
+const TAG_JSON: u8 = b'J';
+const TAG_MSGPACK: u8 = b'M';
+const TAG_BINCODE: u8 = b'B';
+
+const FLAG_RAW: u8 = 0;
+const FLAG_DEFLATE: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Json,
+    MessagePack,
+    Bincode,
+}
+impl Codec {
+    fn tag(&self) -> u8 {
+        match self {
+            Codec::Json => TAG_JSON,
+            Codec::MessagePack => TAG_MSGPACK,
+            Codec::Bincode => TAG_BINCODE,
+        }
+    }
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            TAG_JSON => Some(Codec::Json),
+            TAG_MSGPACK => Some(Codec::MessagePack),
+            TAG_BINCODE => Some(Codec::Bincode),
+            _ => None,
+        }
+    }
+
+    fn serialize<T: Serialize>(&self, envelope: &Envelope<T>) -> Result<Vec<u8>, String> {
+        match self {
+            Codec::Json => serde_json::to_vec(envelope).map_err(|e| e.to_string()),
+            Codec::MessagePack => rmp_serde::to_vec(envelope).map_err(|e| e.to_string()),
+            Codec::Bincode => bincode::serialize(envelope).map_err(|e| e.to_string()),
+        }
+    }
+    fn deserialize<T: DeserializeOwned>(&self, s: &[u8]) -> Result<Envelope<T>, String> {
+        match self {
+            Codec::Json => serde_json::from_slice(s).map_err(|e| e.to_string()),
+            Codec::MessagePack => rmp_serde::from_slice(s).map_err(|e| e.to_string()),
+            Codec::Bincode => bincode::deserialize(s).map_err(|e| e.to_string()),
+        }
+    }
+}
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(Codec::Json),
+            "msgpack" | "messagepack" => Ok(Codec::MessagePack),
+            "bincode" => Ok(Codec::Bincode),
+            _ => Err(format!("unknown codec: {}", s)),
+        }
+    }
+}
+
+/// Encodes persons into the bytes stored in redis.
+///
+/// Every entry starts with a tag of its codec and a compression flag, so entries written by
+/// any codec can be read regardless of the configured one. Plain JSON is written untagged,
+/// as it was before the tags were introduced, and such entries are read as JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheCodec {
+    codec: Codec,
+    // payloads larger than this are deflated
+    compress_threshold: Option<usize>,
+}
+impl Default for CacheCodec {
+    fn default() -> Self {
+        Self::new(Codec::Json, None)
+    }
+}
+impl CacheCodec {
+    pub fn new(codec: Codec, compress_threshold: Option<usize>) -> Self {
+        Self {
+            codec,
+            compress_threshold,
+        }
+    }
+
+    pub fn encode(&self, person: &PersonDto) -> Result<Vec<u8>, CaoError> {
+        self.encode_value(person)
+    }
+    pub fn decode(&self, s: &[u8]) -> Result<PersonDto, CaoError> {
+        self.decode_value(s)
+    }
+    pub fn encode_list(&self, persons: &[(PersonId, PersonDto)]) -> Result<Vec<u8>, CaoError> {
+        self.encode_value(persons)
+    }
+    pub fn decode_list(&self, s: &[u8]) -> Result<Vec<(PersonId, PersonDto)>, CaoError> {
+        self.decode_value(s)
+    }
+
+    fn encode_value<T: Serialize>(&self, value: T) -> Result<Vec<u8>, CaoError> {
+        let payload = self
+            .codec
+            .serialize(&Envelope {
+                v: CACHE_FORMAT_VERSION,
+                person: value,
+            })
+            .map_err(|e| CaoError::Corrupted(format!("cannot serialize: {}", e)))?;
+
+        match self.compress_threshold {
+            Some(threshold) if payload.len() > threshold => {
+                let mut encoder = DeflateEncoder::new(
+                    vec![self.codec.tag(), FLAG_DEFLATE],
+                    Compression::default(),
+                );
+                encoder
+                    .write_all(&payload)
+                    .and_then(|_| encoder.finish())
+                    .map_err(|e| CaoError::Corrupted(format!("cannot compress: {}", e)))
+            }
+            _ if self.codec == Codec::Json => Ok(payload),
+            _ => {
+                let mut v = vec![self.codec.tag(), FLAG_RAW];
+                v.extend(payload);
+                Ok(v)
+            }
+        }
+    }
+
+    fn decode_value<T: DeserializeOwned>(&self, s: &[u8]) -> Result<T, CaoError> {
+        match s {
+            [b'{', ..] => decode_json(s),
+            [tag, flag, payload @ ..] => {
+                let codec = Codec::from_tag(*tag)
+                    .ok_or(CaoError::Corrupted(format!("unknown codec tag: {}", tag)))?;
+                let envelope = match *flag {
+                    FLAG_RAW => codec.deserialize(payload),
+                    FLAG_DEFLATE => {
+                        let mut v = vec![];
+                        DeflateDecoder::new(payload)
+                            .read_to_end(&mut v)
+                            .map_err(|e| {
+                                CaoError::Corrupted(format!("cannot decompress: {}", e))
+                            })?;
+                        codec.deserialize(&v)
+                    }
+                    _ => {
+                        return Err(CaoError::Corrupted(format!(
+                            "unknown compression flag: {}",
+                            flag
+                        )))
+                    }
+                }
+                .map_err(|e| CaoError::Corrupted(format!("cannot deserialize: {}", e)))?;
+
+                if envelope.v != CACHE_FORMAT_VERSION {
+                    return Err(CaoError::Corrupted(format!(
+                        "unsupported version: {} (expected {})",
+                        envelope.v, CACHE_FORMAT_VERSION
+                    )));
+                }
+                Ok(envelope.person)
+            }
+            _ => Err(CaoError::Corrupted("too short".to_string())),
+        }
+    }
+}
+
+// untagged JSON
+fn decode_json<T: DeserializeOwned>(s: &[u8]) -> Result<T, CaoError> {
+    #[derive(Deserialize)]
+    struct Version {
+        v: u32,
+    }
+
+    match serde_json::from_slice::<Version>(s) {
+        Ok(Version { v }) if v == CACHE_FORMAT_VERSION => serde_json::from_slice::<Envelope<T>>(s)
+            .map(|e| e.person)
+            .map_err(|e| CaoError::Corrupted(format!("cannot deserialize: {}", e))),
+        Ok(Version { v }) => Err(CaoError::Corrupted(format!(
+            "unsupported version: {} (expected {})",
+            v, CACHE_FORMAT_VERSION
+        ))),
+        // written before the envelope was introduced
+        Err(_) => serde_json::from_slice::<T>(s)
+            .map_err(|e| CaoError::Corrupted(format!("cannot deserialize: {}", e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::date;
+
+    fn person(data: &str) -> PersonDto {
+        PersonDto::new("Alice", date(2000, 1, 1), None, Some(data), 3)
+    }
+    fn codecs() -> Vec<CacheCodec> {
+        let mut result = vec![];
+        for codec in [Codec::Json, Codec::MessagePack, Codec::Bincode] {
+            result.push(CacheCodec::new(codec, None));
+            result.push(CacheCodec::new(codec, Some(64)));
+        }
+        result
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let small = person("Alice is here");
+        let large = person(&"Alice is here. ".repeat(100));
+
+        for codec in codecs() {
+            for p in [&small, &large] {
+                let s = codec.encode(p).unwrap();
+                assert_eq!(codec.decode(&s).as_ref(), Ok(p), "{:?}", codec);
+            }
+        }
+    }
+
+    #[test]
+    fn test_encode_decode_list() {
+        let persons = vec![
+            (uuid::Uuid::now_v7(), person("Alice is here")),
+            (uuid::Uuid::now_v7(), person(&"Alice is here. ".repeat(100))),
+        ];
+
+        for codec in codecs() {
+            let s = codec.encode_list(&persons).unwrap();
+            assert_eq!(codec.decode_list(&s), Ok(persons.clone()), "{:?}", codec);
+        }
+    }
+
+    #[test]
+    fn test_plain_json_is_untagged() {
+        let s = CacheCodec::default()
+            .encode(&person("Alice is here"))
+            .unwrap();
+        assert!(s.starts_with(br#"{"v":1,"#));
+    }
+
+    #[test]
+    fn test_compress_only_large_payload() {
+        let codec = CacheCodec::new(Codec::MessagePack, Some(64));
+
+        let s = codec.encode(&person("Alice is here")).unwrap();
+        assert_eq!(s[..2], [TAG_MSGPACK, FLAG_RAW]);
+
+        let large = person(&"Alice is here. ".repeat(100));
+        let s = codec.encode(&large).unwrap();
+        assert_eq!(s[..2], [TAG_MSGPACK, FLAG_DEFLATE]);
+        assert!(s.len() < large.data.as_ref().unwrap().len());
+    }
+
+    #[test]
+    fn test_decode_other_codec() {
+        // コーデックを切り替えても既存のエントリは読める
+        let p = person(&"Alice is here. ".repeat(100));
+        for writer in codecs() {
+            let s = writer.encode(&p).unwrap();
+            for reader in codecs() {
+                assert_eq!(reader.decode(&s), Ok(p.clone()), "{:?}", writer);
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_legacy() {
+        // envelope が無い頃の形式も読める
+        let s = serde_json::to_string(&person("Alice is here")).unwrap();
+        assert_eq!(
+            CacheCodec::default().decode(s.as_bytes()),
+            Ok(person("Alice is here"))
+        );
+    }
+
+    #[test]
+    fn test_decode_corrupted() {
+        let codec = CacheCodec::default();
+
+        assert!(matches!(codec.decode(b""), Err(CaoError::Corrupted(_))));
+        assert!(matches!(
+            codec.decode(b"garbage"),
+            Err(CaoError::Corrupted(_))
+        ));
+        assert!(matches!(
+            codec.decode(br#"{"v":1,"person":{"name":"Alice"}}"#),
+            Err(CaoError::Corrupted(_))
+        ));
+        assert!(matches!(
+            codec.decode(br#"{"v":999,"person":{}}"#),
+            Err(CaoError::Corrupted(_))
+        ));
+        assert!(matches!(
+            codec.decode(&[TAG_BINCODE, FLAG_DEFLATE, 0xff, 0xff]),
+            Err(CaoError::Corrupted(_))
+        ));
+        assert!(matches!(
+            codec.decode(&[TAG_MSGPACK, 9, 0x00]),
+            Err(CaoError::Corrupted(_))
+        ));
+    }
+
+    #[test]
+    fn test_decode_unknown_version() {
+        for codec in [Codec::Json, Codec::MessagePack, Codec::Bincode] {
+            let mut s = vec![codec.tag(), FLAG_RAW];
+            s.extend(
+                codec
+                    .serialize(&Envelope {
+                        v: 999,
+                        person: &person("Alice is here"),
+                    })
+                    .unwrap(),
+            );
+            assert!(matches!(
+                CacheCodec::new(codec, None).decode(&s),
+                Err(CaoError::Corrupted(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_parse_codec() {
+        assert_eq!("json".parse(), Ok(Codec::Json));
+        assert_eq!("MsgPack".parse(), Ok(Codec::MessagePack));
+        assert_eq!("bincode".parse(), Ok(Codec::Bincode));
+        assert!("xml".parse::<Codec>().is_err());
+    }
+}
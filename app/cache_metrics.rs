@@ -0,0 +1,421 @@
+use log::trace;
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tx_rs::Tx;
+
+use crate::cache::{CaoError, LockToken, PersonCao};
+use crate::domain::PersonId;
+use crate::dto::PersonDto;
+
+// upper bounds of the latency buckets in microseconds
+const BUCKETS: [u64; 10] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 50_000, 250_000, 1_000_000,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheOp {
+    Find,
+    Load,
+    Unload,
+}
+impl CacheOp {
+    const ALL: [CacheOp; 3] = [CacheOp::Find, CacheOp::Load, CacheOp::Unload];
+
+    fn name(&self) -> &'static str {
+        match self {
+            CacheOp::Find => "find",
+            CacheOp::Load => "load",
+            CacheOp::Unload => "unload",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Histogram {
+    // not cumulative, the last one is for the larger than any bucket
+    pub counts: [u64; BUCKETS.len() + 1],
+    pub count: u64,
+    pub sum: Duration,
+}
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let micros = elapsed.as_micros() as u64;
+        let i = BUCKETS
+            .iter()
+            .position(|b| micros <= *b)
+            .unwrap_or(BUCKETS.len());
+        self.counts[i] += 1;
+        self.count += 1;
+        self.sum += elapsed;
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub load_failures: u64,
+    // removed by unload, including the corrupted ones
+    pub evictions: u64,
+    pub decode_errors: u64,
+    pub latency: [Histogram; 3],
+}
+impl CacheStats {
+    pub fn latency(&self, op: CacheOp) -> &Histogram {
+        &self.latency[op as usize]
+    }
+}
+
+/// Counters of the cache shared by every clone, since the app is single threaded.
+#[derive(Debug, Clone, Default)]
+pub struct CacheMetrics {
+    stats: Rc<RefCell<CacheStats>>,
+}
+impl CacheMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn snapshot(&self) -> CacheStats {
+        self.stats.borrow().clone()
+    }
+
+    fn observe(&self, op: CacheOp, elapsed: Duration) {
+        self.stats.borrow_mut().latency[op as usize].observe(elapsed);
+    }
+
+    /// Renders in the prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let stats = self.stats.borrow();
+        let mut s = String::new();
+
+        for (name, help, v) in [
+            ("hits", "persons found in the cache", stats.hits),
+            ("misses", "persons not found in the cache", stats.misses),
+            (
+                "load_failures",
+                "failures to load persons into the cache",
+                stats.load_failures,
+            ),
+            (
+                "evictions",
+                "persons removed from the cache",
+                stats.evictions,
+            ),
+            (
+                "decode_errors",
+                "corrupted persons found in the cache",
+                stats.decode_errors,
+            ),
+        ] {
+            let _ = writeln!(s, "# HELP person_cache_{}_total {}", name, help);
+            let _ = writeln!(s, "# TYPE person_cache_{}_total counter", name);
+            let _ = writeln!(s, "person_cache_{}_total {}", name, v);
+        }
+
+        let _ = writeln!(
+            s,
+            "# HELP person_cache_op_duration_seconds latency of the cache operations"
+        );
+        let _ = writeln!(s, "# TYPE person_cache_op_duration_seconds histogram");
+        for op in CacheOp::ALL {
+            let h = stats.latency(op);
+            let mut cumulative = 0;
+            for (i, b) in BUCKETS.iter().enumerate() {
+                cumulative += h.counts[i];
+                let _ = writeln!(
+                    s,
+                    "person_cache_op_duration_seconds_bucket{{op=\"{}\",le=\"{}\"}} {}",
+                    op.name(),
+                    *b as f64 / 1_000_000.0,
+                    cumulative
+                );
+            }
+            let _ = writeln!(
+                s,
+                "person_cache_op_duration_seconds_bucket{{op=\"{}\",le=\"+Inf\"}} {}",
+                op.name(),
+                h.count
+            );
+            let _ = writeln!(
+                s,
+                "person_cache_op_duration_seconds_sum{{op=\"{}\"}} {}",
+                op.name(),
+                h.sum.as_secs_f64()
+            );
+            let _ = writeln!(
+                s,
+                "person_cache_op_duration_seconds_count{{op=\"{}\"}} {}",
+                op.name(),
+                h.count
+            );
+        }
+
+        s
+    }
+}
+
+/// Counts and times the operations of the wrapped cache.
+#[derive(Debug, Clone)]
+pub struct MeteredPersonCao<C> {
+    inner: C,
+    metrics: CacheMetrics,
+}
+impl<C> MeteredPersonCao<C> {
+    pub fn new(inner: C, metrics: CacheMetrics) -> Self {
+        Self { inner, metrics }
+    }
+}
+
+impl<Ctx, C: PersonCao<Ctx>> PersonCao<Ctx> for MeteredPersonCao<C> {
+    fn get_conn(&self) -> Result<Ctx, CaoError> {
+        self.inner.get_conn()
+    }
+
+    fn run_tx<T, F>(&self, f: F) -> Result<T, CaoError>
+    where
+        F: tx_rs::Tx<Ctx, Item = T, Err = CaoError>,
+    {
+        self.inner.run_tx(f)
+    }
+
+    fn find(&self, id: PersonId) -> impl tx_rs::Tx<Ctx, Item = Option<PersonDto>, Err = CaoError> {
+        tx_rs::with_tx(move |ctx: &mut Ctx| {
+            let start = Instant::now();
+            let result = self.inner.find(id).run(ctx);
+            self.metrics.observe(CacheOp::Find, start.elapsed());
+
+            let mut stats = self.metrics.stats.borrow_mut();
+            match &result {
+                Ok(Some(_)) => stats.hits += 1,
+                Ok(None) => stats.misses += 1,
+                Err(CaoError::Corrupted(_)) => {
+                    // corrupted one has been evicted by the cache
+                    stats.misses += 1;
+                    stats.decode_errors += 1;
+                    stats.evictions += 1;
+                }
+                Err(_) => {}
+            }
+            trace!("cache stats: hits={} misses={}", stats.hits, stats.misses);
+            result
+        })
+    }
+    fn peek(&self, id: PersonId) -> impl tx_rs::Tx<Ctx, Item = Option<PersonDto>, Err = CaoError> {
+        self.inner.peek(id)
+    }
+    fn load(
+        &self,
+        id: PersonId,
+        person: &PersonDto,
+    ) -> impl tx_rs::Tx<Ctx, Item = (), Err = CaoError> {
+        tx_rs::with_tx(move |ctx: &mut Ctx| {
+            let start = Instant::now();
+            let result = self.inner.load(id, person).run(ctx);
+            self.metrics.observe(CacheOp::Load, start.elapsed());
+
+            if result.is_err() {
+                self.metrics.stats.borrow_mut().load_failures += 1;
+            }
+            result
+        })
+    }
+    fn unload(&self, id: PersonId) -> impl tx_rs::Tx<Ctx, Item = (), Err = CaoError> {
+        tx_rs::with_tx(move |ctx: &mut Ctx| {
+            let start = Instant::now();
+            let result = self.inner.unload(id).run(ctx);
+            self.metrics.observe(CacheOp::Unload, start.elapsed());
+
+            if result.is_ok() {
+                self.metrics.stats.borrow_mut().evictions += 1;
+            }
+            result
+        })
+    }
+    fn ids(&self) -> impl tx_rs::Tx<Ctx, Item = Vec<PersonId>, Err = CaoError> {
+        self.inner.ids()
+    }
+
+    fn lock(
+        &self,
+        id: PersonId,
+        ttl: Duration,
+    ) -> impl tx_rs::Tx<Ctx, Item = Option<LockToken>, Err = CaoError> {
+        self.inner.lock(id, ttl)
+    }
+    fn unlock(
+        &self,
+        id: PersonId,
+        token: LockToken,
+    ) -> impl tx_rs::Tx<Ctx, Item = (), Err = CaoError> {
+        self.inner.unlock(id, token)
+    }
+
+    fn generation(&self) -> impl tx_rs::Tx<Ctx, Item = u64, Err = CaoError> {
+        self.inner.generation()
+    }
+    fn bump_generation(&self) -> impl tx_rs::Tx<Ctx, Item = (), Err = CaoError> {
+        self.inner.bump_generation()
+    }
+    fn find_all(
+        &self,
+        generation: u64,
+    ) -> impl tx_rs::Tx<Ctx, Item = Option<Vec<(PersonId, PersonDto)>>, Err = CaoError> {
+        self.inner.find_all(generation)
+    }
+    fn load_all(
+        &self,
+        generation: u64,
+        persons: &[(PersonId, PersonDto)],
+    ) -> impl tx_rs::Tx<Ctx, Item = (), Err = CaoError> {
+        self.inner.load_all(generation, persons)
+    }
+    fn unload_all(&self, generation: u64) -> impl tx_rs::Tx<Ctx, Item = (), Err = CaoError> {
+        self.inner.unload_all(generation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::domain::date;
+    use crate::lru_cache::LruPersonCao;
+
+    struct CorruptedPersonCao;
+    impl PersonCao<()> for CorruptedPersonCao {
+        fn get_conn(&self) -> Result<(), CaoError> {
+            Ok(())
+        }
+        fn run_tx<T, F>(&self, f: F) -> Result<T, CaoError>
+        where
+            F: tx_rs::Tx<(), Item = T, Err = CaoError>,
+        {
+            f.run(&mut ())
+        }
+        fn find(
+            &self,
+            _id: PersonId,
+        ) -> impl tx_rs::Tx<(), Item = Option<PersonDto>, Err = CaoError> {
+            tx_rs::with_tx(|&mut ()| Err(CaoError::Corrupted("corrupted cao".to_string())))
+        }
+        fn load(
+            &self,
+            _id: PersonId,
+            _person: &PersonDto,
+        ) -> impl tx_rs::Tx<(), Item = (), Err = CaoError> {
+            tx_rs::with_tx(|&mut ()| Err(CaoError::Unavailable("corrupted cao".to_string())))
+        }
+        fn unload(&self, _id: PersonId) -> impl tx_rs::Tx<(), Item = (), Err = CaoError> {
+            tx_rs::with_tx(|&mut ()| Ok(()))
+        }
+        fn ids(&self) -> impl tx_rs::Tx<(), Item = Vec<PersonId>, Err = CaoError> {
+            tx_rs::with_tx(|&mut ()| Ok(vec![]))
+        }
+    }
+
+    #[test]
+    fn test_count_hits_and_misses() {
+        let metrics = CacheMetrics::new();
+        let cao = MeteredPersonCao::new(
+            LruPersonCao::new(NonZeroUsize::new(2).unwrap()),
+            metrics.clone(),
+        );
+        let id = Uuid::now_v7();
+        let person = PersonDto::new("Alice", date(2000, 1, 1), None, Some("Alice"), 0);
+
+        cao.run_tx(cao.find(id)).unwrap();
+        cao.run_tx(cao.load(id, &person)).unwrap();
+        cao.run_tx(cao.find(id)).unwrap();
+        cao.run_tx(cao.find(id)).unwrap();
+        cao.run_tx(cao.unload(id)).unwrap();
+
+        let stats = metrics.snapshot();
+        assert_eq!(stats.hits, 2);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.load_failures, 0);
+        assert_eq!(stats.evictions, 1);
+        assert_eq!(stats.decode_errors, 0);
+        assert_eq!(stats.latency(CacheOp::Find).count, 3);
+        assert_eq!(stats.latency(CacheOp::Load).count, 1);
+        assert_eq!(stats.latency(CacheOp::Unload).count, 1);
+    }
+
+    #[test]
+    fn test_peek_not_counted() {
+        let metrics = CacheMetrics::new();
+        let cao = MeteredPersonCao::new(
+            LruPersonCao::new(NonZeroUsize::new(2).unwrap()),
+            metrics.clone(),
+        );
+        let id = Uuid::now_v7();
+        let person = PersonDto::new("Alice", date(2000, 1, 1), None, Some("Alice"), 0);
+
+        cao.run_tx(cao.find(id)).unwrap();
+        // ロード待ちの間に何度見ても数えない
+        assert_eq!(cao.run_tx(cao.peek(id)), Ok(None));
+        assert_eq!(cao.run_tx(cao.peek(id)), Ok(None));
+        cao.run_tx(cao.load(id, &person)).unwrap();
+        assert_eq!(cao.run_tx(cao.peek(id)), Ok(Some(person)));
+
+        let stats = metrics.snapshot();
+        assert_eq!(stats.hits, 0);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.latency(CacheOp::Find).count, 1);
+    }
+
+    #[test]
+    fn test_count_failures() {
+        let metrics = CacheMetrics::new();
+        let cao = MeteredPersonCao::new(CorruptedPersonCao, metrics.clone());
+        let id = Uuid::now_v7();
+        let person = PersonDto::new("Alice", date(2000, 1, 1), None, Some("Alice"), 0);
+
+        assert!(cao.run_tx(cao.find(id)).is_err());
+        assert!(cao.run_tx(cao.load(id, &person)).is_err());
+
+        let stats = metrics.snapshot();
+        assert_eq!(stats.hits, 0);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.load_failures, 1);
+        assert_eq!(stats.evictions, 1);
+        assert_eq!(stats.decode_errors, 1);
+    }
+
+    #[test]
+    fn test_histogram() {
+        let mut h = Histogram::default();
+        h.observe(Duration::from_micros(50));
+        h.observe(Duration::from_micros(100));
+        h.observe(Duration::from_millis(3));
+        h.observe(Duration::from_secs(2));
+
+        assert_eq!(h.counts[0], 2, "<= 100us");
+        assert_eq!(h.counts[5], 1, "<= 5ms");
+        assert_eq!(h.counts[BUCKETS.len()], 1, "> 1s");
+        assert_eq!(h.count, 4);
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = CacheMetrics::new();
+        metrics.stats.borrow_mut().hits = 3;
+        metrics.observe(CacheOp::Find, Duration::from_micros(200));
+
+        let s = metrics.render();
+        assert!(s.contains("person_cache_hits_total 3\n"));
+        assert!(s.contains("person_cache_misses_total 0\n"));
+        assert!(
+            s.contains("person_cache_op_duration_seconds_bucket{op=\"find\",le=\"0.0001\"} 0\n")
+        );
+        assert!(
+            s.contains("person_cache_op_duration_seconds_bucket{op=\"find\",le=\"0.00025\"} 1\n")
+        );
+        assert!(s.contains("person_cache_op_duration_seconds_bucket{op=\"find\",le=\"+Inf\"} 1\n"));
+        assert!(s.contains("person_cache_op_duration_seconds_count{op=\"load\"} 0\n"));
+    }
+}
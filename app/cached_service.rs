@@ -1,18 +1,198 @@
 use chrono::NaiveDate;
 use log::{error, trace, warn};
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::cache::PersonCao;
+use crate::cache::{CaoError, LockToken, PersonCao};
 use crate::domain::PersonId;
 use crate::dto::PersonDto;
+use crate::error_detail::ErrorDetail;
+use crate::invalidation::InvalidationPublisher;
 use crate::location;
 use crate::reporter::{Level, Reporter};
 use crate::service::{InvalidErrorKind, PersonOutputBoundary, PersonService, ServiceError};
 
+// how long the loader may hold the lock
+const LOCK_TTL: Duration = Duration::from_secs(5);
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+enum Waited {
+    Loaded(PersonDto),
+    Locked(LockToken),
+    GaveUp,
+}
+
+// wait for the loader to load the person into the cache.
+// if the loader released the lock without loading (e.g. not found), take over it.
+// the miss has been counted by the find before, so the polls aren't.
+fn wait_for_loader<Conn>(cao: &impl PersonCao<Conn>, id: PersonId, timeout: Duration) -> Waited {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        thread::sleep(LOCK_POLL_INTERVAL);
+
+        match cao.run_tx(cao.peek(id)) {
+            Ok(Some(p)) => return Waited::Loaded(p),
+            Ok(None) => {}
+            Err(_) => return Waited::GaveUp,
+        }
+        match cao.run_tx(cao.lock(id, LOCK_TTL)) {
+            Ok(Some(token)) => return Waited::Locked(token),
+            Ok(None) => {}
+            Err(_) => return Waited::GaveUp,
+        }
+    }
+    Waited::GaveUp
+}
+
+fn unlock<Conn>(cao: &impl PersonCao<Conn>, id: PersonId, token: LockToken) {
+    if let Err(e) = cao.run_tx(cao.unlock(id, token)) {
+        // 期限が来れば消えるのでエラーを返す必要はない
+        warn!("failed to unlock person in cache: {}", e);
+    } else {
+        trace!("unlock person in cache: {}", id);
+    }
+}
+
+fn load_to_cache<'a, Conn>(
+    cao: &impl PersonCao<Conn>,
+    reporter: &impl Reporter<'a>,
+    id: PersonId,
+    person: &PersonDto,
+) -> Result<(), CaoError> {
+    if let Err(e) = cao.run_tx(cao.load(id, person)) {
+        warn!("failed to load person to cache: {}", e);
+        report_unavailable(reporter, &e, "cache service not available");
+        return Err(e);
+    }
+    trace!("load person to cache: {:?}", person);
+    Ok(())
+}
+
+fn unload_from_cache<'a, Conn>(
+    cao: &impl PersonCao<Conn>,
+    reporter: &impl Reporter<'a>,
+    id: PersonId,
+) {
+    if let Err(e) = cao.run_tx(cao.unload(id)) {
+        // ここはエラーを返す必要はない
+        warn!("failed to unload person from cache: {}", e);
+        report_unavailable(reporter, &e, "cache service not available");
+    } else {
+        trace!("unload from cache: {}", id);
+    }
+}
+
+// the other instances may still have the person in their local cache
+fn publish_invalidation<'a>(
+    publisher: &impl InvalidationPublisher,
+    reporter: &impl Reporter<'a>,
+    id: PersonId,
+) {
+    if let Err(e) = publisher.publish(id) {
+        warn!("failed to publish invalidation: {}", e);
+        report_unavailable(reporter, &e, "cache invalidation not available");
+    } else {
+        trace!("invalidation published: {}", id);
+    }
+}
+
+// the snapshots of list_all taken before the mutation are never served again
+fn bump_generation<'a, Conn>(cao: &impl PersonCao<Conn>, reporter: &impl Reporter<'a>) {
+    let Err(e) = cao.run_tx(cao.bump_generation()) else {
+        trace!("bump generation in cache");
+        return;
+    };
+    // ここはエラーを返す必要はない
+    warn!("failed to bump generation in cache: {}", e);
+    report_unavailable(reporter, &e, "cache service not available");
+
+    // the snapshot of the generation would still be served, so it's dropped instead
+    match cao
+        .run_tx(cao.generation())
+        .and_then(|g| cao.run_tx(cao.unload_all(g)))
+    {
+        Ok(()) => trace!("unload snapshot from cache"),
+        // expires after a while
+        Err(e) => warn!("failed to unload snapshot from cache: {}", e),
+    }
+}
+
+// an open circuit has been reported once by the breaker, so it isn't reported on every call
+fn report_unavailable<'a>(reporter: &impl Reporter<'a>, e: &CaoError, message: &str) {
+    if matches!(e, CaoError::CircuitOpen(_)) {
+        return;
+    }
+    let error = ErrorDetail::of(e);
+    if let Err(e) = reporter.send_error(Level::Error, "admin", message, &error, location!()) {
+        error!("reporter service not available: {}", e);
+    }
+}
+
+/// How the cache follows the mutations.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CachePolicy {
+    // the mutated person is written to the cache.
+    // the mutations which don't return the person (e.g. death) invalidate it instead.
+    #[default]
+    WriteThrough,
+    // the mutated person is removed from the cache, and loaded on the next find
+    InvalidateOnWrite,
+    // the cache is never filled by the service, only by warm-up and audit.
+    // the mutated person is still removed from it, so that it's never served stale.
+    ReadOnly,
+}
+impl CachePolicy {
+    // whether the persons read from the db are loaded to the cache
+    fn fills_on_read(&self) -> bool {
+        *self != CachePolicy::ReadOnly
+    }
+}
+impl FromStr for CachePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "write-through" => Ok(CachePolicy::WriteThrough),
+            "invalidate-on-write" => Ok(CachePolicy::InvalidateOnWrite),
+            "read-only" => Ok(CachePolicy::ReadOnly),
+            _ => Err(format!("unknown cache policy: {}", s)),
+        }
+    }
+}
+
+/// Differences between the cache and the db found by `audit`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CacheAudit {
+    // cached, but differs from the db
+    pub stale: Vec<PersonId>,
+    // cached, but deleted from the db
+    pub orphaned: Vec<PersonId>,
+    // in the db, but not cached
+    pub missing: Vec<PersonId>,
+}
+impl CacheAudit {
+    pub fn is_consistent(&self) -> bool {
+        self.stale.is_empty() && self.orphaned.is_empty() && self.missing.is_empty()
+    }
+}
+
 pub trait PersonCachedService<'a, Conn, Ctx>: PersonService<'a, Ctx> {
     type C: PersonCao<Conn>;
+    type P: InvalidationPublisher;
 
     fn get_cao(&self) -> Self::C;
+    fn get_publisher(&self) -> Self::P;
+
+    fn get_cache_policy(&self) -> CachePolicy {
+        CachePolicy::default()
+    }
+    // how long to wait for the other loader on cache miss, before going to the db by itself
+    fn get_lock_timeout(&self) -> Duration {
+        Duration::from_secs(2)
+    }
 
     fn register(
         &'a mut self,
@@ -28,27 +208,26 @@ pub trait PersonCachedService<'a, Conn, Ctx>: PersonService<'a, Ctx> {
             death_date,
             data
         );
+        let policy = self.get_cache_policy();
         let cao = self.get_cao();
+        let publisher = self.get_publisher();
         let reporter = self.get_reporter();
 
         let result = PersonService::register(self, name, birth_date, death_date, data);
         trace!("register person to db: {:?}", result);
 
         if let Ok((id, person)) = &result {
-            if let Err(e) = cao.run_tx(cao.load(*id, &person)) {
-                // ここはエラーを返す必要はない
-                warn!("failed to load person to cache: {}", e);
-                if let Err(e) = reporter.send_report(
-                    Level::Error,
-                    "admin",
-                    "cache service not available",
-                    location!(),
-                ) {
-                    error!("reporter service not available: {}", e);
+            bump_generation(&cao, &reporter);
+            match policy {
+                CachePolicy::WriteThrough => {
+                    // ここはエラーを返す必要はない
+                    let _ = load_to_cache(&cao, &reporter, *id, person);
+                }
+                CachePolicy::InvalidateOnWrite | CachePolicy::ReadOnly => {
+                    unload_from_cache(&cao, &reporter, *id);
+                    publish_invalidation(&publisher, &reporter, *id);
                 }
             }
-
-            trace!("load person to cache: {:?}", person);
         }
 
         result
@@ -56,36 +235,84 @@ pub trait PersonCachedService<'a, Conn, Ctx>: PersonService<'a, Ctx> {
 
     fn find(&'a mut self, id: PersonId) -> Result<Option<PersonDto>, ServiceError> {
         trace!("cached find: {}", id);
+        let policy = self.get_cache_policy();
         let cao = self.get_cao();
         let reporter = self.get_reporter();
 
         // if the person is found in the cache, return it
-        if let Ok(Some(p)) = cao.run_tx(cao.find(id)) {
-            trace!("cache hit!: {}", id);
-            return Ok(Some(p));
-        }
-        trace!("cache miss!: {}", id);
-
-        let result = PersonService::find(self, id)?;
-        trace!("find person in db: {:?}", result);
-
-        // if the person is found in the db, load it to the cache
-        if let Some(person) = &result {
-            if let Err(e) = cao.run_tx(cao.load(id, &person)) {
-                // ここはエラーを返す必要はない
-                warn!("failed to load person to cache: {}", e);
+        let mut available = true;
+        match cao.run_tx(cao.find(id)) {
+            Ok(Some(p)) => {
+                trace!("cache hit!: {}", id);
+                return Ok(Some(p));
+            }
+            Ok(None) => {}
+            Err(CaoError::Corrupted(e)) => {
+                // broken entry has been evicted by cao, so it's just a miss
+                warn!("corrupted person in cache: {}", e);
                 if let Err(e) = reporter.send_report(
-                    Level::Error,
+                    Level::Warn,
                     "admin",
-                    "cache service not available",
+                    "cache payload corrupted",
                     location!(),
                 ) {
                     error!("reporter service not available: {}", e);
                 }
-            } else {
-                trace!("load person to cache: {:?}", person);
+            }
+            Err(e) => {
+                trace!("cache not available: {}", e);
+                available = false;
             }
         }
+        trace!("cache miss!: {}", id);
+
+        // neither lock nor load the cache which is down, the db is enough
+        if !available || !policy.fills_on_read() {
+            return PersonService::find(self, id);
+        }
+
+        // only one of the concurrent finds goes to the db, the others wait for it
+        let token = match cao.run_tx(cao.lock(id, LOCK_TTL)) {
+            Ok(Some(token)) => Some(token),
+            Ok(None) => {
+                trace!("wait for the other loader: {}", id);
+                match wait_for_loader(&cao, id, self.get_lock_timeout()) {
+                    Waited::Loaded(p) => {
+                        trace!("loaded by the other: {}", id);
+                        return Ok(Some(p));
+                    }
+                    Waited::Locked(token) => Some(token),
+                    Waited::GaveUp => {
+                        warn!("gave up waiting for the other loader: {}", id);
+                        None
+                    }
+                }
+            }
+            Err(e) => {
+                trace!("cache lock not available: {}", e);
+                None
+            }
+        };
+
+        let result = match PersonService::find(self, id) {
+            Ok(result) => result,
+            Err(e) => {
+                if let Some(token) = token {
+                    unlock(&cao, id, token);
+                }
+                return Err(e);
+            }
+        };
+        trace!("find person in db: {:?}", result);
+
+        // if the person is found in the db, load it to the cache
+        if let Some(person) = &result {
+            // ここはエラーを返す必要はない
+            let _ = load_to_cache(&cao, &reporter, id, person);
+        }
+        if let Some(token) = token {
+            unlock(&cao, id, token);
+        }
 
         Ok(result)
     }
@@ -102,83 +329,118 @@ pub trait PersonCachedService<'a, Conn, Ctx>: PersonService<'a, Ctx> {
         }
 
         trace!("cached batch import: {:?}", persons);
+        let policy = self.get_cache_policy();
         let cao = self.get_cao();
+        let publisher = self.get_publisher();
         let reporter = self.get_reporter();
 
         let ids = PersonService::batch_import(self, persons.clone().into_iter(), out_port.clone())?;
 
-        // load all persons to the cache
-        for (id, person) in ids.iter().zip(persons.iter()) {
-            // ここはエラーを返す必要はない
-            if let Err(e) = cao.run_tx(cao.load(*id, &person)) {
-                warn!("failed to load person to cache: {}", e);
-                if let Err(e) = reporter.send_report(
-                    Level::Error,
-                    "admin",
-                    "cache service not available",
-                    location!(),
-                ) {
-                    error!("reporter service not available: {}", e);
+        bump_generation(&cao, &reporter);
+        match policy {
+            CachePolicy::WriteThrough => {
+                // load all persons to the cache
+                for (id, person) in ids.iter().zip(persons.iter()) {
+                    // ここはエラーを返す必要はない
+                    if load_to_cache(&cao, &reporter, *id, person).is_err() {
+                        return Ok(ids);
+                    }
+                }
+                trace!("load persons to cache: {:?}", ids);
+            }
+            CachePolicy::InvalidateOnWrite | CachePolicy::ReadOnly => {
+                for id in ids.iter() {
+                    unload_from_cache(&cao, &reporter, *id);
+                    publish_invalidation(&publisher, &reporter, *id);
                 }
-                return Ok(ids);
             }
         }
-        trace!("load persons to cache: {:?}", ids);
 
         Ok(ids)
     }
 
     fn list_all(&'a mut self) -> Result<Vec<(PersonId, PersonDto)>, ServiceError> {
         trace!("cached list all");
+        let policy = self.get_cache_policy();
         let cao = self.get_cao();
         let reporter = self.get_reporter();
 
+        if !policy.fills_on_read() {
+            return PersonService::list_all(self);
+        }
+
+        // the generation is read before the db, so that the snapshot is stored
+        // under the old one if a mutation happens while reading the db
+        let generation = match cao.run_tx(cao.generation()) {
+            Ok(generation) => Some(generation),
+            Err(e) => {
+                trace!("cache not available: {}", e);
+                None
+            }
+        };
+        if let Some(generation) = generation {
+            match cao.run_tx(cao.find_all(generation)) {
+                Ok(Some(persons)) => {
+                    trace!("snapshot hit!: {}", generation);
+                    return Ok(persons);
+                }
+                Ok(None) => {}
+                Err(CaoError::Corrupted(e)) => {
+                    // broken snapshot has been evicted by cao, so it's just a miss
+                    warn!("corrupted snapshot in cache: {}", e);
+                    if let Err(e) = reporter.send_report(
+                        Level::Warn,
+                        "admin",
+                        "cache payload corrupted",
+                        location!(),
+                    ) {
+                        error!("reporter service not available: {}", e);
+                    }
+                }
+                Err(e) => {
+                    trace!("cache not available: {}", e);
+                }
+            }
+            trace!("snapshot miss!: {}", generation);
+        }
+
         let result = PersonService::list_all(self)?;
 
         // load all persons to the cache
         for (id, person) in result.iter() {
             // ここはエラーを返す必要はない
-            if let Err(e) = cao.run_tx(cao.load(*id, &person)) {
-                warn!("failed to load person to cache: {}", e);
-                if let Err(e) = reporter.send_report(
-                    Level::Error,
-                    "admin",
-                    "cache service not available",
-                    location!(),
-                ) {
-                    error!("reporter service not available: {}", e);
-                }
+            if load_to_cache(&cao, &reporter, *id, person).is_err() {
                 return Ok(result);
             }
         }
         trace!("load all persons to cache");
 
+        if let Some(generation) = generation {
+            if let Err(e) = cao.run_tx(cao.load_all(generation, &result)) {
+                // ここはエラーを返す必要はない
+                warn!("failed to load snapshot to cache: {}", e);
+                report_unavailable(&reporter, &e, "cache service not available");
+            } else {
+                trace!("load snapshot to cache: {}", generation);
+            }
+        }
+
         Ok(result)
     }
 
     fn death(&'a mut self, id: PersonId, death_date: NaiveDate) -> Result<(), ServiceError> {
         trace!("cached death: {} {}", id, death_date);
         let cao = self.get_cao();
+        let publisher = self.get_publisher();
         let reporter = self.get_reporter();
 
         let _ = PersonService::death(self, id, death_date)?;
         trace!("update death date in db: {} {}", id, death_date);
 
-        // even if delete from db failed below, this cache clear is not a matter.
-        if let Err(e) = cao.run_tx(cao.unload(id)) {
-            // ここはエラーを返す必要はない
-            warn!("failed to unload person from cache: {}", e);
-            if let Err(e) = reporter.send_report(
-                Level::Error,
-                "admin",
-                "cache service not available",
-                location!(),
-            ) {
-                error!("reporter service not available: {}", e);
-            }
-        } else {
-            trace!("unload from cache: {}", id);
-        }
+        // death doesn't return the updated person, so even write-through invalidates it
+        unload_from_cache(&cao, &reporter, id);
+        bump_generation(&cao, &reporter);
+        publish_invalidation(&publisher, &reporter, id);
 
         Ok(())
     }
@@ -186,29 +448,112 @@ pub trait PersonCachedService<'a, Conn, Ctx>: PersonService<'a, Ctx> {
     fn unregister(&'a mut self, id: PersonId) -> Result<(), ServiceError> {
         trace!("cached unregister: {}", id);
         let cao = self.get_cao();
+        let publisher = self.get_publisher();
         let reporter = self.get_reporter();
 
         // even if delete from db failed below, this cache clear is not a matter.
-        if let Err(e) = cao.run_tx(cao.unload(id)) {
-            // ここはエラーを返す必要はない
-            warn!("failed to unload person from cache: {}", e);
-            if let Err(e) = reporter.send_report(
-                Level::Error,
-                "admin",
-                "cache service not available",
-                location!(),
-            ) {
-                error!("reporter service not available: {}", e);
-            }
-        } else {
-            trace!("unload from cache: {}", id);
-        }
+        unload_from_cache(&cao, &reporter, id);
 
         let result = PersonService::unregister(self, id);
         trace!("delete from db: {}", id);
 
+        if result.is_ok() {
+            bump_generation(&cao, &reporter);
+            publish_invalidation(&publisher, &reporter, id);
+        }
+
         result
     }
+
+    // load persons (all of them without ids) from the db into the cache, e.g. at deploy time
+    fn warm_up(&'a mut self, ids: Option<&[PersonId]>) -> Result<usize, ServiceError> {
+        trace!("cached warm up: {:?}", ids);
+        let cao = self.get_cao();
+        let reporter = self.get_reporter();
+
+        let persons = PersonService::list_all(self)?;
+
+        let mut count = 0;
+        for (id, person) in persons
+            .iter()
+            .filter(|(id, _)| ids.is_none_or(|ids| ids.contains(id)))
+        {
+            // warm up しかしないのでエラーを返す
+            if let Err(e) = load_to_cache(&cao, &reporter, *id, person) {
+                return Err(ServiceError::ServiceUnavailable(e.to_string()));
+            }
+            count += 1;
+        }
+        trace!("load {} persons to cache", count);
+
+        Ok(count)
+    }
+
+    // compare the cache with the db, and make the cache follow the db if repair is true
+    fn audit(&'a mut self, repair: bool) -> Result<CacheAudit, ServiceError> {
+        trace!("cached audit: repair={}", repair);
+        let cao = self.get_cao();
+        let reporter = self.get_reporter();
+        let unavailable = |e: CaoError| {
+            warn!("cache not available: {}", e);
+            report_unavailable(&reporter, &e, "cache service not available");
+            ServiceError::ServiceUnavailable(e.to_string())
+        };
+
+        let persons: HashMap<PersonId, PersonDto> =
+            PersonService::list_all(self)?.into_iter().collect();
+
+        let mut audit = CacheAudit::default();
+        let mut cached = HashSet::new();
+        for id in cao.run_tx(cao.ids()).map_err(unavailable)? {
+            let p = match cao.run_tx(cao.find(id)) {
+                Ok(None) => continue,
+                Ok(Some(p)) => Some(p),
+                // corrupted entry has been evicted already, but it was wrong anyway
+                Err(CaoError::Corrupted(_)) => None,
+                Err(e) => return Err(unavailable(e)),
+            };
+            cached.insert(id);
+            match persons.get(&id) {
+                Some(q) if p.as_ref() == Some(q) => {}
+                Some(_) => audit.stale.push(id),
+                None => audit.orphaned.push(id),
+            }
+        }
+        audit.missing = persons
+            .keys()
+            .filter(|id| !cached.contains(id))
+            .cloned()
+            .collect();
+        audit.stale.sort();
+        audit.orphaned.sort();
+        audit.missing.sort();
+
+        if audit.is_consistent() {
+            trace!("cache is consistent with db");
+            return Ok(audit);
+        }
+        warn!("cache is inconsistent with db: {:?}", audit);
+        if let Err(e) =
+            reporter.send_report(Level::Warn, "admin", "cache inconsistent", location!())
+        {
+            error!("reporter service not available: {}", e);
+        }
+
+        if repair {
+            for id in audit.stale.iter().chain(audit.missing.iter()) {
+                cao.run_tx(cao.load(*id, &persons[id]))
+                    .map_err(unavailable)?;
+            }
+            for id in audit.orphaned.iter() {
+                cao.run_tx(cao.unload(*id)).map_err(unavailable)?;
+            }
+            cao.run_tx(cao.bump_generation()).map_err(unavailable)?;
+            trace!("cache repaired");
+        }
+
+        Ok(audit)
+    }
 }
 
 // # フェイクテスト
@@ -282,10 +627,12 @@ mod fake_tests {
 
     use crate::{
         cache::{CaoError, PersonCao},
-        cached_service::PersonCachedService,
-        dao::{DaoError, HavePersonDao, PersonDao},
+        cached_service::{CacheAudit, CachePolicy, PersonCachedService},
+        dao::{DaoError, HavePersonDao, OutboxEvent, PersonDao},
         domain::{date, PersonId, Revision},
         dto::PersonDto,
+        event::EventId,
+        invalidation::NoInvalidation,
         location::Location,
         reporter::{Level, Reporter, ReporterError},
         usecase::{PersonUsecase, UsecaseError},
@@ -319,6 +666,21 @@ mod fake_tests {
         fn delete(&self, _id: PersonId) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
             tx_rs::with_tx(move |&mut ()| Ok(()))
         }
+        fn enqueue(&self, _event: OutboxEvent) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
+            tx_rs::with_tx(move |&mut ()| Ok(()))
+        }
+        fn pending(
+            &self,
+            _limit: usize,
+        ) -> impl tx_rs::Tx<(), Item = Vec<OutboxEvent>, Err = DaoError> {
+            tx_rs::with_tx(move |&mut ()| Ok(vec![]))
+        }
+        fn mark_delivered(
+            &self,
+            _ids: Vec<EventId>,
+        ) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
+            tx_rs::with_tx(move |&mut ()| Ok(()))
+        }
     }
 
     struct DummyPersonUsecase {
@@ -369,20 +731,20 @@ mod fake_tests {
             &'a mut self,
             _id: PersonId,
             _date: NaiveDate,
-        ) -> impl tx_rs::Tx<(), Item = (), Err = UsecaseError>
+        ) -> impl tx_rs::Tx<(), Item = Revision, Err = UsecaseError>
         where
             (): 'a,
         {
-            tx_rs::with_tx(move |&mut ()| Ok(()))
+            tx_rs::with_tx(move |&mut ()| Ok(1))
         }
         fn remove<'a>(
             &'a mut self,
             _id: PersonId,
-        ) -> impl tx_rs::Tx<(), Item = (), Err = UsecaseError>
+        ) -> impl tx_rs::Tx<(), Item = Option<Revision>, Err = UsecaseError>
         where
             (): 'a,
         {
-            tx_rs::with_tx(move |&mut ()| Ok(()))
+            tx_rs::with_tx(move |&mut ()| Ok(Some(1)))
         }
     }
 
@@ -416,6 +778,7 @@ mod fake_tests {
         db: RefCell<HashMap<PersonId, PersonDto>>,
         usecase: Rc<RefCell<DummyPersonUsecase>>,
         cao: FakePersonCao,
+        policy: CachePolicy,
     }
     // フェイクのサービス実装です。ユースケースより先はダミーです。
     impl crate::service::PersonService<'_, ()> for TargetPersonService {
@@ -489,9 +852,12 @@ mod fake_tests {
             Ok(())
         }
     }
+    type Snapshot = Vec<(PersonId, PersonDto)>;
     #[derive(Debug, Clone, PartialEq, Eq)]
     struct FakePersonCao {
         cache: Rc<RefCell<HashMap<PersonId, PersonDto>>>,
+        generation: Rc<RefCell<u64>>,
+        snapshots: Rc<RefCell<HashMap<u64, Snapshot>>>,
     }
     impl PersonCao<()> for FakePersonCao {
         fn get_conn(&self) -> Result<(), CaoError> {
@@ -525,13 +891,97 @@ mod fake_tests {
                 Ok(())
             })
         }
+        fn ids(&self) -> impl tx_rs::Tx<(), Item = Vec<PersonId>, Err = CaoError> {
+            tx_rs::with_tx(move |&mut ()| Ok(self.cache.borrow().keys().cloned().collect()))
+        }
+        fn generation(&self) -> impl tx_rs::Tx<(), Item = u64, Err = CaoError> {
+            tx_rs::with_tx(move |&mut ()| Ok(*self.generation.borrow()))
+        }
+        fn bump_generation(&self) -> impl tx_rs::Tx<(), Item = (), Err = CaoError> {
+            tx_rs::with_tx(move |&mut ()| {
+                *self.generation.borrow_mut() += 1;
+                Ok(())
+            })
+        }
+        fn find_all(
+            &self,
+            generation: u64,
+        ) -> impl tx_rs::Tx<(), Item = Option<Vec<(PersonId, PersonDto)>>, Err = CaoError> {
+            tx_rs::with_tx(move |&mut ()| Ok(self.snapshots.borrow().get(&generation).cloned()))
+        }
+        fn load_all(
+            &self,
+            generation: u64,
+            persons: &[(PersonId, PersonDto)],
+        ) -> impl tx_rs::Tx<(), Item = (), Err = CaoError> {
+            tx_rs::with_tx(move |&mut ()| {
+                self.snapshots
+                    .borrow_mut()
+                    .insert(generation, persons.to_vec());
+                Ok(())
+            })
+        }
+        fn unload_all(&self, generation: u64) -> impl tx_rs::Tx<(), Item = (), Err = CaoError> {
+            tx_rs::with_tx(move |&mut ()| {
+                self.snapshots.borrow_mut().remove(&generation);
+                Ok(())
+            })
+        }
+    }
+    // 世代を上げられないキャッシュ
+    struct StuckGenerationCao(FakePersonCao);
+    impl PersonCao<()> for StuckGenerationCao {
+        fn get_conn(&self) -> Result<(), CaoError> {
+            self.0.get_conn()
+        }
+        fn run_tx<T, F>(&self, f: F) -> Result<T, CaoError>
+        where
+            F: tx_rs::Tx<(), Item = T, Err = CaoError>,
+        {
+            self.0.run_tx(f)
+        }
+        fn find(
+            &self,
+            id: PersonId,
+        ) -> impl tx_rs::Tx<(), Item = Option<PersonDto>, Err = CaoError> {
+            self.0.find(id)
+        }
+        fn load(
+            &self,
+            id: PersonId,
+            person: &PersonDto,
+        ) -> impl tx_rs::Tx<(), Item = (), Err = CaoError> {
+            self.0.load(id, person)
+        }
+        fn unload(&self, id: PersonId) -> impl tx_rs::Tx<(), Item = (), Err = CaoError> {
+            self.0.unload(id)
+        }
+        fn ids(&self) -> impl tx_rs::Tx<(), Item = Vec<PersonId>, Err = CaoError> {
+            self.0.ids()
+        }
+        fn generation(&self) -> impl tx_rs::Tx<(), Item = u64, Err = CaoError> {
+            self.0.generation()
+        }
+        fn bump_generation(&self) -> impl tx_rs::Tx<(), Item = (), Err = CaoError> {
+            tx_rs::with_tx(move |&mut ()| Err(CaoError::Unavailable("stuck".to_string())))
+        }
+        fn unload_all(&self, generation: u64) -> impl tx_rs::Tx<(), Item = (), Err = CaoError> {
+            self.0.unload_all(generation)
+        }
     }
     impl PersonCachedService<'_, (), ()> for TargetPersonService {
         type C = FakePersonCao;
+        type P = NoInvalidation;
 
         fn get_cao(&self) -> Self::C {
             self.cao.clone()
         }
+        fn get_publisher(&self) -> Self::P {
+            NoInvalidation
+        }
+        fn get_cache_policy(&self) -> CachePolicy {
+            self.policy
+        }
     }
 
     struct DummyPersonOutputBoundary;
@@ -555,7 +1005,10 @@ mod fake_tests {
             })),
             cao: FakePersonCao {
                 cache: RefCell::new(HashMap::new()).into(),
+                generation: RefCell::new(0).into(),
+                snapshots: RefCell::new(HashMap::new()).into(),
             },
+            policy: CachePolicy::WriteThrough,
         };
 
         let expected = PersonDto::new("Alice", date(2000, 1, 1), None, Some("Alice is here"), 0);
@@ -577,7 +1030,10 @@ mod fake_tests {
             })),
             cao: FakePersonCao {
                 cache: RefCell::new(HashMap::new()).into(),
+                generation: RefCell::new(0).into(),
+                snapshots: RefCell::new(HashMap::new()).into(),
             },
+            policy: CachePolicy::WriteThrough,
         };
 
         let result = service.find(id1);
@@ -601,7 +1057,10 @@ mod fake_tests {
                     .collect(),
                 )
                 .into(),
+                generation: RefCell::new(0).into(),
+                snapshots: RefCell::new(HashMap::new()).into(),
             },
+            policy: CachePolicy::WriteThrough,
         };
 
         let expected = PersonDto::new("Alice", date(2000, 1, 1), None, Some("Alice is here"), 0);
@@ -625,7 +1084,10 @@ mod fake_tests {
             })),
             cao: FakePersonCao {
                 cache: RefCell::new(HashMap::new()).into(),
+                generation: RefCell::new(0).into(),
+                snapshots: RefCell::new(HashMap::new()).into(),
             },
+            policy: CachePolicy::WriteThrough,
         };
 
         let expected = PersonDto::new("Alice", date(2000, 1, 1), None, Some("Alice is here"), 0);
@@ -647,7 +1109,10 @@ mod fake_tests {
             })),
             cao: FakePersonCao {
                 cache: RefCell::new(HashMap::new()).into(),
+                generation: RefCell::new(0).into(),
+                snapshots: RefCell::new(HashMap::new()).into(),
             },
+            policy: CachePolicy::WriteThrough,
         };
 
         let result = service.batch_import(
@@ -687,7 +1152,10 @@ mod fake_tests {
             })),
             cao: FakePersonCao {
                 cache: RefCell::new(HashMap::new()).into(),
+                generation: RefCell::new(0).into(),
+                snapshots: RefCell::new(HashMap::new()).into(),
             },
+            policy: CachePolicy::WriteThrough,
         };
 
         let result = service.list_all();
@@ -720,7 +1188,10 @@ mod fake_tests {
             })),
             cao: FakePersonCao {
                 cache: RefCell::new(HashMap::new()).into(),
+                generation: RefCell::new(0).into(),
+                snapshots: RefCell::new(HashMap::new()).into(),
             },
+            policy: CachePolicy::WriteThrough,
         };
 
         let result = service.death(id, date(2030, 11, 22));
@@ -754,7 +1225,10 @@ mod fake_tests {
             })),
             cao: FakePersonCao {
                 cache: RefCell::new(HashMap::new()).into(),
+                generation: RefCell::new(0).into(),
+                snapshots: RefCell::new(HashMap::new()).into(),
             },
+            policy: CachePolicy::WriteThrough,
         };
 
         let result = service.unregister(id1);
@@ -762,54 +1236,489 @@ mod fake_tests {
         assert!(result.is_ok());
         assert_eq!(result, Ok(()));
     }
-}
 
-// # スパイテスト(モック利用)
-//
-// ## 目的
-//
-//   CachedService の各メソッドが、 Cache, Reporter と Service のメソッドを適切に呼び出していることを保障する
-//   つまり、
-//    1. 必要なメソッドを必要回数だけ呼び出していること
-//    2. 不必要なメソッドを呼び出していないこと
-//    3. CachedService に渡った引数が適切に Cache, Reporter や Service のメソッドに渡されていること
-//   を保障する
-//
-// ## 方針
-//
-//   スパイ Service と スパイ Cache, スパイ Reporter は呼び出されるたびに、それらを全て記録する
-//   ただし、 Service の返り値が Cache や Reporter に使われたりその逆があるため、
-//   各スパイは返り値も制御する必要がある
-//   よってスタブを兼ねる必要があるため、それぞれをモックとして実装する
-//   各メソッドの呼び出された記録をテストの最後で確認する
-//
-// ## 実装
-//
-//                                   Test Double
-//        +----------------+        +------------------+.oOo.+----------------+
-//        | Cached Service |        | Spy Service      |     | Dummy Usecase  |
-//        | ============== |        | ============     |     | ============== |
-//        |                |        |                  |     |                |
-//   ---->| -------------> |--c+--->| --> [ c ] request|     |                |
-//        |                |  ||    |       |    log   |     |                |
-//   <----| <------------- |<-||----| <--   |          |     |                |
-//        +----------------+  ||    +-------|----------+     +----------------+
-//                            ||            +-- ここを確認する
-//              テスト対象 ---+|     Test Double
-//                             |    +------------------+
-//                             |    | Spy Cache        |
-//                             |    | ============     |
-//                             |    |                  |
-//                             +-c->| --> [ c ] request|
-//                             | |  |       |    log   |
-//              テスト対象 ----|-+  | <--   |          |
-//                             |    +-------|----------+
-//                             |            |
-//                             |            +-- ここを確認する
-//                             |     Test Double
-//                             |    +------------------+
-//                             |    | Spy Reporter     |
-//                             |    | ============     |
+    #[test]
+    fn test_warm_up() {
+        let (id1, id2) = (Uuid::now_v7(), Uuid::now_v7());
+        let alice = PersonDto::new("Alice", date(2000, 1, 1), None, Some("Alice is here"), 0);
+        let bob = PersonDto::new("Bob", date(2000, 1, 2), None, Some("Bob is here"), 0);
+        let mut service = TargetPersonService {
+            next_id: RefCell::new(VecDeque::new()),
+            db: RefCell::new(
+                vec![(id1, alice.clone()), (id2, bob.clone())]
+                    .into_iter()
+                    .collect(),
+            ),
+            usecase: Rc::new(RefCell::new(DummyPersonUsecase {
+                dao: DummyPersonDao,
+            })),
+            cao: FakePersonCao {
+                cache: RefCell::new(HashMap::new()).into(),
+                generation: RefCell::new(0).into(),
+                snapshots: RefCell::new(HashMap::new()).into(),
+            },
+            policy: CachePolicy::WriteThrough,
+        };
+
+        let result = service.warm_up(Some(&[id2]));
+        assert_eq!(result, Ok(1));
+        assert_eq!(service.cao.cache.borrow().get(&id1), None, "not selected");
+        assert_eq!(service.cao.cache.borrow().get(&id2), Some(&bob));
+
+        let result = service.warm_up(None);
+        assert_eq!(result, Ok(2));
+        assert_eq!(service.cao.cache.borrow().get(&id1), Some(&alice));
+        assert_eq!(service.cao.cache.borrow().get(&id2), Some(&bob));
+    }
+
+    #[test]
+    fn test_audit() {
+        let (id1, id2, id3, id4) = (
+            Uuid::now_v7(),
+            Uuid::now_v7(),
+            Uuid::now_v7(),
+            Uuid::now_v7(),
+        );
+        let alice = PersonDto::new("Alice", date(2000, 1, 1), None, Some("Alice is here"), 1);
+        let bob = PersonDto::new("Bob", date(2000, 1, 2), None, Some("Bob is here"), 0);
+        let charlie = PersonDto::new("Charlie", date(2000, 1, 3), None, Some("Charlie"), 0);
+        let mut service = TargetPersonService {
+            next_id: RefCell::new(VecDeque::new()),
+            db: RefCell::new(
+                vec![
+                    (id1, alice.clone()),
+                    (id2, bob.clone()),
+                    (id3, charlie.clone()),
+                ]
+                .into_iter()
+                .collect(),
+            ),
+            usecase: Rc::new(RefCell::new(DummyPersonUsecase {
+                dao: DummyPersonDao,
+            })),
+            cao: FakePersonCao {
+                cache: RefCell::new(
+                    vec![
+                        // 古いリビジョンが残っている
+                        (
+                            id1,
+                            PersonDto::new("Alice", date(2000, 1, 1), None, Some("Alice"), 0),
+                        ),
+                        (id2, bob.clone()),
+                        // DB からは消えている
+                        (
+                            id4,
+                            PersonDto::new("Dave", date(2000, 1, 4), None, Some("Dave"), 0),
+                        ),
+                    ]
+                    .into_iter()
+                    .collect(),
+                )
+                .into(),
+                generation: RefCell::new(0).into(),
+                snapshots: RefCell::new(HashMap::new()).into(),
+            },
+            policy: CachePolicy::WriteThrough,
+        };
+
+        let expected = CacheAudit {
+            stale: vec![id1],
+            orphaned: vec![id4],
+            missing: vec![id3],
+        };
+        assert_eq!(service.audit(false), Ok(expected.clone()));
+        assert_eq!(service.cao.cache.borrow().len(), 3, "not repaired");
+
+        assert_eq!(service.audit(true), Ok(expected));
+        assert_eq!(
+            *service.cao.cache.borrow(),
+            vec![(id1, alice), (id2, bob), (id3, charlie)]
+                .into_iter()
+                .collect(),
+            "repaired"
+        );
+        assert_eq!(service.audit(false).map(|a| a.is_consistent()), Ok(true));
+    }
+
+    // ポリシーごとのテスト用に、DB とキャッシュの初期状態を指定してサービスを作る
+    fn target(
+        policy: CachePolicy,
+        next_id: Vec<PersonId>,
+        db: Vec<(PersonId, PersonDto)>,
+        cache: Vec<(PersonId, PersonDto)>,
+    ) -> TargetPersonService {
+        TargetPersonService {
+            next_id: RefCell::new(next_id.into()),
+            db: RefCell::new(db.into_iter().collect()),
+            usecase: Rc::new(RefCell::new(DummyPersonUsecase {
+                dao: DummyPersonDao,
+            })),
+            cao: FakePersonCao {
+                cache: RefCell::new(cache.into_iter().collect()).into(),
+                generation: RefCell::new(0).into(),
+                snapshots: RefCell::new(HashMap::new()).into(),
+            },
+            policy,
+        }
+    }
+
+    #[test]
+    fn test_policy_write_through() {
+        let (id1, id2) = (Uuid::now_v7(), Uuid::now_v7());
+        let alice = PersonDto::new("Alice", date(2000, 1, 1), None, Some("Alice is here"), 0);
+        let bob = PersonDto::new("Bob", date(2000, 1, 2), None, Some("Bob is here"), 0);
+        let old = PersonDto::new("Alice", date(2000, 1, 1), None, Some("old"), 0);
+
+        let mut service = target(CachePolicy::WriteThrough, vec![id1], vec![], vec![]);
+        let _ = service.register("Alice", date(2000, 1, 1), None, "Alice is here");
+        assert_eq!(
+            service.cao.cache.borrow().get(&id1),
+            Some(&alice),
+            "register"
+        );
+
+        let mut service = target(CachePolicy::WriteThrough, vec![id1, id2], vec![], vec![]);
+        let _ = service.batch_import(
+            vec![alice.clone(), bob.clone()],
+            Rc::new(DummyPersonOutputBoundary),
+        );
+        assert_eq!(service.cao.cache.borrow().len(), 2, "batch_import");
+
+        let mut service = target(
+            CachePolicy::WriteThrough,
+            vec![],
+            vec![(id1, alice.clone())],
+            vec![],
+        );
+        let _ = service.find(id1);
+        assert_eq!(service.cao.cache.borrow().get(&id1), Some(&alice), "find");
+
+        let mut service = target(
+            CachePolicy::WriteThrough,
+            vec![],
+            vec![(id1, alice.clone()), (id2, bob.clone())],
+            vec![],
+        );
+        let _ = service.list_all();
+        assert_eq!(service.cao.cache.borrow().len(), 2, "list_all");
+
+        // death は更新後の person を返さないので無効化になる
+        let mut service = target(
+            CachePolicy::WriteThrough,
+            vec![],
+            vec![(id1, alice.clone())],
+            vec![(id1, old.clone())],
+        );
+        let _ = service.death(id1, date(2030, 11, 22));
+        assert_eq!(service.cao.cache.borrow().get(&id1), None, "death");
+
+        let mut service = target(
+            CachePolicy::WriteThrough,
+            vec![],
+            vec![(id1, alice.clone())],
+            vec![(id1, alice.clone())],
+        );
+        let _ = service.unregister(id1);
+        assert_eq!(service.cao.cache.borrow().get(&id1), None, "unregister");
+    }
+
+    #[test]
+    fn test_policy_invalidate_on_write() {
+        let (id1, id2) = (Uuid::now_v7(), Uuid::now_v7());
+        let alice = PersonDto::new("Alice", date(2000, 1, 1), None, Some("Alice is here"), 0);
+        let bob = PersonDto::new("Bob", date(2000, 1, 2), None, Some("Bob is here"), 0);
+        let old = PersonDto::new("Alice", date(2000, 1, 1), None, Some("old"), 0);
+
+        let mut service = target(
+            CachePolicy::InvalidateOnWrite,
+            vec![id1],
+            vec![],
+            vec![(id1, old.clone())],
+        );
+        let _ = service.register("Alice", date(2000, 1, 1), None, "Alice is here");
+        assert_eq!(service.cao.cache.borrow().get(&id1), None, "register");
+
+        let mut service = target(
+            CachePolicy::InvalidateOnWrite,
+            vec![id1, id2],
+            vec![],
+            vec![(id1, old.clone())],
+        );
+        let _ = service.batch_import(
+            vec![alice.clone(), bob.clone()],
+            Rc::new(DummyPersonOutputBoundary),
+        );
+        assert!(service.cao.cache.borrow().is_empty(), "batch_import");
+
+        // 読み込みではキャッシュに載る
+        let mut service = target(
+            CachePolicy::InvalidateOnWrite,
+            vec![],
+            vec![(id1, alice.clone())],
+            vec![],
+        );
+        let _ = service.find(id1);
+        assert_eq!(service.cao.cache.borrow().get(&id1), Some(&alice), "find");
+
+        let mut service = target(
+            CachePolicy::InvalidateOnWrite,
+            vec![],
+            vec![(id1, alice.clone()), (id2, bob.clone())],
+            vec![],
+        );
+        let _ = service.list_all();
+        assert_eq!(service.cao.cache.borrow().len(), 2, "list_all");
+
+        let mut service = target(
+            CachePolicy::InvalidateOnWrite,
+            vec![],
+            vec![(id1, alice.clone())],
+            vec![(id1, old.clone())],
+        );
+        let _ = service.death(id1, date(2030, 11, 22));
+        assert_eq!(service.cao.cache.borrow().get(&id1), None, "death");
+
+        let mut service = target(
+            CachePolicy::InvalidateOnWrite,
+            vec![],
+            vec![(id1, alice.clone())],
+            vec![(id1, alice.clone())],
+        );
+        let _ = service.unregister(id1);
+        assert_eq!(service.cao.cache.borrow().get(&id1), None, "unregister");
+    }
+
+    #[test]
+    fn test_policy_read_only() {
+        let (id1, id2) = (Uuid::now_v7(), Uuid::now_v7());
+        let alice = PersonDto::new("Alice", date(2000, 1, 1), None, Some("Alice is here"), 0);
+        let bob = PersonDto::new("Bob", date(2000, 1, 2), None, Some("Bob is here"), 0);
+        let old = PersonDto::new("Alice", date(2000, 1, 1), None, Some("old"), 0);
+
+        // 変更されたものはキャッシュから消すが、載せはしない
+        let mut service = target(
+            CachePolicy::ReadOnly,
+            vec![id1],
+            vec![],
+            vec![(id1, old.clone())],
+        );
+        let _ = service.register("Alice", date(2000, 1, 1), None, "Alice is here");
+        assert!(service.cao.cache.borrow().is_empty(), "register");
+
+        let mut service = target(
+            CachePolicy::ReadOnly,
+            vec![id1, id2],
+            vec![],
+            vec![(id1, old.clone())],
+        );
+        let _ = service.batch_import(
+            vec![alice.clone(), bob.clone()],
+            Rc::new(DummyPersonOutputBoundary),
+        );
+        assert!(service.cao.cache.borrow().is_empty(), "batch_import");
+
+        // キャッシュは読むが、DB から読んだものは載せない
+        let mut service = target(
+            CachePolicy::ReadOnly,
+            vec![],
+            vec![(id1, alice.clone()), (id2, bob.clone())],
+            vec![(id1, old.clone())],
+        );
+        assert_eq!(service.find(id1), Ok(Some(old.clone())), "hit cache");
+        let mut service = target(
+            CachePolicy::ReadOnly,
+            vec![],
+            vec![(id2, bob.clone())],
+            vec![],
+        );
+        assert_eq!(service.find(id2), Ok(Some(bob.clone())), "found db");
+        assert!(service.cao.cache.borrow().is_empty(), "find");
+
+        let mut service = target(
+            CachePolicy::ReadOnly,
+            vec![],
+            vec![(id1, alice.clone()), (id2, bob.clone())],
+            vec![],
+        );
+        let _ = service.list_all();
+        assert!(service.cao.cache.borrow().is_empty(), "list_all");
+
+        let mut service = target(
+            CachePolicy::ReadOnly,
+            vec![],
+            vec![(id1, alice.clone())],
+            vec![(id1, old.clone())],
+        );
+        let _ = service.death(id1, date(2030, 11, 22));
+        assert_eq!(service.cao.cache.borrow().get(&id1), None, "death");
+        assert_eq!(*service.cao.generation.borrow(), 1, "death");
+
+        let mut service = target(
+            CachePolicy::ReadOnly,
+            vec![],
+            vec![(id1, alice.clone())],
+            vec![(id1, alice.clone())],
+        );
+        let _ = service.unregister(id1);
+        assert_eq!(service.cao.cache.borrow().get(&id1), None, "unregister");
+        assert_eq!(*service.cao.generation.borrow(), 1, "unregister");
+    }
+
+    #[test]
+    fn test_list_all_snapshot() {
+        let (id1, id2) = (Uuid::now_v7(), Uuid::now_v7());
+        let alice = PersonDto::new("Alice", date(2000, 1, 1), None, Some("Alice is here"), 0);
+        let bob = PersonDto::new("Bob", date(2000, 1, 2), None, Some("Bob is here"), 0);
+
+        let mut service = target(
+            CachePolicy::WriteThrough,
+            vec![],
+            vec![(id1, alice.clone())],
+            vec![],
+        );
+        let cao = service.cao.clone();
+        assert_eq!(service.list_all(), Ok(vec![(id1, alice.clone())]));
+        assert_eq!(
+            cao.snapshots.borrow().get(&0),
+            Some(&vec![(id1, alice.clone())]),
+            "snapshot loaded"
+        );
+
+        // DB を読まずにスナップショットを返す
+        let mut service = target(CachePolicy::WriteThrough, vec![], vec![], vec![]);
+        service.cao = cao.clone();
+        assert_eq!(service.list_all(), Ok(vec![(id1, alice.clone())]), "hit");
+
+        // 更新するとスナップショットは使われなくなる
+        let mut service = target(
+            CachePolicy::WriteThrough,
+            vec![id2],
+            vec![(id1, alice.clone())],
+            vec![],
+        );
+        service.cao = cao.clone();
+        let _ = service.register("Bob", date(2000, 1, 2), None, "Bob is here");
+        assert_eq!(*cao.generation.borrow(), 1, "register");
+
+        let mut service = target(
+            CachePolicy::WriteThrough,
+            vec![],
+            vec![(id1, alice.clone()), (id2, bob.clone())],
+            vec![],
+        );
+        service.cao = cao.clone();
+        let mut result = service.list_all().unwrap();
+        result.sort_by_key(|(id, _)| *id);
+        assert_eq!(result, vec![(id1, alice.clone()), (id2, bob.clone())]);
+
+        let mut service = target(
+            CachePolicy::WriteThrough,
+            vec![],
+            vec![(id1, alice.clone()), (id2, bob.clone())],
+            vec![],
+        );
+        service.cao = cao.clone();
+        let _ = service.death(id1, date(2030, 11, 22));
+        assert_eq!(*cao.generation.borrow(), 2, "death");
+
+        let mut service = target(
+            CachePolicy::WriteThrough,
+            vec![],
+            vec![(id1, alice.clone()), (id2, bob.clone())],
+            vec![],
+        );
+        service.cao = cao.clone();
+        let _ = service.unregister(id2);
+        assert_eq!(*cao.generation.borrow(), 3, "unregister");
+
+        // 読み取り専用ではスナップショットも使わない
+        let mut service = target(
+            CachePolicy::ReadOnly,
+            vec![],
+            vec![(id1, alice.clone())],
+            vec![],
+        );
+        let _ = service.list_all();
+        assert!(service.cao.snapshots.borrow().is_empty(), "read only");
+    }
+
+    #[test]
+    fn test_bump_generation_failure() {
+        let id = Uuid::now_v7();
+        let alice = PersonDto::new("Alice", date(2000, 1, 1), None, Some("Alice is here"), 0);
+        let cao = FakePersonCao {
+            cache: RefCell::new(HashMap::new()).into(),
+            generation: RefCell::new(3).into(),
+            snapshots: RefCell::new(HashMap::from([(2, vec![]), (3, vec![(id, alice.clone())])]))
+                .into(),
+        };
+
+        super::bump_generation(&StuckGenerationCao(cao.clone()), &DummyReporter);
+
+        // 世代を上げられなければ今の世代のスナップショットを捨てる
+        assert_eq!(*cao.generation.borrow(), 3);
+        assert!(!cao.snapshots.borrow().contains_key(&3));
+        assert!(cao.snapshots.borrow().contains_key(&2));
+    }
+
+    #[test]
+    fn test_parse_policy() {
+        assert_eq!("write-through".parse(), Ok(CachePolicy::WriteThrough));
+        assert_eq!(
+            "Invalidate-On-Write".parse(),
+            Ok(CachePolicy::InvalidateOnWrite)
+        );
+        assert_eq!("read-only".parse(), Ok(CachePolicy::ReadOnly));
+        assert!("write-back".parse::<CachePolicy>().is_err());
+    }
+}
+
+// # スパイテスト(モック利用)
+//
+// ## 目的
+//
+//   CachedService の各メソッドが、 Cache, Reporter と Service のメソッドを適切に呼び出していることを保障する
+//   つまり、
+//    1. 必要なメソッドを必要回数だけ呼び出していること
+//    2. 不必要なメソッドを呼び出していないこと
+//    3. CachedService に渡った引数が適切に Cache, Reporter や Service のメソッドに渡されていること
+//   を保障する
+//
+// ## 方針
+//
+//   スパイ Service と スパイ Cache, スパイ Reporter は呼び出されるたびに、それらを全て記録する
+//   ただし、 Service の返り値が Cache や Reporter に使われたりその逆があるため、
+//   各スパイは返り値も制御する必要がある
+//   よってスタブを兼ねる必要があるため、それぞれをモックとして実装する
+//   各メソッドの呼び出された記録をテストの最後で確認する
+//
+// ## 実装
+//
+//                                   Test Double
+//        +----------------+        +------------------+.oOo.+----------------+
+//        | Cached Service |        | Spy Service      |     | Dummy Usecase  |
+//        | ============== |        | ============     |     | ============== |
+//        |                |        |                  |     |                |
+//   ---->| -------------> |--c+--->| --> [ c ] request|     |                |
+//        |                |  ||    |       |    log   |     |                |
+//   <----| <------------- |<-||----| <--   |          |     |                |
+//        +----------------+  ||    +-------|----------+     +----------------+
+//                            ||            +-- ここを確認する
+//              テスト対象 ---+|     Test Double
+//                             |    +------------------+
+//                             |    | Spy Cache        |
+//                             |    | ============     |
+//                             |    |                  |
+//                             +-c->| --> [ c ] request|
+//                             | |  |       |    log   |
+//              テスト対象 ----|-+  | <--   |          |
+//                             |    +-------|----------+
+//                             |            |
+//                             |            +-- ここを確認する
+//                             |     Test Double
+//                             |    +------------------+
+//                             |    | Spy Reporter     |
+//                             |    | ============     |
 //                             |    |                  |
 //                             +-c->| --> [ c ] request|
 //                               |  |       |    log   |
@@ -839,14 +1748,17 @@ mod spy_tests {
     use chrono::NaiveDate;
     use std::cell::RefCell;
     use std::rc::Rc;
+    use std::time::Duration;
     use uuid::Uuid;
 
     use crate::{
-        cache::{CaoError, PersonCao},
+        cache::{CaoError, LockToken, PersonCao},
         cached_service::PersonCachedService,
-        dao::{DaoError, HavePersonDao, PersonDao},
+        dao::{DaoError, HavePersonDao, OutboxEvent, PersonDao},
         domain::{date, PersonId, Revision},
         dto::PersonDto,
+        event::EventId,
+        invalidation::InvalidationPublisher,
         location::Location,
         reporter::{Level, Reporter, ReporterError},
         usecase::{PersonUsecase, UsecaseError},
@@ -880,6 +1792,21 @@ mod spy_tests {
         fn delete(&self, _id: PersonId) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
             tx_rs::with_tx(move |&mut ()| Ok(()))
         }
+        fn enqueue(&self, _event: OutboxEvent) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
+            tx_rs::with_tx(move |&mut ()| Ok(()))
+        }
+        fn pending(
+            &self,
+            _limit: usize,
+        ) -> impl tx_rs::Tx<(), Item = Vec<OutboxEvent>, Err = DaoError> {
+            tx_rs::with_tx(move |&mut ()| Ok(vec![]))
+        }
+        fn mark_delivered(
+            &self,
+            _ids: Vec<EventId>,
+        ) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
+            tx_rs::with_tx(move |&mut ()| Ok(()))
+        }
     }
 
     struct DummyPersonUsecase {
@@ -930,20 +1857,20 @@ mod spy_tests {
             &'a mut self,
             _id: PersonId,
             _date: NaiveDate,
-        ) -> impl tx_rs::Tx<(), Item = (), Err = UsecaseError>
+        ) -> impl tx_rs::Tx<(), Item = Revision, Err = UsecaseError>
         where
             (): 'a,
         {
-            tx_rs::with_tx(move |&mut ()| Ok(()))
+            tx_rs::with_tx(move |&mut ()| Ok(1))
         }
         fn remove<'a>(
             &'a mut self,
             _id: PersonId,
-        ) -> impl tx_rs::Tx<(), Item = (), Err = UsecaseError>
+        ) -> impl tx_rs::Tx<(), Item = Option<Revision>, Err = UsecaseError>
         where
             (): 'a,
         {
-            tx_rs::with_tx(move |&mut ()| Ok(()))
+            tx_rs::with_tx(move |&mut ()| Ok(Some(1)))
         }
     }
 
@@ -994,6 +1921,7 @@ mod spy_tests {
 
         usecase: RefCell<DummyPersonUsecase>,
         cao: MockPersonCao,
+        publisher: MockPublisher,
         reporter: SpyReporter,
     }
     // スパイサービス実装です。ユースケースより先はダミーです。
@@ -1078,6 +2006,9 @@ mod spy_tests {
         load_result: Result<(), CaoError>,
         unload: Rc<RefCell<Vec<PersonId>>>,
         unload_result: Result<(), CaoError>,
+        lock: Rc<RefCell<Vec<PersonId>>>,
+        lock_result: Result<bool, CaoError>,
+        unlock: Rc<RefCell<Vec<PersonId>>>,
     }
     impl PersonCao<()> for MockPersonCao {
         fn get_conn(&self) -> Result<(), CaoError> {
@@ -1114,13 +2045,58 @@ mod spy_tests {
                 self.unload_result.clone()
             })
         }
+        fn ids(&self) -> impl tx_rs::Tx<(), Item = Vec<PersonId>, Err = CaoError> {
+            tx_rs::with_tx(move |&mut ()| Ok(vec![]))
+        }
+        fn lock(
+            &self,
+            id: PersonId,
+            _ttl: Duration,
+        ) -> impl tx_rs::Tx<(), Item = Option<LockToken>, Err = CaoError> {
+            tx_rs::with_tx(move |&mut ()| {
+                self.lock.borrow_mut().push(id);
+                self.lock_result
+                    .clone()
+                    .map(|locked| locked.then(|| format!("token-{}", id)))
+            })
+        }
+        fn unlock(
+            &self,
+            id: PersonId,
+            token: LockToken,
+        ) -> impl tx_rs::Tx<(), Item = (), Err = CaoError> {
+            assert_eq!(token, format!("token-{}", id));
+            tx_rs::with_tx(move |&mut ()| {
+                self.unlock.borrow_mut().push(id);
+                Ok(())
+            })
+        }
+    }
+    // スパイパブリッシャ実装です
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct MockPublisher {
+        publish: Rc<RefCell<Vec<PersonId>>>,
+        publish_result: Result<(), CaoError>,
+    }
+    impl InvalidationPublisher for MockPublisher {
+        fn publish(&self, id: PersonId) -> Result<(), CaoError> {
+            self.publish.borrow_mut().push(id);
+            self.publish_result.clone()
+        }
     }
     impl PersonCachedService<'_, (), ()> for TargetPersonService {
         type C = MockPersonCao;
+        type P = MockPublisher;
 
         fn get_cao(&self) -> Self::C {
             self.cao.clone()
         }
+        fn get_publisher(&self) -> Self::P {
+            self.publisher.clone()
+        }
+        fn get_lock_timeout(&self) -> Duration {
+            Duration::from_millis(50)
+        }
     }
 
     struct DummyPersonOutputBoundary;
@@ -1162,6 +2138,13 @@ mod spy_tests {
                 load_result: Ok(()), // 使われない
                 unload: Rc::new(RefCell::new(vec![])),
                 unload_result: Ok(()), // 使われない
+                lock: Rc::new(RefCell::new(vec![])),
+                lock_result: Ok(true),
+                unlock: Rc::new(RefCell::new(vec![])),
+            },
+            publisher: MockPublisher {
+                publish: Rc::new(RefCell::new(vec![])),
+                publish_result: Ok(()), // 使われない
             },
             reporter: SpyReporter {
                 report: RefCell::new(vec![]).into(),
@@ -1199,6 +2182,7 @@ mod spy_tests {
             )]
         );
         assert_eq!(*service.cao.unload.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(*service.publisher.publish.borrow(), vec![] as Vec<PersonId>);
         assert_eq!(
             *service.reporter.report.borrow(),
             vec![] as Vec<(Level, String, String)>
@@ -1230,6 +2214,13 @@ mod spy_tests {
                 load_result: Err(CaoError::Unavailable("valid cao".to_string())),
                 unload: Rc::new(RefCell::new(vec![])),
                 unload_result: Ok(()), // 使われない
+                lock: Rc::new(RefCell::new(vec![])),
+                lock_result: Ok(true),
+                unlock: Rc::new(RefCell::new(vec![])),
+            },
+            publisher: MockPublisher {
+                publish: Rc::new(RefCell::new(vec![])),
+                publish_result: Ok(()), // 使われない
             },
             reporter: SpyReporter {
                 report: RefCell::new(vec![]).into(),
@@ -1267,6 +2258,7 @@ mod spy_tests {
             )]
         );
         assert_eq!(*service.cao.unload.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(*service.publisher.publish.borrow(), vec![] as Vec<PersonId>);
         assert_eq!(
             *service.reporter.report.borrow(),
             vec![(
@@ -1275,6 +2267,53 @@ mod spy_tests {
                 "cache service not available".to_string()
             )],
         );
+
+        // ブレーカーが開いているときは毎回報告しない
+        let mut service = TargetPersonService {
+            register: RefCell::new(vec![]),
+            register_result: Ok((
+                id,
+                PersonDto::new("Alice", date(2000, 1, 1), None, Some("Alice is here"), 0),
+            )),
+            find: RefCell::new(vec![]),
+            find_result: Ok(None), // 使われない
+            batch_import: RefCell::new(vec![]),
+            batch_import_result: Ok(vec![]), // 使われない
+            list_all: RefCell::new(0),
+            list_all_result: Ok(vec![]), // 使われない
+            death: RefCell::new(vec![]),
+            death_result: Ok(()), // 使われない
+            unregister: RefCell::new(vec![]),
+            unregister_result: Ok(()), // 使われない
+            usecase: RefCell::new(DummyPersonUsecase {
+                dao: DummyPersonDao,
+            }),
+            cao: MockPersonCao {
+                find: Rc::new(RefCell::new(vec![])),
+                find_result: Ok(None), // 使われない
+                load: Rc::new(RefCell::new(vec![])),
+                load_result: Err(CaoError::CircuitOpen("cache".to_string())),
+                unload: Rc::new(RefCell::new(vec![])),
+                unload_result: Ok(()), // 使われない
+                lock: Rc::new(RefCell::new(vec![])),
+                lock_result: Ok(true),
+                unlock: Rc::new(RefCell::new(vec![])),
+            },
+            publisher: MockPublisher {
+                publish: Rc::new(RefCell::new(vec![])),
+                publish_result: Ok(()), // 使われない
+            },
+            reporter: SpyReporter {
+                report: RefCell::new(vec![]).into(),
+            },
+        };
+
+        let result = service.register("Alice", date(2000, 1, 1), None, "Alice is here");
+        assert!(result.is_ok());
+        assert_eq!(
+            *service.reporter.report.borrow(),
+            vec![] as Vec<(Level, String, String)>
+        );
     }
 
     #[test]
@@ -1312,6 +2351,13 @@ mod spy_tests {
                 load_result: Ok(()), // 使われない
                 unload: Rc::new(RefCell::new(vec![])),
                 unload_result: Ok(()), // 使われない
+                lock: Rc::new(RefCell::new(vec![])),
+                lock_result: Ok(true),
+                unlock: Rc::new(RefCell::new(vec![])),
+            },
+            publisher: MockPublisher {
+                publish: Rc::new(RefCell::new(vec![])),
+                publish_result: Ok(()), // 使われない
             },
             reporter: SpyReporter {
                 report: RefCell::new(vec![]).into(),
@@ -1334,6 +2380,9 @@ mod spy_tests {
             vec![] as Vec<(PersonId, PersonDto)>
         );
         assert_eq!(*service.cao.unload.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(*service.cao.lock.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(*service.cao.unlock.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(*service.publisher.publish.borrow(), vec![] as Vec<PersonId>);
         assert_eq!(
             *service.reporter.report.borrow(),
             vec![] as Vec<(Level, String, String)>
@@ -1371,6 +2420,13 @@ mod spy_tests {
                 load_result: Ok(()), // 使われない
                 unload: Rc::new(RefCell::new(vec![])),
                 unload_result: Ok(()), // 使われない
+                lock: Rc::new(RefCell::new(vec![])),
+                lock_result: Ok(true),
+                unlock: Rc::new(RefCell::new(vec![])),
+            },
+            publisher: MockPublisher {
+                publish: Rc::new(RefCell::new(vec![])),
+                publish_result: Ok(()), // 使われない
             },
             reporter: SpyReporter {
                 report: RefCell::new(vec![]).into(),
@@ -1400,6 +2456,9 @@ mod spy_tests {
             )]
         );
         assert_eq!(*service.cao.unload.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(*service.cao.lock.borrow(), vec![id]);
+        assert_eq!(*service.cao.unlock.borrow(), vec![id]);
+        assert_eq!(*service.publisher.publish.borrow(), vec![] as Vec<PersonId>);
         assert_eq!(
             *service.reporter.report.borrow(),
             vec![] as Vec<(Level, String, String)>
@@ -1437,12 +2496,20 @@ mod spy_tests {
                 load_result: Ok(()), // 使われない
                 unload: Rc::new(RefCell::new(vec![])),
                 unload_result: Ok(()), // 使われない
+                lock: Rc::new(RefCell::new(vec![])),
+                lock_result: Ok(true), // 使われない
+                unlock: Rc::new(RefCell::new(vec![])),
+            },
+            publisher: MockPublisher {
+                publish: Rc::new(RefCell::new(vec![])),
+                publish_result: Ok(()), // 使われない
             },
             reporter: SpyReporter {
                 report: RefCell::new(vec![]).into(),
             },
         };
 
+        // キャッシュが落ちていればロックも取らず、読み込みもしない
         let _ = service.find(id);
         assert_eq!(*service.register.borrow(), vec![]);
         assert_eq!(*service.find.borrow(), vec![id]);
@@ -1460,12 +2527,12 @@ mod spy_tests {
         assert_eq!(*service.cao.find.borrow(), vec![id]);
         assert_eq!(
             *service.cao.load.borrow(),
-            vec![(
-                id,
-                PersonDto::new("Alice", date(2000, 1, 1), None, Some("Alice is here"), 0)
-            )]
+            vec![] as Vec<(PersonId, PersonDto)>
         );
         assert_eq!(*service.cao.unload.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(*service.cao.lock.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(*service.cao.unlock.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(*service.publisher.publish.borrow(), vec![] as Vec<PersonId>);
         assert_eq!(
             *service.reporter.report.borrow(),
             vec![] as Vec<(Level, String, String)>
@@ -1503,6 +2570,13 @@ mod spy_tests {
                 load_result: Err(CaoError::Unavailable("valid cao".to_string())),
                 unload: Rc::new(RefCell::new(vec![])),
                 unload_result: Ok(()), // 使われない
+                lock: Rc::new(RefCell::new(vec![])),
+                lock_result: Ok(true),
+                unlock: Rc::new(RefCell::new(vec![])),
+            },
+            publisher: MockPublisher {
+                publish: Rc::new(RefCell::new(vec![])),
+                publish_result: Ok(()), // 使われない
             },
             reporter: SpyReporter {
                 report: RefCell::new(vec![]).into(),
@@ -1532,6 +2606,9 @@ mod spy_tests {
             )]
         );
         assert_eq!(*service.cao.unload.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(*service.cao.lock.borrow(), vec![id]);
+        assert_eq!(*service.cao.unlock.borrow(), vec![id]);
+        assert_eq!(*service.publisher.publish.borrow(), vec![] as Vec<PersonId>);
         assert_eq!(
             *service.reporter.report.borrow(),
             vec![(
@@ -1540,6 +2617,244 @@ mod spy_tests {
                 "cache service not available".to_string()
             )],
         );
+
+        let mut service = TargetPersonService {
+            register: RefCell::new(vec![]),
+            register_result: Ok((
+                Uuid::now_v7(),
+                PersonDto::new("", date(2000, 1, 1), None, Some(""), 0),
+            )), // 使われない
+            find: RefCell::new(vec![]),
+            find_result: Ok(Some(PersonDto::new(
+                "Alice",
+                date(2000, 1, 1),
+                None,
+                Some("Alice is here"),
+                0,
+            ))),
+            batch_import: RefCell::new(vec![]),
+            batch_import_result: Ok(vec![]), // 使われない
+            list_all: RefCell::new(0),
+            list_all_result: Ok(vec![]), // 使われない
+            death: RefCell::new(vec![]),
+            death_result: Ok(()), // 使われない
+            unregister: RefCell::new(vec![]),
+            unregister_result: Ok(()), // 使われない
+            usecase: RefCell::new(DummyPersonUsecase {
+                dao: DummyPersonDao,
+            }),
+            cao: MockPersonCao {
+                find: Rc::new(RefCell::new(vec![])),
+                find_result: Err(CaoError::Corrupted("valid cao".to_string())),
+                load: Rc::new(RefCell::new(vec![])),
+                load_result: Ok(()),
+                unload: Rc::new(RefCell::new(vec![])),
+                unload_result: Ok(()), // 使われない
+                lock: Rc::new(RefCell::new(vec![])),
+                lock_result: Ok(true),
+                unlock: Rc::new(RefCell::new(vec![])),
+            },
+            publisher: MockPublisher {
+                publish: Rc::new(RefCell::new(vec![])),
+                publish_result: Ok(()), // 使われない
+            },
+            reporter: SpyReporter {
+                report: RefCell::new(vec![]).into(),
+            },
+        };
+
+        let _ = service.find(id);
+        assert_eq!(*service.register.borrow(), vec![]);
+        assert_eq!(*service.find.borrow(), vec![id]);
+        assert_eq!(
+            *service.batch_import.borrow(),
+            vec![] as Vec<Vec<PersonDto>>
+        );
+        assert_eq!(*service.list_all.borrow(), 0);
+        assert_eq!(
+            *service.death.borrow(),
+            vec![] as Vec<(PersonId, NaiveDate)>
+        );
+        assert_eq!(*service.unregister.borrow(), vec![] as Vec<PersonId>);
+
+        assert_eq!(*service.cao.find.borrow(), vec![id]);
+        assert_eq!(
+            *service.cao.load.borrow(),
+            vec![(
+                id,
+                PersonDto::new("Alice", date(2000, 1, 1), None, Some("Alice is here"), 0)
+            )]
+        );
+        assert_eq!(*service.cao.unload.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(*service.cao.lock.borrow(), vec![id]);
+        assert_eq!(*service.cao.unlock.borrow(), vec![id]);
+        assert_eq!(*service.publisher.publish.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(
+            *service.reporter.report.borrow(),
+            vec![(
+                Level::Warn,
+                "admin".to_string(),
+                "cache payload corrupted".to_string()
+            )],
+        );
+
+        let mut service = TargetPersonService {
+            register: RefCell::new(vec![]),
+            register_result: Ok((
+                Uuid::now_v7(),
+                PersonDto::new("", date(2000, 1, 1), None, Some(""), 0),
+            )), // 使われない
+            find: RefCell::new(vec![]),
+            find_result: Ok(Some(PersonDto::new(
+                "Alice",
+                date(2000, 1, 1),
+                None,
+                Some("Alice is here"),
+                0,
+            ))),
+            batch_import: RefCell::new(vec![]),
+            batch_import_result: Ok(vec![]), // 使われない
+            list_all: RefCell::new(0),
+            list_all_result: Ok(vec![]), // 使われない
+            death: RefCell::new(vec![]),
+            death_result: Ok(()), // 使われない
+            unregister: RefCell::new(vec![]),
+            unregister_result: Ok(()), // 使われない
+            usecase: RefCell::new(DummyPersonUsecase {
+                dao: DummyPersonDao,
+            }),
+            cao: MockPersonCao {
+                find: Rc::new(RefCell::new(vec![])),
+                find_result: Ok(None),
+                load: Rc::new(RefCell::new(vec![])),
+                load_result: Ok(()),
+                unload: Rc::new(RefCell::new(vec![])),
+                unload_result: Ok(()), // 使われない
+                lock: Rc::new(RefCell::new(vec![])),
+                lock_result: Ok(false),
+                unlock: Rc::new(RefCell::new(vec![])),
+            },
+            publisher: MockPublisher {
+                publish: Rc::new(RefCell::new(vec![])),
+                publish_result: Ok(()), // 使われない
+            },
+            reporter: SpyReporter {
+                report: RefCell::new(vec![]).into(),
+            },
+        };
+
+        // 他のローダーを待ったがタイムアウトしたので自分で DB を見に行く
+        let _ = service.find(id);
+        assert_eq!(*service.register.borrow(), vec![]);
+        assert_eq!(*service.find.borrow(), vec![id]);
+        assert_eq!(
+            *service.batch_import.borrow(),
+            vec![] as Vec<Vec<PersonDto>>
+        );
+        assert_eq!(*service.list_all.borrow(), 0);
+        assert_eq!(
+            *service.death.borrow(),
+            vec![] as Vec<(PersonId, NaiveDate)>
+        );
+        assert_eq!(*service.unregister.borrow(), vec![] as Vec<PersonId>);
+
+        assert!(service.cao.find.borrow().len() > 1, "polled while waiting");
+        assert!(service.cao.lock.borrow().len() > 1, "retried to lock");
+        assert_eq!(
+            *service.cao.load.borrow(),
+            vec![(
+                id,
+                PersonDto::new("Alice", date(2000, 1, 1), None, Some("Alice is here"), 0)
+            )]
+        );
+        assert_eq!(*service.cao.unload.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(
+            *service.cao.unlock.borrow(),
+            vec![] as Vec<PersonId>,
+            "not locked by itself"
+        );
+        assert_eq!(*service.publisher.publish.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(
+            *service.reporter.report.borrow(),
+            vec![] as Vec<(Level, String, String)>
+        );
+
+        let mut service = TargetPersonService {
+            register: RefCell::new(vec![]),
+            register_result: Ok((
+                Uuid::now_v7(),
+                PersonDto::new("", date(2000, 1, 1), None, Some(""), 0),
+            )), // 使われない
+            find: RefCell::new(vec![]),
+            find_result: Ok(Some(PersonDto::new(
+                "Alice",
+                date(2000, 1, 1),
+                None,
+                Some("Alice is here"),
+                0,
+            ))),
+            batch_import: RefCell::new(vec![]),
+            batch_import_result: Ok(vec![]), // 使われない
+            list_all: RefCell::new(0),
+            list_all_result: Ok(vec![]), // 使われない
+            death: RefCell::new(vec![]),
+            death_result: Ok(()), // 使われない
+            unregister: RefCell::new(vec![]),
+            unregister_result: Ok(()), // 使われない
+            usecase: RefCell::new(DummyPersonUsecase {
+                dao: DummyPersonDao,
+            }),
+            cao: MockPersonCao {
+                find: Rc::new(RefCell::new(vec![])),
+                find_result: Ok(None),
+                load: Rc::new(RefCell::new(vec![])),
+                load_result: Ok(()),
+                unload: Rc::new(RefCell::new(vec![])),
+                unload_result: Ok(()), // 使われない
+                lock: Rc::new(RefCell::new(vec![])),
+                lock_result: Err(CaoError::Unavailable("valid cao".to_string())),
+                unlock: Rc::new(RefCell::new(vec![])),
+            },
+            publisher: MockPublisher {
+                publish: Rc::new(RefCell::new(vec![])),
+                publish_result: Ok(()), // 使われない
+            },
+            reporter: SpyReporter {
+                report: RefCell::new(vec![]).into(),
+            },
+        };
+
+        // ロックが取れなくても DB を見に行く
+        let _ = service.find(id);
+        assert_eq!(*service.register.borrow(), vec![]);
+        assert_eq!(*service.find.borrow(), vec![id]);
+        assert_eq!(
+            *service.batch_import.borrow(),
+            vec![] as Vec<Vec<PersonDto>>
+        );
+        assert_eq!(*service.list_all.borrow(), 0);
+        assert_eq!(
+            *service.death.borrow(),
+            vec![] as Vec<(PersonId, NaiveDate)>
+        );
+        assert_eq!(*service.unregister.borrow(), vec![] as Vec<PersonId>);
+
+        assert_eq!(*service.cao.find.borrow(), vec![id]);
+        assert_eq!(
+            *service.cao.load.borrow(),
+            vec![(
+                id,
+                PersonDto::new("Alice", date(2000, 1, 1), None, Some("Alice is here"), 0)
+            )]
+        );
+        assert_eq!(*service.cao.unload.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(*service.cao.lock.borrow(), vec![id]);
+        assert_eq!(*service.cao.unlock.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(*service.publisher.publish.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(
+            *service.reporter.report.borrow(),
+            vec![] as Vec<(Level, String, String)>
+        );
     }
 
     #[test]
@@ -1573,6 +2888,13 @@ mod spy_tests {
                 load_result: Ok(()), // 使われない
                 unload: Rc::new(RefCell::new(vec![])),
                 unload_result: Ok(()), // 使われない
+                lock: Rc::new(RefCell::new(vec![])),
+                lock_result: Ok(true),
+                unlock: Rc::new(RefCell::new(vec![])),
+            },
+            publisher: MockPublisher {
+                publish: Rc::new(RefCell::new(vec![])),
+                publish_result: Ok(()), // 使われない
             },
             reporter: SpyReporter {
                 report: RefCell::new(vec![]).into(),
@@ -1623,6 +2945,7 @@ mod spy_tests {
             ]
         );
         assert_eq!(*service.cao.unload.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(*service.publisher.publish.borrow(), vec![] as Vec<PersonId>);
         assert_eq!(
             *service.reporter.report.borrow(),
             vec![] as Vec<(Level, String, String)>
@@ -1654,6 +2977,13 @@ mod spy_tests {
                 load_result: Err(CaoError::Unavailable("valid cao".to_string())),
                 unload: Rc::new(RefCell::new(vec![])),
                 unload_result: Ok(()), // 使われない
+                lock: Rc::new(RefCell::new(vec![])),
+                lock_result: Ok(true),
+                unlock: Rc::new(RefCell::new(vec![])),
+            },
+            publisher: MockPublisher {
+                publish: Rc::new(RefCell::new(vec![])),
+                publish_result: Ok(()), // 使われない
             },
             reporter: SpyReporter {
                 report: RefCell::new(vec![]).into(),
@@ -1696,6 +3026,7 @@ mod spy_tests {
             ),]
         );
         assert_eq!(*service.cao.unload.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(*service.publisher.publish.borrow(), vec![] as Vec<PersonId>);
         assert_eq!(
             *service.reporter.report.borrow(),
             vec![(
@@ -1750,6 +3081,13 @@ mod spy_tests {
                 load_result: Ok(()), // 使われない
                 unload: Rc::new(RefCell::new(vec![])),
                 unload_result: Ok(()), // 使われない
+                lock: Rc::new(RefCell::new(vec![])),
+                lock_result: Ok(true),
+                unlock: Rc::new(RefCell::new(vec![])),
+            },
+            publisher: MockPublisher {
+                publish: Rc::new(RefCell::new(vec![])),
+                publish_result: Ok(()), // 使われない
             },
             reporter: SpyReporter {
                 report: RefCell::new(vec![]).into(),
@@ -1789,6 +3127,7 @@ mod spy_tests {
             ]
         );
         assert_eq!(*service.cao.unload.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(*service.publisher.publish.borrow(), vec![] as Vec<PersonId>);
         assert_eq!(*service.reporter.report.borrow(), vec![]);
 
         let mut service = TargetPersonService {
@@ -1830,6 +3169,13 @@ mod spy_tests {
                 load_result: Err(CaoError::Unavailable("valid cao".to_string())),
                 unload: Rc::new(RefCell::new(vec![])),
                 unload_result: Ok(()), // 使われない
+                lock: Rc::new(RefCell::new(vec![])),
+                lock_result: Ok(true),
+                unlock: Rc::new(RefCell::new(vec![])),
+            },
+            publisher: MockPublisher {
+                publish: Rc::new(RefCell::new(vec![])),
+                publish_result: Ok(()), // 使われない
             },
             reporter: SpyReporter {
                 report: RefCell::new(vec![]).into(),
@@ -1859,6 +3205,7 @@ mod spy_tests {
             ),]
         );
         assert_eq!(*service.cao.unload.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(*service.publisher.publish.borrow(), vec![] as Vec<PersonId>);
         assert_eq!(
             *service.reporter.report.borrow(),
             vec![(
@@ -1898,6 +3245,13 @@ mod spy_tests {
                 load_result: Ok(()), // 使われない
                 unload: Rc::new(RefCell::new(vec![])),
                 unload_result: Ok(()), // 使われない
+                lock: Rc::new(RefCell::new(vec![])),
+                lock_result: Ok(true),
+                unlock: Rc::new(RefCell::new(vec![])),
+            },
+            publisher: MockPublisher {
+                publish: Rc::new(RefCell::new(vec![])),
+                publish_result: Ok(()),
             },
             reporter: SpyReporter {
                 report: RefCell::new(vec![]).into(),
@@ -1924,6 +3278,7 @@ mod spy_tests {
             vec![] as Vec<(PersonId, PersonDto)>
         );
         assert_eq!(*service.cao.unload.borrow(), vec![id]);
+        assert_eq!(*service.publisher.publish.borrow(), vec![id]);
         assert_eq!(*service.reporter.report.borrow(), vec![]);
 
         let mut service = TargetPersonService {
@@ -1952,6 +3307,13 @@ mod spy_tests {
                 load_result: Ok(()), // 使われない
                 unload: Rc::new(RefCell::new(vec![])),
                 unload_result: Err(CaoError::Unavailable("cao valid".to_string())),
+                lock: Rc::new(RefCell::new(vec![])),
+                lock_result: Ok(true),
+                unlock: Rc::new(RefCell::new(vec![])),
+            },
+            publisher: MockPublisher {
+                publish: Rc::new(RefCell::new(vec![])),
+                publish_result: Ok(()),
             },
             reporter: SpyReporter {
                 report: RefCell::new(vec![]).into(),
@@ -1978,6 +3340,7 @@ mod spy_tests {
             vec![] as Vec<(PersonId, PersonDto)>
         );
         assert_eq!(*service.cao.unload.borrow(), vec![id]);
+        assert_eq!(*service.publisher.publish.borrow(), vec![id]);
         assert_eq!(
             *service.reporter.report.borrow(),
             vec![(
@@ -1986,6 +3349,279 @@ mod spy_tests {
                 "cache service not available".to_string()
             )]
         );
+
+        let mut service = TargetPersonService {
+            register: RefCell::new(vec![]),
+            register_result: Ok((
+                Uuid::now_v7(),
+                PersonDto::new("", date(2000, 1, 1), None, Some(""), 0),
+            )), // 使われない
+            find: RefCell::new(vec![]),
+            find_result: Ok(None), // 使われない
+            batch_import: RefCell::new(vec![]),
+            batch_import_result: Ok(vec![]), // 使われない
+            list_all: RefCell::new(0),
+            list_all_result: Ok(vec![]), // 使われない
+            death: RefCell::new(vec![]),
+            death_result: Ok(()), // 使われない
+            unregister: RefCell::new(vec![]),
+            unregister_result: Err(crate::service::ServiceError::TransactionFailed(
+                UsecaseError::RemovePersonFailed(DaoError::DeleteError("valid dao".to_string())),
+            )),
+            usecase: RefCell::new(DummyPersonUsecase {
+                dao: DummyPersonDao,
+            }),
+            cao: MockPersonCao {
+                find: Rc::new(RefCell::new(vec![])),
+                find_result: Ok(None), // 使われない
+                load: Rc::new(RefCell::new(vec![])),
+                load_result: Ok(()), // 使われない
+                unload: Rc::new(RefCell::new(vec![])),
+                unload_result: Ok(()),
+                lock: Rc::new(RefCell::new(vec![])),
+                lock_result: Ok(true),
+                unlock: Rc::new(RefCell::new(vec![])),
+            },
+            publisher: MockPublisher {
+                publish: Rc::new(RefCell::new(vec![])),
+                publish_result: Ok(()), // 使われない,
+            },
+            reporter: SpyReporter {
+                report: RefCell::new(vec![]).into(),
+            },
+        };
+
+        let _ = service.unregister(id);
+        assert_eq!(*service.register.borrow(), vec![]);
+        assert_eq!(*service.find.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(
+            *service.batch_import.borrow(),
+            vec![] as Vec<Vec<PersonDto>>
+        );
+        assert_eq!(*service.list_all.borrow(), 0);
+        assert_eq!(
+            *service.death.borrow(),
+            vec![] as Vec<(PersonId, NaiveDate)>
+        );
+        assert_eq!(*service.unregister.borrow(), vec![id]);
+
+        assert_eq!(*service.cao.find.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(
+            *service.cao.load.borrow(),
+            vec![] as Vec<(PersonId, PersonDto)>
+        );
+        assert_eq!(*service.cao.unload.borrow(), vec![id]);
+        assert_eq!(
+            *service.publisher.publish.borrow(),
+            vec![] as Vec<PersonId>,
+            "not published if db failed"
+        );
+        assert_eq!(*service.reporter.report.borrow(), vec![]);
+
+        let mut service = TargetPersonService {
+            register: RefCell::new(vec![]),
+            register_result: Ok((
+                Uuid::now_v7(),
+                PersonDto::new("", date(2000, 1, 1), None, Some(""), 0),
+            )), // 使われない
+            find: RefCell::new(vec![]),
+            find_result: Ok(None), // 使われない
+            batch_import: RefCell::new(vec![]),
+            batch_import_result: Ok(vec![]), // 使われない
+            list_all: RefCell::new(0),
+            list_all_result: Ok(vec![]), // 使われない
+            death: RefCell::new(vec![]),
+            death_result: Ok(()), // 使われない
+            unregister: RefCell::new(vec![]),
+            unregister_result: Ok(()),
+            usecase: RefCell::new(DummyPersonUsecase {
+                dao: DummyPersonDao,
+            }),
+            cao: MockPersonCao {
+                find: Rc::new(RefCell::new(vec![])),
+                find_result: Ok(None), // 使われない
+                load: Rc::new(RefCell::new(vec![])),
+                load_result: Ok(()), // 使われない
+                unload: Rc::new(RefCell::new(vec![])),
+                unload_result: Ok(()),
+                lock: Rc::new(RefCell::new(vec![])),
+                lock_result: Ok(true),
+                unlock: Rc::new(RefCell::new(vec![])),
+            },
+            publisher: MockPublisher {
+                publish: Rc::new(RefCell::new(vec![])),
+                publish_result: Err(CaoError::Unavailable("valid publisher".to_string())),
+            },
+            reporter: SpyReporter {
+                report: RefCell::new(vec![]).into(),
+            },
+        };
+
+        let _ = service.unregister(id);
+        assert_eq!(*service.register.borrow(), vec![]);
+        assert_eq!(*service.find.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(
+            *service.batch_import.borrow(),
+            vec![] as Vec<Vec<PersonDto>>
+        );
+        assert_eq!(*service.list_all.borrow(), 0);
+        assert_eq!(
+            *service.death.borrow(),
+            vec![] as Vec<(PersonId, NaiveDate)>
+        );
+        assert_eq!(*service.unregister.borrow(), vec![id]);
+
+        assert_eq!(*service.cao.find.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(
+            *service.cao.load.borrow(),
+            vec![] as Vec<(PersonId, PersonDto)>
+        );
+        assert_eq!(*service.cao.unload.borrow(), vec![id]);
+        assert_eq!(*service.publisher.publish.borrow(), vec![id]);
+        assert_eq!(
+            *service.reporter.report.borrow(),
+            vec![(
+                Level::Error,
+                "admin".to_string(),
+                "cache invalidation not available".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_death() {
+        let id = Uuid::now_v7();
+        let mut service = TargetPersonService {
+            register: RefCell::new(vec![]),
+            register_result: Ok((
+                Uuid::now_v7(),
+                PersonDto::new("", date(2000, 1, 1), None, Some(""), 0),
+            )), // 使われない
+            find: RefCell::new(vec![]),
+            find_result: Ok(None), // 使われない
+            batch_import: RefCell::new(vec![]),
+            batch_import_result: Ok(vec![]), // 使われない
+            list_all: RefCell::new(0),
+            list_all_result: Ok(vec![]), // 使われない
+            death: RefCell::new(vec![]),
+            death_result: Ok(()),
+            unregister: RefCell::new(vec![]),
+            unregister_result: Ok(()), // 使われない
+            usecase: RefCell::new(DummyPersonUsecase {
+                dao: DummyPersonDao,
+            }),
+            cao: MockPersonCao {
+                find: Rc::new(RefCell::new(vec![])),
+                find_result: Ok(None), // 使われない
+                load: Rc::new(RefCell::new(vec![])),
+                load_result: Ok(()), // 使われない
+                unload: Rc::new(RefCell::new(vec![])),
+                unload_result: Ok(()),
+                lock: Rc::new(RefCell::new(vec![])),
+                lock_result: Ok(true),
+                unlock: Rc::new(RefCell::new(vec![])),
+            },
+            publisher: MockPublisher {
+                publish: Rc::new(RefCell::new(vec![])),
+                publish_result: Ok(()),
+            },
+            reporter: SpyReporter {
+                report: RefCell::new(vec![]).into(),
+            },
+        };
+
+        let _ = service.death(id, date(2030, 11, 22));
+        assert_eq!(*service.register.borrow(), vec![]);
+        assert_eq!(*service.find.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(
+            *service.batch_import.borrow(),
+            vec![] as Vec<Vec<PersonDto>>
+        );
+        assert_eq!(*service.list_all.borrow(), 0);
+        assert_eq!(*service.death.borrow(), vec![(id, date(2030, 11, 22))]);
+        assert_eq!(*service.unregister.borrow(), vec![] as Vec<PersonId>);
+
+        assert_eq!(*service.cao.find.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(
+            *service.cao.load.borrow(),
+            vec![] as Vec<(PersonId, PersonDto)>
+        );
+        assert_eq!(*service.cao.unload.borrow(), vec![id]);
+        assert_eq!(*service.publisher.publish.borrow(), vec![id]);
+        assert_eq!(*service.reporter.report.borrow(), vec![]);
+
+        let mut service = TargetPersonService {
+            register: RefCell::new(vec![]),
+            register_result: Ok((
+                Uuid::now_v7(),
+                PersonDto::new("", date(2000, 1, 1), None, Some(""), 0),
+            )), // 使われない
+            find: RefCell::new(vec![]),
+            find_result: Ok(None), // 使われない
+            batch_import: RefCell::new(vec![]),
+            batch_import_result: Ok(vec![]), // 使われない
+            list_all: RefCell::new(0),
+            list_all_result: Ok(vec![]), // 使われない
+            death: RefCell::new(vec![]),
+            death_result: Ok(()),
+            unregister: RefCell::new(vec![]),
+            unregister_result: Ok(()), // 使われない
+            usecase: RefCell::new(DummyPersonUsecase {
+                dao: DummyPersonDao,
+            }),
+            cao: MockPersonCao {
+                find: Rc::new(RefCell::new(vec![])),
+                find_result: Ok(None), // 使われない
+                load: Rc::new(RefCell::new(vec![])),
+                load_result: Ok(()), // 使われない
+                unload: Rc::new(RefCell::new(vec![])),
+                unload_result: Err(CaoError::Unavailable("valid cao".to_string())),
+                lock: Rc::new(RefCell::new(vec![])),
+                lock_result: Ok(true),
+                unlock: Rc::new(RefCell::new(vec![])),
+            },
+            publisher: MockPublisher {
+                publish: Rc::new(RefCell::new(vec![])),
+                publish_result: Err(CaoError::Unavailable("valid publisher".to_string())),
+            },
+            reporter: SpyReporter {
+                report: RefCell::new(vec![]).into(),
+            },
+        };
+
+        let _ = service.death(id, date(2030, 11, 22));
+        assert_eq!(*service.register.borrow(), vec![]);
+        assert_eq!(*service.find.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(
+            *service.batch_import.borrow(),
+            vec![] as Vec<Vec<PersonDto>>
+        );
+        assert_eq!(*service.list_all.borrow(), 0);
+        assert_eq!(*service.death.borrow(), vec![(id, date(2030, 11, 22))]);
+        assert_eq!(*service.unregister.borrow(), vec![] as Vec<PersonId>);
+
+        assert_eq!(*service.cao.find.borrow(), vec![] as Vec<PersonId>);
+        assert_eq!(
+            *service.cao.load.borrow(),
+            vec![] as Vec<(PersonId, PersonDto)>
+        );
+        assert_eq!(*service.cao.unload.borrow(), vec![id]);
+        assert_eq!(*service.publisher.publish.borrow(), vec![id]);
+        assert_eq!(
+            *service.reporter.report.borrow(),
+            vec![
+                (
+                    Level::Error,
+                    "admin".to_string(),
+                    "cache service not available".to_string()
+                ),
+                (
+                    Level::Error,
+                    "admin".to_string(),
+                    "cache invalidation not available".to_string()
+                ),
+            ]
+        );
     }
 }
 
@@ -2060,9 +3696,11 @@ mod error_stub_tests {
     use crate::{
         cache::{CaoError, PersonCao},
         cached_service::PersonCachedService,
-        dao::{DaoError, HavePersonDao, PersonDao},
+        dao::{DaoError, HavePersonDao, OutboxEvent, PersonDao},
         domain::{date, PersonId, Revision},
         dto::PersonDto,
+        event::EventId,
+        invalidation::InvalidationPublisher,
         location::Location,
         reporter::{Level, Reporter, ReporterError},
         usecase::{PersonUsecase, UsecaseError},
@@ -2096,6 +3734,21 @@ mod error_stub_tests {
         fn delete(&self, _id: PersonId) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
             tx_rs::with_tx(move |&mut ()| Ok(()))
         }
+        fn enqueue(&self, _event: OutboxEvent) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
+            tx_rs::with_tx(move |&mut ()| Ok(()))
+        }
+        fn pending(
+            &self,
+            _limit: usize,
+        ) -> impl tx_rs::Tx<(), Item = Vec<OutboxEvent>, Err = DaoError> {
+            tx_rs::with_tx(move |&mut ()| Ok(vec![]))
+        }
+        fn mark_delivered(
+            &self,
+            _ids: Vec<EventId>,
+        ) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
+            tx_rs::with_tx(move |&mut ()| Ok(()))
+        }
     }
 
     struct DummyPersonUsecase {
@@ -2146,20 +3799,20 @@ mod error_stub_tests {
             &'a mut self,
             _id: PersonId,
             _date: NaiveDate,
-        ) -> impl tx_rs::Tx<(), Item = (), Err = UsecaseError>
+        ) -> impl tx_rs::Tx<(), Item = Revision, Err = UsecaseError>
         where
             (): 'a,
         {
-            tx_rs::with_tx(move |&mut ()| Ok(()))
+            tx_rs::with_tx(move |&mut ()| Ok(1))
         }
         fn remove<'a>(
             &'a mut self,
             _id: PersonId,
-        ) -> impl tx_rs::Tx<(), Item = (), Err = UsecaseError>
+        ) -> impl tx_rs::Tx<(), Item = Option<Revision>, Err = UsecaseError>
         where
             (): 'a,
         {
-            tx_rs::with_tx(move |&mut ()| Ok(()))
+            tx_rs::with_tx(move |&mut ()| Ok(Some(1)))
         }
     }
 
@@ -2292,13 +3945,27 @@ mod error_stub_tests {
         fn unload(&self, _id: PersonId) -> impl tx_rs::Tx<(), Item = (), Err = CaoError> {
             tx_rs::with_tx(move |&mut ()| self.unload_result.clone())
         }
+        fn ids(&self) -> impl tx_rs::Tx<(), Item = Vec<PersonId>, Err = CaoError> {
+            tx_rs::with_tx(move |&mut ()| Ok(vec![]))
+        }
+    }
+    // スタブパブリッシャ実装です。常に失敗します。
+    struct StubPublisher;
+    impl InvalidationPublisher for StubPublisher {
+        fn publish(&self, _id: PersonId) -> Result<(), CaoError> {
+            Err(CaoError::Unavailable("valid publisher".to_string()))
+        }
     }
     impl PersonCachedService<'_, (), ()> for TargetPersonService {
         type C = StubPersonCao;
+        type P = StubPublisher;
 
         fn get_cao(&self) -> Self::C {
             self.cao.clone()
         }
+        fn get_publisher(&self) -> Self::P {
+            StubPublisher
+        }
     }
 
     struct DummyPersonOutputBoundary;
@@ -0,0 +1,553 @@
+use log::{error, info, trace, warn};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::cache::{CaoError, LockToken, PersonCao};
+use crate::domain::PersonId;
+use crate::dto::PersonDto;
+use crate::error_detail::ErrorDetail;
+use crate::event::EventEnvelope;
+use crate::invalidation::InvalidationPublisher;
+use crate::location;
+use crate::location::Location;
+use crate::reporter::{Level, Observer, Reporter, ReporterError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    // calls go through
+    Closed,
+    // calls are rejected without touching the dependency
+    Open,
+    // a single call probes whether the dependency has come back, the others are rejected
+    HalfOpen,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BreakerSettings {
+    // consecutive failures to open the breaker
+    pub failure_threshold: u32,
+    // how long to stay open before probing again
+    pub open_timeout: Duration,
+}
+impl Default for BreakerSettings {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 3,
+            open_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+struct BreakerInner {
+    state: BreakerState,
+    failures: u32,
+    opened_at: Option<Instant>,
+    // the probe in half-open has been let through, and not recorded yet
+    probing: bool,
+}
+
+type TransitionHandler = Rc<dyn Fn(&str, BreakerState)>;
+
+/// Skips a failing dependency fast, instead of waiting for its timeout on every call.
+/// Clones share the same state, so it survives across `get_cao()` calls.
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    name: String,
+    settings: BreakerSettings,
+    inner: Rc<RefCell<BreakerInner>>,
+    on_transition: Option<TransitionHandler>,
+}
+impl CircuitBreaker {
+    pub fn new(name: &str, settings: BreakerSettings) -> Self {
+        Self {
+            name: name.to_string(),
+            settings,
+            inner: Rc::new(RefCell::new(BreakerInner {
+                state: BreakerState::Closed,
+                failures: 0,
+                opened_at: None,
+                probing: false,
+            })),
+            on_transition: None,
+        }
+    }
+
+    // called once per state change, not per call
+    pub fn on_transition(self, f: impl Fn(&str, BreakerState) + 'static) -> Self {
+        Self {
+            on_transition: Some(Rc::new(f)),
+            ..self
+        }
+    }
+
+    // reports opening and closing through the reporter.
+    // the reporter must not contain the observer guarded by this breaker.
+    #[cfg_attr(feature = "use_hash", allow(unused))]
+    pub fn report_to<R>(self, reporter: R) -> Self
+    where
+        R: Reporter<'static> + 'static,
+    {
+        self.on_transition(move |name, state| {
+            let (level, message) = match state {
+                BreakerState::Open => (Level::Error, format!("{} service not available", name)),
+                BreakerState::Closed => (Level::Info, format!("{} service recovered", name)),
+                BreakerState::HalfOpen => return,
+            };
+            if let Err(e) = reporter.send_report(level, "admin", &message, location!()) {
+                error!("reporter service not available: {}", e);
+            }
+        })
+    }
+
+    #[cfg(test)]
+    pub fn state(&self) -> BreakerState {
+        self.inner.borrow().state
+    }
+
+    // whether the dependency may be called now
+    fn allow(&self) -> bool {
+        let mut inner = self.inner.borrow_mut();
+        match inner.state {
+            BreakerState::Closed => true,
+            BreakerState::HalfOpen => !std::mem::replace(&mut inner.probing, true),
+            BreakerState::Open => {
+                if inner
+                    .opened_at
+                    .is_some_and(|t| t.elapsed() < self.settings.open_timeout)
+                {
+                    return false;
+                }
+                inner.state = BreakerState::HalfOpen;
+                inner.probing = true;
+                drop(inner);
+                self.transit(BreakerState::HalfOpen);
+                true
+            }
+        }
+    }
+
+    fn record(&self, success: bool) {
+        let mut inner = self.inner.borrow_mut();
+        inner.probing = false;
+        let next = if success {
+            inner.failures = 0;
+            BreakerState::Closed
+        } else {
+            inner.failures += 1;
+            match inner.state {
+                BreakerState::Closed if inner.failures < self.settings.failure_threshold => {
+                    BreakerState::Closed
+                }
+                // the probe failed, or too many failures
+                _ => {
+                    inner.opened_at = Some(Instant::now());
+                    BreakerState::Open
+                }
+            }
+        };
+        if inner.state == next {
+            return;
+        }
+        inner.state = next;
+        drop(inner);
+        self.transit(next);
+    }
+
+    fn transit(&self, state: BreakerState) {
+        match state {
+            BreakerState::Open => warn!("circuit breaker opened: {}", self.name),
+            BreakerState::HalfOpen => info!("circuit breaker half-open: {}", self.name),
+            BreakerState::Closed => info!("circuit breaker closed: {}", self.name),
+        }
+        if let Some(f) = &self.on_transition {
+            f(&self.name, state);
+        }
+    }
+}
+
+/// A dependency guarded by a circuit breaker.
+/// Only `Unavailable` counts as a failure, a corrupted entry means the dependency is alive.
+/// Likewise an observer which rejects a report is alive.
+#[derive(Clone)]
+pub struct Guarded<T> {
+    inner: T,
+    breaker: CircuitBreaker,
+}
+impl<T> Guarded<T> {
+    pub fn new(inner: T, breaker: CircuitBreaker) -> Self {
+        Self { inner, breaker }
+    }
+
+    fn open_error(&self) -> CaoError {
+        trace!("circuit open: {}", self.breaker.name);
+        CaoError::CircuitOpen(self.breaker.name.clone())
+    }
+    fn record<U>(&self, result: &Result<U, CaoError>) {
+        self.breaker
+            .record(!matches!(result, Err(CaoError::Unavailable(_))));
+    }
+}
+
+impl<Ctx, C: PersonCao<Ctx>> PersonCao<Ctx> for Guarded<C> {
+    fn get_conn(&self) -> Result<Ctx, CaoError> {
+        if !self.breaker.allow() {
+            return Err(self.open_error());
+        }
+        let result = self.inner.get_conn();
+        self.record(&result);
+        result
+    }
+
+    fn run_tx<T, F>(&self, f: F) -> Result<T, CaoError>
+    where
+        F: tx_rs::Tx<Ctx, Item = T, Err = CaoError>,
+    {
+        if !self.breaker.allow() {
+            return Err(self.open_error());
+        }
+        let result = self.inner.run_tx(f);
+        self.record(&result);
+        result
+    }
+
+    fn find(&self, id: PersonId) -> impl tx_rs::Tx<Ctx, Item = Option<PersonDto>, Err = CaoError> {
+        self.inner.find(id)
+    }
+    fn load(
+        &self,
+        id: PersonId,
+        person: &PersonDto,
+    ) -> impl tx_rs::Tx<Ctx, Item = (), Err = CaoError> {
+        self.inner.load(id, person)
+    }
+    fn unload(&self, id: PersonId) -> impl tx_rs::Tx<Ctx, Item = (), Err = CaoError> {
+        self.inner.unload(id)
+    }
+    fn ids(&self) -> impl tx_rs::Tx<Ctx, Item = Vec<PersonId>, Err = CaoError> {
+        self.inner.ids()
+    }
+
+    fn lock(
+        &self,
+        id: PersonId,
+        ttl: Duration,
+    ) -> impl tx_rs::Tx<Ctx, Item = Option<LockToken>, Err = CaoError> {
+        self.inner.lock(id, ttl)
+    }
+    fn unlock(
+        &self,
+        id: PersonId,
+        token: LockToken,
+    ) -> impl tx_rs::Tx<Ctx, Item = (), Err = CaoError> {
+        self.inner.unlock(id, token)
+    }
+
+    fn generation(&self) -> impl tx_rs::Tx<Ctx, Item = u64, Err = CaoError> {
+        self.inner.generation()
+    }
+    fn bump_generation(&self) -> impl tx_rs::Tx<Ctx, Item = (), Err = CaoError> {
+        self.inner.bump_generation()
+    }
+    fn find_all(
+        &self,
+        generation: u64,
+    ) -> impl tx_rs::Tx<Ctx, Item = Option<Vec<(PersonId, PersonDto)>>, Err = CaoError> {
+        self.inner.find_all(generation)
+    }
+    fn load_all(
+        &self,
+        generation: u64,
+        persons: &[(PersonId, PersonDto)],
+    ) -> impl tx_rs::Tx<Ctx, Item = (), Err = CaoError> {
+        self.inner.load_all(generation, persons)
+    }
+    fn unload_all(&self, generation: u64) -> impl tx_rs::Tx<Ctx, Item = (), Err = CaoError> {
+        self.inner.unload_all(generation)
+    }
+}
+
+impl<P: InvalidationPublisher> InvalidationPublisher for Guarded<P> {
+    fn publish(&self, id: PersonId) -> Result<(), CaoError> {
+        if !self.breaker.allow() {
+            return Err(self.open_error());
+        }
+        let result = self.inner.publish(id);
+        self.record(&result);
+        result
+    }
+}
+
+impl<O: Observer> Guarded<O> {
+    fn notify(&self, f: impl FnOnce(&O) -> Result<(), ReporterError>) -> Result<(), ReporterError> {
+        if !self.breaker.allow() {
+            // not delivered, so that the outbox relay keeps the event for the next time
+            trace!("circuit open, notification rejected: {}", self.breaker.name);
+            return Err(ReporterError::Unavailable(format!(
+                "circuit open: {}",
+                self.breaker.name
+            )));
+        }
+        let result = f(&self.inner);
+        self.breaker.record(!matches!(
+            result,
+            Err(ReporterError::Unavailable(_) | ReporterError::Unconfirmed(_))
+        ));
+        result
+    }
+}
+
+impl<O: Observer> Observer for Guarded<O> {
+    fn handle_notification(
+        &self,
+        level: Level,
+        to: &str,
+        message: &str,
+        loc: Location,
+    ) -> Result<(), ReporterError> {
+        self.notify(|o| o.handle_notification(level, to, message, loc))
+    }
+    fn handle_error(
+        &self,
+        level: Level,
+        to: &str,
+        message: &str,
+        error: &ErrorDetail,
+        loc: Location,
+    ) -> Result<(), ReporterError> {
+        self.notify(|o| o.handle_error(level, to, message, error, loc))
+    }
+    fn handle_event(&self, envelope: &EventEnvelope, loc: Location) -> Result<(), ReporterError> {
+        self.notify(|o| o.handle_event(envelope, loc))
+    }
+    fn carries_events(&self) -> bool {
+        self.inner.carries_events()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::thread;
+
+    use super::*;
+
+    #[derive(Clone)]
+    struct StubObserver {
+        up: Rc<Cell<bool>>,
+        calls: Rc<Cell<usize>>,
+    }
+    impl Observer for StubObserver {
+        fn handle_notification(
+            &self,
+            _level: Level,
+            _to: &str,
+            _message: &str,
+            _loc: Location,
+        ) -> Result<(), ReporterError> {
+            self.calls.set(self.calls.get() + 1);
+            if self.up.get() {
+                Ok(())
+            } else {
+                Err(ReporterError::Unavailable("stub observer".to_string()))
+            }
+        }
+    }
+
+    fn notify(observer: &impl Observer) -> Result<(), ReporterError> {
+        observer.handle_notification(Level::Info, "to", "message", location!())
+    }
+
+    #[test]
+    fn test_open_after_consecutive_failures() {
+        let stub = StubObserver {
+            up: Rc::new(Cell::new(false)),
+            calls: Rc::new(Cell::new(0)),
+        };
+        let breaker = CircuitBreaker::new(
+            "stub",
+            BreakerSettings {
+                failure_threshold: 2,
+                open_timeout: Duration::from_secs(60),
+            },
+        );
+        let observer = Guarded::new(stub.clone(), breaker.clone());
+
+        assert!(notify(&observer).is_err());
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(notify(&observer).is_err());
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        // 開いている間は呼び出さずに失敗を返す
+        assert_eq!(
+            notify(&observer),
+            Err(ReporterError::Unavailable("circuit open: stub".into()))
+        );
+        assert_eq!(stub.calls.get(), 2);
+    }
+
+    struct RejectingObserver;
+    impl Observer for RejectingObserver {
+        fn handle_notification(
+            &self,
+            _level: Level,
+            _to: &str,
+            _message: &str,
+            _loc: Location,
+        ) -> Result<(), ReporterError> {
+            Err(ReporterError::Rejected("400 Bad Request".to_string()))
+        }
+    }
+
+    #[test]
+    fn test_rejected_is_not_failure() {
+        let breaker = CircuitBreaker::new(
+            "stub",
+            BreakerSettings {
+                failure_threshold: 1,
+                open_timeout: Duration::from_secs(60),
+            },
+        );
+        let observer = Guarded::new(RejectingObserver, breaker.clone());
+
+        // 拒否したということは生きている
+        assert!(notify(&observer).is_err());
+        assert!(notify(&observer).is_err());
+        assert_eq!(breaker.state(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn test_success_resets_failures() {
+        let stub = StubObserver {
+            up: Rc::new(Cell::new(false)),
+            calls: Rc::new(Cell::new(0)),
+        };
+        let breaker = CircuitBreaker::new(
+            "stub",
+            BreakerSettings {
+                failure_threshold: 2,
+                open_timeout: Duration::from_secs(60),
+            },
+        );
+        let observer = Guarded::new(stub.clone(), breaker.clone());
+
+        assert!(notify(&observer).is_err());
+        stub.up.set(true);
+        assert!(notify(&observer).is_ok());
+        stub.up.set(false);
+        assert!(notify(&observer).is_err());
+        assert_eq!(breaker.state(), BreakerState::Closed, "not consecutive");
+    }
+
+    #[test]
+    fn test_probe_after_timeout() {
+        let stub = StubObserver {
+            up: Rc::new(Cell::new(false)),
+            calls: Rc::new(Cell::new(0)),
+        };
+        let transitions = Rc::new(RefCell::new(vec![]));
+        let breaker = CircuitBreaker::new(
+            "stub",
+            BreakerSettings {
+                failure_threshold: 1,
+                open_timeout: Duration::from_millis(10),
+            },
+        )
+        .on_transition({
+            let transitions = transitions.clone();
+            move |_, state| transitions.borrow_mut().push(state)
+        });
+        let observer = Guarded::new(stub.clone(), breaker.clone());
+
+        assert!(notify(&observer).is_err());
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        // 失敗した試行でまた開く
+        thread::sleep(Duration::from_millis(20));
+        assert!(notify(&observer).is_err());
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        thread::sleep(Duration::from_millis(20));
+        stub.up.set(true);
+        assert!(notify(&observer).is_ok());
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(notify(&observer).is_ok());
+
+        assert_eq!(
+            transitions.borrow().as_slice(),
+            &[
+                BreakerState::Open,
+                BreakerState::HalfOpen,
+                BreakerState::Open,
+                BreakerState::HalfOpen,
+                BreakerState::Closed,
+            ],
+            "reported once per change"
+        );
+    }
+
+    #[test]
+    fn test_single_probe() {
+        let breaker = CircuitBreaker::new(
+            "stub",
+            BreakerSettings {
+                failure_threshold: 1,
+                open_timeout: Duration::from_millis(10),
+            },
+        );
+        assert!(breaker.allow());
+        breaker.record(false);
+        assert_eq!(breaker.state(), BreakerState::Open);
+
+        // 試行中は他の呼び出しを通さない
+        thread::sleep(Duration::from_millis(20));
+        assert!(breaker.allow());
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(!breaker.allow());
+        assert!(!breaker.allow());
+
+        breaker.record(true);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.allow());
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn test_guarded_cao() {
+        use crate::lru_cache::LruPersonCao;
+        use std::num::NonZeroUsize;
+
+        let breaker = CircuitBreaker::new(
+            "cache",
+            BreakerSettings {
+                failure_threshold: 1,
+                open_timeout: Duration::from_secs(60),
+            },
+        );
+        let cao = Guarded::new(
+            LruPersonCao::new(NonZeroUsize::new(2).unwrap()),
+            breaker.clone(),
+        );
+        let id = uuid::Uuid::now_v7();
+        assert_eq!(cao.run_tx(cao.find(id)), Ok(None));
+
+        // 壊れたエントリは障害ではない
+        assert!(cao
+            .run_tx(tx_rs::with_tx(|&mut ()| Err::<(), _>(CaoError::Corrupted(
+                "broken".to_string()
+            ))))
+            .is_err());
+        assert_eq!(breaker.state(), BreakerState::Closed);
+
+        assert!(cao
+            .run_tx(tx_rs::with_tx(|&mut ()| Err::<(), _>(
+                CaoError::Unavailable("down".to_string())
+            )))
+            .is_err());
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert_eq!(
+            cao.run_tx(cao.find(id)),
+            Err(CaoError::CircuitOpen("cache".to_string()))
+        );
+    }
+}
@@ -0,0 +1,279 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::error_detail::ErrorDetail;
+use crate::location::Location;
+use crate::reporter::Level;
+use crate::spool::SpooledLocation;
+
+/// How many identical reports pass in a window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limit {
+    pub window: Duration,
+    pub burst: u32,
+}
+impl FromStr for Limit {
+    type Err = String;
+
+    // <seconds>[/<burst>], e.g. "60" or "60/3"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (window, burst) = s.split_once('/').unwrap_or((s, "1"));
+        let window = window
+            .trim()
+            .parse()
+            .map(Duration::from_secs)
+            .map_err(|e| format!("invalid window: {}: {}", s, e))?;
+        let burst = burst
+            .trim()
+            .parse()
+            .ok()
+            .filter(|b| *b > 0)
+            .ok_or(format!("invalid burst: {}", s))?;
+        Ok(Self { window, burst })
+    }
+}
+
+/// The limits of the repeated reports for each topic.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CoalesceSettings {
+    // for the topics not listed, None sends all of them
+    pub default: Option<Limit>,
+    pub topics: HashMap<String, Limit>,
+}
+impl CoalesceSettings {
+    fn limit(&self, to: &str) -> Option<Limit> {
+        self.topics
+            .get(to)
+            .or(self.default.as_ref())
+            .copied()
+            .filter(|l| !l.window.is_zero())
+    }
+}
+impl FromStr for CoalesceSettings {
+    type Err = String;
+
+    // comma separated <topic>=<limit>, where the topic `*` is the default, e.g. "admin=60,*=10/5"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut settings = CoalesceSettings::default();
+        for field in s.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            let (topic, limit) = field
+                .split_once('=')
+                .ok_or(format!("invalid coalesce limit: {}", field))?;
+            let limit = limit.parse()?;
+            match topic.trim() {
+                "*" => settings.default = Some(limit),
+                topic => {
+                    settings.topics.insert(topic.to_string(), limit);
+                }
+            }
+        }
+        Ok(settings)
+    }
+}
+
+/// A report standing for the identical ones held back in a window.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Summary {
+    pub level: Level,
+    pub to: String,
+    pub message: String,
+    // of the last one held back
+    pub error: Option<ErrorDetail>,
+    location: SpooledLocation,
+}
+impl Summary {
+    pub fn location(&self) -> Location<'_> {
+        self.location.as_location()
+    }
+}
+
+struct Window {
+    opened_at: Instant,
+    limit: Limit,
+    sent: u32,
+    held: u64,
+    // of the last one held back
+    error: Option<ErrorDetail>,
+    location: SpooledLocation,
+}
+
+type Key = (Level, String, String);
+
+/// Lets the first reports of the identical ones pass, and sums up the rest.
+pub struct Coalescer {
+    settings: CoalesceSettings,
+    windows: RefCell<HashMap<Key, Window>>,
+}
+impl Coalescer {
+    pub fn new(settings: CoalesceSettings) -> Self {
+        Self {
+            settings,
+            windows: RefCell::new(HashMap::new()),
+        }
+    }
+
+    // true if the report should be sent
+    pub fn admit(
+        &self,
+        level: &Level,
+        to: &str,
+        message: &str,
+        error: Option<&ErrorDetail>,
+        loc: &Location,
+        now: Instant,
+    ) -> bool {
+        let Some(limit) = self.settings.limit(to) else {
+            return true;
+        };
+
+        let key = (level.clone(), to.to_string(), message.to_string());
+        let mut windows = self.windows.borrow_mut();
+        match windows.get_mut(&key) {
+            Some(w) if now < w.opened_at + w.limit.window => {
+                if w.sent < w.limit.burst {
+                    w.sent += 1;
+                    return true;
+                }
+                w.held += 1;
+                w.error = error.cloned();
+                w.location = loc.clone().into();
+                false
+            }
+            _ => {
+                windows.insert(
+                    key,
+                    Window {
+                        opened_at: now,
+                        limit,
+                        sent: 1,
+                        held: 0,
+                        error: None,
+                        location: loc.clone().into(),
+                    },
+                );
+                true
+            }
+        }
+    }
+
+    // the summaries of the windows closed by now, which are forgotten
+    pub fn expired(&self, now: Instant) -> Vec<Summary> {
+        let mut summaries = vec![];
+        self.windows.borrow_mut().retain(|key, w| {
+            if now < w.opened_at + w.limit.window {
+                return true;
+            }
+            summaries.extend(summary(key, w));
+            false
+        });
+        summaries
+    }
+
+    // the summaries of all the windows, which go on counting from zero
+    pub fn drain(&self) -> Vec<Summary> {
+        let mut windows = self.windows.borrow_mut();
+        let summaries = windows.iter().filter_map(|(k, w)| summary(k, w)).collect();
+        windows.values_mut().for_each(|w| w.held = 0);
+        summaries
+    }
+}
+
+fn summary((level, to, message): &Key, w: &Window) -> Option<Summary> {
+    if w.held == 0 {
+        return None;
+    }
+    Some(Summary {
+        level: level.clone(),
+        to: to.clone(),
+        message: format!(
+            "{} (repeated {} times in {}s)",
+            message,
+            w.held,
+            w.limit.window.as_secs()
+        ),
+        error: w.error.clone(),
+        location: w.location.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coalescer(s: &str) -> Coalescer {
+        Coalescer::new(s.parse().unwrap())
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let coalescer = coalescer("admin=60");
+        let t0 = Instant::now();
+        let msg = "cache service not available";
+
+        assert!(coalescer.admit(&Level::Error, "admin", msg, None, &location!(), t0));
+        for i in 1..=3 {
+            let t = t0 + Duration::from_secs(i);
+            assert!(!coalescer.admit(&Level::Error, "admin", msg, None, &location!(), t));
+        }
+        // 別の報告や設定のない宛先は止めない
+        assert!(coalescer.admit(&Level::Error, "admin", "other", None, &location!(), t0));
+        assert!(coalescer.admit(&Level::Warn, "admin", msg, None, &location!(), t0));
+        assert!(coalescer.admit(&Level::Error, "entry_person", msg, None, &location!(), t0));
+        assert!(coalescer.admit(&Level::Error, "entry_person", msg, None, &location!(), t0));
+
+        assert!(coalescer.expired(t0 + Duration::from_secs(59)).is_empty());
+        let summaries = coalescer.expired(t0 + Duration::from_secs(60));
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].level, Level::Error);
+        assert_eq!(summaries[0].to, "admin");
+        assert_eq!(
+            summaries[0].message,
+            "cache service not available (repeated 3 times in 60s)"
+        );
+
+        // 期間が過ぎたら再び最初の 1 件を通す
+        let t = t0 + Duration::from_secs(61);
+        assert!(coalescer.admit(&Level::Error, "admin", msg, None, &location!(), t));
+    }
+
+    #[test]
+    fn test_burst_and_drain() {
+        let coalescer = coalescer("*=10/2");
+        let t0 = Instant::now();
+        let admitted = (0..5)
+            .filter(|_| coalescer.admit(&Level::Error, "admin", "down", None, &location!(), t0))
+            .count();
+        assert_eq!(admitted, 2);
+
+        // 期間中でも drain で要約を出し、数え直す
+        let summaries = coalescer.drain();
+        assert_eq!(summaries[0].message, "down (repeated 3 times in 10s)");
+        assert!(coalescer.drain().is_empty());
+        assert!(!coalescer.admit(&Level::Error, "admin", "down", None, &location!(), t0));
+    }
+
+    #[test]
+    fn test_parse() {
+        let settings: CoalesceSettings = "admin=60, *=10/5, entry_person=0".parse().unwrap();
+        assert_eq!(
+            settings.limit("admin"),
+            Some(Limit {
+                window: Duration::from_secs(60),
+                burst: 1
+            })
+        );
+        assert_eq!(
+            settings.limit("other"),
+            Some(Limit {
+                window: Duration::from_secs(10),
+                burst: 5
+            })
+        );
+        // 0 秒はまとめない
+        assert_eq!(settings.limit("entry_person"), None);
+        assert!("admin".parse::<CoalesceSettings>().is_err());
+        assert!("admin=60/0".parse::<CoalesceSettings>().is_err());
+    }
+}
@@ -0,0 +1,596 @@
+use chrono::Utc;
+use lapin::types::{AMQPValue, FieldTable};
+use log::{error, info, trace, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::domain::PersonId;
+use crate::event::EventEnvelope;
+use crate::reporter::Level;
+
+// how long to wait when all the queues are empty
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+// the number of times the message has been handled and failed
+const RETRIES_HEADER: &str = "x-retries";
+const REASON_HEADER: &str = "x-dead-letter-reason";
+const QUEUE_HEADER: &str = "x-original-queue";
+// the handlers which have handled the message, comma separated, not to handle it again on retry
+const HANDLED_HEADER: &str = "x-handled";
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum ConsumerError {
+    #[error("consumer unavailable: {0}")]
+    Unavailable(String),
+}
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum HandlerError {
+    // never succeeds however many times it's retried, e.g. undecodable
+    #[error("poison message: {0}")]
+    Poison(String),
+    // may succeed on the next try
+    #[error("handler failed: {0}")]
+    Failed(String),
+}
+
+/// A message published by `rabbitmq::Client`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Message {
+    // a change of a person, see `EventEnvelope`
+    Event(EventEnvelope),
+    // the other reports, e.g. to admin
+    Report { level: Level, message: String },
+}
+// the `Payload` of rabbitmq.rs, without the error and the location
+#[derive(Debug, Deserialize)]
+struct Report {
+    level: Level,
+    message: String,
+}
+impl Message {
+    pub fn decode(body: &[u8]) -> Result<Self, HandlerError> {
+        let body = std::str::from_utf8(body).map_err(|e| HandlerError::Poison(e.to_string()))?;
+        if let Ok(envelope) = EventEnvelope::decode(body) {
+            return Ok(Message::Event(envelope));
+        }
+        serde_json::from_str::<Report>(body)
+            .map(|r| Message::Report {
+                level: r.level,
+                message: r.message,
+            })
+            .map_err(|e| HandlerError::Poison(e.to_string()))
+    }
+}
+
+/// Handles the messages taken from the queues.
+///
+/// A failed message is handled again only by the handlers which failed it.
+pub trait Handler {
+    fn handle(&self, queue: &str, message: &Message) -> Result<(), HandlerError>;
+}
+
+pub struct Dispatcher<'a> {
+    handlers: Vec<(String, Box<dyn Handler + 'a>)>,
+}
+impl Default for Dispatcher<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<'a> Dispatcher<'a> {
+    pub fn new() -> Self {
+        Self {
+            handlers: Vec::new(),
+        }
+    }
+    // the name tells the retried message which handlers it has been through
+    pub fn register(&mut self, name: &str, handler: impl Handler + 'a) {
+        self.handlers.push((name.to_string(), Box::new(handler)));
+    }
+    // the handlers in `handled` are skipped, and the ones which succeed are added to it.
+    // the others are tried even after a failure, and the first failure is returned.
+    pub fn dispatch(
+        &self,
+        queue: &str,
+        message: &Message,
+        handled: &mut Vec<String>,
+    ) -> Result<(), HandlerError> {
+        let mut result = Ok(());
+        for (name, handler) in &self.handlers {
+            if handled.contains(name) {
+                trace!("already handled by {}: {}", name, queue);
+                continue;
+            }
+            match handler.handle(queue, message) {
+                Ok(()) => handled.push(name.clone()),
+                Err(e) => {
+                    warn!("{} failed to handle message from {}: {}", name, queue, e);
+                    if result.is_ok() {
+                        result = Err(e);
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Appends the messages to a file as JSON lines.
+pub struct AuditLogHandler {
+    path: PathBuf,
+}
+impl AuditLogHandler {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+impl Handler for AuditLogHandler {
+    fn handle(&self, queue: &str, message: &Message) -> Result<(), HandlerError> {
+        let line = serde_json::json!({
+            "received_at": Utc::now(),
+            "queue": queue,
+            "message": message,
+        });
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut f| writeln!(f, "{}", line))
+            .map_err(|e| HandlerError::Failed(format!("cannot write audit log: {}", e)))?;
+        trace!("audit log written: {}", queue);
+        Ok(())
+    }
+}
+
+/// Makes the cache follow the db for the person of the event.
+pub struct CacheRefreshHandler<F> {
+    refresh: F,
+}
+impl<F> CacheRefreshHandler<F>
+where
+    F: Fn(PersonId) -> Result<(), String>,
+{
+    pub fn new(refresh: F) -> Self {
+        Self { refresh }
+    }
+}
+impl<F> Handler for CacheRefreshHandler<F>
+where
+    F: Fn(PersonId) -> Result<(), String>,
+{
+    fn handle(&self, _queue: &str, message: &Message) -> Result<(), HandlerError> {
+        let Message::Event(envelope) = message else {
+            return Ok(());
+        };
+        let id = envelope.event.person_id();
+        (self.refresh)(id).map_err(HandlerError::Failed)?;
+        trace!("cache refreshed: {}", id);
+        Ok(())
+    }
+}
+
+/// What becomes of a message after it's handled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Disposition {
+    Ack,
+    // published to the retry queue with the retries counted up, and back to the queue after a while
+    Retry(u32),
+    DeadLetter(String),
+}
+fn dispose(result: &Result<(), HandlerError>, retries: u32, max_retries: u32) -> Disposition {
+    match result {
+        Ok(()) => Disposition::Ack,
+        Err(HandlerError::Failed(_)) if retries < max_retries => Disposition::Retry(retries + 1),
+        Err(e) => Disposition::DeadLetter(e.to_string()),
+    }
+}
+
+// the queue where the message waits before its n-th retry.
+// every retry has its own queue, since a queue expires its messages only from the head.
+fn retry_queue(queue: &str, retries: u32) -> String {
+    format!("{}.retry.{}", queue, retries)
+}
+// doubled for each retry
+fn retry_delay(first: Duration, retries: u32) -> Duration {
+    first.saturating_mul(1 << retries.saturating_sub(1).min(16))
+}
+
+/// Which queues are consumed, and where the poison messages go.
+#[derive(Debug, Clone)]
+pub struct ConsumerSettings {
+    pub uri: String,
+    pub queues: Vec<String>,
+    pub max_retries: u32,
+    // before the first retry, doubled for each of the next ones
+    pub retry_delay: Duration,
+    pub dead_letter: String,
+}
+
+pub struct Consumer {
+    async_runtime: Rc<tokio::runtime::Runtime>,
+    conn: lapin::Connection,
+    settings: ConsumerSettings,
+}
+impl Consumer {
+    pub fn open(
+        runtime: Rc<tokio::runtime::Runtime>,
+        settings: ConsumerSettings,
+    ) -> Result<Self, ConsumerError> {
+        trace!("connecting to rabbitmq: {}", settings.uri);
+        let conn = runtime.block_on(async {
+            lapin::Connection::connect(&settings.uri, lapin::ConnectionProperties::default())
+                .await
+                .map_err(|e| {
+                    error!("failed to connect to rabbitmq: {}", e);
+                    ConsumerError::Unavailable(e.to_string())
+                })
+        })?;
+        trace!("connected to rabbitmq with {:?}", conn.configuration());
+
+        Ok(Self {
+            async_runtime: runtime,
+            conn,
+            settings,
+        })
+    }
+
+    // consume the queues until the connection is lost
+    pub fn run(&self, dispatcher: &Dispatcher) -> Result<(), ConsumerError> {
+        self.async_runtime.block_on(async {
+            let chan = self.conn.create_channel().await.map_err(unavailable)?;
+            // the republished messages must reach the broker before the originals are acked
+            chan.confirm_select(lapin::options::ConfirmSelectOptions::default())
+                .await
+                .map_err(unavailable)?;
+            for queue in self
+                .settings
+                .queues
+                .iter()
+                .chain([&self.settings.dead_letter])
+            {
+                chan.queue_declare(
+                    queue,
+                    lapin::options::QueueDeclareOptions {
+                        durable: true,
+                        ..Default::default()
+                    },
+                    FieldTable::default(),
+                )
+                .await
+                .map_err(unavailable)?;
+            }
+            for queue in &self.settings.queues {
+                for retries in 1..=self.settings.max_retries {
+                    self.declare_retry_queue(&chan, queue, retries).await?;
+                }
+            }
+            info!("consuming: {:?}", self.settings.queues);
+
+            loop {
+                let mut idle = true;
+                for queue in &self.settings.queues {
+                    let msg = chan
+                        .basic_get(queue, lapin::options::BasicGetOptions::default())
+                        .await
+                        .map_err(unavailable)?;
+                    if let Some(msg) = msg {
+                        idle = false;
+                        self.process(&chan, queue, msg.delivery, dispatcher).await?;
+                    }
+                }
+                if idle {
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+            }
+        })
+    }
+
+    // the expired messages are dead-lettered back to the queue through the default exchange
+    async fn declare_retry_queue(
+        &self,
+        chan: &lapin::Channel,
+        queue: &str,
+        retries: u32,
+    ) -> Result<(), ConsumerError> {
+        let delay = retry_delay(self.settings.retry_delay, retries);
+        let mut args = FieldTable::default();
+        args.insert(
+            "x-message-ttl".into(),
+            AMQPValue::LongUInt(delay.as_millis().min(u32::MAX as u128) as u32),
+        );
+        args.insert(
+            "x-dead-letter-exchange".into(),
+            AMQPValue::LongString("".into()),
+        );
+        args.insert(
+            "x-dead-letter-routing-key".into(),
+            AMQPValue::LongString(queue.into()),
+        );
+        chan.queue_declare(
+            &retry_queue(queue, retries),
+            lapin::options::QueueDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            args,
+        )
+        .await
+        .map_err(unavailable)?;
+        Ok(())
+    }
+
+    async fn process(
+        &self,
+        chan: &lapin::Channel,
+        queue: &str,
+        delivery: lapin::message::Delivery,
+        dispatcher: &Dispatcher<'_>,
+    ) -> Result<(), ConsumerError> {
+        let retries = header(&delivery.properties, RETRIES_HEADER)
+            .and_then(|v| v.as_long_uint())
+            .unwrap_or(0);
+        let mut handled: Vec<String> = header(&delivery.properties, HANDLED_HEADER)
+            .and_then(|v| v.as_long_string())
+            .map(|s| s.to_string())
+            .unwrap_or_default()
+            .split(',')
+            .filter(|s| !s.is_empty())
+            .map(|s| s.to_string())
+            .collect();
+        let result = Message::decode(&delivery.data)
+            .and_then(|m| dispatcher.dispatch(queue, &m, &mut handled));
+
+        match dispose(&result, retries, self.settings.max_retries) {
+            Disposition::Ack => trace!("handled: {}", queue),
+            Disposition::Retry(retries) => {
+                let delay = retry_delay(self.settings.retry_delay, retries);
+                warn!(
+                    "retry ({}) message from {} in {:?}: {:?}",
+                    retries, queue, delay, result
+                );
+                let mut headers = headers(&delivery.properties);
+                headers.insert(RETRIES_HEADER.into(), AMQPValue::LongUInt(retries));
+                headers.insert(
+                    HANDLED_HEADER.into(),
+                    AMQPValue::LongString(handled.join(",").into()),
+                );
+                republish(chan, &retry_queue(queue, retries), &delivery, headers).await?;
+            }
+            Disposition::DeadLetter(reason) => {
+                error!("dead letter message from {}: {}", queue, reason);
+                let mut headers = headers(&delivery.properties);
+                headers.insert(REASON_HEADER.into(), AMQPValue::LongString(reason.into()));
+                headers.insert(QUEUE_HEADER.into(), AMQPValue::LongString(queue.into()));
+                republish(chan, &self.settings.dead_letter, &delivery, headers).await?;
+            }
+        }
+        delivery
+            .ack(lapin::options::BasicAckOptions::default())
+            .await
+            .map_err(unavailable)
+    }
+}
+
+fn unavailable(e: lapin::Error) -> ConsumerError {
+    error!("rabbitmq not available: {}", e);
+    ConsumerError::Unavailable(e.to_string())
+}
+
+fn headers(properties: &lapin::BasicProperties) -> FieldTable {
+    properties.headers().clone().unwrap_or_default()
+}
+fn header<'a>(properties: &'a lapin::BasicProperties, name: &str) -> Option<&'a AMQPValue> {
+    properties.headers().as_ref()?.inner().get(name)
+}
+
+// through the default exchange, whichever exchange the message came from
+async fn republish(
+    chan: &lapin::Channel,
+    queue: &str,
+    delivery: &lapin::message::Delivery,
+    headers: FieldTable,
+) -> Result<(), ConsumerError> {
+    let confirmation = chan
+        .basic_publish(
+            "",
+            queue,
+            lapin::options::BasicPublishOptions::default(),
+            &delivery.data,
+            delivery.properties.clone().with_headers(headers),
+        )
+        .await
+        .map_err(unavailable)?
+        .await
+        .map_err(unavailable)?;
+    if !confirmation.is_ack() {
+        return Err(ConsumerError::Unavailable(format!(
+            "nacked by broker: {}",
+            queue
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::fs;
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::event::PersonEvent;
+
+    struct SpyHandler {
+        name: &'static str,
+        result: Result<(), HandlerError>,
+        calls: Rc<RefCell<Vec<&'static str>>>,
+    }
+    impl Handler for SpyHandler {
+        fn handle(&self, _queue: &str, _message: &Message) -> Result<(), HandlerError> {
+            self.calls.borrow_mut().push(self.name);
+            self.result.clone()
+        }
+    }
+
+    fn unregistered(id: PersonId) -> Message {
+        Message::Event(EventEnvelope::new(
+            PersonEvent::Unregistered { person_id: id },
+            None,
+        ))
+    }
+
+    #[test]
+    fn test_decode() {
+        let envelope = EventEnvelope::new(
+            PersonEvent::Unregistered {
+                person_id: Uuid::now_v7(),
+            },
+            None,
+        );
+        assert_eq!(
+            Message::decode(envelope.encode().as_bytes()),
+            Ok(Message::Event(envelope))
+        );
+
+        // rabbitmq.rs の Payload
+        let report = r#"{"level":"Error","message":"cannot relay outbox","location":{"file":"app/service.rs","line":1,"column":2}}"#;
+        assert_eq!(
+            Message::decode(report.as_bytes()),
+            Ok(Message::Report {
+                level: Level::Error,
+                message: "cannot relay outbox".to_string(),
+            })
+        );
+
+        assert!(matches!(
+            Message::decode(b"registered person_id: 1"),
+            Err(HandlerError::Poison(_))
+        ));
+        assert!(matches!(
+            Message::decode(&[0xff, 0xfe]),
+            Err(HandlerError::Poison(_))
+        ));
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let calls = Rc::new(RefCell::new(vec![]));
+        let mut dispatcher = Dispatcher::new();
+        for (name, result) in [
+            ("a", Ok(())),
+            ("b", Err(HandlerError::Failed("down".to_string()))),
+            ("c", Ok(())),
+        ] {
+            dispatcher.register(
+                name,
+                SpyHandler {
+                    name,
+                    result,
+                    calls: calls.clone(),
+                },
+            );
+        }
+        let message = unregistered(Uuid::now_v7());
+
+        // 失敗しても残りのハンドラは呼ぶ
+        let mut handled = vec![];
+        let result = dispatcher.dispatch("death_person", &message, &mut handled);
+        assert_eq!(result, Err(HandlerError::Failed("down".to_string())));
+        assert_eq!(*calls.borrow(), vec!["a", "b", "c"]);
+        assert_eq!(handled, vec!["a", "c"]);
+
+        // 再試行では失敗したハンドラだけ呼ぶ
+        calls.borrow_mut().clear();
+        let result = dispatcher.dispatch("death_person", &message, &mut handled);
+        assert!(result.is_err());
+        assert_eq!(*calls.borrow(), vec!["b"]);
+        assert_eq!(handled, vec!["a", "c"]);
+    }
+
+    #[test]
+    fn test_dispose() {
+        let failed = Err(HandlerError::Failed("down".to_string()));
+        assert_eq!(dispose(&Ok(()), 2, 3), Disposition::Ack);
+        assert_eq!(dispose(&failed, 0, 3), Disposition::Retry(1));
+        assert_eq!(dispose(&failed, 2, 3), Disposition::Retry(3));
+        assert_eq!(
+            dispose(&failed, 3, 3),
+            Disposition::DeadLetter("handler failed: down".to_string())
+        );
+        // 再試行しても直らないものはすぐに dead letter へ
+        assert_eq!(
+            dispose(&Err(HandlerError::Poison("broken".to_string())), 0, 3),
+            Disposition::DeadLetter("poison message: broken".to_string())
+        );
+    }
+
+    #[test]
+    fn test_retry_queue() {
+        assert_eq!(retry_queue("entry_person", 1), "entry_person.retry.1");
+        // 再試行のたびに倍にする
+        let first = Duration::from_secs(1);
+        assert_eq!(retry_delay(first, 1), Duration::from_secs(1));
+        assert_eq!(retry_delay(first, 2), Duration::from_secs(2));
+        assert_eq!(retry_delay(first, 3), Duration::from_secs(4));
+        assert_eq!(retry_delay(first, 100), Duration::from_secs(65536));
+    }
+
+    #[test]
+    fn test_cache_refresh_handler() {
+        let refreshed = RefCell::new(vec![]);
+        let handler = CacheRefreshHandler::new(|id| {
+            refreshed.borrow_mut().push(id);
+            Ok(())
+        });
+        let id = Uuid::now_v7();
+
+        assert_eq!(
+            handler.handle("unregister_person", &unregistered(id)),
+            Ok(())
+        );
+        let report = Message::Report {
+            level: Level::Error,
+            message: "cannot relay outbox".to_string(),
+        };
+        assert_eq!(handler.handle("admin", &report), Ok(()));
+        assert_eq!(*refreshed.borrow(), vec![id]);
+
+        let handler = CacheRefreshHandler::new(|_| Err("cache down".to_string()));
+        assert_eq!(
+            handler.handle("unregister_person", &unregistered(id)),
+            Err(HandlerError::Failed("cache down".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_audit_log_handler() {
+        let path = std::env::temp_dir().join(format!("audit-{}.log", Uuid::now_v7()));
+        let handler = AuditLogHandler::new(&path);
+        let id = Uuid::now_v7();
+
+        handler
+            .handle("unregister_person", &unregistered(id))
+            .unwrap();
+        handler
+            .handle("unregister_person", &unregistered(id))
+            .unwrap();
+
+        let lines = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines = lines.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        let line: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(line["queue"], "unregister_person");
+        assert_eq!(
+            line["message"]["event"]["event"]["person_id"],
+            id.to_string()
+        );
+    }
+}
@@ -2,6 +2,19 @@ use thiserror::Error;
 
 use crate::domain::{PersonId, Revision};
 use crate::dto::PersonDto;
+use crate::error_detail::Diagnose;
+use crate::event::EventId;
+
+/// A report written in the same transaction as the change of the person.
+/// The relay publishes it to the observers after the commit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboxEvent {
+    // v7, so that the events are relayed in the order of occurrence
+    pub id: EventId,
+    pub to: String,
+    // the encoded `EventEnvelope`
+    pub message: String,
+}
 
 #[derive(Error, Debug, Clone, PartialEq, Eq)]
 pub enum DaoError {
@@ -13,6 +26,24 @@ pub enum DaoError {
     UpdateError(String),
     #[error("delete error: {0}")]
     DeleteError(String),
+    // more than the store writes in a transaction
+    #[error("transaction too large: {0}")]
+    TooLarge(String),
+}
+impl Diagnose for DaoError {
+    fn kind(&self) -> &'static str {
+        match self {
+            DaoError::InsertError(_) => "DaoError::InsertError",
+            DaoError::SelectError(_) => "DaoError::SelectError",
+            DaoError::UpdateError(_) => "DaoError::UpdateError",
+            DaoError::DeleteError(_) => "DaoError::DeleteError",
+            DaoError::TooLarge(_) => "DaoError::TooLarge",
+        }
+    }
+    // the store may come back, the messages of the drivers don't tell more
+    fn retryable(&self) -> bool {
+        !matches!(self, DaoError::TooLarge(_))
+    }
 }
 pub trait PersonDao<Ctx> {
     fn insert(&self, person: PersonDto) -> impl tx_rs::Tx<Ctx, Item = PersonId, Err = DaoError>;
@@ -25,6 +56,13 @@ pub trait PersonDao<Ctx> {
         person: PersonDto,
     ) -> impl tx_rs::Tx<Ctx, Item = (), Err = DaoError>;
     fn delete(&self, id: PersonId) -> impl tx_rs::Tx<Ctx, Item = (), Err = DaoError>;
+
+    // transactional outbox
+    fn enqueue(&self, event: OutboxEvent) -> impl tx_rs::Tx<Ctx, Item = (), Err = DaoError>;
+    // undelivered events, oldest first
+    fn pending(&self, limit: usize)
+        -> impl tx_rs::Tx<Ctx, Item = Vec<OutboxEvent>, Err = DaoError>;
+    fn mark_delivered(&self, ids: Vec<EventId>) -> impl tx_rs::Tx<Ctx, Item = (), Err = DaoError>;
 }
 
 pub trait HavePersonDao<Ctx> {
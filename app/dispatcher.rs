@@ -0,0 +1,492 @@
+use log::{error, trace, warn};
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+use crate::error_detail::ErrorDetail;
+use crate::event::EventEnvelope;
+use crate::location::Location;
+use crate::reporter::{Level, Observer, ReporterError};
+use crate::spool::{Overflow, Record, Spool, SpoolSettings};
+
+/// What the reporter does when the queue of an observer is full.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Backpressure {
+    // the oldest queued report is dropped
+    #[default]
+    DropOldest,
+    // the caller waits for the worker
+    Block,
+    // the reports wait in <dir>/<observer>.jsonl, up to the given number
+    Spill {
+        dir: PathBuf,
+        capacity: usize,
+    },
+}
+impl FromStr for Backpressure {
+    type Err = String;
+
+    // drop-oldest, block, spill or spill:<dir>
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "drop-oldest" => Ok(Backpressure::DropOldest),
+            "block" => Ok(Backpressure::Block),
+            "spill" => Ok(Backpressure::Spill {
+                dir: ".".into(),
+                capacity: 100000,
+            }),
+            _ => match s.split_once(':') {
+                Some((kind, dir)) if kind.eq_ignore_ascii_case("spill") => {
+                    Ok(Backpressure::Spill {
+                        dir: dir.into(),
+                        capacity: 100000,
+                    })
+                }
+                _ => Err(format!("unknown backpressure: {}", s)),
+            },
+        }
+    }
+}
+
+/// How many reports wait for each observer, and what happens beyond that.
+#[derive(Debug, Clone)]
+pub struct DispatcherSettings {
+    pub capacity: usize,
+    pub backpressure: Backpressure,
+}
+impl Default for DispatcherSettings {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            backpressure: Backpressure::default(),
+        }
+    }
+}
+
+type FlushAck = mpsc::Sender<Result<(), ReporterError>>;
+
+enum Job {
+    Report(Record),
+    Flush(FlushAck),
+}
+
+#[derive(Default)]
+struct State {
+    jobs: VecDeque<Job>,
+    // number of the reports in jobs, the flushes aren't counted
+    reports: usize,
+    closed: bool,
+}
+
+#[derive(Default)]
+struct Queue {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+impl Queue {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // a worker panicked in an observer leaves the queue consistent
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+    fn wait<'a>(&self, state: MutexGuard<'a, State>) -> MutexGuard<'a, State> {
+        self.changed.wait(state).unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// the observer lives in the worker, which sends the jobs to it in order
+fn work(name: &str, observer: impl Observer, queue: &Queue) {
+    // the first failure since the last flush
+    let mut failure = None;
+    loop {
+        let job = {
+            let mut state = queue.lock();
+            while state.jobs.is_empty() && !state.closed {
+                state = queue.wait(state);
+            }
+            let Some(job) = state.jobs.pop_front() else {
+                break;
+            };
+            if matches!(job, Job::Report(_)) {
+                state.reports -= 1;
+            }
+            queue.changed.notify_all();
+            job
+        };
+
+        match job {
+            Job::Report(record) => {
+                if let Err(e) = record.send(&observer) {
+                    error!("{} failed to report: {}", name, e);
+                    failure.get_or_insert(e);
+                }
+            }
+            Job::Flush(ack) => {
+                let _ = ack.send(observer.flush().and(failure.take().map_or(Ok(()), Err)));
+            }
+        }
+    }
+    trace!("{} worker stopped", name);
+}
+
+/// An observer which hands the reports to a worker thread, so that the caller doesn't wait for it.
+///
+/// The observer is created in the worker, since it is not `Send` in general.
+/// The reports are always accepted; the failures are told by `flush`.
+pub struct Dispatched {
+    name: String,
+    settings: DispatcherSettings,
+    queue: Arc<Queue>,
+    spill: Option<Spool>,
+    // number of the reports dropped since the last flush
+    dropped: Cell<usize>,
+    // told by the observer in the worker
+    carries_events: bool,
+    worker: Option<JoinHandle<()>>,
+}
+impl Dispatched {
+    pub fn spawn<O, F>(
+        name: &str,
+        settings: DispatcherSettings,
+        make_observer: F,
+    ) -> Result<Self, ReporterError>
+    where
+        O: Observer,
+        F: FnOnce() -> Result<O, ReporterError> + Send + 'static,
+    {
+        let spill = match &settings.backpressure {
+            Backpressure::Spill { dir, capacity } => Some(Spool::open(SpoolSettings {
+                path: dir.join(format!("{}.jsonl", name)),
+                capacity: *capacity,
+                overflow: Overflow::DropOldest,
+            })?),
+            _ => None,
+        };
+
+        let queue = Arc::new(Queue::default());
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let worker = thread::Builder::new()
+            .name(format!("report-{}", name))
+            .spawn({
+                let name = name.to_string();
+                let queue = queue.clone();
+                move || match make_observer() {
+                    Ok(observer) => {
+                        let _ = ready_tx.send(Ok(observer.carries_events()));
+                        work(&name, observer, &queue);
+                    }
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                    }
+                }
+            })
+            .map_err(|e| ReporterError::Unavailable(e.to_string()))?;
+        // the error of creating the observer is the caller's
+        let carries_events = ready_rx
+            .recv()
+            .map_err(|e| ReporterError::Unavailable(e.to_string()))??;
+        trace!("{} worker started", name);
+
+        Ok(Self {
+            name: name.to_string(),
+            settings,
+            queue,
+            spill,
+            dropped: Cell::new(0),
+            carries_events,
+            worker: Some(worker),
+        })
+    }
+
+    // the spilled reports are moved to the queue as far as it has room, or all of them if wait
+    fn unspill(&self, spill: &Spool, wait: bool) {
+        while !spill.is_empty() {
+            let mut state = self.queue.lock();
+            while wait && state.reports >= self.settings.capacity {
+                state = self.queue.wait(state);
+            }
+            let room = self.settings.capacity.saturating_sub(state.reports);
+            if room == 0 {
+                return;
+            }
+            for record in spill.take(room) {
+                state.jobs.push_back(Job::Report(record));
+                state.reports += 1;
+            }
+            self.queue.changed.notify_all();
+        }
+    }
+
+    fn enqueue(&self, record: Record) -> Result<(), ReporterError> {
+        if let Some(spill) = &self.spill {
+            self.unspill(spill, false);
+            // the new report waits for the spilled ones
+            if !spill.is_empty() {
+                return spill.push(record);
+            }
+        }
+
+        let mut state = self.queue.lock();
+        while state.reports >= self.settings.capacity {
+            match &self.settings.backpressure {
+                Backpressure::DropOldest => {
+                    let oldest = state.jobs.iter().position(|j| matches!(j, Job::Report(_)));
+                    if let Some(i) = oldest {
+                        state.jobs.remove(i);
+                        state.reports -= 1;
+                        self.dropped.set(self.dropped.get() + 1);
+                        warn!("{} queue full, the oldest report dropped", self.name);
+                    }
+                }
+                Backpressure::Block => state = self.queue.wait(state),
+                Backpressure::Spill { .. } => {
+                    drop(state);
+                    return self.spill.as_ref().map_or(Ok(()), |s| s.push(record));
+                }
+            }
+        }
+        state.jobs.push_back(Job::Report(record));
+        state.reports += 1;
+        self.queue.changed.notify_all();
+        Ok(())
+    }
+}
+impl Observer for Dispatched {
+    fn handle_notification(
+        &self,
+        level: Level,
+        to: &str,
+        message: &str,
+        loc: Location,
+    ) -> Result<(), ReporterError> {
+        self.enqueue(Record::Notification {
+            level,
+            to: to.to_string(),
+            message: message.to_string(),
+            error: None,
+            location: loc.into(),
+        })
+    }
+    fn handle_error(
+        &self,
+        level: Level,
+        to: &str,
+        message: &str,
+        error: &ErrorDetail,
+        loc: Location,
+    ) -> Result<(), ReporterError> {
+        self.enqueue(Record::Notification {
+            level,
+            to: to.to_string(),
+            message: message.to_string(),
+            error: Some(error.clone()),
+            location: loc.into(),
+        })
+    }
+    fn handle_event(&self, envelope: &EventEnvelope, loc: Location) -> Result<(), ReporterError> {
+        self.enqueue(Record::Event {
+            envelope: envelope.clone(),
+            location: loc.into(),
+        })
+    }
+    fn carries_events(&self) -> bool {
+        self.carries_events
+    }
+    fn flush(&self) -> Result<(), ReporterError> {
+        if let Some(spill) = &self.spill {
+            self.unspill(spill, true);
+        }
+
+        let (ack_tx, ack_rx) = mpsc::channel();
+        {
+            let mut state = self.queue.lock();
+            state.jobs.push_back(Job::Flush(ack_tx));
+            self.queue.changed.notify_all();
+        }
+        let res = ack_rx
+            .recv()
+            .map_err(|_| ReporterError::Unavailable(format!("{} worker stopped", self.name)))?;
+
+        let dropped = self.dropped.replace(0);
+        if dropped > 0 {
+            return Err(ReporterError::Unavailable(format!(
+                "{} reports dropped by {}",
+                dropped, self.name
+            )));
+        }
+        res
+    }
+}
+// the queued reports are sent before the worker stops, the spilled ones wait for the next run
+impl Drop for Dispatched {
+    fn drop(&mut self) {
+        self.queue.lock().closed = true;
+        self.queue.changed.notify_all();
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                error!("{} worker panicked", self.name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    use super::*;
+
+    // holds the reports until opened, and fails while broken
+    #[derive(Clone, Default)]
+    struct Gate {
+        open: Arc<(Mutex<bool>, Condvar)>,
+        broken: Arc<AtomicBool>,
+        received: Arc<Mutex<Vec<String>>>,
+    }
+    impl Gate {
+        fn open(&self) {
+            *self.open.0.lock().unwrap() = true;
+            self.open.1.notify_all();
+        }
+        fn received(&self) -> Vec<String> {
+            self.received.lock().unwrap().clone()
+        }
+    }
+    impl Observer for Gate {
+        fn handle_notification(
+            &self,
+            _level: Level,
+            _to: &str,
+            message: &str,
+            _loc: Location,
+        ) -> Result<(), ReporterError> {
+            let mut open = self.open.0.lock().unwrap();
+            while !*open {
+                open = self.open.1.wait(open).unwrap();
+            }
+            if self.broken.load(Ordering::SeqCst) {
+                return Err(ReporterError::Unavailable("gate".to_string()));
+            }
+            self.received.lock().unwrap().push(message.to_string());
+            Ok(())
+        }
+    }
+
+    fn spawn(gate: &Gate, backpressure: Backpressure) -> Dispatched {
+        let settings = DispatcherSettings {
+            capacity: 2,
+            backpressure,
+        };
+        let gate = gate.clone();
+        Dispatched::spawn("gate", settings, move || Ok(gate)).unwrap()
+    }
+
+    fn notify(observer: &impl Observer, message: &str) -> Result<(), ReporterError> {
+        observer.handle_notification(Level::Info, "to", message, location!())
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let gate = Gate::default();
+        let observer = spawn(&gate, Backpressure::Block);
+
+        // 配信を待たずに戻る
+        notify(&observer, "1").unwrap();
+        assert!(gate.received().is_empty());
+
+        gate.open();
+        assert_eq!(observer.flush(), Ok(()));
+        assert_eq!(gate.received(), vec!["1"]);
+
+        // 失敗は flush で分かる
+        gate.broken.store(true, Ordering::SeqCst);
+        notify(&observer, "2").unwrap();
+        assert!(observer.flush().is_err());
+        assert_eq!(observer.flush(), Ok(()));
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let gate = Gate::default();
+        let observer = spawn(&gate, Backpressure::DropOldest);
+        // 1 は worker が取り出して待っている
+        notify(&observer, "1").unwrap();
+        while observer.queue.lock().reports > 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        for message in ["2", "3", "4"] {
+            notify(&observer, message).unwrap();
+        }
+
+        gate.open();
+        assert!(observer.flush().is_err());
+        assert_eq!(gate.received(), vec!["1", "3", "4"]);
+    }
+
+    #[test]
+    fn test_spill() {
+        let dir = std::env::temp_dir().join(format!("spill-{}", Uuid::now_v7()));
+        fs::create_dir_all(&dir).unwrap();
+        let gate = Gate::default();
+        let backpressure = Backpressure::Spill {
+            dir: dir.clone(),
+            capacity: 10,
+        };
+        let observer = spawn(&gate, backpressure);
+        for message in ["1", "2", "3", "4", "5"] {
+            notify(&observer, message).unwrap();
+        }
+        // 溢れた分はファイルで待つ
+        assert!(!observer.spill.as_ref().unwrap().is_empty());
+
+        gate.open();
+        assert_eq!(observer.flush(), Ok(()));
+        assert_eq!(gate.received(), vec!["1", "2", "3", "4", "5"]);
+        drop(observer);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_drain_on_drop() {
+        let gate = Gate::default();
+        let observer = spawn(&gate, Backpressure::Block);
+        notify(&observer, "1").unwrap();
+        notify(&observer, "2").unwrap();
+
+        // 止める前にキューに残っている分を送る
+        gate.open();
+        drop(observer);
+        assert_eq!(gate.received(), vec!["1", "2"]);
+    }
+
+    #[test]
+    fn test_spawn_error() {
+        let res = Dispatched::spawn("broken", DispatcherSettings::default(), || {
+            Err::<Gate, _>(ReporterError::Unavailable("cannot connect".to_string()))
+        });
+        assert_eq!(
+            res.err(),
+            Some(ReporterError::Unavailable("cannot connect".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_backpressure() {
+        assert_eq!("drop-oldest".parse(), Ok(Backpressure::DropOldest));
+        assert_eq!("Block".parse(), Ok(Backpressure::Block));
+        assert_eq!(
+            "spill:/var/spool/app".parse(),
+            Ok(Backpressure::Spill {
+                dir: "/var/spool/app".into(),
+                capacity: 100000
+            })
+        );
+        assert!("drop-newest".parse::<Backpressure>().is_err());
+    }
+}
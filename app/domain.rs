@@ -5,6 +5,7 @@ use thiserror::Error;
 use uuid::Uuid;
 
 use crate::dto::PersonDto;
+use crate::error_detail::Diagnose;
 
 pub fn date(year: i32, month: u32, day: u32) -> NaiveDate {
     NaiveDate::from_ymd_opt(year, month, day).expect("create date")
@@ -18,6 +19,14 @@ pub enum PersonDomainError {
     #[error("already dead")]
     AlreadyDead,
 }
+impl Diagnose for PersonDomainError {
+    fn kind(&self) -> &'static str {
+        match self {
+            PersonDomainError::InvalidFieldValue(..) => "PersonDomainError::InvalidFieldValue",
+            PersonDomainError::AlreadyDead => "PersonDomainError::AlreadyDead",
+        }
+    }
+}
 
 pub type PersonId = Uuid;
 pub type Revision = i32;
@@ -1,16 +1,32 @@
-use aws_sdk_dynamodb::types::{AttributeValue, AttributeValueUpdate, ReturnValue};
+use aws_sdk_dynamodb::types::{AttributeValue, Delete, Put, TransactWriteItem};
 use chrono::NaiveDate;
 use log::{debug, trace};
-use std::{collections::HashMap, rc::Rc};
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
 use uuid::Uuid;
 
-use crate::dao::{DaoError, PersonDao};
+use crate::dao::{DaoError, OutboxEvent, PersonDao};
 use crate::domain::{PersonId, Revision};
 use crate::dto::PersonDto;
+use crate::event::EventId;
 
+// the most items TransactWriteItems takes at once, i.e. 50 persons with their events
+const MAX_TRANSACT_ITEMS: usize = 100;
+// the sparse index of the undelivered events, keyed by `pending` and sorted by the id (v7).
+// only the outbox items have `pending`, and it is removed with the item once delivered.
+const PENDING_INDEX: &str = "pending";
+
+/// The person DAO on dynamodb.
+///
+/// DynamoDB has no transaction spanning the requests, so the writes are kept
+/// until `commit`, which sends them at once with TransactWriteItems.
+/// The person and its outbox event are written atomically that way.
+/// A transaction writing more than TransactWriteItems takes is rejected,
+/// since a part of it must not be committed alone.
 #[derive(Debug, Clone)]
 pub struct DynamoDbPersonDao {
     client: aws_sdk_dynamodb::Client,
+    // the writes of the current transaction
+    writes: Rc<RefCell<Vec<TransactWriteItem>>>,
 }
 impl DynamoDbPersonDao {
     pub fn new(runtime: Rc<tokio::runtime::Runtime>, endpoint_url: &str) -> Self {
@@ -22,8 +38,105 @@ impl DynamoDbPersonDao {
         });
         trace!("SdkConfig: {:?}", config);
         let client = aws_sdk_dynamodb::Client::new(&config);
-        Self { client }
+        Self {
+            client,
+            writes: Rc::new(RefCell::new(vec![])),
+        }
+    }
+
+    pub fn commit(&self, runtime: &tokio::runtime::Runtime) -> Result<(), DaoError> {
+        let writes = self.writes.take();
+        trace!("committing {} writes", writes.len());
+        if writes.is_empty() {
+            return Ok(());
+        }
+        runtime.block_on(async {
+            let req = self
+                .client
+                .transact_write_items()
+                .set_transact_items(Some(writes));
+            trace!("request to transact-write-items: {:?}", req);
+
+            let resp = req
+                .send()
+                .await
+                .map_err(|e| DaoError::InsertError(e.to_string()))?;
+            debug!("response of transact-write-items: {:?}", resp);
+
+            Ok(())
+        })
+    }
+
+    pub fn rollback(&self) {
+        let writes = self.writes.take();
+        trace!("discarded {} writes", writes.len());
+    }
+
+    fn put(
+        &self,
+        item: HashMap<String, AttributeValue>,
+        err: fn(String) -> DaoError,
+    ) -> Result<(), DaoError> {
+        let put = Put::builder()
+            .table_name("person")
+            .set_item(Some(item))
+            .build()
+            .map_err(|e| err(e.to_string()))?;
+        debug!("put: {:?}", put);
+        self.write(TransactWriteItem::builder().put(put).build())
+    }
+    fn write(&self, item: TransactWriteItem) -> Result<(), DaoError> {
+        let mut writes = self.writes.borrow_mut();
+        if writes.len() == MAX_TRANSACT_ITEMS {
+            return Err(DaoError::TooLarge(format!(
+                "dynamodb writes at most {} items in a transaction",
+                MAX_TRANSACT_ITEMS
+            )));
+        }
+        writes.push(item);
+        Ok(())
     }
+
+    // the person written in the current transaction, which dynamodb doesn't know yet.
+    // None if it isn't written, Some(None) if it is deleted.
+    fn written(&self, id: PersonId) -> Option<Option<HashMap<String, AttributeValue>>> {
+        let pk = AttributeValue::S(format!("person#{}", id));
+        self.writes.borrow().iter().rev().find_map(|w| {
+            if let Some(put) = w.put().filter(|p| p.item().get("PK") == Some(&pk)) {
+                return Some(Some(put.item().clone()));
+            }
+            w.delete()
+                .filter(|d| d.key().get("PK") == Some(&pk))
+                .map(|_| None)
+        })
+    }
+}
+
+fn person_item(id: PersonId, person: PersonDto) -> HashMap<String, AttributeValue> {
+    let mut item = HashMap::from([
+        ("PK".into(), AttributeValue::S(format!("person#{}", id))),
+        ("SK".into(), AttributeValue::S("person".into())),
+        ("id".into(), AttributeValue::S(id.into())),
+        ("name".into(), AttributeValue::S(person.name)),
+        (
+            "birth_date".into(),
+            AttributeValue::S(person.birth_date.to_string()),
+        ),
+        (
+            "revision".into(),
+            AttributeValue::N(person.revision.to_string()),
+        ),
+    ]);
+    if let Some(death_date) = person.death_date {
+        item.insert(
+            "death_date".into(),
+            AttributeValue::S(death_date.to_string()),
+        );
+    }
+    if let Some(data) = person.data {
+        item.insert("data".into(), AttributeValue::S(data));
+    }
+    item
 }
 
 fn convert(hm: HashMap<String, AttributeValue>) -> Result<(PersonId, PersonDto), DaoError> {
@@ -78,58 +191,44 @@ fn convert(hm: HashMap<String, AttributeValue>) -> Result<(PersonId, PersonDto),
     ))
 }
 
+fn convert_event(hm: HashMap<String, AttributeValue>) -> Result<OutboxEvent, DaoError> {
+    debug!("found event: {:?}", hm);
+    let attr = |name: &str| {
+        hm.get(name)
+            .ok_or(DaoError::SelectError(format!(
+                "not found {} attr in event",
+                name
+            )))?
+            .as_s()
+            .map_err(|e| DaoError::SelectError(format!("invalid S value: {:?}", e)))
+    };
+    let id = attr("id").and_then(|d| {
+        Uuid::parse_str(d)
+            .map_err(|e| DaoError::SelectError(format!("failed to parse as UUID: {:?}", e)))
+    })?;
+
+    Ok(OutboxEvent {
+        id,
+        to: attr("queue")?.to_string(),
+        message: attr("message")?.to_string(),
+    })
+}
+
 impl PersonDao<Rc<tokio::runtime::Runtime>> for DynamoDbPersonDao {
     fn insert(
         &self,
         person: PersonDto,
     ) -> impl tx_rs::Tx<Rc<tokio::runtime::Runtime>, Item = PersonId, Err = DaoError> {
         trace!("inserting person: {:?}", person);
-        tx_rs::with_tx(move |tx: &mut Rc<tokio::runtime::Runtime>| {
-            tx.block_on(async {
-                let id = Uuid::now_v7();
-                debug!("new id: {:?}", id);
-
-                let mut item = HashMap::from([
-                    ("PK".into(), AttributeValue::S(format!("person#{}", id))),
-                    ("SK".into(), AttributeValue::S("person".into())),
-                    ("id".into(), AttributeValue::S(id.into())),
-                    ("name".into(), AttributeValue::S(person.name)),
-                    (
-                        "birth_date".into(),
-                        AttributeValue::S(person.birth_date.to_string()),
-                    ),
-                    (
-                        "revision".into(),
-                        AttributeValue::N(person.revision.to_string()),
-                    ),
-                ]);
-                if let Some(death_date) = person.death_date {
-                    item.insert(
-                        "death_date".into(),
-                        AttributeValue::S(death_date.to_string()),
-                    );
-                }
-                if let Some(data) = person.data {
-                    item.insert("data".into(), AttributeValue::S(data));
-                }
-                debug!("new person: {:?}", item);
+        tx_rs::with_tx(move |_: &mut Rc<tokio::runtime::Runtime>| {
+            let id = Uuid::now_v7();
+            debug!("new id: {:?}", id);
 
-                let req = self
-                    .client
-                    .put_item()
-                    .table_name("person")
-                    .set_item(Some(item))
-                    .return_values(ReturnValue::None);
-                trace!("request to put-item person: {:?}", req);
+            let item = person_item(id, person);
+            debug!("new person: {:?}", item);
+            self.put(item, DaoError::InsertError)?;
 
-                let resp = req
-                    .send()
-                    .await
-                    .map_err(|e| DaoError::InsertError(e.to_string()))?;
-                debug!("response of put-item person: {:?}", resp);
-
-                Ok(id)
-            })
+            Ok(id)
         })
     }
     fn fetch(
@@ -138,6 +237,9 @@ impl PersonDao<Rc<tokio::runtime::Runtime>> for DynamoDbPersonDao {
     ) -> impl tx_rs::Tx<Rc<tokio::runtime::Runtime>, Item = Option<PersonDto>, Err = DaoError> {
         trace!("fetching person: {:?}", id);
         tx_rs::with_tx(move |tx: &mut Rc<tokio::runtime::Runtime>| {
+            if let Some(item) = self.written(id) {
+                return item.map(convert).transpose().map(|p| p.map(|(_, p)| p));
+            }
             tx.block_on(async {
                 let req = self
                     .client
@@ -200,86 +302,273 @@ impl PersonDao<Rc<tokio::runtime::Runtime>> for DynamoDbPersonDao {
         person: PersonDto,
     ) -> impl tx_rs::Tx<Rc<tokio::runtime::Runtime>, Item = (), Err = DaoError> {
         trace!("saving person: {:?}", id);
+        tx_rs::with_tx(move |_: &mut Rc<tokio::runtime::Runtime>| {
+            // the person is written as a whole
+            let item = person_item(id, PersonDto { revision, ..person });
+            debug!("saved person: {:?}", item);
+            self.put(item, DaoError::UpdateError)
+        })
+    }
+    fn delete(
+        &self,
+        id: PersonId,
+    ) -> impl tx_rs::Tx<Rc<tokio::runtime::Runtime>, Item = (), Err = DaoError> {
+        trace!("deleting person: {:?}", id);
+        tx_rs::with_tx(move |_: &mut Rc<tokio::runtime::Runtime>| {
+            let delete = Delete::builder()
+                .table_name("person")
+                .key("PK", AttributeValue::S(format!("person#{}", id)))
+                .key("SK", AttributeValue::S("person".into()))
+                .build()
+                .map_err(|e| DaoError::DeleteError(e.to_string()))?;
+            debug!("delete: {:?}", delete);
+            self.write(TransactWriteItem::builder().delete(delete).build())
+        })
+    }
+
+    // put in the same TransactWriteItems as the change of the person
+    fn enqueue(
+        &self,
+        event: OutboxEvent,
+    ) -> impl tx_rs::Tx<Rc<tokio::runtime::Runtime>, Item = (), Err = DaoError> {
+        trace!("enqueueing event: {:?}", event);
+        tx_rs::with_tx(move |_: &mut Rc<tokio::runtime::Runtime>| {
+            self.put(
+                HashMap::from([
+                    (
+                        "PK".into(),
+                        AttributeValue::S(format!("outbox#{}", event.id)),
+                    ),
+                    ("SK".into(), AttributeValue::S("event".into())),
+                    ("id".into(), AttributeValue::S(event.id.into())),
+                    ("queue".into(), AttributeValue::S(event.to)),
+                    ("message".into(), AttributeValue::S(event.message)),
+                    ("pending".into(), AttributeValue::S("outbox".into())),
+                ]),
+                DaoError::InsertError,
+            )
+        })
+    }
+    fn pending(
+        &self,
+        limit: usize,
+    ) -> impl tx_rs::Tx<Rc<tokio::runtime::Runtime>, Item = Vec<OutboxEvent>, Err = DaoError> {
+        trace!("fetching pending events: {}", limit);
         tx_rs::with_tx(move |tx: &mut Rc<tokio::runtime::Runtime>| {
             tx.block_on(async {
-                let attr_name_upd = AttributeValueUpdate::builder()
-                    .action(aws_sdk_dynamodb::types::AttributeAction::Put)
-                    .value(AttributeValue::S(person.name))
-                    .build();
-                let attr_birth_upd = AttributeValueUpdate::builder()
-                    .action(aws_sdk_dynamodb::types::AttributeAction::Put)
-                    .value(AttributeValue::S(person.birth_date.to_string()))
-                    .build();
-                let attr_death_upd = match person.death_date {
-                    Some(d) => AttributeValueUpdate::builder()
-                        .action(aws_sdk_dynamodb::types::AttributeAction::Put)
-                        .value(AttributeValue::S(d.to_string()))
-                        .build(),
-                    None => AttributeValueUpdate::builder()
-                        .action(aws_sdk_dynamodb::types::AttributeAction::Delete)
-                        .build(),
-                };
-                let attr_data_upd = match person.data {
-                    Some(d) => AttributeValueUpdate::builder()
-                        .action(aws_sdk_dynamodb::types::AttributeAction::Put)
-                        .value(AttributeValue::S(d.to_string()))
-                        .build(),
-                    None => AttributeValueUpdate::builder()
-                        .action(aws_sdk_dynamodb::types::AttributeAction::Delete)
-                        .build(),
-                };
-                let attr_revision_upd = AttributeValueUpdate::builder()
-                    .action(aws_sdk_dynamodb::types::AttributeAction::Put)
-                    .value(AttributeValue::N(revision.to_string()))
-                    .build();
-
                 let req = self
                     .client
-                    .update_item()
+                    .query()
                     .table_name("person")
-                    .key("PK", AttributeValue::S(format!("person#{}", id)))
-                    .key("SK", AttributeValue::S("person".into()))
-                    .attribute_updates("name", attr_name_upd)
-                    .attribute_updates("birth_date", attr_birth_upd)
-                    .attribute_updates("death_date", attr_death_upd)
-                    .attribute_updates("data", attr_data_upd)
-                    .attribute_updates("revision", attr_revision_upd)
-                    .return_values(ReturnValue::None);
-                debug!("request for update-item person: {:?}", req);
+                    .index_name(PENDING_INDEX)
+                    .key_condition_expression("pending = :pending")
+                    .expression_attribute_values(":pending", AttributeValue::S("outbox".into()))
+                    .scan_index_forward(true)
+                    .limit(limit.min(i32::MAX as usize) as i32);
+                trace!("request to query pending events: {:?}", req);
 
                 let resp = req
                     .send()
                     .await
-                    .map_err(|e| DaoError::UpdateError(e.to_string()))?;
-                debug!("response of update-item person: {:?}", resp);
+                    .map_err(|e| DaoError::SelectError(e.to_string()))?;
+                debug!("response of query pending events: {:?}", resp);
 
-                Ok(())
+                resp.items
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(convert_event)
+                    .collect()
             })
         })
     }
-    fn delete(
+    // deleted at once, since they have been published already
+    fn mark_delivered(
         &self,
-        id: PersonId,
+        ids: Vec<EventId>,
     ) -> impl tx_rs::Tx<Rc<tokio::runtime::Runtime>, Item = (), Err = DaoError> {
-        trace!("deleting person: {:?}", id);
+        trace!("deleting delivered events: {:?}", ids);
         tx_rs::with_tx(move |tx: &mut Rc<tokio::runtime::Runtime>| {
             tx.block_on(async {
-                let req = self
-                    .client
-                    .delete_item()
-                    .table_name("person")
-                    .key("PK", AttributeValue::S(format!("person#{}", id)))
-                    .key("SK", AttributeValue::S("person".into()));
-                trace!("request to delete-item person: {:?}", req);
+                for id in ids {
+                    let req = self
+                        .client
+                        .delete_item()
+                        .table_name("person")
+                        .key("PK", AttributeValue::S(format!("outbox#{}", id)))
+                        .key("SK", AttributeValue::S("event".into()));
+                    debug!("request for delete-item event: {:?}", req);
 
-                let resp = req
-                    .send()
-                    .await
-                    .map_err(|e| DaoError::DeleteError(e.to_string()))?;
-                debug!("response of delete-item person: {:?}", resp);
+                    let resp = req
+                        .send()
+                        .await
+                        .map_err(|e| DaoError::DeleteError(e.to_string()))?;
+                    debug!("response of delete-item event: {:?}", resp);
+                }
 
                 Ok(())
             })
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use aws_sdk_dynamodb::config::{BehaviorVersion, Credentials, Region};
+    use serde_json::{json, Value};
+    use std::collections::BTreeMap;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use tx_rs::Tx;
+
+    use super::*;
+    use crate::domain::date;
+    use crate::error_detail::Diagnose;
+
+    // answers the requests of the DAO as dynamodb does, keeping the items by PK
+    fn mock_dynamodb() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let mut items = BTreeMap::<String, Value>::new();
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut headers = vec![];
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                loop {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+                    match line.trim_end().split_once(": ") {
+                        Some((n, v)) => headers.push((n.to_ascii_lowercase(), v.to_string())),
+                        None => break,
+                    }
+                }
+                let header = |name: &str| {
+                    headers
+                        .iter()
+                        .find(|(n, _)| n == name)
+                        .map(|(_, v)| v.clone())
+                        .unwrap_or_default()
+                };
+                let mut body = vec![0; header("content-length").parse().unwrap_or(0)];
+                reader.read_exact(&mut body).unwrap();
+                let req: Value = serde_json::from_slice(&body).unwrap();
+                let pk = |item: &Value| item["PK"]["S"].as_str().unwrap().to_string();
+
+                let res = match header("x-amz-target").as_str() {
+                    "DynamoDB_20120810.TransactWriteItems" => {
+                        for w in req["TransactItems"].as_array().unwrap() {
+                            if let Some(item) = w["Put"].get("Item") {
+                                items.insert(pk(item), item.clone());
+                            } else {
+                                items.remove(&pk(&w["Delete"]["Key"]));
+                            }
+                        }
+                        json!({})
+                    }
+                    "DynamoDB_20120810.Query" => {
+                        let pending = &req["ExpressionAttributeValues"][":pending"];
+                        let mut found: Vec<&Value> = items
+                            .values()
+                            .filter(|i| i.get("pending") == Some(pending))
+                            .collect();
+                        found.sort_by_key(|i| i["id"]["S"].as_str().unwrap().to_string());
+                        found.truncate(req["Limit"].as_u64().unwrap() as usize);
+                        json!({ "Items": found, "Count": found.len() })
+                    }
+                    "DynamoDB_20120810.DeleteItem" => {
+                        items.remove(&pk(&req["Key"]));
+                        json!({})
+                    }
+                    target => panic!("unexpected request: {}", target),
+                };
+                let res = res.to_string();
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/x-amz-json-1.0\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    res.len(),
+                    res
+                )
+                .unwrap();
+            }
+        });
+        url
+    }
+
+    fn dao(url: &str) -> DynamoDbPersonDao {
+        let config = aws_sdk_dynamodb::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-west-1"))
+            .credentials_provider(Credentials::new("dummy", "dummy", None, None, "test"))
+            .endpoint_url(url)
+            .build();
+        DynamoDbPersonDao {
+            client: aws_sdk_dynamodb::Client::from_conf(config),
+            writes: Rc::new(RefCell::new(vec![])),
+        }
+    }
+
+    fn runtime() -> Rc<tokio::runtime::Runtime> {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .into()
+    }
+
+    fn event(message: &str) -> OutboxEvent {
+        OutboxEvent {
+            id: Uuid::now_v7(),
+            to: "entry_person".to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_delivered_events_not_pending() {
+        let mut runtime = runtime();
+        let dao = dao(&mock_dynamodb());
+        let (e1, e2) = (event("1"), event("2"));
+
+        dao.insert(PersonDto::new("Alice", date(2000, 1, 1), None, None, 0))
+            .run(&mut runtime)
+            .unwrap();
+        dao.enqueue(e1.clone()).run(&mut runtime).unwrap();
+        dao.enqueue(e2.clone()).run(&mut runtime).unwrap();
+        dao.commit(&runtime).unwrap();
+
+        // 古いものから limit 件だけ返す
+        assert_eq!(
+            dao.pending(10).run(&mut runtime),
+            Ok(vec![e1.clone(), e2.clone()])
+        );
+        assert_eq!(dao.pending(1).run(&mut runtime), Ok(vec![e1.clone()]));
+
+        // 配信済みのイベントはもう返らない
+        dao.mark_delivered(vec![e1.id]).run(&mut runtime).unwrap();
+        assert_eq!(dao.pending(10).run(&mut runtime), Ok(vec![e2]));
+    }
+
+    #[test]
+    fn test_too_large_transaction() {
+        let mut runtime = runtime();
+        // 何も送らないので繋がらなくてよい
+        let dao = dao("http://127.0.0.1:1");
+
+        for i in 0..MAX_TRANSACT_ITEMS {
+            dao.enqueue(event(&i.to_string()))
+                .run(&mut runtime)
+                .unwrap();
+        }
+        // 一部だけコミットすることはせず、超えたら失敗させる
+        assert!(matches!(
+            dao.enqueue(event("over")).run(&mut runtime),
+            Err(DaoError::TooLarge(_))
+        ));
+        assert!(!DaoError::TooLarge(String::new()).retryable());
+
+        dao.rollback();
+        assert_eq!(dao.commit(&runtime), Ok(()));
+    }
+}
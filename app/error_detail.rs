@@ -0,0 +1,76 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// One of the causes of an error.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorSource {
+    pub kind: String,
+    pub message: String,
+}
+
+/// Why an operation failed, sent with the error reports.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorDetail {
+    // the variant, e.g. "ServiceError::TransactionFailed"
+    pub kind: String,
+    pub message: String,
+    // the causes, the outermost first
+    #[serde(default)]
+    pub sources: Vec<ErrorSource>,
+    // the store or the service which failed, if known
+    #[serde(default)]
+    pub backend: Option<String>,
+    // a hint whether the same request may succeed later
+    pub retryable: bool,
+}
+impl ErrorDetail {
+    pub fn of(e: &dyn Diagnose) -> Self {
+        let mut sources = vec![];
+        let mut cause = e.cause();
+        while let Some(c) = cause {
+            sources.push(ErrorSource {
+                kind: c.kind().to_string(),
+                message: c.to_string(),
+            });
+            cause = c.cause();
+        }
+
+        Self {
+            kind: e.kind().to_string(),
+            message: e.to_string(),
+            sources,
+            backend: e.backend().map(str::to_string),
+            retryable: e.retryable(),
+        }
+    }
+
+    // the backend known by the caller, for the errors which don't tell it
+    pub fn or_backend(self, backend: Option<&str>) -> Self {
+        Self {
+            backend: self.backend.or(backend.map(str::to_string)),
+            ..self
+        }
+    }
+}
+impl fmt::Display for ErrorDetail {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// An error which tells its variant and cause to the reports.
+pub trait Diagnose: fmt::Display {
+    fn kind(&self) -> &'static str;
+
+    fn cause(&self) -> Option<&dyn Diagnose> {
+        None
+    }
+
+    // the innermost cause knows them by default
+    fn retryable(&self) -> bool {
+        self.cause().is_some_and(|c| c.retryable())
+    }
+    fn backend(&self) -> Option<&str> {
+        self.cause().and_then(|c| c.backend())
+    }
+}
@@ -0,0 +1,209 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::dao::OutboxEvent;
+use crate::domain::{PersonId, Revision};
+use crate::dto::PersonDto;
+
+/// Version of the serialized form of `EventEnvelope`.
+/// Bump it on a change the consumers can't read, e.g. renaming or removing a field.
+pub const SCHEMA_VERSION: u32 = 1;
+
+pub type EventId = Uuid;
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum EventError {
+    #[error("malformed event: {0}")]
+    Malformed(String),
+    #[error("unsupported schema version: {0}")]
+    UnsupportedSchema(u32),
+}
+
+/// What happened to a person.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PersonEvent {
+    Registered {
+        person_id: PersonId,
+        person: PersonDto,
+    },
+    Imported {
+        person_id: PersonId,
+        person: PersonDto,
+    },
+    Died {
+        person_id: PersonId,
+        death_date: NaiveDate,
+    },
+    Unregistered {
+        person_id: PersonId,
+    },
+}
+impl PersonEvent {
+    // the same as the `type` of the serialized form
+    pub fn name(&self) -> &'static str {
+        match self {
+            PersonEvent::Registered { .. } => "registered",
+            PersonEvent::Imported { .. } => "imported",
+            PersonEvent::Died { .. } => "died",
+            PersonEvent::Unregistered { .. } => "unregistered",
+        }
+    }
+    pub fn person_id(&self) -> PersonId {
+        match self {
+            PersonEvent::Registered { person_id, .. }
+            | PersonEvent::Imported { person_id, .. }
+            | PersonEvent::Died { person_id, .. }
+            | PersonEvent::Unregistered { person_id } => *person_id,
+        }
+    }
+    // the queue the event is published to
+    pub fn queue(&self) -> &'static str {
+        match self {
+            PersonEvent::Registered { .. } | PersonEvent::Imported { .. } => "entry_person",
+            PersonEvent::Died { .. } => "death_person",
+            PersonEvent::Unregistered { .. } => "unregister_person",
+        }
+    }
+}
+// the text message for the observers which don't know the events
+impl fmt::Display for PersonEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PersonEvent::Registered { person_id, .. } => {
+                write!(f, "registered person_id: {}", person_id)
+            }
+            PersonEvent::Imported { person_id, .. } => {
+                write!(f, "imported person_id: {}", person_id)
+            }
+            PersonEvent::Died {
+                person_id,
+                death_date,
+            } => write!(
+                f,
+                "death person_id: {}, death_date: {}",
+                person_id, death_date
+            ),
+            PersonEvent::Unregistered { person_id } => {
+                write!(f, "unregistered person_id: {}", person_id)
+            }
+        }
+    }
+}
+
+/// An event with what the consumers need to order and deduplicate it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EventEnvelope {
+    pub schema: u32,
+    pub id: EventId,
+    pub occurred_at: DateTime<Utc>,
+    // the revision of the person after the change, if the service knows it
+    pub revision: Option<Revision>,
+    pub event: PersonEvent,
+}
+impl EventEnvelope {
+    pub fn new(event: PersonEvent, revision: Option<Revision>) -> Self {
+        Self {
+            schema: SCHEMA_VERSION,
+            id: Uuid::now_v7(),
+            occurred_at: Utc::now(),
+            revision,
+            event,
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        // never fails, all the keys are strings
+        serde_json::to_string(self).expect("serialize event")
+    }
+
+    pub fn decode(s: &str) -> Result<Self, EventError> {
+        let envelope: Self =
+            serde_json::from_str(s).map_err(|e| EventError::Malformed(e.to_string()))?;
+        if envelope.schema > SCHEMA_VERSION {
+            return Err(EventError::UnsupportedSchema(envelope.schema));
+        }
+        Ok(envelope)
+    }
+}
+impl From<&EventEnvelope> for OutboxEvent {
+    fn from(envelope: &EventEnvelope) -> Self {
+        OutboxEvent {
+            id: envelope.id,
+            to: envelope.event.queue().to_string(),
+            message: envelope.encode(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::date;
+
+    #[test]
+    fn test_encode_decode() {
+        let id = Uuid::now_v7();
+        let envelope = EventEnvelope::new(
+            PersonEvent::Registered {
+                person_id: id,
+                person: PersonDto::new("Alice", date(2012, 11, 2), None, Some("sender"), 0),
+            },
+            Some(0),
+        );
+
+        let json = envelope.encode();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["schema"], SCHEMA_VERSION);
+        assert_eq!(value["event"]["type"], envelope.event.name());
+        assert_eq!(value["event"]["person_id"], id.to_string());
+        assert_eq!(EventEnvelope::decode(&json), Ok(envelope));
+    }
+
+    #[test]
+    fn test_decode_error() {
+        let mut envelope = EventEnvelope::new(
+            PersonEvent::Unregistered {
+                person_id: Uuid::now_v7(),
+            },
+            None,
+        );
+        envelope.schema = SCHEMA_VERSION + 1;
+
+        // 知らない版は読まない
+        assert_eq!(
+            EventEnvelope::decode(&envelope.encode()),
+            Err(EventError::UnsupportedSchema(SCHEMA_VERSION + 1))
+        );
+        // outbox に残っている旧来のテキストの報告
+        assert!(matches!(
+            EventEnvelope::decode("registered person_id: 1"),
+            Err(EventError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_text_message() {
+        let id = Uuid::now_v7();
+        let died = PersonEvent::Died {
+            person_id: id,
+            death_date: date(2100, 1, 1),
+        };
+        assert_eq!(died.queue(), "death_person");
+        assert_eq!(
+            died.to_string(),
+            format!("death person_id: {}, death_date: 2100-01-01", id)
+        );
+
+        // 取り込みは登録とは区別する
+        let imported = PersonEvent::Imported {
+            person_id: id,
+            person: PersonDto::new("Alice", date(2000, 1, 1), None, None, 0),
+        };
+        assert_eq!(imported.queue(), "entry_person");
+        assert_eq!(imported.to_string(), format!("imported person_id: {}", id));
+    }
+}
@@ -0,0 +1,318 @@
+use chrono::{DateTime, Utc};
+use log::{error, trace};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::{
+    error_detail::ErrorDetail,
+    location::Location,
+    reporter::{self, Level, Observer},
+};
+
+/// Where the reports are appended, and when the file is rotated.
+#[derive(Debug, Clone)]
+pub struct FileLogSettings {
+    pub path: PathBuf,
+    // rotate before the file grows larger than this
+    pub max_bytes: Option<u64>,
+    // rotate when the file has been written for this long
+    pub max_age: Option<Duration>,
+    // number of the rotated files kept as <path>.1 (the newest) to <path>.<keep>
+    pub keep: usize,
+    // the reports of these levels are synced to the disk
+    pub sync: Vec<Level>,
+}
+
+#[derive(Debug, Serialize)]
+struct Record<'a> {
+    timestamp: DateTime<Utc>,
+    level: &'a Level,
+    to: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a ErrorDetail>,
+    location: Location<'a>,
+}
+
+struct Current {
+    file: File,
+    size: u64,
+    // the age of a file left by the previous run is counted from the start
+    opened_at: Instant,
+}
+impl Current {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            file,
+            size,
+            opened_at: Instant::now(),
+        })
+    }
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    name.into()
+}
+
+/// An observer which appends every report to a file as JSON lines.
+pub struct Client {
+    settings: FileLogSettings,
+    current: RefCell<Current>,
+}
+impl Client {
+    pub fn open(settings: FileLogSettings) -> Result<Self, reporter::ReporterError> {
+        trace!("opening report log: {:?}", settings.path);
+        let current = Current::open(&settings.path).map_err(|e| {
+            error!("cannot open report log: {}", e);
+            reporter::ReporterError::Unavailable(e.to_string())
+        })?;
+
+        Ok(Self {
+            settings,
+            current: RefCell::new(current),
+        })
+    }
+
+    fn should_rotate(&self, current: &Current, len: u64) -> bool {
+        if current.size == 0 {
+            return false;
+        }
+        let too_large = self
+            .settings
+            .max_bytes
+            .is_some_and(|max| current.size + len > max);
+        let too_old = self
+            .settings
+            .max_age
+            .is_some_and(|max| current.opened_at.elapsed() >= max);
+        too_large || too_old
+    }
+
+    // <path>.<keep> is dropped, the others are shifted by one, and <path> becomes <path>.1
+    fn rotate(&self, current: &mut Current) -> io::Result<()> {
+        let path = &self.settings.path;
+        trace!("rotating report log: {:?}", path);
+        if self.settings.keep == 0 {
+            fs::remove_file(path)?;
+        } else {
+            for n in (1..self.settings.keep).rev() {
+                match fs::rename(rotated(path, n), rotated(path, n + 1)) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(path, rotated(path, 1))?;
+        }
+        *current = Current::open(path)?;
+        Ok(())
+    }
+
+    fn write(&self, level: &Level, line: &str) -> io::Result<()> {
+        let mut current = self.current.borrow_mut();
+        let len = line.len() as u64 + 1;
+        if self.should_rotate(&current, len) {
+            self.rotate(&mut current)?;
+        }
+
+        writeln!(current.file, "{}", line)?;
+        current.size += len;
+        if self.settings.sync.contains(level) {
+            current.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn append(
+        &self,
+        level: Level,
+        to: &str,
+        message: &str,
+        error: Option<&ErrorDetail>,
+        loc: Location,
+    ) -> Result<(), reporter::ReporterError> {
+        let line = serde_json::to_string(&Record {
+            timestamp: Utc::now(),
+            level: &level,
+            to,
+            message,
+            error,
+            location: loc,
+        })
+        .expect("serialize report");
+        self.write(&level, &line).map_err(|e| {
+            error!("cannot write report log: {}", e);
+            reporter::ReporterError::Unavailable(e.to_string())
+        })
+    }
+}
+
+impl Observer for Client {
+    fn handle_notification(
+        &self,
+        level: Level,
+        to: &str,
+        message: &str,
+        loc: Location,
+    ) -> Result<(), reporter::ReporterError> {
+        self.append(level, to, message, None, loc)
+    }
+    fn handle_error(
+        &self,
+        level: Level,
+        to: &str,
+        message: &str,
+        error: &ErrorDetail,
+        loc: Location,
+    ) -> Result<(), reporter::ReporterError> {
+        self.append(level, to, message, Some(error), loc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn settings() -> FileLogSettings {
+        FileLogSettings {
+            path: std::env::temp_dir().join(format!("reports-{}.jsonl", Uuid::now_v7())),
+            max_bytes: None,
+            max_age: None,
+            keep: 2,
+            sync: vec![Level::Error],
+        }
+    }
+
+    fn lines(path: &Path) -> Vec<serde_json::Value> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect()
+    }
+
+    fn cleanup(settings: &FileLogSettings) {
+        for path in [
+            settings.path.clone(),
+            rotated(&settings.path, 1),
+            rotated(&settings.path, 2),
+            rotated(&settings.path, 3),
+        ] {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    #[test]
+    fn test_append() {
+        let settings = settings();
+        let client = Client::open(settings.clone()).unwrap();
+        client
+            .handle_notification(Level::Info, "entry_person", "registered", location!())
+            .unwrap();
+        client
+            .handle_notification(Level::Error, "admin", "failed", location!())
+            .unwrap();
+
+        let lines = lines(&settings.path);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["level"], "Info");
+        assert_eq!(lines[0]["to"], "entry_person");
+        assert_eq!(lines[0]["message"], "registered");
+        assert_eq!(lines[1]["location"]["file"], file!());
+        assert!(lines[1]["timestamp"]
+            .as_str()
+            .unwrap()
+            .parse::<DateTime<Utc>>()
+            .is_ok());
+        cleanup(&settings);
+    }
+
+    #[test]
+    fn test_error() {
+        let settings = settings();
+        let client = Client::open(settings.clone()).unwrap();
+        let error = ErrorDetail {
+            kind: "ServiceError::TransactionFailed".to_string(),
+            message: "transaction failed: find person failed: select error: timeout".to_string(),
+            sources: vec![],
+            backend: Some("postgres".to_string()),
+            retryable: true,
+        };
+        client
+            .handle_error(
+                Level::Error,
+                "admin",
+                "cannot find person",
+                &error,
+                location!(),
+            )
+            .unwrap();
+        client
+            .handle_notification(Level::Info, "admin", "recovered", location!())
+            .unwrap();
+
+        let lines = lines(&settings.path);
+        assert_eq!(lines[0]["message"], "cannot find person");
+        assert_eq!(lines[0]["error"]["kind"], "ServiceError::TransactionFailed");
+        assert_eq!(lines[0]["error"]["backend"], "postgres");
+        assert_eq!(lines[0]["error"]["retryable"], true);
+        // エラーのない報告には付けない
+        assert!(lines[1].get("error").is_none());
+        cleanup(&settings);
+    }
+
+    #[test]
+    fn test_rotate_by_size() {
+        let settings = FileLogSettings {
+            max_bytes: Some(1),
+            ..settings()
+        };
+        let client = Client::open(settings.clone()).unwrap();
+        for message in ["1", "2", "3", "4"] {
+            client
+                .handle_notification(Level::Info, "admin", message, location!())
+                .unwrap();
+        }
+
+        // 1 行ごとに切り替わり、古いものは 2 つだけ残る
+        assert_eq!(lines(&settings.path)[0]["message"], "4");
+        assert_eq!(lines(&rotated(&settings.path, 1))[0]["message"], "3");
+        assert_eq!(lines(&rotated(&settings.path, 2))[0]["message"], "2");
+        assert!(!rotated(&settings.path, 3).exists());
+        cleanup(&settings);
+    }
+
+    #[test]
+    fn test_rotate_by_age() {
+        let settings = FileLogSettings {
+            max_age: Some(Duration::from_secs(3600)),
+            ..settings()
+        };
+        let mut client = Client::open(settings.clone()).unwrap();
+        client
+            .handle_notification(Level::Info, "admin", "1", location!())
+            .unwrap();
+        client
+            .handle_notification(Level::Info, "admin", "2", location!())
+            .unwrap();
+        assert_eq!(lines(&settings.path).len(), 2);
+
+        // 期限を過ぎたことにする
+        client.settings.max_age = Some(Duration::ZERO);
+        client
+            .handle_notification(Level::Info, "admin", "3", location!())
+            .unwrap();
+        assert_eq!(lines(&settings.path).len(), 1);
+        assert_eq!(lines(&rotated(&settings.path, 1)).len(), 2);
+        cleanup(&settings);
+    }
+}
@@ -6,18 +6,28 @@ use std::{
 };
 use uuid::Uuid;
 
-use crate::dao::{DaoError, PersonDao};
+use crate::dao::{DaoError, OutboxEvent, PersonDao};
 use crate::domain::{PersonId, Revision};
 use crate::dto::PersonDto;
+use crate::event::EventId;
 
 #[derive(Debug, Clone)]
 pub struct HashDB {
     pub persons: Rc<RefCell<HashMap<PersonId, PersonDto>>>,
+    // events not delivered yet, the delivered ones are dropped.
+    // NOTE: nothing is rolled back in this db, so neither is the outbox.
+    pub outbox: Rc<RefCell<Vec<OutboxEvent>>>,
+}
+impl Default for HashDB {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 impl HashDB {
     pub fn new() -> Self {
         Self {
             persons: Rc::new(RefCell::new(HashMap::new())),
+            outbox: Rc::new(RefCell::new(Vec::new())),
         }
     }
 }
@@ -98,4 +108,39 @@ impl<'a> PersonDao<RefMut<'a, HashMap<PersonId, PersonDto>>> for HashDB {
             Ok(())
         })
     }
+
+    fn enqueue(
+        &self,
+        event: OutboxEvent,
+    ) -> impl tx_rs::Tx<RefMut<'a, HashMap<PersonId, PersonDto>>, Item = (), Err = DaoError> {
+        trace!("enqueueing event: {:?}", event);
+        tx_rs::with_tx(move |_: &mut RefMut<'a, HashMap<PersonId, PersonDto>>| {
+            self.outbox.borrow_mut().push(event);
+            Ok(())
+        })
+    }
+
+    fn pending(
+        &self,
+        limit: usize,
+    ) -> impl tx_rs::Tx<RefMut<'a, HashMap<PersonId, PersonDto>>, Item = Vec<OutboxEvent>, Err = DaoError>
+    {
+        trace!("fetching pending events: {}", limit);
+        tx_rs::with_tx(move |_: &mut RefMut<'a, HashMap<PersonId, PersonDto>>| {
+            Ok(self.outbox.borrow().iter().take(limit).cloned().collect())
+        })
+    }
+
+    fn mark_delivered(
+        &self,
+        ids: Vec<EventId>,
+    ) -> impl tx_rs::Tx<RefMut<'a, HashMap<PersonId, PersonDto>>, Item = (), Err = DaoError> {
+        trace!("marking events delivered: {:?}", ids);
+        tx_rs::with_tx(move |_: &mut RefMut<'a, HashMap<PersonId, PersonDto>>| {
+            self.outbox
+                .borrow_mut()
+                .retain(|event| !ids.contains(&event.id));
+            Ok(())
+        })
+    }
 }
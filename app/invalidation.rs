@@ -0,0 +1,34 @@
+use crate::cache::CaoError;
+use crate::domain::PersonId;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Invalidation {
+    Evict(PersonId),
+    // some messages may have been lost, so nothing local can be trusted
+    Resync,
+}
+
+/// Tells the other instances that a person has been changed.
+pub trait InvalidationPublisher {
+    fn publish(&self, id: PersonId) -> Result<(), CaoError>;
+}
+
+/// Receives the invalidations published by the other instances (and by itself).
+pub trait InvalidationSubscriber {
+    // never blocks, returns what has arrived since the last poll
+    fn poll(&self) -> Vec<Invalidation>;
+}
+
+/// For a single instance which has no other instances to tell.
+#[derive(Debug, Clone)]
+pub struct NoInvalidation;
+impl InvalidationPublisher for NoInvalidation {
+    fn publish(&self, _id: PersonId) -> Result<(), CaoError> {
+        Ok(())
+    }
+}
+impl InvalidationSubscriber for NoInvalidation {
+    fn poll(&self) -> Vec<Invalidation> {
+        vec![]
+    }
+}
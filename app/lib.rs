@@ -0,0 +1,33 @@
+// the modules shared by the app and the consumer
+pub mod cache;
+pub mod cache_codec;
+pub mod cache_metrics;
+pub mod cached_service;
+pub mod circuit_breaker;
+pub mod dao;
+pub mod domain;
+pub mod dto;
+#[macro_use]
+pub mod location;
+pub mod coalesce;
+pub mod consumer;
+pub mod dispatcher;
+pub mod dynamodb;
+pub mod error_detail;
+pub mod event;
+pub mod file_log;
+pub mod hs_db;
+pub mod invalidation;
+pub mod lru_cache;
+pub mod pg_db;
+pub mod rabbitmq;
+pub mod redis_cache;
+pub mod redis_invalidation;
+pub mod reporter;
+pub mod service;
+pub mod service_impl;
+pub mod spool;
+pub mod syslog;
+pub mod tiered_cache;
+pub mod usecase;
+pub mod webhook;
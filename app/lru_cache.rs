@@ -0,0 +1,211 @@
+use log::trace;
+use lru::LruCache;
+use std::{cell::RefCell, num::NonZeroUsize, rc::Rc};
+
+use crate::cache::{CaoError, PersonCao};
+use crate::domain::PersonId;
+use crate::dto::PersonDto;
+use crate::invalidation::{Invalidation, InvalidationSubscriber};
+
+/// In-process bounded cache.
+/// The least recently used person is evicted when the capacity is exceeded.
+/// Clones share the same storage, so it survives across `get_cao()` calls.
+#[derive(Clone)]
+pub struct LruPersonCao {
+    cache: Rc<RefCell<LruCache<PersonId, PersonDto>>>,
+    subscriber: Option<Rc<dyn InvalidationSubscriber>>,
+}
+impl LruPersonCao {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            cache: Rc::new(RefCell::new(LruCache::new(capacity))),
+            subscriber: None,
+        }
+    }
+
+    // evict the persons changed by the other instances before reading
+    pub fn subscribe(self, subscriber: impl InvalidationSubscriber + 'static) -> Self {
+        Self {
+            subscriber: Some(Rc::new(subscriber)),
+            ..self
+        }
+    }
+
+    fn sync(&self) {
+        let Some(subscriber) = &self.subscriber else {
+            return;
+        };
+        let mut cache = self.cache.borrow_mut();
+        for invalidation in subscriber.poll() {
+            match invalidation {
+                Invalidation::Evict(id) => {
+                    trace!("evict person from local cache: {}", id);
+                    cache.pop(&id);
+                }
+                Invalidation::Resync => {
+                    trace!("clear local cache");
+                    cache.clear();
+                }
+            }
+        }
+    }
+}
+
+impl PersonCao<()> for LruPersonCao {
+    fn get_conn(&self) -> Result<(), CaoError> {
+        Ok(())
+    }
+
+    fn run_tx<T, F>(&self, f: F) -> Result<T, CaoError>
+    where
+        F: tx_rs::Tx<(), Item = T, Err = CaoError>,
+    {
+        f.run(&mut ())
+    }
+
+    fn find(&self, id: PersonId) -> impl tx_rs::Tx<(), Item = Option<PersonDto>, Err = CaoError> {
+        trace!("find person in local cache: {}", id);
+        tx_rs::with_tx(move |&mut ()| {
+            self.sync();
+            let p = self.cache.borrow_mut().get(&id).cloned();
+            trace!("found person in local cache: {:?}", p);
+            Ok(p)
+        })
+    }
+    fn load(
+        &self,
+        id: PersonId,
+        person: &PersonDto,
+    ) -> impl tx_rs::Tx<(), Item = (), Err = CaoError> {
+        trace!("load person into local cache: {}", id);
+        tx_rs::with_tx(move |&mut ()| {
+            if let Some((evicted, _)) = self.cache.borrow_mut().push(id, person.clone()) {
+                if evicted != id {
+                    trace!("person evicted from local cache: {}", evicted);
+                }
+            }
+            Ok(())
+        })
+    }
+    fn unload(&self, id: PersonId) -> impl tx_rs::Tx<(), Item = (), Err = CaoError> {
+        trace!("unload person from local cache: {}", id);
+        tx_rs::with_tx(move |&mut ()| {
+            self.cache.borrow_mut().pop(&id);
+            Ok(())
+        })
+    }
+    fn ids(&self) -> impl tx_rs::Tx<(), Item = Vec<PersonId>, Err = CaoError> {
+        trace!("list persons in local cache");
+        tx_rs::with_tx(move |&mut ()| {
+            self.sync();
+            Ok(self.cache.borrow().iter().map(|(id, _)| *id).collect())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::domain::date;
+
+    fn person(name: &str) -> PersonDto {
+        PersonDto::new(name, date(2000, 1, 1), None, Some(name), 0)
+    }
+
+    #[test]
+    fn test_load_and_find() {
+        let cao = LruPersonCao::new(NonZeroUsize::new(2).unwrap());
+        let id = Uuid::now_v7();
+
+        assert_eq!(cao.run_tx(cao.find(id)), Ok(None));
+        assert_eq!(cao.run_tx(cao.load(id, &person("Alice"))), Ok(()));
+        assert_eq!(cao.run_tx(cao.find(id)), Ok(Some(person("Alice"))));
+    }
+
+    #[test]
+    fn test_unload() {
+        let cao = LruPersonCao::new(NonZeroUsize::new(2).unwrap());
+        let id = Uuid::now_v7();
+
+        cao.run_tx(cao.load(id, &person("Alice"))).unwrap();
+        assert_eq!(cao.run_tx(cao.unload(id)), Ok(()));
+        assert_eq!(cao.run_tx(cao.find(id)), Ok(None));
+
+        // 存在しないものを unload してもエラーにはならない
+        assert_eq!(cao.run_tx(cao.unload(id)), Ok(()));
+    }
+
+    #[test]
+    fn test_ids() {
+        let cao = LruPersonCao::new(NonZeroUsize::new(2).unwrap());
+        let (id1, id2) = (Uuid::now_v7(), Uuid::now_v7());
+        cao.run_tx(cao.load(id1, &person("Alice"))).unwrap();
+        cao.run_tx(cao.load(id2, &person("Bob"))).unwrap();
+
+        let mut ids = cao.run_tx(cao.ids()).unwrap();
+        ids.sort();
+        assert_eq!(ids, vec![id1, id2]);
+    }
+
+    #[test]
+    fn test_evict_least_recently_used() {
+        let cao = LruPersonCao::new(NonZeroUsize::new(2).unwrap());
+        let (id1, id2, id3) = (Uuid::now_v7(), Uuid::now_v7(), Uuid::now_v7());
+
+        cao.run_tx(cao.load(id1, &person("Alice"))).unwrap();
+        cao.run_tx(cao.load(id2, &person("Bob"))).unwrap();
+        // id1 を参照して id2 を最も古いものにする
+        cao.run_tx(cao.find(id1)).unwrap();
+        cao.run_tx(cao.load(id3, &person("Charlie"))).unwrap();
+
+        assert_eq!(cao.run_tx(cao.find(id1)), Ok(Some(person("Alice"))));
+        assert_eq!(cao.run_tx(cao.find(id2)), Ok(None), "evicted");
+        assert_eq!(cao.run_tx(cao.find(id3)), Ok(Some(person("Charlie"))));
+    }
+
+    #[derive(Debug, Clone)]
+    struct StubSubscriber {
+        invalidations: Rc<RefCell<Vec<Invalidation>>>,
+    }
+    impl InvalidationSubscriber for StubSubscriber {
+        fn poll(&self) -> Vec<Invalidation> {
+            self.invalidations.borrow_mut().drain(..).collect()
+        }
+    }
+
+    #[test]
+    fn test_evict_by_subscriber() {
+        let subscriber = StubSubscriber {
+            invalidations: Rc::new(RefCell::new(vec![])),
+        };
+        let cao = LruPersonCao::new(NonZeroUsize::new(2).unwrap()).subscribe(subscriber.clone());
+        let (id1, id2) = (Uuid::now_v7(), Uuid::now_v7());
+        cao.run_tx(cao.load(id1, &person("Alice"))).unwrap();
+        cao.run_tx(cao.load(id2, &person("Bob"))).unwrap();
+
+        subscriber
+            .invalidations
+            .borrow_mut()
+            .push(Invalidation::Evict(id1));
+        assert_eq!(cao.run_tx(cao.find(id1)), Ok(None), "evicted");
+        assert_eq!(cao.run_tx(cao.find(id2)), Ok(Some(person("Bob"))));
+
+        subscriber
+            .invalidations
+            .borrow_mut()
+            .push(Invalidation::Resync);
+        assert_eq!(cao.run_tx(cao.find(id2)), Ok(None), "cleared");
+    }
+
+    #[test]
+    fn test_shared_between_clones() {
+        let cao = LruPersonCao::new(NonZeroUsize::new(2).unwrap());
+        let id = Uuid::now_v7();
+
+        let other = cao.clone();
+        other.run_tx(other.load(id, &person("Alice"))).unwrap();
+        assert_eq!(cao.run_tx(cao.find(id)), Ok(Some(person("Alice"))));
+    }
+}
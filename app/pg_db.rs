@@ -1,11 +1,16 @@
 use chrono::NaiveDate;
 use log::trace;
 use std::str;
+use std::time::Duration;
 use uuid::Uuid;
 
-use crate::dao::{DaoError, PersonDao};
+use crate::dao::{DaoError, OutboxEvent, PersonDao};
 use crate::domain::{PersonId, Revision};
 use crate::dto::PersonDto;
+use crate::event::EventId;
+
+// the delivered events are kept for a while to look into, and then deleted
+const OUTBOX_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
 
 #[derive(Debug, Clone)]
 pub struct PgPersonDao;
@@ -147,4 +152,73 @@ impl<'a> PersonDao<postgres::Transaction<'a>> for PgPersonDao {
                 .map_err(|e| DaoError::DeleteError(e.to_string()))
         })
     }
+
+    fn enqueue(
+        &self,
+        event: OutboxEvent,
+    ) -> impl tx_rs::Tx<postgres::Transaction<'a>, Item = (), Err = DaoError> {
+        trace!("enqueueing event: {:?}", event);
+        tx_rs::with_tx(move |tx: &mut postgres::Transaction<'_>| {
+            tx.execute(
+                r#"INSERT INTO outbox ( id
+                                      , queue
+                                      , message
+                                      )
+                   VALUES ($1, $2, $3)"#,
+                &[&event.id, &event.to, &event.message],
+            )
+            .map(|_| ())
+            .map_err(|e| DaoError::InsertError(e.to_string()))
+        })
+    }
+    fn pending(
+        &self,
+        limit: usize,
+    ) -> impl tx_rs::Tx<postgres::Transaction<'a>, Item = Vec<OutboxEvent>, Err = DaoError> {
+        trace!("fetching pending events: {}", limit);
+        tx_rs::with_tx(move |tx: &mut postgres::Transaction<'_>| {
+            // not locked, since they are marked delivered in another transaction after publishing
+            tx.query(
+                r#"SELECT id,
+                          queue,
+                          message
+                     FROM outbox
+                    WHERE delivered_at IS NULL
+                 ORDER BY id
+                    LIMIT $1"#,
+                &[&(limit as i64)],
+            )
+            .map(|rows| {
+                rows.iter()
+                    .map(|row| OutboxEvent {
+                        id: row.get::<usize, Uuid>(0),
+                        to: row.get::<usize, String>(1),
+                        message: row.get::<usize, String>(2),
+                    })
+                    .collect()
+            })
+            .map_err(|e| DaoError::SelectError(e.to_string()))
+        })
+    }
+    fn mark_delivered(
+        &self,
+        ids: Vec<EventId>,
+    ) -> impl tx_rs::Tx<postgres::Transaction<'a>, Item = (), Err = DaoError> {
+        trace!("marking events delivered: {:?}", ids);
+        tx_rs::with_tx(move |tx: &mut postgres::Transaction<'_>| {
+            tx.execute(
+                "UPDATE outbox SET delivered_at = now() WHERE id = ANY($1)",
+                &[&ids],
+            )
+            .map_err(|e| DaoError::UpdateError(e.to_string()))?;
+            let pruned = tx
+                .execute(
+                    "DELETE FROM outbox WHERE delivered_at < now() - make_interval(secs => $1)",
+                    &[&(OUTBOX_RETENTION.as_secs() as f64)],
+                )
+                .map_err(|e| DaoError::DeleteError(e.to_string()))?;
+            trace!("pruned delivered events: {}", pruned);
+            Ok(())
+        })
+    }
 }
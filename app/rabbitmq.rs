@@ -1,46 +1,441 @@
-pub use log::{error, trace};
-use serde::{Deserialize, Serialize};
+pub use log::{error, trace, warn};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::HashSet;
 pub use std::rc::Rc;
+use std::str::FromStr;
+use std::time::Duration;
 
+use crate::error_detail::ErrorDetail;
+use crate::event::EventEnvelope;
 use crate::reporter::{self, Level, Location, Observer};
+use crate::spool::SpoolSettings;
+
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+// persistent delivery mode of AMQP
+const PERSISTENT: u8 = 2;
+const JSON: &str = "application/json";
+
+/// How hard the reports are delivered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Delivery {
+    // transient messages without publisher confirms
+    #[default]
+    FireAndForget,
+    // persistent messages, each of them confirmed by the broker
+    Confirmed,
+    // confirmed, and the unconfirmed ones are published again up to the given times
+    AtLeastOnce(u32),
+}
+impl Delivery {
+    fn confirms(&self) -> bool {
+        *self != Delivery::FireAndForget
+    }
+    // a confirmed message which no queue receives is returned, not to be acked as delivered
+    fn publish_options(&self) -> lapin::options::BasicPublishOptions {
+        lapin::options::BasicPublishOptions {
+            mandatory: self.confirms(),
+            ..Default::default()
+        }
+    }
+    // publishing again may duplicate the report, which only at-least-once accepts
+    fn retries(&self) -> u32 {
+        match self {
+            Delivery::AtLeastOnce(retries) => *retries,
+            _ => 0,
+        }
+    }
+}
+impl FromStr for Delivery {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "fire-and-forget" => Ok(Delivery::FireAndForget),
+            "confirmed" => Ok(Delivery::Confirmed),
+            "at-least-once" => Ok(Delivery::AtLeastOnce(3)),
+            s => match s.strip_prefix("at-least-once:").map(|n| n.parse()) {
+                Some(Ok(retries)) => Ok(Delivery::AtLeastOnce(retries)),
+                _ => Err(format!("unknown delivery: {}", s)),
+            },
+        }
+    }
+}
+
+/// A queue bound to the exchange, and the routing keys it receives.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Binding {
+    pub queue: String,
+    pub pattern: String,
+}
+// <queue>=<pattern>, e.g. audit=info.person.#
+impl FromStr for Binding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((queue, pattern)) if !queue.is_empty() && !pattern.is_empty() => Ok(Binding {
+                queue: queue.trim().to_string(),
+                pattern: pattern.trim().to_string(),
+            }),
+            _ => Err(format!("invalid binding: {}", s)),
+        }
+    }
+}
+
+/// The exchange the reports are published to, instead of the default one.
+///
+/// The routing keys are `<level>.<to>` for the text reports, e.g. `error.admin`,
+/// and `<level>.person.<event>` for the events, e.g. `info.person.registered`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exchange {
+    pub name: String,
+    // topic or fanout
+    pub kind: lapin::ExchangeKind,
+    // declared along with the exchange
+    pub bindings: Vec<Binding>,
+}
+// <name>[:topic|:fanout], topic by default
+impl FromStr for Exchange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, kind) = s.split_once(':').unwrap_or((s, "topic"));
+        let kind = match kind.to_ascii_lowercase().as_str() {
+            "topic" => lapin::ExchangeKind::Topic,
+            "fanout" => lapin::ExchangeKind::Fanout,
+            _ => return Err(format!("unsupported exchange kind: {}", kind)),
+        };
+        if name.is_empty() {
+            return Err(format!("invalid exchange: {}", s));
+        }
+        Ok(Exchange {
+            name: name.to_string(),
+            kind,
+            bindings: vec![],
+        })
+    }
+}
+
+/// Where and how the reports are sent.
+#[derive(Debug, Clone)]
+pub struct MqSettings {
+    pub uri: String,
+    pub delivery: Delivery,
+    // None publishes to the queue named after the kind of the report
+    pub exchange: Option<Exchange>,
+    // where the reports wait while the broker is unavailable
+    pub spool: SpoolSettings,
+}
 
 #[derive(Debug, Clone)]
 pub struct Client {
     async_runtime: Rc<tokio::runtime::Runtime>,
-    conn: Rc<lapin::Connection>,
+    addr: String,
+    // None until connected, e.g. the broker was down at startup
+    conn: Rc<RefCell<Option<Rc<lapin::Connection>>>>,
+    // reused across notifications, recreated after the broker has closed it
+    channel: Rc<RefCell<Option<lapin::Channel>>>,
+    // queues already declared through the current channel
+    declared: Rc<RefCell<HashSet<String>>>,
+    delivery: Delivery,
+    exchange: Option<Exchange>,
 }
 impl Client {
-    pub fn open(
-        runtime: Rc<tokio::runtime::Runtime>,
-        addr: &str,
-    ) -> Result<Self, reporter::ReporterError> {
-        trace!("connecting to rabbitmq: {}", addr);
-        let conn = runtime.block_on(async {
-            lapin::Connection::connect(addr, lapin::ConnectionProperties::default())
+    // the service starts even if the broker is down, the connection is retried on publishing
+    pub fn open(runtime: Rc<tokio::runtime::Runtime>, addr: &str) -> Self {
+        let client = Self {
+            async_runtime: runtime,
+            addr: addr.to_string(),
+            conn: Rc::new(RefCell::new(None)),
+            channel: Rc::new(RefCell::new(None)),
+            declared: Rc::new(RefCell::new(HashSet::new())),
+            delivery: Delivery::default(),
+            exchange: None,
+        };
+        if let Err(e) = client.async_runtime.block_on(client.connection()) {
+            warn!("start without rabbitmq: {}", e);
+        }
+        client
+    }
+
+    pub fn delivery(self, delivery: Delivery) -> Self {
+        Self { delivery, ..self }
+    }
+
+    pub fn exchange(self, exchange: Option<Exchange>) -> Self {
+        Self { exchange, ..self }
+    }
+
+    async fn connection(&self) -> Result<Rc<lapin::Connection>, reporter::ReporterError> {
+        let conn = self.conn.borrow().clone();
+        if let Some(conn) = conn.filter(|c| c.status().connected()) {
+            return Ok(conn);
+        }
+
+        trace!("connecting to rabbitmq: {}", self.addr);
+        let conn = lapin::Connection::connect(&self.addr, lapin::ConnectionProperties::default())
+            .await
+            .map_err(|e| {
+                error!("failed to connect to rabbitmq: {}", e);
+                reporter::ReporterError::Unavailable(e.to_string())
+            })?;
+        trace!("connected to rabbitmq with {:?}", conn.configuration());
+        let conn = Rc::new(conn);
+        *self.conn.borrow_mut() = Some(conn.clone());
+        Ok(conn)
+    }
+
+    async fn channel(&self) -> Result<lapin::Channel, reporter::ReporterError> {
+        let chan = self.channel.borrow().clone();
+        if let Some(chan) = chan.filter(|c| c.status().connected()) {
+            return Ok(chan);
+        }
+
+        let conn = self.connection().await?;
+        let chan = conn.create_channel().await.map_err(|e| {
+            error!("failed to create channel: {}", e);
+            reporter::ReporterError::Unavailable(e.to_string())
+        })?;
+        trace!("channel created");
+        if self.delivery.confirms() {
+            chan.confirm_select(lapin::options::ConfirmSelectOptions::default())
                 .await
                 .map_err(|e| {
-                    error!("failed to connect to rabbitmq: {}", e);
+                    error!("failed to enable publisher confirms: {}", e);
                     reporter::ReporterError::Unavailable(e.to_string())
-                })
+                })?;
+            trace!("publisher confirms enabled");
+        }
+        self.declared.borrow_mut().clear();
+        *self.channel.borrow_mut() = Some(chan.clone());
+        Ok(chan)
+    }
+
+    // a queue is declared only once per channel
+    async fn declare(
+        &self,
+        chan: &lapin::Channel,
+        to: &str,
+    ) -> Result<(), reporter::ReporterError> {
+        if self.declared.borrow().contains(to) {
+            return Ok(());
+        }
+        chan.queue_declare(
+            to,
+            lapin::options::QueueDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            lapin::types::FieldTable::default(),
+        )
+        .await
+        .map_err(|e| {
+            error!("failed to declare queue: {}", e);
+            reporter::ReporterError::Unavailable(e.to_string())
         })?;
-        trace!("connected to rabbitmq with {:?}", conn.configuration());
+        trace!("queue declared: {}", to);
+        self.declared.borrow_mut().insert(to.to_string());
+        Ok(())
+    }
 
-        Ok(Self {
-            async_runtime: runtime,
-            conn: Rc::new(conn),
+    // the exchange and its bindings are declared only once per channel, too
+    async fn declare_exchange(
+        &self,
+        chan: &lapin::Channel,
+        exchange: &Exchange,
+    ) -> Result<(), reporter::ReporterError> {
+        let key = format!("exchange:{}", exchange.name);
+        if self.declared.borrow().contains(&key) {
+            return Ok(());
+        }
+        chan.exchange_declare(
+            &exchange.name,
+            exchange.kind.clone(),
+            lapin::options::ExchangeDeclareOptions {
+                durable: true,
+                ..Default::default()
+            },
+            lapin::types::FieldTable::default(),
+        )
+        .await
+        .map_err(|e| {
+            error!("failed to declare exchange: {}", e);
+            reporter::ReporterError::Unavailable(e.to_string())
+        })?;
+        trace!("exchange declared: {}", exchange.name);
+        for binding in &exchange.bindings {
+            self.declare(chan, &binding.queue).await?;
+            chan.queue_bind(
+                &binding.queue,
+                &exchange.name,
+                &binding.pattern,
+                lapin::options::QueueBindOptions::default(),
+                lapin::types::FieldTable::default(),
+            )
+            .await
+            .map_err(|e| {
+                error!("failed to bind queue: {}", e);
+                reporter::ReporterError::Unavailable(e.to_string())
+            })?;
+            trace!("queue bound: {:?}", binding);
+        }
+        self.declared.borrow_mut().insert(key);
+        Ok(())
+    }
+
+    // the queue to publish to without an exchange, or the routing key with it
+    fn routing_key(&self, level: &Level, name: &str, to: &str) -> String {
+        match self.exchange {
+            None => to.to_string(),
+            Some(_) => format!("{}.{}", level_name(level), name),
+        }
+    }
+
+    async fn publish(
+        &self,
+        routing_key: &str,
+        payload: &[u8],
+        properties: lapin::BasicProperties,
+    ) -> Result<(), reporter::ReporterError> {
+        let chan = self.channel().await?;
+        let exchange = match &self.exchange {
+            None => {
+                self.declare(&chan, routing_key).await?;
+                ""
+            }
+            Some(exchange) => {
+                self.declare_exchange(&chan, exchange).await?;
+                exchange.name.as_str()
+            }
+        };
+        let properties = if self.delivery.confirms() {
+            properties.with_delivery_mode(PERSISTENT)
+        } else {
+            properties
+        };
+        let confirm = chan
+            .basic_publish(
+                exchange,
+                routing_key,
+                self.delivery.publish_options(),
+                payload,
+                properties,
+            )
+            .await
+            .map_err(|e| {
+                error!("failed to publish message: {}", e);
+                reporter::ReporterError::Unavailable(e.to_string())
+            })?;
+        if !self.delivery.confirms() {
+            return Ok(());
+        }
+
+        let confirmation = confirm.await.map_err(|e| {
+            error!("failed to wait for confirmation: {}", e);
+            reporter::ReporterError::Unconfirmed(e.to_string())
+        })?;
+        match confirmation {
+            lapin::publisher_confirm::Confirmation::Ack(None) => {}
+            // acked, but routed to no queue
+            lapin::publisher_confirm::Confirmation::Ack(Some(returned)) => {
+                error!("message returned: {:?}", returned);
+                return Err(reporter::ReporterError::Unconfirmed(format!(
+                    "returned by broker: {}: {}",
+                    routing_key, returned.reply_text
+                )));
+            }
+            confirmation => {
+                error!("message not confirmed: {:?}", confirmation);
+                return Err(reporter::ReporterError::Unconfirmed(format!(
+                    "nacked by broker: {}",
+                    routing_key
+                )));
+            }
+        }
+        trace!("confirmed: {}", routing_key);
+        Ok(())
+    }
+
+    // the broker closes the channel on any error, so start over with a new one
+    fn reset(&self) {
+        *self.channel.borrow_mut() = None;
+        self.declared.borrow_mut().clear();
+    }
+
+    fn send(
+        &self,
+        routing_key: &str,
+        payload: &[u8],
+        properties: lapin::BasicProperties,
+    ) -> Result<(), reporter::ReporterError> {
+        self.async_runtime.block_on(async {
+            let mut result = self.publish(routing_key, payload, properties.clone()).await;
+            for retry in 1..=self.delivery.retries() {
+                let Err(e) = &result else {
+                    break;
+                };
+                warn!("retry with a new channel ({}): {}", retry, e);
+                self.reset();
+                if retry > 1 {
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                }
+                result = self.publish(routing_key, payload, properties.clone()).await;
+            }
+            // the next report starts over with a new channel
+            if result.is_err() {
+                self.reset();
+            }
+            result
+        })
+    }
+}
+
+fn level_name(level: &Level) -> &'static str {
+    match level {
+        Level::Trace => "trace",
+        Level::Info => "info",
+        Level::Warn => "warn",
+        Level::Error => "error",
+    }
+}
+
+impl Client {
+    fn report(
+        &self,
+        level: Level,
+        to: &str,
+        message: &str,
+        error: Option<&ErrorDetail>,
+        loc: Location,
+    ) -> Result<(), reporter::ReporterError> {
+        let routing_key = self.routing_key(&level, to, to);
+        let payload = serde_json::to_string(&Payload {
+            level,
+            message,
+            error,
+            location: loc,
         })
+        .unwrap_or_default();
+        let properties = lapin::BasicProperties::default().with_content_type(JSON.into());
+        self.send(&routing_key, payload.as_bytes(), properties)?;
+        trace!("published: {} to {}", message, routing_key);
+
+        Ok(())
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize)]
 struct Payload<'a> {
     level: Level,
     message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a ErrorDetail>,
     location: Location<'a>,
 }
 
 impl Observer for Client {
-    // to: queue name
+    // to: queue name, or the last part of the routing key with an exchange
     // message: message to send
     fn handle_notification(
         &self,
@@ -49,47 +444,98 @@ impl Observer for Client {
         message: &str,
         loc: Location,
     ) -> Result<(), reporter::ReporterError> {
-        self.async_runtime.block_on(async {
-            let chan = self.conn.create_channel().await.map_err(|e| {
-                error!("failed to create channel: {}", e);
-                reporter::ReporterError::Unavailable(e.to_string())
-            })?;
-            trace!("channel created");
-            chan.queue_declare(
-                to,
-                lapin::options::QueueDeclareOptions {
-                    durable: true,
-                    ..Default::default()
-                },
-                lapin::types::FieldTable::default(),
-            )
-            .await
-            .map_err(|e| {
-                error!("failed to declare queue: {}", e);
-                reporter::ReporterError::Unavailable(e.to_string())
-            })?;
-            trace!("queue declared: {}", to);
-            let payload = serde_json::to_string(&Payload {
-                level,
-                message,
-                location: loc,
-            })
-            .unwrap_or_default();
-            chan.basic_publish(
-                "",
-                to,
-                lapin::options::BasicPublishOptions::default(),
-                payload.as_bytes(),
-                lapin::BasicProperties::default(),
-            )
-            .await
-            .map_err(|e| {
-                error!("failed to publish message: {}", e);
-                reporter::ReporterError::Unavailable(e.to_string())
-            })?;
-            trace!("published: {} to {}", message, to);
+        self.report(level, to, message, None, loc)
+    }
 
-            Ok(())
-        })
+    fn handle_error(
+        &self,
+        level: Level,
+        to: &str,
+        message: &str,
+        error: &ErrorDetail,
+        loc: Location,
+    ) -> Result<(), reporter::ReporterError> {
+        self.report(level, to, message, Some(error), loc)
+    }
+
+    // the broker is where the outbox events go
+    fn carries_events(&self) -> bool {
+        true
+    }
+
+    // the event is published as it is, instead of its text message
+    fn handle_event(
+        &self,
+        envelope: &EventEnvelope,
+        _loc: Location,
+    ) -> Result<(), reporter::ReporterError> {
+        let routing_key = self.routing_key(
+            &Level::Info,
+            &format!("person.{}", envelope.event.name()),
+            envelope.event.queue(),
+        );
+        let properties = lapin::BasicProperties::default()
+            .with_content_type(JSON.into())
+            .with_message_id(envelope.id.to_string().into())
+            .with_timestamp(envelope.occurred_at.timestamp() as u64);
+        self.send(&routing_key, envelope.encode().as_bytes(), properties)?;
+        trace!("published: {} to {}", envelope.id, routing_key);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_delivery() {
+        assert_eq!("fire-and-forget".parse(), Ok(Delivery::FireAndForget));
+        assert_eq!("Confirmed".parse(), Ok(Delivery::Confirmed));
+        assert_eq!("at-least-once".parse(), Ok(Delivery::AtLeastOnce(3)));
+        assert_eq!("at-least-once:5".parse(), Ok(Delivery::AtLeastOnce(5)));
+        assert!("at-least-once:x".parse::<Delivery>().is_err());
+        assert!("twice".parse::<Delivery>().is_err());
+    }
+
+    #[test]
+    fn test_delivery_retries() {
+        // 重複を許す at-least-once だけが再送する
+        assert_eq!(Delivery::FireAndForget.retries(), 0);
+        assert_eq!(Delivery::Confirmed.retries(), 0);
+        assert_eq!(Delivery::AtLeastOnce(0).retries(), 0);
+        assert_eq!(Delivery::AtLeastOnce(5).retries(), 5);
+        assert!(!Delivery::FireAndForget.confirms());
+        assert!(Delivery::AtLeastOnce(5).confirms());
+        // 確認するものはどのキューにも届かなければ返してもらう
+        assert!(!Delivery::FireAndForget.publish_options().mandatory);
+        assert!(Delivery::Confirmed.publish_options().mandatory);
+    }
+
+    #[test]
+    fn test_parse_exchange() {
+        let exchange: Exchange = "persons".parse().unwrap();
+        assert_eq!(exchange.name, "persons");
+        assert_eq!(exchange.kind, lapin::ExchangeKind::Topic);
+        let exchange: Exchange = "persons:Fanout".parse().unwrap();
+        assert_eq!(exchange.kind, lapin::ExchangeKind::Fanout);
+        assert!(exchange.bindings.is_empty());
+        // direct や headers は扱わない
+        assert!("persons:direct".parse::<Exchange>().is_err());
+        assert!(":topic".parse::<Exchange>().is_err());
+    }
+
+    #[test]
+    fn test_parse_binding() {
+        assert_eq!(
+            "audit=info.person.#".parse(),
+            Ok(Binding {
+                queue: "audit".to_string(),
+                pattern: "info.person.#".to_string(),
+            })
+        );
+        assert!("audit".parse::<Binding>().is_err());
+        assert!("=error.#".parse::<Binding>().is_err());
     }
 }
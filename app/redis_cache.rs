@@ -1,39 +1,94 @@
-use log::trace;
-use redis::{self, Commands, FromRedisValue, ToRedisArgs};
+use log::{trace, warn};
+use redis::{self, Commands};
 use std::time::Duration;
+use uuid::Uuid;
 
-use crate::cache::{CaoError, PersonCao};
+use crate::cache::{CaoError, LockToken, PersonCao};
+use crate::cache_codec::CacheCodec;
 use crate::domain::PersonId;
 use crate::dto::PersonDto;
 
-// this suppose PersonDto is serde-ized
-impl ToRedisArgs for PersonDto {
-    fn write_redis_args<W: ?Sized>(&self, out: &mut W)
-    where
-        W: redis::RedisWrite,
-    {
-        let s = serde_json::to_string(self).expect("serialize");
-        out.write_arg(s.as_bytes());
-    }
+// snapshots of the former generations are never read, they just expire
+const SNAPSHOT_TTL: Duration = Duration::from_secs(600);
+
+// deletes the lock only if it's still the one acquired with the token,
+// not the one of the next loader after it expired
+const UNLOCK_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+end
+return 0
+"#;
+
+/// Namespace of the keys in redis, e.g. `staging:v2:person:<id>`.
+/// Bumping the version abandons every key written with the former one at once.
+/// Without both of them the keys are the same as before they were introduced.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeySpace {
+    prefix: Option<String>,
+    version: Option<u32>,
 }
-// this suppose PersonDto is serde-ized
-impl FromRedisValue for PersonDto {
-    fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
-        let s: String = redis::from_redis_value(v)?;
-        let p: PersonDto = serde_json::from_str(&s).expect("deserialize");
-        Ok(p)
+impl KeySpace {
+    #[cfg_attr(feature = "use_hash", allow(unused))]
+    pub fn new(prefix: Option<&str>, version: Option<u32>) -> Self {
+        Self {
+            prefix: prefix.map(|p| p.to_string()),
+            version,
+        }
+    }
+
+    pub fn key(&self, name: &str) -> String {
+        let mut key = String::new();
+        if let Some(prefix) = &self.prefix {
+            key.push_str(prefix);
+            key.push(':');
+        }
+        if let Some(version) = self.version {
+            key.push_str(&format!("v{}:", version));
+        }
+        key.push_str(name);
+        key
+    }
+    pub fn person_key(&self, id: PersonId) -> String {
+        self.key(&format!("person:{}", id))
+    }
+    pub fn lock_key(&self, id: PersonId) -> String {
+        self.key(&format!("lock:person:{}", id))
+    }
+    pub fn generation_key(&self) -> String {
+        self.key("persons:generation")
+    }
+    pub fn snapshot_key(&self, generation: u64) -> String {
+        self.key(&format!("persons:all:{}", generation))
     }
 }
+
+/// How persons are stored in redis.
+#[derive(Debug, Clone, Default)]
+pub struct RedisCacheSettings {
+    pub codec: CacheCodec,
+    pub key_space: KeySpace,
+}
+
 #[derive(Debug, Clone)]
 pub struct RedisPersonCao {
     client: redis::Client,
     connect_timeout: Duration,
+    codec: CacheCodec,
+    key_space: KeySpace,
 }
 impl RedisPersonCao {
-    pub fn new(client: redis::Client, connect_timeout: Duration) -> Self {
+    pub fn new(
+        client: redis::Client,
+        connect_timeout: Duration,
+        codec: CacheCodec,
+        key_space: KeySpace,
+    ) -> Self {
         Self {
             client,
             connect_timeout,
+            codec,
+            key_space,
         }
     }
 }
@@ -61,12 +116,29 @@ impl PersonCao<redis::Connection> for RedisPersonCao {
     ) -> impl tx_rs::Tx<redis::Connection, Item = Option<PersonDto>, Err = CaoError> {
         trace!("find person: {}", id);
         tx_rs::with_tx(move |conn: &mut redis::Connection| {
-            let key = format!("person:{}", id);
-            let p: Option<PersonDto> = conn
+            let key = self.key_space.person_key(id);
+            let v: Option<Vec<u8>> = conn
                 .get(&key)
                 .map_err(|e| CaoError::Unavailable(e.to_string()))?;
-            trace!("found person in cache: {:?}", p);
-            Ok(p.into())
+            let Some(v) = v else {
+                trace!("person not found in cache: {}", id);
+                return Ok(None);
+            };
+
+            match self.codec.decode(&v) {
+                Ok(p) => {
+                    trace!("found person in cache: {:?}", p);
+                    Ok(Some(p))
+                }
+                Err(e) => {
+                    // evict it, otherwise it would be corrupted forever
+                    warn!("evict corrupted person in cache: {}: {}", key, e);
+                    let _: () = conn
+                        .del(&key)
+                        .map_err(|e| CaoError::Unavailable(e.to_string()))?;
+                    Err(e)
+                }
+            }
         })
     }
     fn load(
@@ -76,11 +148,12 @@ impl PersonCao<redis::Connection> for RedisPersonCao {
     ) -> impl tx_rs::Tx<redis::Connection, Item = (), Err = CaoError> {
         trace!("load person: {}", id);
         tx_rs::with_tx(move |conn: &mut redis::Connection| {
-            let key = format!("person:{}", id);
+            let key = self.key_space.person_key(id);
+            let v = self.codec.encode(person)?;
             // NOTE: this is current workaround for: https://github.com/rust-lang/rust/issues/123748
             // reference: https://github.com/redis-rs/redis-rs/issues/1322
             let _: () = conn
-                .set(&key, &person)
+                .set(&key, v)
                 .map_err(|e| CaoError::Unavailable(e.to_string()))?;
             trace!("person loaded into cache: {:?}", person);
             Ok(())
@@ -89,7 +162,7 @@ impl PersonCao<redis::Connection> for RedisPersonCao {
     fn unload(&self, id: PersonId) -> impl tx_rs::Tx<redis::Connection, Item = (), Err = CaoError> {
         trace!("unload person: {}", id);
         tx_rs::with_tx(move |conn: &mut redis::Connection| {
-            let key = format!("person:{}", id);
+            let key = self.key_space.person_key(id);
             // NOTE: this is current workaround for: https://github.com/rust-lang/rust/issues/123748
             // reference: https://github.com/redis-rs/redis-rs/issues/1322
             let _: () = conn
@@ -99,4 +172,190 @@ impl PersonCao<redis::Connection> for RedisPersonCao {
             Ok(())
         })
     }
+
+    fn ids(&self) -> impl tx_rs::Tx<redis::Connection, Item = Vec<PersonId>, Err = CaoError> {
+        trace!("list persons in cache");
+        tx_rs::with_tx(move |conn: &mut redis::Connection| {
+            let prefix = self.key_space.key("person:");
+            let keys: Vec<String> = conn
+                .scan_match(format!("{}*", prefix))
+                .map_err(|e| CaoError::Unavailable(e.to_string()))?
+                .collect();
+
+            let mut ids = vec![];
+            for key in keys {
+                match key.strip_prefix(&prefix).and_then(|s| s.parse().ok()) {
+                    Some(id) => ids.push(id),
+                    None => warn!("unknown key in cache: {}", key),
+                }
+            }
+            trace!("{} persons in cache", ids.len());
+            Ok(ids)
+        })
+    }
+
+    fn lock(
+        &self,
+        id: PersonId,
+        ttl: Duration,
+    ) -> impl tx_rs::Tx<redis::Connection, Item = Option<LockToken>, Err = CaoError> {
+        trace!("lock person: {}", id);
+        tx_rs::with_tx(move |conn: &mut redis::Connection| {
+            let key = self.key_space.lock_key(id);
+            let token = Uuid::now_v7().to_string();
+            // the lock expires by itself, even if the loader died without unlock
+            let v: Option<String> = redis::cmd("SET")
+                .arg(&key)
+                .arg(&token)
+                .arg("NX")
+                .arg("PX")
+                .arg(ttl.as_millis() as u64)
+                .query(conn)
+                .map_err(|e| CaoError::Unavailable(e.to_string()))?;
+            trace!("person locked: {} {}", id, v.is_some());
+            Ok(v.map(|_| token))
+        })
+    }
+    fn unlock(
+        &self,
+        id: PersonId,
+        token: LockToken,
+    ) -> impl tx_rs::Tx<redis::Connection, Item = (), Err = CaoError> {
+        trace!("unlock person: {}", id);
+        tx_rs::with_tx(move |conn: &mut redis::Connection| {
+            let key = self.key_space.lock_key(id);
+            let deleted: u32 = redis::Script::new(UNLOCK_SCRIPT)
+                .key(&key)
+                .arg(&token)
+                .invoke(conn)
+                .map_err(|e| CaoError::Unavailable(e.to_string()))?;
+            trace!("person unlocked: {} {}", id, deleted > 0);
+            Ok(())
+        })
+    }
+
+    fn generation(&self) -> impl tx_rs::Tx<redis::Connection, Item = u64, Err = CaoError> {
+        trace!("get generation");
+        tx_rs::with_tx(move |conn: &mut redis::Connection| {
+            let v: Option<u64> = conn
+                .get(self.key_space.generation_key())
+                .map_err(|e| CaoError::Unavailable(e.to_string()))?;
+            trace!("generation: {:?}", v);
+            Ok(v.unwrap_or(0))
+        })
+    }
+    fn bump_generation(&self) -> impl tx_rs::Tx<redis::Connection, Item = (), Err = CaoError> {
+        trace!("bump generation");
+        tx_rs::with_tx(move |conn: &mut redis::Connection| {
+            let v: u64 = conn
+                .incr(self.key_space.generation_key(), 1)
+                .map_err(|e| CaoError::Unavailable(e.to_string()))?;
+            trace!("generation bumped: {}", v);
+            Ok(())
+        })
+    }
+    fn find_all(
+        &self,
+        generation: u64,
+    ) -> impl tx_rs::Tx<redis::Connection, Item = Option<Vec<(PersonId, PersonDto)>>, Err = CaoError>
+    {
+        trace!("find snapshot: {}", generation);
+        tx_rs::with_tx(move |conn: &mut redis::Connection| {
+            let key = self.key_space.snapshot_key(generation);
+            let v: Option<Vec<u8>> = conn
+                .get(&key)
+                .map_err(|e| CaoError::Unavailable(e.to_string()))?;
+            let Some(v) = v else {
+                trace!("snapshot not found in cache: {}", generation);
+                return Ok(None);
+            };
+
+            match self.codec.decode_list(&v) {
+                Ok(persons) => {
+                    trace!("found snapshot in cache: {} persons", persons.len());
+                    Ok(Some(persons))
+                }
+                Err(e) => {
+                    warn!("evict corrupted snapshot in cache: {}: {}", key, e);
+                    let _: () = conn
+                        .del(&key)
+                        .map_err(|e| CaoError::Unavailable(e.to_string()))?;
+                    Err(e)
+                }
+            }
+        })
+    }
+    fn load_all(
+        &self,
+        generation: u64,
+        persons: &[(PersonId, PersonDto)],
+    ) -> impl tx_rs::Tx<redis::Connection, Item = (), Err = CaoError> {
+        trace!("load snapshot: {}", generation);
+        tx_rs::with_tx(move |conn: &mut redis::Connection| {
+            let key = self.key_space.snapshot_key(generation);
+            let v = self.codec.encode_list(persons)?;
+            let _: () = conn
+                .set_ex(&key, v, SNAPSHOT_TTL.as_secs())
+                .map_err(|e| CaoError::Unavailable(e.to_string()))?;
+            trace!("snapshot loaded into cache: {} persons", persons.len());
+            Ok(())
+        })
+    }
+    fn unload_all(
+        &self,
+        generation: u64,
+    ) -> impl tx_rs::Tx<redis::Connection, Item = (), Err = CaoError> {
+        trace!("unload snapshot: {}", generation);
+        tx_rs::with_tx(move |conn: &mut redis::Connection| {
+            let _: () = conn
+                .del(self.key_space.snapshot_key(generation))
+                .map_err(|e| CaoError::Unavailable(e.to_string()))?;
+            trace!("snapshot unloaded from cache: {}", generation);
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    fn test_key_space() {
+        let id = Uuid::now_v7();
+
+        assert_eq!(
+            KeySpace::default().person_key(id),
+            format!("person:{}", id),
+            "compatible with the keys without namespace"
+        );
+        assert_eq!(
+            KeySpace::new(Some("staging"), None).person_key(id),
+            format!("staging:person:{}", id)
+        );
+        assert_eq!(
+            KeySpace::new(None, Some(2)).person_key(id),
+            format!("v2:person:{}", id)
+        );
+        assert_eq!(
+            KeySpace::new(Some("qa"), Some(3)).key("person:invalidation"),
+            "qa:v3:person:invalidation"
+        );
+        assert_eq!(
+            KeySpace::new(Some("qa"), None).lock_key(id),
+            format!("qa:lock:person:{}", id)
+        );
+        assert_eq!(
+            KeySpace::new(Some("qa"), Some(3)).snapshot_key(7),
+            "qa:v3:persons:all:7"
+        );
+        assert!(
+            !KeySpace::default()
+                .generation_key()
+                .starts_with(&KeySpace::default().key("person:")),
+            "not listed as a person"
+        );
+    }
 }
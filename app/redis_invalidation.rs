@@ -0,0 +1,206 @@
+use log::{trace, warn};
+use redis::{self, Commands};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::cache::CaoError;
+use crate::domain::PersonId;
+use crate::invalidation::{Invalidation, InvalidationPublisher, InvalidationSubscriber};
+
+pub const INVALIDATION_CHANNEL: &str = "person:invalidation";
+
+#[derive(Debug, Clone)]
+pub struct RedisInvalidationPublisher {
+    client: redis::Client,
+    channel: String,
+    connect_timeout: Duration,
+}
+impl RedisInvalidationPublisher {
+    pub fn new(client: redis::Client, channel: &str, connect_timeout: Duration) -> Self {
+        Self {
+            client,
+            channel: channel.to_string(),
+            connect_timeout,
+        }
+    }
+}
+impl InvalidationPublisher for RedisInvalidationPublisher {
+    fn publish(&self, id: PersonId) -> Result<(), CaoError> {
+        trace!("publish invalidation: {} to {}", id, self.channel);
+        let mut conn = self
+            .client
+            .get_connection_with_timeout(self.connect_timeout)
+            .map_err(|e| CaoError::Unavailable(e.to_string()))?;
+        let receivers: i64 = conn
+            .publish(&self.channel, id.to_string())
+            .map_err(|e| CaoError::Unavailable(e.to_string()))?;
+        trace!("invalidation received by {} subscribers", receivers);
+        Ok(())
+    }
+}
+
+/// Subscribes the invalidation channel on a dedicated connection in a worker thread,
+/// so reading the local cache never waits on the socket.
+/// A disconnect is told by a single `Resync`, and another one follows the resubscription,
+/// because the messages published in between are lost.
+pub struct RedisInvalidationSubscriber {
+    received: mpsc::Receiver<Invalidation>,
+    closed: Arc<AtomicBool>,
+}
+impl RedisInvalidationSubscriber {
+    pub fn new(
+        client: redis::Client,
+        channel: &str,
+        connect_timeout: Duration,
+        retry_interval: Duration,
+    ) -> Self {
+        let (sender, received) = mpsc::channel();
+        let closed = Arc::new(AtomicBool::new(false));
+        let worker = Worker {
+            client,
+            channel: channel.to_string(),
+            connect_timeout,
+            retry_interval,
+            closed: closed.clone(),
+        };
+        // without the worker the receiver is disconnected, which poll answers by Resync
+        if let Err(e) = thread::Builder::new()
+            .name("invalidation".to_string())
+            .spawn(move || worker.run(&sender))
+        {
+            warn!("failed to start invalidation subscriber: {}", e);
+        }
+
+        Self { received, closed }
+    }
+}
+impl InvalidationSubscriber for RedisInvalidationSubscriber {
+    fn poll(&self) -> Vec<Invalidation> {
+        let mut result = vec![];
+        loop {
+            match self.received.try_recv() {
+                Ok(invalidation) => result.push(invalidation),
+                Err(TryRecvError::Empty) => break,
+                // nobody tells the changes anymore
+                Err(TryRecvError::Disconnected) => {
+                    result.push(Invalidation::Resync);
+                    break;
+                }
+            }
+        }
+        if !result.is_empty() {
+            trace!("invalidations polled: {:?}", result);
+        }
+
+        result
+    }
+}
+// the worker isn't joined, it stops within the retry interval
+impl Drop for RedisInvalidationSubscriber {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+}
+
+struct Worker {
+    client: redis::Client,
+    channel: String,
+    connect_timeout: Duration,
+    retry_interval: Duration,
+    closed: Arc<AtomicBool>,
+}
+impl Worker {
+    fn subscribe(&self) -> redis::RedisResult<redis::Connection> {
+        let mut conn = self
+            .client
+            .get_connection_with_timeout(self.connect_timeout)?;
+        let _: redis::Value = redis::cmd("SUBSCRIBE")
+            .arg(&self.channel)
+            .query(&mut conn)?;
+        // wakes up now and then to see whether the subscriber is dropped
+        conn.set_read_timeout(Some(self.retry_interval))?;
+        trace!("subscribed: {}", self.channel);
+        Ok(conn)
+    }
+
+    // stops when the subscriber is dropped
+    fn run(&self, sender: &mpsc::Sender<Invalidation>) {
+        // whether the current outage has been told
+        let mut told = false;
+        while !self.closed.load(Ordering::Relaxed) {
+            let mut conn = match self.subscribe() {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("failed to subscribe invalidation: {}", e);
+                    if !told && sender.send(Invalidation::Resync).is_err() {
+                        return;
+                    }
+                    told = true;
+                    thread::sleep(self.retry_interval);
+                    continue;
+                }
+            };
+            // anything published before (re)subscribing has been missed
+            if sender.send(Invalidation::Resync).is_err() {
+                return;
+            }
+            told = false;
+
+            while !self.closed.load(Ordering::Relaxed) {
+                match conn.recv_response() {
+                    Ok(v) => {
+                        let Some(msg) = redis::Msg::from_owned_value(v) else {
+                            continue;
+                        };
+                        match msg
+                            .get_payload::<String>()
+                            .ok()
+                            .and_then(|s| s.parse::<PersonId>().ok())
+                        {
+                            Some(id) => {
+                                if sender.send(Invalidation::Evict(id)).is_err() {
+                                    return;
+                                }
+                            }
+                            None => warn!("invalid invalidation message: {:?}", msg),
+                        }
+                    }
+                    Err(e) if e.is_timeout() => continue,
+                    Err(e) => {
+                        warn!("invalidation subscriber disconnected: {}", e);
+                        if sender.send(Invalidation::Resync).is_err() {
+                            return;
+                        }
+                        told = true;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unreachable() {
+        // 繋がらない間も Resync は一度だけ
+        let client = redis::Client::open("redis://127.0.0.1:1").unwrap();
+        let subscriber = RedisInvalidationSubscriber::new(
+            client,
+            INVALIDATION_CHANNEL,
+            Duration::from_millis(100),
+            Duration::from_millis(10),
+        );
+        thread::sleep(Duration::from_millis(200));
+
+        assert_eq!(subscriber.poll(), vec![Invalidation::Resync]);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(subscriber.poll(), vec![]);
+    }
+}
@@ -1,22 +1,48 @@
 use serde::{Deserialize, Serialize};
+use std::cell::Cell;
 use std::rc::Rc;
+use std::str::FromStr;
+use std::time::Instant;
 use thiserror::Error;
 
+use crate::coalesce::{CoalesceSettings, Coalescer, Summary};
+use crate::error_detail::ErrorDetail;
+use crate::event::EventEnvelope;
 pub use crate::location::Location;
 
 #[derive(Debug, Error, Clone, PartialEq, Eq)]
 pub enum ReporterError {
     #[error("reporter unavailable: {0}")]
     Unavailable(String),
+    // published, but the broker didn't confirm it
+    #[error("reporter unconfirmed: {0}")]
+    Unconfirmed(String),
+    // received, but refused, which won't change by retrying
+    #[error("reporter rejected: {0}")]
+    Rejected(String),
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+// ordered by severity
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum Level {
     Trace,
     Info,
     Warn,
     Error,
 }
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "trace" => Ok(Level::Trace),
+            "info" => Ok(Level::Info),
+            "warn" => Ok(Level::Warn),
+            "error" => Ok(Level::Error),
+            _ => Err(format!("unknown level: {}", s)),
+        }
+    }
+}
 
 pub trait Observer {
     fn handle_notification(
@@ -26,10 +52,194 @@ pub trait Observer {
         message: &str,
         loc: Location,
     ) -> Result<(), ReporterError>;
+
+    // the observers which don't know the events get them as text messages
+    fn handle_event(&self, envelope: &EventEnvelope, loc: Location) -> Result<(), ReporterError> {
+        self.handle_notification(
+            Level::Info,
+            envelope.event.queue(),
+            &envelope.event.to_string(),
+            loc,
+        )
+    }
+
+    // the observers which don't know the error details get the error in the message
+    fn handle_error(
+        &self,
+        level: Level,
+        to: &str,
+        message: &str,
+        error: &ErrorDetail,
+        loc: Location,
+    ) -> Result<(), ReporterError> {
+        self.handle_notification(level, to, &format!("{}: {}", message, error), loc)
+    }
+
+    // wait for the reports handed to the background, and tell whether any of them failed
+    fn flush(&self) -> Result<(), ReporterError> {
+        Ok(())
+    }
+
+    // true if the outbox events are delivered through it, e.g. a broker.
+    // the relay keeps the events pending until these accept them, the others get a copy at best.
+    fn carries_events(&self) -> bool {
+        false
+    }
+}
+
+/// Which reports an observer receives.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subscription {
+    pub min_level: Level,
+    // exact topics or patterns with `*`, empty accepts all
+    pub topics: Vec<String>,
+    // the fraction of the matching reports delivered, from 0.0 to 1.0
+    pub sample: f64,
+}
+impl Default for Subscription {
+    fn default() -> Self {
+        Self {
+            min_level: Level::Trace,
+            topics: vec![],
+            sample: 1.0,
+        }
+    }
+}
+impl FromStr for Subscription {
+    type Err = String;
+
+    // e.g. "level=warn;topics=admin,*_person;sample=0.1", the omitted ones accept all
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut subscription = Subscription::default();
+        for field in s.split(';').map(str::trim).filter(|f| !f.is_empty()) {
+            match field.split_once('=') {
+                Some(("level", v)) => subscription.min_level = v.trim().parse()?,
+                Some(("topics", v)) => {
+                    subscription.topics = v
+                        .split(',')
+                        .map(|t| t.trim().to_string())
+                        .filter(|t| !t.is_empty())
+                        .collect()
+                }
+                Some(("sample", v)) => {
+                    subscription.sample = v
+                        .trim()
+                        .parse()
+                        .ok()
+                        .filter(|r| (0.0..=1.0).contains(r))
+                        .ok_or(format!("sample must be from 0.0 to 1.0: {}", v))?
+                }
+                _ => return Err(format!("unknown subscription field: {}", field)),
+            }
+        }
+        Ok(subscription)
+    }
+}
+
+// `*` matches any sequence of characters
+fn matches(pattern: &str, topic: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == topic,
+        Some((prefix, rest)) => {
+            let Some(topic) = topic.strip_prefix(prefix) else {
+                return false;
+            };
+            (0..=topic.len())
+                .filter(|&i| topic.is_char_boundary(i))
+                .any(|i| matches(rest, &topic[i..]))
+        }
+    }
+}
+
+/// An observer which receives only the reports of its subscription.
+pub struct Subscribed<O> {
+    inner: O,
+    subscription: Subscription,
+    // number of the matching reports, to sample them evenly
+    matched: Cell<u64>,
+}
+impl<O: Observer> Subscribed<O> {
+    pub fn new(inner: O, subscription: Subscription) -> Self {
+        Self {
+            inner,
+            subscription,
+            matched: Cell::new(0),
+        }
+    }
+
+    fn accepts_topic(&self, to: &str) -> bool {
+        self.subscription.topics.is_empty()
+            || self.subscription.topics.iter().any(|p| matches(p, to))
+    }
+    fn accepts(&self, level: &Level, to: &str) -> bool {
+        if *level < self.subscription.min_level || !self.accepts_topic(to) {
+            return false;
+        }
+
+        // delivered when the sampled count goes up, e.g. every 10th for 0.1
+        let n = self.matched.get();
+        self.matched.set(n + 1);
+        let rate = self.subscription.sample;
+        ((n + 1) as f64 * rate).floor() > (n as f64 * rate).floor()
+    }
+}
+impl<O: Observer> Observer for Subscribed<O> {
+    fn handle_notification(
+        &self,
+        level: Level,
+        to: &str,
+        message: &str,
+        loc: Location,
+    ) -> Result<(), ReporterError> {
+        if !self.accepts(&level, to) {
+            return Ok(());
+        }
+        self.inner.handle_notification(level, to, message, loc)
+    }
+    fn handle_error(
+        &self,
+        level: Level,
+        to: &str,
+        message: &str,
+        error: &ErrorDetail,
+        loc: Location,
+    ) -> Result<(), ReporterError> {
+        if !self.accepts(&level, to) {
+            return Ok(());
+        }
+        self.inner.handle_error(level, to, message, error, loc)
+    }
+    fn handle_event(&self, envelope: &EventEnvelope, loc: Location) -> Result<(), ReporterError> {
+        let to = envelope.event.queue();
+        // the outbox marks the skipped events delivered, so a carrier only drops the unsubscribed queues
+        let accepted = if self.inner.carries_events() {
+            self.accepts_topic(to)
+        } else {
+            self.accepts(&Level::Info, to)
+        };
+        if !accepted {
+            return Ok(());
+        }
+        self.inner.handle_event(envelope, loc)
+    }
+    fn flush(&self) -> Result<(), ReporterError> {
+        self.inner.flush()
+    }
+    fn carries_events(&self) -> bool {
+        self.inner.carries_events()
+    }
 }
 
 pub trait Reporter<'a> {
     fn register(&mut self, observer: impl Observer + 'a) -> Result<(), ReporterError>;
+    // the observer receives only the reports of the subscription
+    fn subscribe(
+        &mut self,
+        observer: impl Observer + 'a,
+        subscription: Subscription,
+    ) -> Result<(), ReporterError> {
+        self.register(Subscribed::new(observer, subscription))
+    }
     fn get_observers(&self) -> Vec<&dyn Observer>;
     fn send_report(
         &self,
@@ -38,27 +248,131 @@ pub trait Reporter<'a> {
         message: &str,
         loc: Location,
     ) -> Result<(), ReporterError> {
+        broadcast(&self.get_observers(), level, to, message, None, loc)
+    }
+    // the reporters which don't know the error details send the message only
+    fn send_error(
+        &self,
+        level: Level,
+        to: &str,
+        message: &str,
+        _error: &ErrorDetail,
+        loc: Location,
+    ) -> Result<(), ReporterError> {
+        self.send_report(level, to, message, loc)
+    }
+    fn send_event(&self, envelope: &EventEnvelope, loc: Location) -> Result<(), ReporterError> {
         for observer in self.get_observers() {
-            observer
-                .handle_notification(level.clone(), to, message, loc.clone())
-                .or_else(|e| {
-                    eprintln!("reporter error: {}", e);
-                    Ok(())
-                })?;
+            observer.handle_event(envelope, loc.clone()).or_else(|e| {
+                eprintln!("reporter error: {}", e);
+                Ok(())
+            })?;
         }
         Ok(())
     }
+    // all the observers are flushed, and the first failure is returned
+    fn flush(&self) -> Result<(), ReporterError> {
+        flush_all(&self.get_observers())
+    }
+}
+
+fn broadcast(
+    observers: &[&dyn Observer],
+    level: Level,
+    to: &str,
+    message: &str,
+    error: Option<&ErrorDetail>,
+    loc: Location,
+) -> Result<(), ReporterError> {
+    for observer in observers {
+        let result = match error {
+            Some(error) => observer.handle_error(level.clone(), to, message, error, loc.clone()),
+            None => observer.handle_notification(level.clone(), to, message, loc.clone()),
+        };
+        result.or_else(|e| {
+            eprintln!("reporter error: {}", e);
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+// a failed summary doesn't keep the others or the report itself from being sent
+fn broadcast_summaries(observers: &[&dyn Observer], summaries: Vec<Summary>) {
+    for s in summaries {
+        let sent = broadcast(
+            observers,
+            s.level.clone(),
+            &s.to,
+            &s.message,
+            s.error.as_ref(),
+            s.location(),
+        );
+        if let Err(e) = sent {
+            eprintln!("reporter error: {}", e);
+        }
+    }
+}
+
+// all the observers are flushed, and the first failure is returned
+fn flush_all(observers: &[&dyn Observer]) -> Result<(), ReporterError> {
+    let mut res = Ok(());
+    for observer in observers {
+        let flushed = observer.flush();
+        if res.is_ok() {
+            res = flushed;
+        }
+    }
+    res
 }
 
 #[derive(Clone)]
 pub struct DefaultReporter<'a> {
     observers: Vec<Rc<dyn Observer + 'a>>,
+    // None sends all the repeated reports
+    coalescer: Option<Rc<Coalescer>>,
+}
+impl Default for DefaultReporter<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 impl<'a> DefaultReporter<'a> {
     pub fn new() -> Self {
         Self {
             observers: Vec::new(),
+            coalescer: None,
+        }
+    }
+
+    // the identical reports in a window are sent once, and then summed up
+    pub fn coalesce(self, settings: CoalesceSettings) -> Self {
+        Self {
+            coalescer: Some(Rc::new(Coalescer::new(settings))),
+            ..self
+        }
+    }
+
+    fn report(
+        &self,
+        level: Level,
+        to: &str,
+        message: &str,
+        error: Option<&ErrorDetail>,
+        loc: Location,
+    ) -> Result<(), ReporterError> {
+        let observers = self.get_observers();
+        let Some(coalescer) = &self.coalescer else {
+            return broadcast(&observers, level, to, message, error, loc);
+        };
+
+        let now = Instant::now();
+        // the summaries are sent as the later reports come
+        broadcast_summaries(&observers, coalescer.expired(now));
+        if !coalescer.admit(&level, to, message, error, &loc, now) {
+            return Ok(());
         }
+        broadcast(&observers, level, to, message, error, loc)
     }
 }
 impl<'a> Reporter<'a> for DefaultReporter<'a> {
@@ -69,11 +383,38 @@ impl<'a> Reporter<'a> for DefaultReporter<'a> {
     fn get_observers(&self) -> Vec<&dyn Observer> {
         self.observers.iter().map(|o| o.as_ref()).collect()
     }
+    fn send_report(
+        &self,
+        level: Level,
+        to: &str,
+        message: &str,
+        loc: Location,
+    ) -> Result<(), ReporterError> {
+        self.report(level, to, message, None, loc)
+    }
+    fn send_error(
+        &self,
+        level: Level,
+        to: &str,
+        message: &str,
+        error: &ErrorDetail,
+        loc: Location,
+    ) -> Result<(), ReporterError> {
+        self.report(level, to, message, Some(error), loc)
+    }
+    fn flush(&self) -> Result<(), ReporterError> {
+        let observers = self.get_observers();
+        if let Some(coalescer) = &self.coalescer {
+            broadcast_summaries(&observers, coalescer.drain());
+        }
+        flush_all(&observers)
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::event::PersonEvent;
     use crate::location::Location;
     use std::cell::RefCell;
 
@@ -96,6 +437,41 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_reporter_send_error() {
+        let observer = SpyObserver {
+            messages: Rc::new(RefCell::new(Vec::new())),
+        };
+        let mut reporter = DefaultReporter::new();
+        reporter.register(observer.clone()).unwrap();
+        let error = ErrorDetail {
+            kind: "ServiceError::ServiceUnavailable".to_string(),
+            message: "service unavailable: connection refused".to_string(),
+            sources: vec![],
+            backend: Some("postgres".to_string()),
+            retryable: true,
+        };
+        reporter
+            .send_error(
+                Level::Error,
+                "admin",
+                "cannot find person",
+                &error,
+                location!(),
+            )
+            .unwrap();
+
+        // 詳細を知らない observer にはメッセージに付けて渡す
+        assert_eq!(
+            observer.messages.borrow().as_slice(),
+            &[(
+                Level::Error,
+                "admin".to_string(),
+                "cannot find person: service unavailable: connection refused".to_string()
+            )]
+        );
+    }
+
     #[test]
     fn test_reporter_for_single_observer() {
         let observer = SpyObserver {
@@ -112,6 +488,197 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_reporter_for_event() {
+        let observer = SpyObserver {
+            messages: Rc::new(RefCell::new(Vec::new())),
+        };
+        let mut reporter = DefaultReporter::new();
+        reporter.register(observer.clone()).unwrap();
+        let id = uuid::Uuid::now_v7();
+        let envelope = EventEnvelope::new(PersonEvent::Unregistered { person_id: id }, None);
+        reporter.send_event(&envelope, location!()).unwrap();
+
+        // イベントを知らない Observer にはテキストで届く
+        assert_eq!(
+            observer.messages.borrow().as_slice(),
+            &[(
+                Level::Info,
+                "unregister_person".to_string(),
+                format!("unregistered person_id: {}", id)
+            )]
+        );
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let observer = SpyObserver {
+            messages: Rc::new(RefCell::new(Vec::new())),
+        };
+        let mut reporter = DefaultReporter::new();
+        reporter
+            .subscribe(
+                observer.clone(),
+                "level=info;topics=admin,*_person".parse().unwrap(),
+            )
+            .unwrap();
+        reporter
+            .send_report(Level::Trace, "admin", "trace", location!())
+            .unwrap();
+        reporter
+            .send_report(Level::Error, "cache", "other", location!())
+            .unwrap();
+        reporter
+            .send_report(Level::Warn, "admin", "warn", location!())
+            .unwrap();
+        let id = uuid::Uuid::now_v7();
+        let envelope = EventEnvelope::new(PersonEvent::Unregistered { person_id: id }, None);
+        reporter.send_event(&envelope, location!()).unwrap();
+
+        // 購読していない level と宛先は届かない
+        assert_eq!(
+            observer.messages.borrow().as_slice(),
+            &[
+                (Level::Warn, "admin".to_string(), "warn".to_string()),
+                (
+                    Level::Info,
+                    "unregister_person".to_string(),
+                    format!("unregistered person_id: {}", id)
+                )
+            ]
+        );
+    }
+
+    #[test]
+    fn test_subscribe_sample() {
+        let observer = SpyObserver {
+            messages: Rc::new(RefCell::new(Vec::new())),
+        };
+        let mut reporter = DefaultReporter::new();
+        reporter
+            .subscribe(observer.clone(), "sample=0.25".parse().unwrap())
+            .unwrap();
+        for i in 0..8 {
+            reporter
+                .send_report(Level::Info, "to", &i.to_string(), location!())
+                .unwrap();
+        }
+
+        // 4 件に 1 件だけ届く
+        let messages: Vec<String> = observer
+            .messages
+            .borrow()
+            .iter()
+            .map(|(_, _, m)| m.clone())
+            .collect();
+        assert_eq!(messages, vec!["3", "7"]);
+    }
+
+    #[derive(Debug, Clone)]
+    struct SpyCarrier {
+        events: Rc<RefCell<Vec<EventEnvelope>>>,
+    }
+    impl Observer for SpyCarrier {
+        fn handle_notification(
+            &self,
+            _level: Level,
+            _to: &str,
+            _message: &str,
+            _loc: Location,
+        ) -> Result<(), ReporterError> {
+            Ok(())
+        }
+        fn handle_event(
+            &self,
+            envelope: &EventEnvelope,
+            _loc: Location,
+        ) -> Result<(), ReporterError> {
+            self.events.borrow_mut().push(envelope.clone());
+            Ok(())
+        }
+        fn carries_events(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_subscribe_carrier() {
+        let observer = SpyCarrier {
+            events: Rc::new(RefCell::new(Vec::new())),
+        };
+        let mut reporter = DefaultReporter::new();
+        reporter
+            .subscribe(
+                observer.clone(),
+                "level=warn;topics=unregister_person;sample=0.5"
+                    .parse()
+                    .unwrap(),
+            )
+            .unwrap();
+        let id = uuid::Uuid::now_v7();
+        for _ in 0..3 {
+            let envelope = EventEnvelope::new(PersonEvent::Unregistered { person_id: id }, None);
+            reporter.send_event(&envelope, location!()).unwrap();
+        }
+        let envelope = EventEnvelope::new(
+            PersonEvent::Died {
+                person_id: id,
+                death_date: chrono::NaiveDate::from_ymd_opt(2000, 1, 1).unwrap(),
+            },
+            None,
+        );
+        reporter.send_event(&envelope, location!()).unwrap();
+
+        // イベントを運ぶ observer には level と sample をかけず、購読する宛先のイベントはすべて届く
+        assert_eq!(observer.events.borrow().len(), 3);
+    }
+
+    #[test]
+    fn test_parse_subscription() {
+        assert_eq!("".parse(), Ok(Subscription::default()));
+        assert_eq!(
+            " level=Warn ; topics=admin, entry_* ".parse(),
+            Ok(Subscription {
+                min_level: Level::Warn,
+                topics: vec!["admin".to_string(), "entry_*".to_string()],
+                sample: 1.0,
+            })
+        );
+        assert!("sample=2".parse::<Subscription>().is_err());
+        assert!("level=fatal".parse::<Subscription>().is_err());
+        assert!(matches("*_person", "death_person"));
+        assert!(matches("a*c*", "abxc"));
+        assert!(!matches("entry_*", "death_person"));
+    }
+
+    #[test]
+    fn test_reporter_coalesce() {
+        let observer = SpyObserver {
+            messages: Rc::new(RefCell::new(Vec::new())),
+        };
+        let mut reporter = DefaultReporter::new().coalesce("admin=60".parse().unwrap());
+        reporter.register(observer.clone()).unwrap();
+        for _ in 0..1000 {
+            reporter
+                .send_report(Level::Error, "admin", "down", location!())
+                .unwrap();
+        }
+        reporter.flush().unwrap();
+
+        // 最初の 1 件と、残りの要約だけが届く
+        assert_eq!(
+            observer.messages.borrow().as_slice(),
+            &[
+                (Level::Error, "admin".to_string(), "down".to_string()),
+                (
+                    Level::Error,
+                    "admin".to_string(),
+                    "down (repeated 999 times in 60s)".to_string()
+                ),
+            ]
+        );
+    }
+
     #[test]
     fn test_reporter_for_multi_observers() {
         let observer1 = SpyObserver {
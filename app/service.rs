@@ -1,12 +1,15 @@
 use chrono::NaiveDate;
-use log::{error, trace};
+use log::{error, trace, warn};
 use std::fmt;
 use std::iter::Iterator;
 use std::rc::Rc;
 use thiserror::Error;
 
+use crate::dao::OutboxEvent;
 use crate::domain::PersonId;
 use crate::dto::PersonDto;
+use crate::error_detail::{Diagnose, ErrorDetail};
+use crate::event::{EventEnvelope, EventId, PersonEvent};
 use crate::reporter::{Level, Reporter};
 use crate::usecase::{PersonUsecase, UsecaseError};
 use tx_rs::Tx;
@@ -31,6 +34,28 @@ impl fmt::Display for InvalidErrorKind {
         }
     }
 }
+impl Diagnose for ServiceError {
+    fn kind(&self) -> &'static str {
+        match self {
+            ServiceError::TransactionFailed(_) => "ServiceError::TransactionFailed",
+            ServiceError::ServiceUnavailable(_) => "ServiceError::ServiceUnavailable",
+            ServiceError::InvalidRequest(_) => "ServiceError::InvalidRequest",
+        }
+    }
+    fn cause(&self) -> Option<&dyn Diagnose> {
+        match self {
+            ServiceError::TransactionFailed(e) => Some(e),
+            _ => None,
+        }
+    }
+    fn retryable(&self) -> bool {
+        match self {
+            ServiceError::TransactionFailed(e) => e.retryable(),
+            ServiceError::ServiceUnavailable(_) => true,
+            ServiceError::InvalidRequest(_) => false,
+        }
+    }
+}
 
 pub trait PersonOutputBoundary<T, E> {
     fn started(&self);
@@ -49,6 +74,17 @@ pub trait PersonService<'a, Ctx> {
 
     fn get_reporter(&self) -> Self::N;
 
+    // the store behind the usecase, told in the error reports
+    fn backend(&self) -> Option<&'static str> {
+        None
+    }
+
+    // whether the reports of the changes are written to the outbox in the same transaction,
+    // instead of being sent after the commit. they are sent by `relay_outbox` then.
+    fn uses_outbox(&self) -> bool {
+        false
+    }
+
     fn register(
         &'a mut self,
         name: &str,
@@ -64,15 +100,30 @@ pub trait PersonService<'a, Ctx> {
             data
         );
         let reporter = self.get_reporter();
+        let backend = self.backend();
+        let outbox = self.uses_outbox();
 
         self.run_tx(move |usecase, ctx| {
-            usecase
+            let (id, p) = usecase
                 .entry_and_verify(PersonDto::new(name, birth_date, death_date, Some(data), 0))
-                .run(ctx)
+                .run(ctx)?;
+            let event = EventEnvelope::new(
+                PersonEvent::Registered {
+                    person_id: id,
+                    person: p.clone(),
+                },
+                Some(p.revision),
+            );
+            if outbox {
+                usecase.record_event((&event).into()).run(ctx)?;
+            }
+            Ok((id, p, event))
         })
-        .and_then(|(id, p)| {
-            let msg = format!("registered person_id: {}", id);
-            if let Err(e) = reporter.send_report(Level::Info, "entry_person", &msg, location!()) {
+        .and_then(|(id, p, event)| {
+            if outbox {
+                return Ok((id, p));
+            }
+            if let Err(e) = reporter.send_event(&event, location!()) {
                 error!("reporter service not available: {}", e);
             }
             return Ok((id, p));
@@ -82,7 +133,8 @@ pub trait PersonService<'a, Ctx> {
                 "cannot register person: name={}, birth_date={}, death_date={:?}, data={}",
                 name, birth_date, death_date, data
             );
-            if let Err(e) = reporter.send_report(Level::Error, "admin", &msg, location!()) {
+            let error = ErrorDetail::of(&e).or_backend(backend);
+            if let Err(e) = reporter.send_error(Level::Error, "admin", &msg, &error, location!()) {
                 error!("reporter service not available: {}", e);
             }
             return e;
@@ -92,11 +144,15 @@ pub trait PersonService<'a, Ctx> {
     fn find(&'a mut self, id: PersonId) -> Result<Option<PersonDto>, ServiceError> {
         trace!("find person: id={}", id);
         let reporter = self.get_reporter();
+        let backend = self.backend();
 
         self.run_tx(move |usecase, ctx| usecase.find(id).run(ctx))
             .map_err(|e| {
                 let msg = format!("cannot find person: id={}", id);
-                if let Err(e) = reporter.send_report(Level::Error, "admin", &msg, location!()) {
+                let error = ErrorDetail::of(&e).or_backend(backend);
+                if let Err(e) =
+                    reporter.send_error(Level::Error, "admin", &msg, &error, location!())
+                {
                     error!("reporter service not available: {}", e);
                 }
                 return e;
@@ -111,21 +167,34 @@ pub trait PersonService<'a, Ctx> {
         trace!("batch import persons");
         out_port.started();
         let reporter = self.get_reporter();
+        let backend = self.backend();
+        let outbox = self.uses_outbox();
 
         let mut ids = vec![];
         let (lower_bound, upper_bound) = persons.size_hint();
         let total = upper_bound.unwrap_or(lower_bound) as u64;
         self.run_tx(move |usecase, ctx| {
             for person in persons {
-                let res = usecase.entry(person).run(ctx);
+                let res = usecase.entry(person.clone()).run(ctx);
                 match res {
                     Ok(id) => {
                         ids.push(id);
 
-                        let msg = format!("registered person_id: {}", id);
-                        if let Err(e) =
-                            reporter.send_report(Level::Info, "entry_person", &msg, location!())
-                        {
+                        let revision = person.revision;
+                        let event = EventEnvelope::new(
+                            PersonEvent::Imported {
+                                person_id: id,
+                                person,
+                            },
+                            Some(revision),
+                        );
+                        if outbox {
+                            // 失敗したらインポート全体をロールバックする
+                            if let Err(e) = usecase.record_event((&event).into()).run(ctx) {
+                                out_port.aborted(ServiceError::TransactionFailed(e.clone()));
+                                return Err(e);
+                            }
+                        } else if let Err(e) = reporter.send_event(&event, location!()) {
                             error!("reporter service not available: {}", e);
                         }
                     }
@@ -134,8 +203,9 @@ pub trait PersonService<'a, Ctx> {
                         out_port.aborted(ServiceError::TransactionFailed(e.clone()));
 
                         let msg = format!("cannot entry person: {:?}", e);
+                        let error = ErrorDetail::of(&e).or_backend(backend);
                         if let Err(e) =
-                            reporter.send_report(Level::Error, "admin", &msg, location!())
+                            reporter.send_error(Level::Error, "admin", &msg, &error, location!())
                         {
                             error!("reporter service not available: {}", e);
                         }
@@ -154,13 +224,15 @@ pub trait PersonService<'a, Ctx> {
     fn list_all(&'a mut self) -> Result<Vec<(PersonId, PersonDto)>, ServiceError> {
         trace!("list all persons");
         let reporter = self.get_reporter();
+        let backend = self.backend();
 
         self.run_tx(move |usecase, ctx| usecase.collect().run(ctx))
             .map_err(|e| {
-                if let Err(e) = reporter.send_report(
+                if let Err(e) = reporter.send_error(
                     Level::Error,
                     "admin",
                     "cannot list all persons",
+                    &ErrorDetail::of(&e).or_backend(backend),
                     location!(),
                 ) {
                     error!("reporter service not available: {}", e);
@@ -172,46 +244,170 @@ pub trait PersonService<'a, Ctx> {
     fn death(&'a mut self, id: PersonId, death_date: NaiveDate) -> Result<(), ServiceError> {
         trace!("death person: id={}, death_date={}", id, death_date);
         let reporter = self.get_reporter();
+        let backend = self.backend();
+        let outbox = self.uses_outbox();
 
-        self.run_tx(move |usecase, ctx| usecase.death(id, death_date).run(ctx))
-            .and_then(|_| {
-                let msg = format!("death person_id: {}, death_date: {}", id, death_date);
-                if let Err(e) = reporter.send_report(Level::Info, "death_person", &msg, location!())
-                {
-                    error!("reporter service not available: {}", e);
-                }
+        self.run_tx(move |usecase, ctx| {
+            let revision = usecase.death(id, death_date).run(ctx)?;
+            let event = EventEnvelope::new(
+                PersonEvent::Died {
+                    person_id: id,
+                    death_date,
+                },
+                Some(revision),
+            );
+            if outbox {
+                usecase.record_event((&event).into()).run(ctx)?;
+            }
+            Ok(event)
+        })
+        .and_then(|event| {
+            if outbox {
                 return Ok(());
-            })
-            .map_err(|e| {
-                let msg = format!("cannot death person: id={}, death_date={}", id, death_date);
-                if let Err(e) = reporter.send_report(Level::Error, "admin", &msg, location!()) {
-                    error!("reporter service not available: {}", e);
-                }
-                return e;
-            })
+            }
+            if let Err(e) = reporter.send_event(&event, location!()) {
+                error!("reporter service not available: {}", e);
+            }
+            return Ok(());
+        })
+        .map_err(|e| {
+            let msg = format!("cannot death person: id={}, death_date={}", id, death_date);
+            let error = ErrorDetail::of(&e).or_backend(backend);
+            if let Err(e) = reporter.send_error(Level::Error, "admin", &msg, &error, location!()) {
+                error!("reporter service not available: {}", e);
+            }
+            return e;
+        })
     }
 
     fn unregister(&'a mut self, id: PersonId) -> Result<(), ServiceError> {
         trace!("unregister person: id={}", id);
         let reporter = self.get_reporter();
+        let backend = self.backend();
+        let outbox = self.uses_outbox();
 
-        self.run_tx(move |usecase, ctx| usecase.remove(id).run(ctx))
-            .and_then(|_| {
-                let msg = format!("unregistered person_id: {}", id);
-                if let Err(e) =
-                    reporter.send_report(Level::Info, "unregister_person", &msg, location!())
-                {
-                    error!("reporter service not available: {}", e);
-                }
+        self.run_tx(move |usecase, ctx| {
+            let revision = usecase.remove(id).run(ctx)?;
+            let event = EventEnvelope::new(PersonEvent::Unregistered { person_id: id }, revision);
+            if outbox {
+                usecase.record_event((&event).into()).run(ctx)?;
+            }
+            Ok(event)
+        })
+        .and_then(|event| {
+            if outbox {
                 return Ok(());
-            })
-            .map_err(|e| {
-                let msg = format!("cannot remove person: id={}", id);
-                if let Err(e) = reporter.send_report(Level::Error, "admin", &msg, location!()) {
-                    error!("reporter service not available: {}", e);
+            }
+            if let Err(e) = reporter.send_event(&event, location!()) {
+                error!("reporter service not available: {}", e);
+            }
+            return Ok(());
+        })
+        .map_err(|e| {
+            let msg = format!("cannot remove person: id={}", id);
+            let error = ErrorDetail::of(&e).or_backend(backend);
+            if let Err(e) = reporter.send_error(Level::Error, "admin", &msg, &error, location!()) {
+                error!("reporter service not available: {}", e);
+            }
+            return e;
+        })
+    }
+
+    // the events not delivered yet, the oldest first
+    fn pending_events(&'a mut self, limit: usize) -> Result<Vec<OutboxEvent>, ServiceError> {
+        trace!("pending events: limit={}", limit);
+        let reporter = self.get_reporter();
+        let backend = self.backend();
+
+        self.run_tx(move |usecase, ctx| usecase.pending_events(limit).run(ctx))
+            .inspect_err(|e| report_relay_error(&reporter, e, backend))
+    }
+
+    fn mark_delivered(&'a mut self, ids: Vec<EventId>) -> Result<(), ServiceError> {
+        trace!("mark events delivered: {:?}", ids);
+        let reporter = self.get_reporter();
+        let backend = self.backend();
+
+        self.run_tx(move |usecase, ctx| usecase.mark_delivered(ids).run(ctx))
+            .inspect_err(|e| report_relay_error(&reporter, e, backend))
+    }
+}
+
+fn report_relay_error<'a>(reporter: &impl Reporter<'a>, e: &ServiceError, backend: Option<&str>) {
+    if let Err(e) = reporter.send_error(
+        Level::Error,
+        "admin",
+        "cannot relay outbox",
+        &ErrorDetail::of(e).or_backend(backend),
+        location!(),
+    ) {
+        error!("reporter service not available: {}", e);
+    }
+}
+
+/// The outbox of a service, relayed in transactions of its own.
+///
+/// Every call of `PersonService` takes the service for its transaction,
+/// so the services implement this to read and mark the outbox in separate calls.
+pub trait Outbox {
+    fn pending(&mut self, limit: usize) -> Result<Vec<OutboxEvent>, ServiceError>;
+    fn delivered(&mut self, ids: Vec<EventId>) -> Result<(), ServiceError>;
+
+    // publish the events in the outbox to the observers, and mark them delivered.
+    // they are marked only after the observers carrying them have flushed, not to keep the rows locked meanwhile,
+    // so a crash or another relay in between publishes them again.
+    fn relay_outbox<'a>(
+        &mut self,
+        reporter: &impl Reporter<'a>,
+        limit: usize,
+    ) -> Result<usize, ServiceError> {
+        trace!("relay outbox: limit={}", limit);
+        let events = self.pending(limit)?;
+        let observers = reporter.get_observers();
+
+        let mut delivered = vec![];
+        'events: for event in events {
+            // the reports recorded as text before the events were introduced
+            let envelope = EventEnvelope::decode(&event.message)
+                .inspect_err(|e| warn!("relay event as text: {}: {}", event.id, e))
+                .ok();
+            for observer in observers.iter() {
+                let result = match &envelope {
+                    Some(envelope) => observer.handle_event(envelope, location!()),
+                    None => observer.handle_notification(
+                        Level::Info,
+                        &event.to,
+                        &event.message,
+                        location!(),
+                    ),
+                };
+                match result {
+                    Err(e) if observer.carries_events() => {
+                        // 順序を保つため、残りは次の relay に回す
+                        warn!("failed to relay event: {}: {}", event.id, e);
+                        break 'events;
+                    }
+                    // the others get a copy at best, and don't keep the event pending
+                    Err(e) => warn!("failed to copy event: {}: {}", event.id, e),
+                    Ok(()) => {}
                 }
-                return e;
-            })
+            }
+            delivered.push(event.id);
+        }
+        // the carriers working in the background tell their failures only now
+        for observer in observers.iter().filter(|o| o.carries_events()) {
+            if let Err(e) = observer.flush() {
+                warn!("failed to relay events, they are sent again: {}", e);
+                delivered.clear();
+            }
+        }
+
+        let count = delivered.len();
+        if count > 0 {
+            self.delivered(delivered)?;
+        }
+        trace!("relayed {} events", count);
+        Ok(count)
     }
 }
 
@@ -313,6 +509,21 @@ mod fake_tests {
         fn delete(&self, _id: PersonId) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
             tx_rs::with_tx(move |&mut ()| Ok(()))
         }
+        fn enqueue(&self, _event: OutboxEvent) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
+            tx_rs::with_tx(move |&mut ()| Ok(()))
+        }
+        fn pending(
+            &self,
+            _limit: usize,
+        ) -> impl tx_rs::Tx<(), Item = Vec<OutboxEvent>, Err = DaoError> {
+            tx_rs::with_tx(move |&mut ()| Ok(vec![]))
+        }
+        fn mark_delivered(
+            &self,
+            _ids: Vec<EventId>,
+        ) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
+            tx_rs::with_tx(move |&mut ()| Ok(()))
+        }
     }
 
     struct FakePersonUsecase {
@@ -379,28 +590,35 @@ mod fake_tests {
             &'a mut self,
             id: PersonId,
             date: NaiveDate,
-        ) -> impl tx_rs::Tx<(), Item = (), Err = UsecaseError>
+        ) -> impl tx_rs::Tx<(), Item = Revision, Err = UsecaseError>
         where
             (): 'a,
         {
             let person = self.db.iter_mut().find(|(i, _)| *i == id);
 
+            let mut revision = 0;
             if let Some((_, p)) = person {
                 p.death_date = Some(date);
+                revision = p.revision;
             }
 
-            tx_rs::with_tx(move |&mut ()| Ok(()))
+            tx_rs::with_tx(move |&mut ()| Ok(revision))
         }
         fn remove<'a>(
             &'a mut self,
             id: PersonId,
-        ) -> impl tx_rs::Tx<(), Item = (), Err = UsecaseError>
+        ) -> impl tx_rs::Tx<(), Item = Option<Revision>, Err = UsecaseError>
         where
             (): 'a,
         {
+            let revision = self
+                .db
+                .iter()
+                .find(|(i, _)| *i == id)
+                .map(|(_, p)| p.revision);
             self.db.retain(|(i, _)| *i != id);
 
-            tx_rs::with_tx(move |&mut ()| Ok(()))
+            tx_rs::with_tx(move |&mut ()| Ok(revision))
         }
     }
 
@@ -750,6 +968,21 @@ mod spy_tests {
         fn delete(&self, _id: PersonId) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
             tx_rs::with_tx(move |&mut ()| Ok(()))
         }
+        fn enqueue(&self, _event: OutboxEvent) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
+            tx_rs::with_tx(move |&mut ()| Ok(()))
+        }
+        fn pending(
+            &self,
+            _limit: usize,
+        ) -> impl tx_rs::Tx<(), Item = Vec<OutboxEvent>, Err = DaoError> {
+            tx_rs::with_tx(move |&mut ()| Ok(vec![]))
+        }
+        fn mark_delivered(
+            &self,
+            _ids: Vec<EventId>,
+        ) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
+            tx_rs::with_tx(move |&mut ()| Ok(()))
+        }
     }
 
     struct SpyPersonUsecase {
@@ -818,26 +1051,26 @@ mod spy_tests {
             &'a mut self,
             id: PersonId,
             date: NaiveDate,
-        ) -> impl tx_rs::Tx<(), Item = (), Err = UsecaseError>
+        ) -> impl tx_rs::Tx<(), Item = Revision, Err = UsecaseError>
         where
             (): 'a,
         {
             self.death.borrow_mut().push((id, date));
 
             // 返り値に意味はない
-            tx_rs::with_tx(move |&mut ()| Ok(()))
+            tx_rs::with_tx(move |&mut ()| Ok(1))
         }
         fn remove<'a>(
             &'a mut self,
             id: PersonId,
-        ) -> impl tx_rs::Tx<(), Item = (), Err = UsecaseError>
+        ) -> impl tx_rs::Tx<(), Item = Option<Revision>, Err = UsecaseError>
         where
             (): 'a,
         {
             self.remove.borrow_mut().push(id);
 
             // 返り値に意味はない
-            tx_rs::with_tx(move |&mut ()| Ok(()))
+            tx_rs::with_tx(move |&mut ()| Ok(Some(1)))
         }
     }
 
@@ -866,6 +1099,20 @@ mod spy_tests {
                 .borrow_mut()
                 .push((_to.to_string(), _message.to_string()));
 
+            // 返り値に意味はない
+            Ok(())
+        }
+        fn send_event(
+            &self,
+            envelope: &EventEnvelope,
+            _loc: Location,
+        ) -> Result<(), ReporterError> {
+            // イベントはテキストにして記録する
+            self.report.borrow_mut().push((
+                envelope.event.queue().to_string(),
+                envelope.event.to_string(),
+            ));
+
             // 返り値に意味はない
             Ok(())
         }
@@ -1018,15 +1265,15 @@ mod spy_tests {
             vec![
                 (
                     "entry_person".to_string(),
-                    format!("registered person_id: {}", ids[0])
+                    format!("imported person_id: {}", ids[0])
                 ),
                 (
                     "entry_person".to_string(),
-                    format!("registered person_id: {}", ids[1])
+                    format!("imported person_id: {}", ids[1])
                 ),
                 (
                     "entry_person".to_string(),
-                    format!("registered person_id: {}", ids[2])
+                    format!("imported person_id: {}", ids[2])
                 )
             ]
         );
@@ -1255,6 +1502,21 @@ mod error_stub_tests {
         fn delete(&self, _id: PersonId) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
             tx_rs::with_tx(move |&mut ()| Ok(()))
         }
+        fn enqueue(&self, _event: OutboxEvent) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
+            tx_rs::with_tx(move |&mut ()| Ok(()))
+        }
+        fn pending(
+            &self,
+            _limit: usize,
+        ) -> impl tx_rs::Tx<(), Item = Vec<OutboxEvent>, Err = DaoError> {
+            tx_rs::with_tx(move |&mut ()| Ok(vec![]))
+        }
+        fn mark_delivered(
+            &self,
+            _ids: Vec<EventId>,
+        ) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
+            tx_rs::with_tx(move |&mut ()| Ok(()))
+        }
     }
 
     struct StubPersonUsecase {
@@ -1263,8 +1525,8 @@ mod error_stub_tests {
         find_result: Result<Option<PersonDto>, UsecaseError>,
         entry_and_verify_result: Result<(PersonId, PersonDto), UsecaseError>,
         collect_result: Result<Vec<(PersonId, PersonDto)>, UsecaseError>,
-        death_result: Result<(), UsecaseError>,
-        remove_result: Result<(), UsecaseError>,
+        death_result: Result<Revision, UsecaseError>,
+        remove_result: Result<Option<Revision>, UsecaseError>,
     }
     impl HavePersonDao<()> for StubPersonUsecase {
         fn get_dao(&self) -> &impl PersonDao<()> {
@@ -1311,7 +1573,7 @@ mod error_stub_tests {
             &'a mut self,
             _id: PersonId,
             _date: NaiveDate,
-        ) -> impl tx_rs::Tx<(), Item = (), Err = UsecaseError>
+        ) -> impl tx_rs::Tx<(), Item = Revision, Err = UsecaseError>
         where
             (): 'a,
         {
@@ -1320,7 +1582,7 @@ mod error_stub_tests {
         fn remove<'a>(
             &'a mut self,
             _id: PersonId,
-        ) -> impl tx_rs::Tx<(), Item = (), Err = UsecaseError>
+        ) -> impl tx_rs::Tx<(), Item = Option<Revision>, Err = UsecaseError>
         where
             (): 'a,
         {
@@ -1361,6 +1623,14 @@ mod error_stub_tests {
                 _ => self.otherwise_result.clone(),
             }
         }
+        fn send_event(&self, envelope: &EventEnvelope, loc: Location) -> Result<(), ReporterError> {
+            self.send_report(
+                Level::Info,
+                envelope.event.queue(),
+                &envelope.event.to_string(),
+                loc,
+            )
+        }
     }
 
     struct TargetPersonService {
@@ -1402,8 +1672,8 @@ mod error_stub_tests {
                 DaoError::InsertError("valid dao".to_string()),
             )),
             collect_result: Ok(vec![]), // 使わない
-            death_result: Ok(()),       // 使わない
-            remove_result: Ok(()),      // 使わない
+            death_result: Ok(1),        // 使わない
+            remove_result: Ok(Some(1)), // 使わない
         }));
         let reporter = StubReporter {
             admin_result: Ok(()),
@@ -1439,8 +1709,8 @@ mod error_stub_tests {
                 PersonDto::new("Alice", date(2012, 11, 2), None, Some("Alice is sender"), 0),
             )),
             collect_result: Ok(vec![]), // 使わない
-            death_result: Ok(()),       // 使わない
-            remove_result: Ok(()),      // 使わない
+            death_result: Ok(1),        // 使わない
+            remove_result: Ok(Some(1)), // 使わない
         }));
         let reporter = StubReporter {
             admin_result: Ok(()),
@@ -1469,8 +1739,8 @@ mod error_stub_tests {
                 DaoError::InsertError("valid dao".to_string()),
             )),
             collect_result: Ok(vec![]), // 使わない
-            death_result: Ok(()),       // 使わない
-            remove_result: Ok(()),      // 使わない
+            death_result: Ok(1),        // 使わない
+            remove_result: Ok(Some(1)), // 使わない
         }));
         let reporter = StubReporter {
             admin_result: Err(ReporterError::Unavailable("valid req".to_string())),
@@ -1508,8 +1778,8 @@ mod error_stub_tests {
                 PersonDto::new("Alice", date(2012, 11, 2), None, None, 0),
             )), // 使わない
             collect_result: Ok(vec![]), // 使わない
-            death_result: Ok(()),  // 使わない
-            remove_result: Ok(()), // 使わない
+            death_result: Ok(1),   // 使わない
+            remove_result: Ok(Some(1)), // 使わない
         }));
         let reporter = StubReporter {
             admin_result: Ok(()),
@@ -1548,8 +1818,8 @@ mod error_stub_tests {
                 PersonDto::new("Alice", date(2012, 11, 2), None, None, 0),
             )), // 使わない
             collect_result: Ok(vec![]), // 使わない
-            death_result: Ok(()),  // 使わない
-            remove_result: Ok(()), // 使わない
+            death_result: Ok(1),   // 使わない
+            remove_result: Ok(Some(1)), // 使わない
         }));
         let reporter = StubReporter {
             admin_result: Ok(()),
@@ -1589,8 +1859,8 @@ mod error_stub_tests {
                 PersonDto::new("Alice", date(2012, 11, 2), None, None, 0),
             )), // 使わない
             collect_result: Ok(vec![]), // 使わない
-            death_result: Ok(()),  // 使わない
-            remove_result: Ok(()), // 使わない
+            death_result: Ok(1),   // 使わない
+            remove_result: Ok(Some(1)), // 使わない
         }));
         let reporter = StubReporter {
             admin_result: Err(ReporterError::Unavailable("valid req".to_string())),
@@ -1633,8 +1903,8 @@ mod error_stub_tests {
             collect_result: Err(UsecaseError::CollectPersonFailed(DaoError::SelectError(
                 "valid dao".to_string(),
             ))),
-            death_result: Ok(()),  // 使わない
-            remove_result: Ok(()), // 使わない
+            death_result: Ok(1),        // 使わない
+            remove_result: Ok(Some(1)), // 使わない
         }));
         let reporter = StubReporter {
             admin_result: Ok(()),
@@ -1668,8 +1938,8 @@ mod error_stub_tests {
             collect_result: Err(UsecaseError::CollectPersonFailed(DaoError::SelectError(
                 "valid dao".to_string(),
             ))),
-            death_result: Ok(()),  // 使わない
-            remove_result: Ok(()), // 使わない
+            death_result: Ok(1),        // 使わない
+            remove_result: Ok(Some(1)), // 使わない
         }));
         let reporter = StubReporter {
             admin_result: Err(ReporterError::Unavailable("valid req".to_string())),
@@ -1701,7 +1971,7 @@ mod error_stub_tests {
                 PersonDto::new("Alice", date(2012, 11, 2), None, None, 0),
             )), // 使わない
             collect_result: Ok(vec![]),       // 使わない
-            death_result: Ok(()),             // 使わない
+            death_result: Ok(1),              // 使わない
             remove_result: Err(UsecaseError::RemovePersonFailed(DaoError::DeleteError(
                 "valid dao".to_string(),
             ))),
@@ -1736,8 +2006,8 @@ mod error_stub_tests {
                 PersonDto::new("Alice", date(2012, 11, 2), None, None, 0),
             )), // 使わない
             collect_result: Ok(vec![]),       // 使わない
-            death_result: Ok(()),
-            remove_result: Ok(()), // 使わない
+            death_result: Ok(1),
+            remove_result: Ok(Some(1)), // 使わない
         }));
         let reporter = StubReporter {
             admin_result: Ok(()),
@@ -1771,7 +2041,7 @@ mod error_stub_tests {
             death_result: Err(UsecaseError::SavePersonFailed(DaoError::UpdateError(
                 "valid dao".to_string(),
             ))),
-            remove_result: Ok(()), // 使わない
+            remove_result: Ok(Some(1)), // 使わない
         }));
         let reporter = StubReporter {
             admin_result: Err(ReporterError::Unavailable("valid req".to_string())),
@@ -1803,8 +2073,8 @@ mod error_stub_tests {
                 PersonDto::new("Alice", date(2012, 11, 2), None, None, 0),
             )), // 使わない
             collect_result: Ok(vec![]),       // 使わない
-            death_result: Ok(()),             // 使わない
-            remove_result: Ok(()),
+            death_result: Ok(1),              // 使わない
+            remove_result: Ok(Some(1)),
         }));
         let reporter = StubReporter {
             admin_result: Ok(()),
@@ -1835,7 +2105,7 @@ mod error_stub_tests {
                 PersonDto::new("Alice", date(2012, 11, 2), None, None, 0),
             )), // 使わない
             collect_result: Ok(vec![]),       // 使わない
-            death_result: Ok(()),             // 使わない
+            death_result: Ok(1),              // 使わない
             remove_result: Err(UsecaseError::RemovePersonFailed(DaoError::DeleteError(
                 "valid dao".to_string(),
             ))),
@@ -1858,3 +2128,514 @@ mod error_stub_tests {
         assert_eq!(result, Err(ServiceError::TransactionFailed(expected)));
     }
 }
+
+// # アウトボックスのテスト
+//
+// ## 目的
+//
+//   uses_outbox が有効なとき、変更の報告がトランザクション内でアウトボックスに記録され、
+//   relay_outbox によって Observer に届けられることを保障する
+//
+// ## 方針
+//
+//   アウトボックスを持つフェイクの DAO を Usecase の既定の実装にプラグインし、
+//   Reporter にはスパイの Observer を登録する
+//   送信と relay の結果をフェイクの DAO とスパイの Observer の記録で確認する
+//
+#[cfg(test)]
+mod outbox_tests {
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        dao::{DaoError, HavePersonDao, OutboxEvent, PersonDao},
+        domain::{date, Revision},
+        dto::PersonDto,
+        event::EventId,
+        reporter::{DefaultReporter, Location, Observer, ReporterError},
+        spool::{Overflow, Spool, SpoolSettings, Spooled},
+    };
+
+    #[derive(Default)]
+    struct FakePersonDao {
+        persons: RefCell<Vec<(PersonId, PersonDto)>>,
+        outbox: RefCell<Vec<(OutboxEvent, bool)>>,
+    }
+    impl PersonDao<()> for FakePersonDao {
+        fn insert(&self, person: PersonDto) -> impl tx_rs::Tx<(), Item = PersonId, Err = DaoError> {
+            tx_rs::with_tx(move |&mut ()| {
+                let id = Uuid::now_v7();
+                self.persons.borrow_mut().push((id, person));
+                Ok(id)
+            })
+        }
+        fn fetch(
+            &self,
+            id: PersonId,
+        ) -> impl tx_rs::Tx<(), Item = Option<PersonDto>, Err = DaoError> {
+            tx_rs::with_tx(move |&mut ()| {
+                Ok(self
+                    .persons
+                    .borrow()
+                    .iter()
+                    .find(|(i, _)| *i == id)
+                    .map(|(_, p)| p.clone()))
+            })
+        }
+        fn select(&self) -> impl tx_rs::Tx<(), Item = Vec<(PersonId, PersonDto)>, Err = DaoError> {
+            tx_rs::with_tx(move |&mut ()| Ok(self.persons.borrow().clone()))
+        }
+        fn save(
+            &self,
+            id: PersonId,
+            _revision: Revision,
+            person: PersonDto,
+        ) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
+            tx_rs::with_tx(move |&mut ()| {
+                for (i, p) in self.persons.borrow_mut().iter_mut() {
+                    if *i == id {
+                        *p = person.clone();
+                    }
+                }
+                Ok(())
+            })
+        }
+        fn delete(&self, id: PersonId) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
+            tx_rs::with_tx(move |&mut ()| {
+                self.persons.borrow_mut().retain(|(i, _)| *i != id);
+                Ok(())
+            })
+        }
+        fn enqueue(&self, event: OutboxEvent) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
+            tx_rs::with_tx(move |&mut ()| {
+                self.outbox.borrow_mut().push((event, false));
+                Ok(())
+            })
+        }
+        fn pending(
+            &self,
+            limit: usize,
+        ) -> impl tx_rs::Tx<(), Item = Vec<OutboxEvent>, Err = DaoError> {
+            tx_rs::with_tx(move |&mut ()| {
+                Ok(self
+                    .outbox
+                    .borrow()
+                    .iter()
+                    .filter(|(_, delivered)| !delivered)
+                    .take(limit)
+                    .map(|(e, _)| e.clone())
+                    .collect())
+            })
+        }
+        fn mark_delivered(
+            &self,
+            ids: Vec<EventId>,
+        ) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
+            tx_rs::with_tx(move |&mut ()| {
+                for (e, delivered) in self.outbox.borrow_mut().iter_mut() {
+                    if ids.contains(&e.id) {
+                        *delivered = true;
+                    }
+                }
+                Ok(())
+            })
+        }
+    }
+
+    // Usecase は既定の実装をそのまま使う
+    struct TargetPersonUsecase {
+        dao: FakePersonDao,
+    }
+    impl HavePersonDao<()> for TargetPersonUsecase {
+        fn get_dao(&self) -> &impl PersonDao<()> {
+            &self.dao
+        }
+    }
+    impl PersonUsecase<()> for TargetPersonUsecase {}
+
+    #[derive(Clone)]
+    struct SpyObserver {
+        up: Rc<Cell<bool>>,
+        // fails on flush, as if a report sent in the background had failed
+        lost: Rc<Cell<bool>>,
+        received: Rc<RefCell<Vec<(String, String)>>>,
+        // a broker, or a side channel like syslog
+        carrier: bool,
+    }
+    impl Observer for SpyObserver {
+        fn handle_notification(
+            &self,
+            _level: Level,
+            to: &str,
+            message: &str,
+            _loc: Location,
+        ) -> Result<(), ReporterError> {
+            if !self.up.get() {
+                return Err(ReporterError::Unavailable("spy observer".to_string()));
+            }
+            self.received
+                .borrow_mut()
+                .push((to.to_string(), message.to_string()));
+            Ok(())
+        }
+        fn flush(&self) -> Result<(), ReporterError> {
+            if self.lost.get() {
+                return Err(ReporterError::Unavailable("spy observer".to_string()));
+            }
+            Ok(())
+        }
+        fn carries_events(&self) -> bool {
+            self.carrier
+        }
+    }
+    fn spy_observer(carrier: bool) -> SpyObserver {
+        SpyObserver {
+            up: Rc::new(Cell::new(true)),
+            lost: Rc::new(Cell::new(false)),
+            received: Rc::new(RefCell::new(vec![])),
+            carrier,
+        }
+    }
+
+    #[derive(Clone)]
+    struct SpyReporter {
+        observer: SpyObserver,
+        side: SpyObserver,
+        report: Rc<RefCell<Vec<(String, String)>>>,
+    }
+    impl Reporter<'_> for SpyReporter {
+        fn register(&mut self, _observer: impl Observer) -> Result<(), ReporterError> {
+            Ok(())
+        }
+        fn get_observers(&self) -> Vec<&dyn Observer> {
+            vec![&self.observer, &self.side]
+        }
+        fn send_report(
+            &self,
+            _level: Level,
+            to: &str,
+            message: &str,
+            _loc: Location,
+        ) -> Result<(), ReporterError> {
+            self.report
+                .borrow_mut()
+                .push((to.to_string(), message.to_string()));
+            Ok(())
+        }
+    }
+
+    struct TargetPersonService {
+        usecase: Rc<RefCell<TargetPersonUsecase>>,
+        reporter: SpyReporter,
+    }
+    impl PersonService<'_, ()> for TargetPersonService {
+        type U = TargetPersonUsecase;
+        type N = SpyReporter;
+
+        fn run_tx<T, F>(&mut self, f: F) -> Result<T, ServiceError>
+        where
+            F: FnOnce(&mut Self::U, &mut ()) -> Result<T, UsecaseError>,
+        {
+            let mut usecase = self.usecase.borrow_mut();
+            f(&mut usecase, &mut ()).map_err(ServiceError::TransactionFailed)
+        }
+
+        fn get_reporter(&self) -> Self::N {
+            self.reporter.clone()
+        }
+
+        fn uses_outbox(&self) -> bool {
+            true
+        }
+    }
+    impl Outbox for TargetPersonService {
+        fn pending(&mut self, limit: usize) -> Result<Vec<OutboxEvent>, ServiceError> {
+            self.pending_events(limit)
+        }
+        fn delivered(&mut self, ids: Vec<EventId>) -> Result<(), ServiceError> {
+            self.mark_delivered(ids)
+        }
+    }
+
+    fn target() -> TargetPersonService {
+        TargetPersonService {
+            usecase: Rc::new(RefCell::new(TargetPersonUsecase {
+                dao: FakePersonDao::default(),
+            })),
+            reporter: SpyReporter {
+                observer: spy_observer(true),
+                side: spy_observer(false),
+                report: Rc::new(RefCell::new(vec![])),
+            },
+        }
+    }
+
+    fn relay(service: &mut TargetPersonService, limit: usize) -> Result<usize, ServiceError> {
+        let reporter = service.reporter.clone();
+        service.relay_outbox(&reporter, limit)
+    }
+
+    #[test]
+    fn test_changes_are_recorded() {
+        let mut service = target();
+
+        let (id, _) = service
+            .register("Alice", date(2012, 11, 2), None, "Alice is sender")
+            .unwrap();
+        service.death(id, date(2100, 1, 1)).unwrap();
+        service.unregister(id).unwrap();
+
+        // コミット後には送らない
+        assert!(service.reporter.report.borrow().is_empty());
+        assert!(service.reporter.observer.received.borrow().is_empty());
+
+        let usecase = service.usecase.borrow();
+        let outbox = usecase.dao.outbox.borrow();
+        assert!(outbox.iter().all(|(_, delivered)| !delivered));
+        assert_eq!(
+            outbox
+                .iter()
+                .map(|(e, _)| (
+                    e.to.as_str(),
+                    EventEnvelope::decode(&e.message).unwrap().event
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    "entry_person",
+                    PersonEvent::Registered {
+                        person_id: id,
+                        person: PersonDto::new(
+                            "Alice",
+                            date(2012, 11, 2),
+                            None,
+                            Some("Alice is sender"),
+                            0
+                        ),
+                    }
+                ),
+                (
+                    "death_person",
+                    PersonEvent::Died {
+                        person_id: id,
+                        death_date: date(2100, 1, 1),
+                    }
+                ),
+                (
+                    "unregister_person",
+                    PersonEvent::Unregistered { person_id: id }
+                ),
+            ]
+        );
+        // 変更後の revision を付ける
+        assert_eq!(
+            outbox
+                .iter()
+                .map(|(e, _)| EventEnvelope::decode(&e.message).unwrap().revision)
+                .collect::<Vec<_>>(),
+            vec![Some(0), Some(1), Some(1)]
+        );
+        // 記録したイベントの id で配信済みにする
+        assert!(outbox
+            .iter()
+            .all(|(e, _)| EventEnvelope::decode(&e.message).unwrap().id == e.id));
+    }
+
+    #[test]
+    fn test_relay_outbox() {
+        let mut service = target();
+        let (id1, _) = service
+            .register("Alice", date(2012, 11, 2), None, "Alice is sender")
+            .unwrap();
+        let (id2, _) = service
+            .register("Bob", date(2012, 11, 3), None, "Bob is receiver")
+            .unwrap();
+        service.unregister(id1).unwrap();
+
+        // limit 件ずつ、記録した順に届ける
+        assert_eq!(relay(&mut service, 2), Ok(2));
+        assert_eq!(relay(&mut service, 2), Ok(1));
+        assert_eq!(relay(&mut service, 2), Ok(0));
+        assert_eq!(
+            *service.reporter.observer.received.borrow(),
+            vec![
+                (
+                    "entry_person".to_string(),
+                    format!("registered person_id: {}", id1)
+                ),
+                (
+                    "entry_person".to_string(),
+                    format!("registered person_id: {}", id2)
+                ),
+                (
+                    "unregister_person".to_string(),
+                    format!("unregistered person_id: {}", id1)
+                ),
+            ]
+        );
+        assert!(service
+            .usecase
+            .borrow()
+            .dao
+            .outbox
+            .borrow()
+            .iter()
+            .all(|(_, delivered)| *delivered));
+    }
+
+    #[test]
+    fn test_relay_outbox_observer_down() {
+        let mut service = target();
+        service
+            .register("Alice", date(2012, 11, 2), None, "Alice is sender")
+            .unwrap();
+
+        // 届かなかったイベントは未配信のまま残す
+        service.reporter.observer.up.set(false);
+        assert_eq!(relay(&mut service, 10), Ok(0));
+        assert!(service.reporter.observer.received.borrow().is_empty());
+        assert!(!service.usecase.borrow().dao.outbox.borrow()[0].1);
+
+        // 復旧したら次の relay で届ける
+        service.reporter.observer.up.set(true);
+        assert_eq!(relay(&mut service, 10), Ok(1));
+        assert_eq!(service.reporter.observer.received.borrow().len(), 1);
+        assert!(service.usecase.borrow().dao.outbox.borrow()[0].1);
+    }
+
+    #[test]
+    fn test_relay_outbox_flush_failed() {
+        let mut service = target();
+        service
+            .register("Alice", date(2012, 11, 2), None, "Alice is sender")
+            .unwrap();
+
+        // 後から失敗が分かったイベントも未配信のまま残す
+        service.reporter.observer.lost.set(true);
+        assert_eq!(relay(&mut service, 10), Ok(0));
+        assert!(!service.usecase.borrow().dao.outbox.borrow()[0].1);
+
+        service.reporter.observer.lost.set(false);
+        assert_eq!(relay(&mut service, 10), Ok(1));
+        assert_eq!(service.reporter.observer.received.borrow().len(), 2);
+        assert!(service.usecase.borrow().dao.outbox.borrow()[0].1);
+    }
+
+    #[test]
+    fn test_relay_outbox_side_observer_down() {
+        let mut service = target();
+        service
+            .register("Alice", date(2012, 11, 2), None, "Alice is sender")
+            .unwrap();
+
+        // ブローカー以外の失敗では未配信に戻さない
+        service.reporter.side.up.set(false);
+        service.reporter.side.lost.set(true);
+        assert_eq!(relay(&mut service, 10), Ok(1));
+        assert_eq!(service.reporter.observer.received.borrow().len(), 1);
+        assert!(service.reporter.side.received.borrow().is_empty());
+        assert!(service.usecase.borrow().dao.outbox.borrow()[0].1);
+    }
+
+    #[test]
+    fn test_relay_outbox_spooled_broker_down() {
+        let mut service = target();
+        service
+            .register("Alice", date(2012, 11, 2), None, "Alice is sender")
+            .unwrap();
+        let broker = spy_observer(true);
+        let settings = SpoolSettings {
+            path: std::env::temp_dir().join(format!("spool-{}.jsonl", Uuid::now_v7())),
+            capacity: 10,
+            overflow: Overflow::DropOldest,
+        };
+        let mut reporter = DefaultReporter::new();
+        reporter
+            .register(Spooled::new(
+                broker.clone(),
+                Spool::open(settings.clone()).unwrap(),
+            ))
+            .unwrap();
+
+        // spool に書いただけでは配信済みにしない
+        broker.up.set(false);
+        assert_eq!(service.relay_outbox(&reporter, 10), Ok(0));
+        assert!(!service.usecase.borrow().dao.outbox.borrow()[0].1);
+        assert!(!settings.path.exists());
+
+        broker.up.set(true);
+        assert_eq!(service.relay_outbox(&reporter, 10), Ok(1));
+        assert_eq!(broker.received.borrow().len(), 1);
+        assert!(service.usecase.borrow().dao.outbox.borrow()[0].1);
+    }
+
+    #[test]
+    fn test_relay_outbox_text_message() {
+        let mut service = target();
+        let id = Uuid::now_v7();
+        let message = format!("registered person_id: {}", id);
+        service.usecase.borrow().dao.outbox.borrow_mut().push((
+            OutboxEvent {
+                id: Uuid::now_v7(),
+                to: "entry_person".to_string(),
+                message: message.clone(),
+            },
+            false,
+        ));
+
+        // イベント導入前に記録されたテキストの報告はそのまま届ける
+        assert_eq!(relay(&mut service, 10), Ok(1));
+        assert_eq!(
+            *service.reporter.observer.received.borrow(),
+            vec![("entry_person".to_string(), message)]
+        );
+    }
+}
+
+#[cfg(test)]
+mod error_detail_tests {
+    use super::*;
+    use crate::dao::DaoError;
+    use crate::error_detail::{ErrorDetail, ErrorSource};
+    use crate::usecase::UsecaseError;
+
+    #[test]
+    fn test_error_detail() {
+        let e = ServiceError::TransactionFailed(UsecaseError::CollectPersonFailed(
+            DaoError::SelectError("connection refused".to_string()),
+        ));
+        let detail = ErrorDetail::of(&e).or_backend(Some("postgres"));
+
+        assert_eq!(detail.kind, "ServiceError::TransactionFailed");
+        assert_eq!(detail.message, e.to_string());
+        assert_eq!(
+            detail.sources,
+            vec![
+                ErrorSource {
+                    kind: "UsecaseError::CollectPersonFailed".to_string(),
+                    message: "collect person failed: select error: connection refused".to_string(),
+                },
+                ErrorSource {
+                    kind: "DaoError::SelectError".to_string(),
+                    message: "select error: connection refused".to_string(),
+                },
+            ]
+        );
+        assert_eq!(detail.backend, Some("postgres".to_string()));
+        assert!(detail.retryable);
+    }
+
+    #[test]
+    fn test_not_retryable() {
+        // 不正な要求は何度送っても失敗する
+        let e = ServiceError::InvalidRequest(InvalidErrorKind::EmptyArgument);
+        let detail = ErrorDetail::of(&e);
+        assert_eq!(detail.kind, "ServiceError::InvalidRequest");
+        assert!(detail.sources.is_empty());
+        assert_eq!(detail.backend, None);
+        assert!(!detail.retryable);
+    }
+}
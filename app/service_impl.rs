@@ -1,3 +1,115 @@
 pub mod db_base;
 pub mod hash_base;
 pub mod nosql_base;
+
+use std::rc::Rc;
+
+use crate::circuit_breaker::{BreakerSettings, CircuitBreaker, Guarded};
+use crate::coalesce::CoalesceSettings;
+use crate::dispatcher::{Dispatched, DispatcherSettings};
+use crate::file_log::FileLogSettings;
+use crate::rabbitmq::{self, MqSettings};
+use crate::reporter::{DefaultReporter, Reporter, ReporterError, Subscription};
+use crate::spool::{Spool, Spooled};
+use crate::syslog::SyslogSettings;
+use crate::webhook::WebhookSettings;
+
+/// Where the services send their reports.
+#[derive(Debug, Clone)]
+pub struct ReportSettings {
+    pub mq: MqSettings,
+    pub syslog: SyslogSettings,
+    // None writes no local report log
+    pub file: Option<FileLogSettings>,
+    // None posts to no webhook
+    pub webhook: Option<WebhookSettings>,
+    pub subscriptions: Subscriptions,
+    pub dispatcher: DispatcherSettings,
+    pub coalesce: CoalesceSettings,
+}
+
+/// Which reports each of the observers receives.
+#[derive(Debug, Clone, Default)]
+pub struct Subscriptions {
+    pub mq: Subscription,
+    pub syslog: Subscription,
+    pub file: Subscription,
+    pub webhook: Subscription,
+}
+
+// each observer has its own runtime in its worker
+fn worker_runtime() -> Result<Rc<tokio::runtime::Runtime>, ReporterError> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map(Rc::new)
+        .map_err(|e| ReporterError::Unavailable(e.to_string()))
+}
+
+// the observers report in the background, so a slow one doesn't delay the requests
+pub fn make_reporter(
+    settings: ReportSettings,
+    program_name: &str,
+    pid: u32,
+) -> DefaultReporter<'static> {
+    let mut reporter = DefaultReporter::new().coalesce(settings.coalesce);
+
+    let mq_settings = settings.mq;
+    // the service starts without the broker, the reports are spooled until it comes
+    let mq_client = Dispatched::spawn("rabbitmq", settings.dispatcher.clone(), move || {
+        let mq_client = rabbitmq::Client::open(worker_runtime()?, &mq_settings.uri)
+            .delivery(mq_settings.delivery)
+            .exchange(mq_settings.exchange);
+        Ok(Spooled::new(
+            Guarded::new(
+                mq_client,
+                CircuitBreaker::new("rabbitmq", BreakerSettings::default()),
+            ),
+            Spool::open(mq_settings.spool)?,
+        ))
+    })
+    .expect("create mq client");
+    reporter
+        .subscribe(mq_client, settings.subscriptions.mq)
+        .expect("register observer: rabbitmq");
+
+    let syslog_settings = settings.syslog;
+    let program_name = program_name.to_string();
+    let syslog_client = Dispatched::spawn("syslog", settings.dispatcher.clone(), move || {
+        let syslog_client = crate::syslog::Client::open(syslog_settings, &program_name, pid);
+        Ok(Guarded::new(
+            syslog_client,
+            CircuitBreaker::new("syslog", BreakerSettings::default()),
+        ))
+    })
+    .expect("create syslog client");
+    reporter
+        .subscribe(syslog_client, settings.subscriptions.syslog)
+        .expect("register observer: syslog");
+
+    if let Some(file_settings) = settings.file {
+        let file_client = Dispatched::spawn("report_log", settings.dispatcher.clone(), move || {
+            crate::file_log::Client::open(file_settings)
+        })
+        .expect("create report log client");
+        reporter
+            .subscribe(file_client, settings.subscriptions.file)
+            .expect("register observer: report log");
+    }
+
+    if let Some(webhook_settings) = settings.webhook {
+        let webhook_client = Dispatched::spawn("webhook", settings.dispatcher.clone(), move || {
+            let webhook_client = crate::webhook::Client::open(worker_runtime()?, webhook_settings)?;
+            Ok(Guarded::new(
+                webhook_client,
+                CircuitBreaker::new("webhook", BreakerSettings::default()),
+            ))
+        })
+        .expect("create webhook client");
+        reporter
+            .subscribe(webhook_client, settings.subscriptions.webhook)
+            .expect("register observer: webhook");
+    }
+
+    reporter
+}
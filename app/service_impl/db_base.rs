@@ -1,17 +1,25 @@
 use log::{error, trace};
 use postgres::NoTls;
 use std::cell::RefCell;
+use std::num::NonZeroUsize;
 use std::process;
-use std::rc::Rc;
 use std::time::Duration;
 
-use crate::cached_service::PersonCachedService;
+use crate::cache_metrics::{CacheMetrics, MeteredPersonCao};
+use crate::cached_service::{CachePolicy, PersonCachedService};
+use crate::circuit_breaker::{BreakerSettings, CircuitBreaker, Guarded};
 use crate::dao::{self, HavePersonDao};
+use crate::event::EventId;
+use crate::lru_cache::LruPersonCao;
 use crate::pg_db::PgPersonDao;
-use crate::rabbitmq;
-use crate::redis_cache;
-use crate::reporter::{DefaultReporter, Reporter};
-use crate::service::{PersonOutputBoundary, PersonService, ServiceError};
+use crate::redis_cache::{self, RedisCacheSettings};
+use crate::redis_invalidation::{
+    RedisInvalidationPublisher, RedisInvalidationSubscriber, INVALIDATION_CHANNEL,
+};
+use crate::reporter::DefaultReporter;
+use crate::service::{Outbox, PersonOutputBoundary, PersonService, ServiceError};
+use crate::service_impl::ReportSettings;
+use crate::tiered_cache::TieredPersonCao;
 use crate::usecase::{PersonUsecase, UsecaseError};
 
 #[derive(Debug, Clone)]
@@ -34,41 +42,59 @@ impl<'a> HavePersonDao<postgres::Transaction<'a>> for PersonUsecaseImpl {
 pub struct PersonServiceImpl {
     db_client: postgres::Client,
     cache_client: redis::Client,
+    redis_settings: RedisCacheSettings,
+    local_cache: LruPersonCao,
+    cache_policy: CachePolicy,
+    cache_metrics: CacheMetrics,
+    cache_breaker: CircuitBreaker,
     reporter: DefaultReporter<'static>,
     usecase: RefCell<PersonUsecaseImpl>,
 }
 impl PersonServiceImpl {
     #[cfg_attr(any(feature = "use_dynamo", feature = "use_hash"), allow(unused))]
     pub fn new(
-        runtime: Rc<tokio::runtime::Runtime>,
         db_uri: &str,
         cache_uri: &str,
-        mq_uri: &str,
+        report_settings: ReportSettings,
+        local_cache_capacity: NonZeroUsize,
+        redis_settings: RedisCacheSettings,
+        cache_policy: CachePolicy,
     ) -> Self {
         let pid = process::id();
         trace!("pid: {}", pid);
         let db_client = postgres::Client::connect(db_uri, NoTls).expect("create db client");
         let cache_client = redis::Client::open(cache_uri).expect("create cache client");
-        let mq_client = rabbitmq::Client::open(runtime, mq_uri).expect("create mq client");
-        let syslog_client =
-            crate::syslog::Client::new("ddd_tx_tut", pid).expect("crate syslog client");
-        let mut reporter = DefaultReporter::new();
-        reporter
-            .register(mq_client)
-            .expect("register observer: rabbitmq");
-        reporter
-            .register(syslog_client)
-            .expect("register observer: syslog");
+        let local_cache =
+            LruPersonCao::new(local_cache_capacity).subscribe(RedisInvalidationSubscriber::new(
+                cache_client.clone(),
+                &redis_settings.key_space.key(INVALIDATION_CHANNEL),
+                Duration::from_secs(2),
+                Duration::from_secs(10),
+            ));
+        let reporter = super::make_reporter(report_settings, "ddd_tx_tut", pid);
+        // the observers are guarded by their own breakers, so the reporter can tell about redis
+        let cache_breaker =
+            CircuitBreaker::new("cache", BreakerSettings::default()).report_to(reporter.clone());
 
         let usecase = RefCell::new(PersonUsecaseImpl::new(PgPersonDao));
 
         Self {
             db_client,
             cache_client,
+            redis_settings,
+            local_cache,
+            cache_policy,
+            cache_metrics: CacheMetrics::new(),
+            cache_breaker,
             reporter,
             usecase,
         }
     }
+
+    #[cfg_attr(any(feature = "use_dynamo", feature = "use_hash"), allow(unused))]
+    pub fn cache_metrics(&self) -> &CacheMetrics {
+        &self.cache_metrics
+    }
 }
 impl<'a> PersonService<'a, postgres::Transaction<'a>> for PersonServiceImpl {
     type U = PersonUsecaseImpl;
@@ -108,14 +134,60 @@ impl<'a> PersonService<'a, postgres::Transaction<'a>> for PersonServiceImpl {
     fn get_reporter(&self) -> Self::N {
         self.reporter.clone()
     }
+
+    fn backend(&self) -> Option<&'static str> {
+        Some("postgres")
+    }
+
+    fn uses_outbox(&self) -> bool {
+        true
+    }
+}
+// each call takes the service for a transaction of its own
+impl Outbox for PersonServiceImpl {
+    fn pending(&mut self, limit: usize) -> Result<Vec<dao::OutboxEvent>, ServiceError> {
+        PersonService::pending_events(self, limit)
+    }
+    fn delivered(&mut self, ids: Vec<EventId>) -> Result<(), ServiceError> {
+        PersonService::mark_delivered(self, ids)
+    }
 }
-impl<'a> PersonCachedService<'a, redis::Connection, postgres::Transaction<'a>>
-    for PersonServiceImpl
-{
-    type C = redis_cache::RedisPersonCao;
+impl<'a> PersonCachedService<'a, (), postgres::Transaction<'a>> for PersonServiceImpl {
+    type C = MeteredPersonCao<
+        TieredPersonCao<LruPersonCao, Guarded<redis_cache::RedisPersonCao>, redis::Connection>,
+    >;
+    type P = Guarded<RedisInvalidationPublisher>;
 
     fn get_cao(&self) -> Self::C {
-        redis_cache::RedisPersonCao::new(self.cache_client.clone(), Duration::from_secs(2))
+        MeteredPersonCao::new(
+            TieredPersonCao::new(
+                self.local_cache.clone(),
+                Guarded::new(
+                    redis_cache::RedisPersonCao::new(
+                        self.cache_client.clone(),
+                        Duration::from_secs(2),
+                        self.redis_settings.codec,
+                        self.redis_settings.key_space.clone(),
+                    ),
+                    self.cache_breaker.clone(),
+                ),
+            ),
+            self.cache_metrics.clone(),
+        )
+    }
+    fn get_publisher(&self) -> Self::P {
+        // same redis, same breaker
+        Guarded::new(
+            RedisInvalidationPublisher::new(
+                self.cache_client.clone(),
+                &self.redis_settings.key_space.key(INVALIDATION_CHANNEL),
+                Duration::from_secs(2),
+            ),
+            self.cache_breaker.clone(),
+        )
+    }
+    fn get_cache_policy(&self) -> CachePolicy {
+        self.cache_policy
     }
 }
 
@@ -1,15 +1,18 @@
 use log::{error, trace};
-use std::{cell::RefCell, cell::RefMut, collections::HashMap, process, rc::Rc, time::Duration};
+use std::{cell::RefCell, cell::RefMut, collections::HashMap, num::NonZeroUsize, process};
 
-use crate::cached_service::PersonCachedService;
+use crate::cache_metrics::{CacheMetrics, MeteredPersonCao};
+use crate::cached_service::{CachePolicy, PersonCachedService};
 use crate::dao::{self, HavePersonDao};
 use crate::domain::PersonId;
 use crate::dto::PersonDto;
+use crate::event::EventId;
 use crate::hs_db::HashDB;
-use crate::rabbitmq;
-use crate::redis_cache;
-use crate::reporter::{DefaultReporter, Reporter};
-use crate::service::{PersonOutputBoundary, PersonService, ServiceError};
+use crate::invalidation::NoInvalidation;
+use crate::lru_cache::LruPersonCao;
+use crate::reporter::DefaultReporter;
+use crate::service::{Outbox, PersonOutputBoundary, PersonService, ServiceError};
+use crate::service_impl::ReportSettings;
 use crate::usecase::{PersonUsecase, UsecaseError};
 
 #[derive(Debug, Clone)]
@@ -31,37 +34,42 @@ impl<'a> HavePersonDao<RefMut<'a, HashMap<PersonId, PersonDto>>> for PersonUseca
 
 pub struct PersonServiceImpl {
     hs_db: HashDB,
-    cache_client: redis::Client,
+    local_cache: LruPersonCao,
+    cache_policy: CachePolicy,
+    cache_metrics: CacheMetrics,
     reporter: DefaultReporter<'static>,
     usecase: RefCell<PersonUsecaseImpl>,
 }
 impl PersonServiceImpl {
     #[cfg_attr(any(feature = "use_pq", feature = "use_dynamo"), allow(unused))]
-    pub fn new(runtime: Rc<tokio::runtime::Runtime>, cache_uri: &str, mq_uri: &str) -> Self {
+    pub fn new(
+        report_settings: ReportSettings,
+        local_cache_capacity: NonZeroUsize,
+        cache_policy: CachePolicy,
+    ) -> Self {
         let pid = process::id();
         trace!("pid: {}", pid);
-        let cache_client = redis::Client::open(cache_uri).expect("create cache client");
-        let mq_client = rabbitmq::Client::open(runtime, mq_uri).expect("create mq client");
-        let syslog_client =
-            crate::syslog::Client::new("ddd_tx_tut", pid).expect("crate syslog client");
-        let mut reporter = DefaultReporter::new();
-        reporter
-            .register(mq_client)
-            .expect("register observer: rabbitmq");
-        reporter
-            .register(syslog_client)
-            .expect("register observer: syslog");
+        // no redis: the in-process cache works standalone
+        let local_cache = LruPersonCao::new(local_cache_capacity);
+        let reporter = super::make_reporter(report_settings, "ddd_tx_tut", pid);
 
         let dao = HashDB::new();
         let usecase = RefCell::new(PersonUsecaseImpl::new(dao.clone()));
 
         Self {
             hs_db: dao,
-            cache_client,
+            local_cache,
+            cache_policy,
+            cache_metrics: CacheMetrics::new(),
             reporter,
             usecase,
         }
     }
+
+    #[cfg_attr(any(feature = "use_pq", feature = "use_dynamo"), allow(unused))]
+    pub fn cache_metrics(&self) -> &CacheMetrics {
+        &self.cache_metrics
+    }
 }
 impl<'a> PersonService<'a, RefMut<'a, HashMap<PersonId, PersonDto>>> for PersonServiceImpl {
     type U = PersonUsecaseImpl;
@@ -96,15 +104,39 @@ impl<'a> PersonService<'a, RefMut<'a, HashMap<PersonId, PersonDto>>> for PersonS
     fn get_reporter(&self) -> Self::N {
         self.reporter.clone()
     }
+
+    fn backend(&self) -> Option<&'static str> {
+        Some("memory")
+    }
+
+    fn uses_outbox(&self) -> bool {
+        true
+    }
+}
+// each call takes the service for a transaction of its own
+impl Outbox for PersonServiceImpl {
+    fn pending(&mut self, limit: usize) -> Result<Vec<dao::OutboxEvent>, ServiceError> {
+        PersonService::pending_events(self, limit)
+    }
+    fn delivered(&mut self, ids: Vec<EventId>) -> Result<(), ServiceError> {
+        PersonService::mark_delivered(self, ids)
+    }
 }
 
-impl<'a> PersonCachedService<'a, redis::Connection, RefMut<'a, HashMap<PersonId, PersonDto>>>
+impl<'a> PersonCachedService<'a, (), RefMut<'a, HashMap<PersonId, PersonDto>>>
     for PersonServiceImpl
 {
-    type C = redis_cache::RedisPersonCao;
+    type C = MeteredPersonCao<LruPersonCao>;
+    type P = NoInvalidation;
 
     fn get_cao(&self) -> Self::C {
-        redis_cache::RedisPersonCao::new(self.cache_client.clone(), Duration::from_secs(2))
+        MeteredPersonCao::new(self.local_cache.clone(), self.cache_metrics.clone())
+    }
+    fn get_publisher(&self) -> Self::P {
+        NoInvalidation
+    }
+    fn get_cache_policy(&self) -> CachePolicy {
+        self.cache_policy
     }
 }
 
@@ -1,13 +1,21 @@
-use log::trace;
-use std::{cell::RefCell, process, rc::Rc, time::Duration};
+use log::{error, trace};
+use std::{cell::RefCell, num::NonZeroUsize, process, rc::Rc, time::Duration};
 
-use crate::cached_service::PersonCachedService;
+use crate::cache_metrics::{CacheMetrics, MeteredPersonCao};
+use crate::cached_service::{CachePolicy, PersonCachedService};
+use crate::circuit_breaker::{BreakerSettings, CircuitBreaker, Guarded};
 use crate::dao::{self, HavePersonDao};
 use crate::dynamodb::DynamoDbPersonDao;
-use crate::rabbitmq;
-use crate::redis_cache;
-use crate::reporter::{DefaultReporter, Reporter};
-use crate::service::{PersonOutputBoundary, PersonService, ServiceError};
+use crate::event::EventId;
+use crate::lru_cache::LruPersonCao;
+use crate::redis_cache::{self, RedisCacheSettings};
+use crate::redis_invalidation::{
+    RedisInvalidationPublisher, RedisInvalidationSubscriber, INVALIDATION_CHANNEL,
+};
+use crate::reporter::DefaultReporter;
+use crate::service::{Outbox, PersonOutputBoundary, PersonService, ServiceError};
+use crate::service_impl::ReportSettings;
+use crate::tiered_cache::TieredPersonCao;
 use crate::usecase::{PersonUsecase, UsecaseError};
 
 #[derive(Debug, Clone)]
@@ -31,6 +39,11 @@ impl HavePersonDao<Rc<tokio::runtime::Runtime>> for PersonUsecaseImpl {
 pub struct PersonServiceImpl {
     runtime: Rc<tokio::runtime::Runtime>,
     cache_client: redis::Client,
+    redis_settings: RedisCacheSettings,
+    local_cache: LruPersonCao,
+    cache_policy: CachePolicy,
+    cache_metrics: CacheMetrics,
+    cache_breaker: CircuitBreaker,
     reporter: DefaultReporter<'static>,
     usecase: RefCell<PersonUsecaseImpl>,
 }
@@ -40,21 +53,25 @@ impl PersonServiceImpl {
         runtime: Rc<tokio::runtime::Runtime>,
         dynamo_uri: &str,
         cache_uri: &str,
-        mq_uri: &str,
+        report_settings: ReportSettings,
+        local_cache_capacity: NonZeroUsize,
+        redis_settings: RedisCacheSettings,
+        cache_policy: CachePolicy,
     ) -> Self {
         let pid = process::id();
         trace!("pid: {}", pid);
         let cache_client = redis::Client::open(cache_uri).expect("create cache client");
-        let mq_client = rabbitmq::Client::open(runtime.clone(), mq_uri).expect("create mq client");
-        let syslog_client =
-            crate::syslog::Client::new("ddd_tx_tut", pid).expect("crate syslog client");
-        let mut reporter = DefaultReporter::new();
-        reporter
-            .register(mq_client)
-            .expect("register observer: rabbitmq");
-        reporter
-            .register(syslog_client)
-            .expect("register observer: syslog");
+        let local_cache =
+            LruPersonCao::new(local_cache_capacity).subscribe(RedisInvalidationSubscriber::new(
+                cache_client.clone(),
+                &redis_settings.key_space.key(INVALIDATION_CHANNEL),
+                Duration::from_secs(2),
+                Duration::from_secs(10),
+            ));
+        let reporter = super::make_reporter(report_settings, "ddd_tx_tut", pid);
+        // the observers are guarded by their own breakers, so the reporter can tell about redis
+        let cache_breaker =
+            CircuitBreaker::new("cache", BreakerSettings::default()).report_to(reporter.clone());
 
         let usecase = RefCell::new(PersonUsecaseImpl::new(DynamoDbPersonDao::new(
             runtime.clone(),
@@ -64,10 +81,20 @@ impl PersonServiceImpl {
         Self {
             runtime,
             cache_client,
+            redis_settings,
+            local_cache,
+            cache_policy,
+            cache_metrics: CacheMetrics::new(),
+            cache_breaker,
             reporter,
             usecase,
         }
     }
+
+    #[cfg_attr(any(feature = "use_pq", feature = "use_hash"), allow(unused))]
+    pub fn cache_metrics(&self) -> &CacheMetrics {
+        &self.cache_metrics
+    }
 }
 impl<'a> PersonService<'a, Rc<tokio::runtime::Runtime>> for PersonServiceImpl {
     type U = PersonUsecaseImpl;
@@ -82,10 +109,16 @@ impl<'a> PersonService<'a, Rc<tokio::runtime::Runtime>> for PersonServiceImpl {
 
         match res {
             Ok(v) => {
-                trace!("transaction succeeded");
+                // the writes are sent at once only now
+                usecase.dao.commit(&self.runtime).map_err(|e| {
+                    error!("failed to commit: {}", e);
+                    ServiceError::ServiceUnavailable(e.to_string())
+                })?;
+                trace!("transaction committed");
                 Ok(v)
             }
             Err(e) => {
+                usecase.dao.rollback();
                 trace!("transaction aborted: {:?}", e);
                 Err(ServiceError::TransactionFailed(e))
             }
@@ -95,14 +128,60 @@ impl<'a> PersonService<'a, Rc<tokio::runtime::Runtime>> for PersonServiceImpl {
     fn get_reporter(&self) -> Self::N {
         self.reporter.clone()
     }
+
+    fn backend(&self) -> Option<&'static str> {
+        Some("dynamodb")
+    }
+
+    fn uses_outbox(&self) -> bool {
+        true
+    }
+}
+// each call takes the service for a transaction of its own
+impl Outbox for PersonServiceImpl {
+    fn pending(&mut self, limit: usize) -> Result<Vec<dao::OutboxEvent>, ServiceError> {
+        PersonService::pending_events(self, limit)
+    }
+    fn delivered(&mut self, ids: Vec<EventId>) -> Result<(), ServiceError> {
+        PersonService::mark_delivered(self, ids)
+    }
 }
-impl<'a> PersonCachedService<'a, redis::Connection, Rc<tokio::runtime::Runtime>>
-    for PersonServiceImpl
-{
-    type C = redis_cache::RedisPersonCao;
+impl<'a> PersonCachedService<'a, (), Rc<tokio::runtime::Runtime>> for PersonServiceImpl {
+    type C = MeteredPersonCao<
+        TieredPersonCao<LruPersonCao, Guarded<redis_cache::RedisPersonCao>, redis::Connection>,
+    >;
+    type P = Guarded<RedisInvalidationPublisher>;
 
     fn get_cao(&self) -> Self::C {
-        redis_cache::RedisPersonCao::new(self.cache_client.clone(), Duration::from_secs(2))
+        MeteredPersonCao::new(
+            TieredPersonCao::new(
+                self.local_cache.clone(),
+                Guarded::new(
+                    redis_cache::RedisPersonCao::new(
+                        self.cache_client.clone(),
+                        Duration::from_secs(2),
+                        self.redis_settings.codec,
+                        self.redis_settings.key_space.clone(),
+                    ),
+                    self.cache_breaker.clone(),
+                ),
+            ),
+            self.cache_metrics.clone(),
+        )
+    }
+    fn get_publisher(&self) -> Self::P {
+        // same redis, same breaker
+        Guarded::new(
+            RedisInvalidationPublisher::new(
+                self.cache_client.clone(),
+                &self.redis_settings.key_space.key(INVALIDATION_CHANNEL),
+                Duration::from_secs(2),
+            ),
+            self.cache_breaker.clone(),
+        )
+    }
+    fn get_cache_policy(&self) -> CachePolicy {
+        self.cache_policy
     }
 }
 
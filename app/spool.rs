@@ -0,0 +1,625 @@
+use log::{error, trace, warn};
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::error_detail::ErrorDetail;
+use crate::event::EventEnvelope;
+use crate::location::Location;
+use crate::reporter::{Level, Observer, ReporterError};
+
+/// Which notification is given up when the spool is full. The events are never given up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Overflow {
+    // keep the recent reports
+    #[default]
+    DropOldest,
+    // keep the reports since the broker went down
+    DropNewest,
+}
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "drop-oldest" => Ok(Overflow::DropOldest),
+            "drop-newest" => Ok(Overflow::DropNewest),
+            _ => Err(format!("unknown overflow policy: {}", s)),
+        }
+    }
+}
+
+/// Where the undeliverable reports are kept, and how many of them.
+#[derive(Debug, Clone)]
+pub struct SpoolSettings {
+    pub path: PathBuf,
+    pub capacity: usize,
+    pub overflow: Overflow,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpooledLocation {
+    file: String,
+    line: u32,
+    column: u32,
+}
+impl SpooledLocation {
+    pub fn as_location(&self) -> Location<'_> {
+        Location {
+            file: &self.file,
+            line: self.line,
+            column: self.column,
+        }
+    }
+}
+impl From<Location<'_>> for SpooledLocation {
+    fn from(loc: Location<'_>) -> Self {
+        Self {
+            file: loc.file.to_string(),
+            line: loc.line,
+            column: loc.column,
+        }
+    }
+}
+
+/// A report owned, to be kept or passed to another thread.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Record {
+    Notification {
+        level: Level,
+        to: String,
+        message: String,
+        // absent in the records spooled before the errors were detailed
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<ErrorDetail>,
+        location: SpooledLocation,
+    },
+    Event {
+        envelope: EventEnvelope,
+        location: SpooledLocation,
+    },
+}
+impl Record {
+    pub fn send(&self, observer: &impl Observer) -> Result<(), ReporterError> {
+        match self {
+            Record::Notification {
+                level,
+                to,
+                message,
+                error: Some(error),
+                location,
+            } => observer.handle_error(level.clone(), to, message, error, location.as_location()),
+            Record::Notification {
+                level,
+                to,
+                message,
+                error: None,
+                location,
+            } => observer.handle_notification(level.clone(), to, message, location.as_location()),
+            Record::Event { envelope, location } => {
+                observer.handle_event(envelope, location.as_location())
+            }
+        }
+    }
+}
+
+/// The reports kept in a JSON lines file, oldest first.
+///
+/// The file is appended to, and compacted when the spooled reports are taken
+/// or it has grown to twice the capacity with the given up ones.
+pub struct Spool {
+    settings: SpoolSettings,
+    records: RefCell<VecDeque<Record>>,
+    // lines in the file, including the given up ones until the next compaction
+    lines: Cell<usize>,
+}
+impl Spool {
+    // the records left by the previous run are replayed, too
+    pub fn open(settings: SpoolSettings) -> Result<Self, ReporterError> {
+        let spool = Self {
+            settings,
+            records: RefCell::new(VecDeque::new()),
+            lines: Cell::new(0),
+        };
+        match File::open(&spool.settings.path) {
+            Ok(f) => {
+                for line in BufReader::new(f).lines() {
+                    let line = line.map_err(|e| ReporterError::Unavailable(e.to_string()))?;
+                    spool.lines.set(spool.lines.get() + 1);
+                    let record = match serde_json::from_str(&line) {
+                        Ok(record) => record,
+                        // a line torn by a crash
+                        Err(e) => {
+                            warn!("skip broken spool record: {}", e);
+                            continue;
+                        }
+                    };
+                    // give up the same ones as when they were pushed.
+                    // the events are kept even if the capacity has been shrunk since then.
+                    if spool.make_room(&record).unwrap_or(true) {
+                        spool.records.borrow_mut().push_back(record);
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(ReporterError::Unavailable(e.to_string())),
+        }
+        trace!("spool opened: {} records", spool.records.borrow().len());
+
+        Ok(spool)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.borrow().is_empty()
+    }
+
+    pub fn push(&self, record: Record) -> Result<(), ReporterError> {
+        if !self.make_room(&record)? {
+            warn!("spool full, the report dropped: {:?}", record);
+            return Ok(());
+        }
+        self.append(&record)?;
+        self.records.borrow_mut().push_back(record);
+
+        // the given up ones are left in the file until then
+        if self.lines.get() > 2 * self.settings.capacity {
+            if let Err(e) = self.persist() {
+                warn!("spool not compacted: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    // false if the record is given up instead
+    fn make_room(&self, record: &Record) -> Result<bool, ReporterError> {
+        if self.records.borrow().len() < self.settings.capacity {
+            return Ok(true);
+        }
+
+        // only the notifications are given up, the events are kept by the caller (e.g. the outbox)
+        let is_event = matches!(record, Record::Event { .. });
+        let make_room = match self.settings.overflow {
+            Overflow::DropOldest => true,
+            Overflow::DropNewest => is_event,
+        };
+        if make_room {
+            if let Some(dropped) = self.drop_notification() {
+                warn!("spool full, the report dropped: {:?}", dropped);
+                return Ok(true);
+            }
+        }
+        if is_event {
+            error!("spool full of events, the event not spooled");
+            return Err(ReporterError::Unavailable("spool full".to_string()));
+        }
+        Ok(false)
+    }
+
+    // none if there is no notification to give up
+    fn drop_notification(&self) -> Option<Record> {
+        let mut records = self.records.borrow_mut();
+        let is_notification = |r: &Record| matches!(r, Record::Notification { .. });
+        let i = match self.settings.overflow {
+            Overflow::DropOldest => records.iter().position(is_notification),
+            Overflow::DropNewest => records.iter().rposition(is_notification),
+        };
+        records.remove(i?)
+    }
+
+    // the oldest ones, removed from the spool
+    pub fn take(&self, n: usize) -> Vec<Record> {
+        let n = n.min(self.records.borrow().len());
+        if n == 0 {
+            return vec![];
+        }
+        let taken = self.records.borrow_mut().drain(..n).collect();
+        if let Err(e) = self.persist() {
+            // 残りと一緒にもう一度送られる
+            warn!("taken reports may be sent again: {}", e);
+        }
+        taken
+    }
+
+    fn append(&self, record: &Record) -> Result<(), ReporterError> {
+        let line = serde_json::to_string(record).expect("serialize spool record");
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.settings.path)
+            .and_then(|mut f| {
+                writeln!(f, "{}", line)?;
+                f.sync_data()
+            })
+            .map(|_| self.lines.set(self.lines.get() + 1))
+            .map_err(|e| {
+                error!("cannot write spool: {}", e);
+                ReporterError::Unavailable(e.to_string())
+            })
+    }
+
+    // rewritten as a whole, and replaced so that a crash leaves either of them
+    fn persist(&self) -> Result<(), ReporterError> {
+        let tmp = self.settings.path.with_extension("tmp");
+        File::create(&tmp)
+            .and_then(|mut f| {
+                for record in self.records.borrow().iter() {
+                    let line = serde_json::to_string(record).expect("serialize spool record");
+                    writeln!(f, "{}", line)?;
+                }
+                f.sync_data()
+            })
+            .and_then(|_| fs::rename(&tmp, &self.settings.path))
+            .map(|_| self.lines.set(self.records.borrow().len()))
+            .map_err(|e| {
+                error!("cannot write spool: {}", e);
+                ReporterError::Unavailable(e.to_string())
+            })
+    }
+}
+
+/// An observer whose undeliverable reports are spooled, and replayed in order later.
+///
+/// The spooled report counts as delivered.
+/// The replay is tried on the next report and on flush, so it follows the reconnection of the inner observer.
+/// The events are not spooled if the inner observer carries them,
+/// its error is returned to keep them pending in the outbox instead.
+pub struct Spooled<O> {
+    inner: O,
+    spool: Spool,
+}
+impl<O: Observer> Spooled<O> {
+    pub fn new(inner: O, spool: Spool) -> Self {
+        Self { inner, spool }
+    }
+
+    // true if all the spooled reports have been delivered
+    fn replay(&self) -> bool {
+        if self.spool.is_empty() {
+            return true;
+        }
+
+        let mut delivered = 0;
+        for record in self.spool.records.borrow().iter() {
+            if let Err(e) = record.send(&self.inner) {
+                trace!("replay suspended: {}", e);
+                break;
+            }
+            delivered += 1;
+        }
+        if delivered == 0 {
+            return false;
+        }
+
+        self.spool.records.borrow_mut().drain(..delivered);
+        trace!("replayed {} reports", delivered);
+        if let Err(e) = self.spool.persist() {
+            // 残りと一緒にもう一度送られる
+            warn!("replayed reports may be sent again: {}", e);
+        }
+        self.spool.is_empty()
+    }
+
+    fn deliver(&self, record: Record) -> Result<(), ReporterError> {
+        let replayed = self.replay();
+        // only the outbox knows when they are delivered, so they don't wait for the older ones
+        if matches!(record, Record::Event { .. }) && self.inner.carries_events() {
+            return record.send(&self.inner);
+        }
+        // the new report waits for the older ones
+        if !replayed {
+            return self.spool.push(record);
+        }
+        if let Err(e) = record.send(&self.inner) {
+            warn!("report spooled: {}", e);
+            return self.spool.push(record);
+        }
+        Ok(())
+    }
+}
+impl<O: Observer> Observer for Spooled<O> {
+    fn handle_notification(
+        &self,
+        level: Level,
+        to: &str,
+        message: &str,
+        loc: Location,
+    ) -> Result<(), ReporterError> {
+        self.deliver(Record::Notification {
+            level,
+            to: to.to_string(),
+            message: message.to_string(),
+            error: None,
+            location: loc.into(),
+        })
+    }
+    fn handle_error(
+        &self,
+        level: Level,
+        to: &str,
+        message: &str,
+        error: &ErrorDetail,
+        loc: Location,
+    ) -> Result<(), ReporterError> {
+        self.deliver(Record::Notification {
+            level,
+            to: to.to_string(),
+            message: message.to_string(),
+            error: Some(error.clone()),
+            location: loc.into(),
+        })
+    }
+    fn handle_event(&self, envelope: &EventEnvelope, loc: Location) -> Result<(), ReporterError> {
+        self.deliver(Record::Event {
+            envelope: envelope.clone(),
+            location: loc.into(),
+        })
+    }
+    fn carries_events(&self) -> bool {
+        self.inner.carries_events()
+    }
+    // a quiet process doesn't keep the spooled reports until the next one
+    fn flush(&self) -> Result<(), ReporterError> {
+        self.replay();
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::event::PersonEvent;
+
+    #[derive(Clone)]
+    struct StubObserver {
+        up: Rc<Cell<bool>>,
+        received: Rc<RefCell<Vec<String>>>,
+        // a broker the outbox relays the events through
+        carrier: bool,
+    }
+    impl Observer for StubObserver {
+        fn handle_notification(
+            &self,
+            _level: Level,
+            _to: &str,
+            message: &str,
+            _loc: Location,
+        ) -> Result<(), ReporterError> {
+            if !self.up.get() {
+                return Err(ReporterError::Unavailable("stub observer".to_string()));
+            }
+            self.received.borrow_mut().push(message.to_string());
+            Ok(())
+        }
+        fn carries_events(&self) -> bool {
+            self.carrier
+        }
+    }
+
+    fn settings(capacity: usize, overflow: Overflow) -> SpoolSettings {
+        SpoolSettings {
+            path: std::env::temp_dir().join(format!("spool-{}.jsonl", Uuid::now_v7())),
+            capacity,
+            overflow,
+        }
+    }
+
+    fn unavailable() -> StubObserver {
+        StubObserver {
+            up: Rc::new(Cell::new(false)),
+            received: Rc::new(RefCell::new(vec![])),
+            carrier: false,
+        }
+    }
+
+    fn notify(observer: &impl Observer, message: &str) -> Result<(), ReporterError> {
+        observer.handle_notification(Level::Info, "to", message, location!())
+    }
+
+    #[test]
+    fn test_replay_in_order() {
+        let settings = settings(10, Overflow::DropOldest);
+        let stub = unavailable();
+        let observer = Spooled::new(stub.clone(), Spool::open(settings.clone()).unwrap());
+
+        // 届かなくても spool に書ければ成功とする
+        assert_eq!(notify(&observer, "1"), Ok(()));
+        let id = Uuid::now_v7();
+        let envelope = EventEnvelope::new(PersonEvent::Unregistered { person_id: id }, None);
+        assert_eq!(observer.handle_event(&envelope, location!()), Ok(()));
+        assert!(stub.received.borrow().is_empty());
+
+        // 復旧後の報告は spool に残っていたものの後に届く
+        stub.up.set(true);
+        assert_eq!(notify(&observer, "3"), Ok(()));
+        assert_eq!(
+            *stub.received.borrow(),
+            vec![
+                "1".to_string(),
+                format!("unregistered person_id: {}", id),
+                "3".to_string()
+            ]
+        );
+        assert!(observer.spool.is_empty());
+        assert_eq!(fs::read_to_string(&settings.path).unwrap(), "");
+        fs::remove_file(&settings.path).unwrap();
+    }
+
+    #[test]
+    fn test_reopen() {
+        let settings = settings(10, Overflow::DropOldest);
+        let stub = unavailable();
+        {
+            let observer = Spooled::new(stub.clone(), Spool::open(settings.clone()).unwrap());
+            notify(&observer, "1").unwrap();
+            notify(&observer, "2").unwrap();
+        }
+        // 書きかけの行は読み飛ばす
+        OpenOptions::new()
+            .append(true)
+            .open(&settings.path)
+            .and_then(|mut f| write!(f, "{{\"kind\":\"notif"))
+            .unwrap();
+
+        // 前回の残りも送る
+        stub.up.set(true);
+        let observer = Spooled::new(stub.clone(), Spool::open(settings.clone()).unwrap());
+        notify(&observer, "3").unwrap();
+        assert_eq!(*stub.received.borrow(), vec!["1", "2", "3"]);
+        fs::remove_file(&settings.path).unwrap();
+    }
+
+    #[test]
+    fn test_overflow() {
+        let settings = settings(2, Overflow::DropOldest);
+        let stub = unavailable();
+        let observer = Spooled::new(stub.clone(), Spool::open(settings.clone()).unwrap());
+        for message in ["1", "2", "3"] {
+            notify(&observer, message).unwrap();
+        }
+        stub.up.set(true);
+        notify(&observer, "4").unwrap();
+        assert_eq!(*stub.received.borrow(), vec!["2", "3", "4"]);
+        fs::remove_file(&settings.path).unwrap();
+
+        let settings = SpoolSettings {
+            overflow: Overflow::DropNewest,
+            ..settings
+        };
+        let stub = unavailable();
+        let observer = Spooled::new(stub.clone(), Spool::open(settings.clone()).unwrap());
+        for message in ["1", "2", "3"] {
+            notify(&observer, message).unwrap();
+        }
+        stub.up.set(true);
+        notify(&observer, "4").unwrap();
+        assert_eq!(*stub.received.borrow(), vec!["1", "2", "4"]);
+        fs::remove_file(&settings.path).unwrap();
+    }
+
+    #[test]
+    fn test_compaction() {
+        let settings = settings(2, Overflow::DropOldest);
+        let stub = unavailable();
+        let observer = Spooled::new(stub.clone(), Spool::open(settings.clone()).unwrap());
+        let lines = || fs::read_to_string(&settings.path).unwrap().lines().count();
+        let messages = |spool: &Spool| {
+            spool
+                .records
+                .borrow()
+                .iter()
+                .map(|r| match r {
+                    Record::Notification { message, .. } => message.clone(),
+                    Record::Event { .. } => panic!("no event spooled"),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // 溢れても書き直さず追記する
+        for message in ["1", "2", "3", "4"] {
+            notify(&observer, message).unwrap();
+        }
+        assert_eq!(lines(), 4);
+        // 開き直しても捨てたものは戻らない
+        assert_eq!(
+            messages(&Spool::open(settings.clone()).unwrap()),
+            vec!["3", "4"]
+        );
+
+        // 容量の 2 倍を超えたら詰める
+        let observer = Spooled::new(stub.clone(), Spool::open(settings.clone()).unwrap());
+        notify(&observer, "5").unwrap();
+        assert_eq!(lines(), 2);
+        assert_eq!(messages(&observer.spool), vec!["4", "5"]);
+        fs::remove_file(&settings.path).unwrap();
+    }
+
+    #[test]
+    fn test_replay_on_flush() {
+        let settings = settings(10, Overflow::DropOldest);
+        let stub = unavailable();
+        let observer = Spooled::new(stub.clone(), Spool::open(settings.clone()).unwrap());
+        notify(&observer, "1").unwrap();
+
+        // 次の報告がなくても flush で送る
+        stub.up.set(true);
+        assert_eq!(observer.flush(), Ok(()));
+        assert_eq!(*stub.received.borrow(), vec!["1"]);
+        assert!(observer.spool.is_empty());
+        fs::remove_file(&settings.path).unwrap();
+    }
+
+    #[test]
+    fn test_overflow_keeps_events() {
+        let settings = settings(2, Overflow::DropOldest);
+        let stub = unavailable();
+        let observer = Spooled::new(stub.clone(), Spool::open(settings.clone()).unwrap());
+        let event = |id| {
+            let envelope = EventEnvelope::new(PersonEvent::Unregistered { person_id: id }, None);
+            observer.handle_event(&envelope, location!())
+        };
+        let (id1, id2, id3) = (Uuid::now_v7(), Uuid::now_v7(), Uuid::now_v7());
+
+        // 溢れたら通知の方を捨てる
+        notify(&observer, "1").unwrap();
+        assert_eq!(event(id1), Ok(()));
+        assert_eq!(event(id2), Ok(()));
+        // イベントは捨てずにエラーを返し、呼び出し元に残させる
+        assert!(event(id3).is_err());
+        assert_eq!(notify(&observer, "2"), Ok(()));
+
+        stub.up.set(true);
+        notify(&observer, "3").unwrap();
+        assert_eq!(
+            *stub.received.borrow(),
+            vec![
+                format!("unregistered person_id: {}", id1),
+                format!("unregistered person_id: {}", id2),
+                "3".to_string()
+            ]
+        );
+        fs::remove_file(&settings.path).unwrap();
+    }
+
+    #[test]
+    fn test_carried_events_not_spooled() {
+        let settings = settings(10, Overflow::DropOldest);
+        let stub = StubObserver {
+            carrier: true,
+            ..unavailable()
+        };
+        let observer = Spooled::new(stub.clone(), Spool::open(settings.clone()).unwrap());
+        let id = Uuid::now_v7();
+        let envelope = EventEnvelope::new(PersonEvent::Unregistered { person_id: id }, None);
+
+        // ブローカーが運ぶイベントは spool せず、outbox に残させる
+        notify(&observer, "1").unwrap();
+        assert!(observer.handle_event(&envelope, location!()).is_err());
+        assert_eq!(observer.spool.records.borrow().len(), 1);
+
+        stub.up.set(true);
+        assert_eq!(observer.handle_event(&envelope, location!()), Ok(()));
+        assert_eq!(
+            *stub.received.borrow(),
+            vec!["1".to_string(), format!("unregistered person_id: {}", id)]
+        );
+        assert!(observer.spool.is_empty());
+        fs::remove_file(&settings.path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_overflow() {
+        assert_eq!("drop-oldest".parse(), Ok(Overflow::DropOldest));
+        assert_eq!("Drop-Newest".parse(), Ok(Overflow::DropNewest));
+        assert!("block".parse::<Overflow>().is_err());
+    }
+}
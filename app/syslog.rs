@@ -1,67 +1,515 @@
+use chrono::{DateTime, SecondsFormat, Utc};
 use log::{error, trace};
-use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
-use syslog::{Formatter3164, Logger, LoggerBackend};
+use std::io::{self, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use syslog::Facility;
 
 use crate::{
+    error_detail::ErrorDetail,
+    event::EventEnvelope,
     location::Location,
     reporter::{self, Level, Observer},
 };
 
+const TIMEOUT: Duration = Duration::from_secs(2);
+// the private enterprise number reserved for documentation (RFC 5612), used in the SD-IDs
+const ENTERPRISE_ID: u32 = 32473;
+// the lengths of the header fields in RFC 5424
+const HOSTNAME_MAX: usize = 255;
+const APP_NAME_MAX: usize = 48;
+const MSGID_MAX: usize = 32;
+
+/// How the messages reach the syslog daemon.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transport {
+    // the local daemon, `/dev/log` by default
+    Unix(PathBuf),
+    // a remote collector, one message per datagram
+    Udp(String),
+    // a remote collector, with octet counting framing (RFC 6587)
+    Tcp(String),
+}
+impl Default for Transport {
+    fn default() -> Self {
+        Transport::Unix("/dev/log".into())
+    }
+}
+impl FromStr for Transport {
+    type Err = String;
+
+    // unix, unix:<path>, udp://<host>[:<port>] or tcp://<host>[:<port>]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn with_port(addr: &str, port: u16) -> String {
+            match addr.rsplit_once(':') {
+                Some((_, p)) if p.parse::<u16>().is_ok() => addr.to_string(),
+                _ => format!("{}:{}", addr, port),
+            }
+        }
+
+        if s == "unix" {
+            return Ok(Transport::default());
+        }
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Transport::Unix(path.into()));
+        }
+        if let Some(addr) = s.strip_prefix("udp://") {
+            return Ok(Transport::Udp(with_port(addr, 514)));
+        }
+        if let Some(addr) = s.strip_prefix("tcp://") {
+            return Ok(Transport::Tcp(with_port(addr, 601)));
+        }
+        Err(format!("unknown syslog transport: {}", s))
+    }
+}
+
+/// Where and as whom the reports are written to syslog.
+#[derive(Debug, Clone, Default)]
+pub struct SyslogSettings {
+    pub transport: Transport,
+    pub facility: Facility,
+    // None leaves the collector to fill it
+    pub hostname: Option<String>,
+}
+
+// RFC 5424 severity
+fn severity(level: &Level) -> u8 {
+    match level {
+        Level::Trace => 7, // debug
+        Level::Info => 6,  // informational
+        Level::Warn => 4,  // warning
+        Level::Error => 3, // error
+    }
+}
+
+// the header fields are printable US-ASCII without spaces, and "-" if empty
+fn header(s: &str, max: usize) -> String {
+    let s: String = s
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max)
+        .collect();
+    if s.is_empty() {
+        "-".to_string()
+    } else {
+        s
+    }
+}
+
+// '"', '\' and ']' are escaped in PARAM-VALUE
+fn param_value(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// SD-ID and its SD-PARAMs
+type SdElement = (&'static str, Vec<(&'static str, String)>);
+
+#[derive(Debug, Clone)]
+struct Formatter {
+    facility: Facility,
+    hostname: String,
+    app_name: String,
+    pid: u32,
+}
+impl Formatter {
+    fn format(
+        &self,
+        timestamp: DateTime<Utc>,
+        level: &Level,
+        msgid: &str,
+        data: &[SdElement],
+        message: &str,
+    ) -> String {
+        let mut sd = String::new();
+        for (id, params) in data {
+            sd.push_str(&format!("[{}@{}", id, ENTERPRISE_ID));
+            for (name, value) in params {
+                sd.push_str(&format!(" {}=\"{}\"", name, param_value(value)));
+            }
+            sd.push(']');
+        }
+        if sd.is_empty() {
+            sd.push('-');
+        }
+
+        format!(
+            "<{}>1 {} {} {} {} {} {} \u{feff}{}",
+            self.facility as u8 | severity(level),
+            timestamp.to_rfc3339_opts(SecondsFormat::Micros, true),
+            self.hostname,
+            self.app_name,
+            self.pid,
+            header(msgid, MSGID_MAX),
+            sd,
+            message
+        )
+    }
+}
+
+enum Writer {
+    Unix(UnixDatagram),
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+}
+impl Writer {
+    fn connect(transport: &Transport) -> io::Result<Self> {
+        match transport {
+            Transport::Unix(path) => {
+                let sock = UnixDatagram::unbound()?;
+                sock.connect(path)?;
+                Ok(Writer::Unix(sock))
+            }
+            Transport::Udp(addr) => {
+                let server = addr.to_socket_addrs()?.next().ok_or(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("cannot resolve {}", addr),
+                ))?;
+                let local = if server.is_ipv4() {
+                    "0.0.0.0:0"
+                } else {
+                    "[::]:0"
+                };
+                let sock = UdpSocket::bind(local)?;
+                sock.connect(server)?;
+                Ok(Writer::Udp(sock))
+            }
+            Transport::Tcp(addr) => {
+                let server = addr.to_socket_addrs()?.next().ok_or(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("cannot resolve {}", addr),
+                ))?;
+                let sock = TcpStream::connect_timeout(&server, TIMEOUT)?;
+                sock.set_write_timeout(Some(TIMEOUT))?;
+                Ok(Writer::Tcp(sock))
+            }
+        }
+    }
+
+    fn send(&mut self, message: &str) -> io::Result<()> {
+        match self {
+            Writer::Unix(sock) => sock.send(message.as_bytes()).map(|_| ()),
+            Writer::Udp(sock) => sock.send(message.as_bytes()).map(|_| ()),
+            Writer::Tcp(sock) => {
+                write!(sock, "{} {}", message.len(), message)?;
+                sock.flush()
+            }
+        }
+    }
+}
+
 pub struct Client {
-    writer: RefCell<Logger<LoggerBackend, Formatter3164>>,
+    transport: Transport,
+    formatter: Formatter,
+    // None until the first message and after a failure, connected on the next message
+    writer: RefCell<Option<Writer>>,
 }
 impl Client {
-    pub fn new(program_name: &str, pid: u32) -> Result<Self, reporter::ReporterError> {
-        let formatter = Formatter3164 {
-            facility: syslog::Facility::LOG_USER,
-            hostname: None,
-            process: program_name.into(),
+    // connects on the first message, so the collector may be down at start
+    pub fn open(settings: SyslogSettings, program_name: &str, pid: u32) -> Self {
+        let formatter = Formatter {
+            facility: settings.facility,
+            hostname: header(
+                settings.hostname.as_deref().unwrap_or_default(),
+                HOSTNAME_MAX,
+            ),
+            app_name: header(program_name, APP_NAME_MAX),
             pid,
         };
-        trace!("connecting to syslog: {:?}", formatter);
-        match syslog::unix(formatter) {
-            Err(e) => {
-                error!("impossible to connect to syslog: {:?}", e);
-                Err(reporter::ReporterError::Unavailable(format!(
-                    "impossible to connect to syslog: {:?}",
-                    e
-                )))
-            }
-            Ok(writer) => {
-                trace!("connected to syslog.");
-                Ok(Self {
-                    writer: RefCell::new(writer),
-                })
-            }
+        Self {
+            transport: settings.transport,
+            formatter,
+            writer: RefCell::new(None),
         }
     }
+
+    fn send(
+        &self,
+        level: &Level,
+        msgid: &str,
+        data: &[SdElement],
+        message: &str,
+    ) -> Result<(), reporter::ReporterError> {
+        let message = self
+            .formatter
+            .format(Utc::now(), level, msgid, data, message);
+
+        let mut writer = self.writer.borrow_mut();
+        if writer.is_none() {
+            trace!("connecting to syslog: {:?}", self.transport);
+            *writer = Some(Writer::connect(&self.transport).map_err(|e| {
+                error!("impossible to connect to syslog: {}", e);
+                reporter::ReporterError::Unavailable(e.to_string())
+            })?);
+            trace!("connected to syslog.");
+        }
+        let res = writer.as_mut().map_or(Ok(()), |w| w.send(&message));
+        res.map_err(|e| {
+            error!("cannot write to syslog: {}", e);
+            // the stream may be broken
+            *writer = None;
+            reporter::ReporterError::Unavailable(e.to_string())
+        })
+    }
+}
+
+// the causes are repeated `source` params, the outermost first
+fn error_data(error: &ErrorDetail) -> SdElement {
+    let mut params = vec![
+        ("kind", error.kind.clone()),
+        ("message", error.message.clone()),
+    ];
+    params.extend(
+        error
+            .sources
+            .iter()
+            .map(|s| ("source", format!("{}: {}", s.kind, s.message))),
+    );
+    if let Some(backend) = &error.backend {
+        params.push(("backend", backend.clone()));
+    }
+    params.push(("retryable", error.retryable.to_string()));
+    ("error", params)
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Payload<'a> {
-    level: Level,
-    message: &'a str,
-    location: Location<'a>,
+fn location_data(loc: &Location) -> SdElement {
+    (
+        "location",
+        vec![
+            ("file", loc.file.to_string()),
+            ("line", loc.line.to_string()),
+            ("column", loc.column.to_string()),
+        ],
+    )
 }
 
 impl Observer for Client {
     fn handle_notification(
         &self,
         level: Level,
-        _to: &str,
+        to: &str,
         message: &str,
-        loc: crate::location::Location,
-    ) -> Result<(), crate::reporter::ReporterError> {
-        let payload = serde_json::to_string(&Payload {
-            level,
+        loc: Location,
+    ) -> Result<(), reporter::ReporterError> {
+        self.send(&level, to, &[location_data(&loc)], message)
+    }
+    fn handle_error(
+        &self,
+        level: Level,
+        to: &str,
+        message: &str,
+        error: &ErrorDetail,
+        loc: Location,
+    ) -> Result<(), reporter::ReporterError> {
+        self.send(
+            &level,
+            to,
+            &[error_data(error), location_data(&loc)],
             message,
-            location: loc,
-        })
-        .unwrap_or_default();
-        self.writer
-            .borrow_mut()
-            .notice(payload.as_str())
-            .map_err(|e| crate::reporter::ReporterError::Unavailable(e.to_string()))
+        )
+    }
+    fn handle_event(
+        &self,
+        envelope: &EventEnvelope,
+        loc: Location,
+    ) -> Result<(), reporter::ReporterError> {
+        let mut event = vec![
+            ("id", envelope.id.to_string()),
+            ("type", envelope.event.name().to_string()),
+            ("person_id", envelope.event.person_id().to_string()),
+            ("schema", envelope.schema.to_string()),
+        ];
+        if let Some(revision) = envelope.revision {
+            event.push(("revision", revision.to_string()));
+        }
+        self.send(
+            &Level::Info,
+            envelope.event.queue(),
+            &[("event", event), location_data(&loc)],
+            &envelope.event.to_string(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::error_detail::ErrorSource;
+    use crate::event::PersonEvent;
+
+    fn formatter() -> Formatter {
+        Formatter {
+            facility: Facility::LOG_LOCAL0,
+            hostname: "host".to_string(),
+            app_name: "app".to_string(),
+            pid: 42,
+        }
+    }
+
+    #[test]
+    fn test_format() {
+        let timestamp = Utc.with_ymd_and_hms(2024, 10, 1, 12, 0, 0).unwrap();
+        let data = vec![(
+            "location",
+            vec![
+                ("file", r#"a "b" \c]"#.to_string()),
+                ("line", "1".to_string()),
+            ],
+        )];
+        assert_eq!(
+            formatter().format(timestamp, &Level::Error, "admin", &data, "failed"),
+            "<131>1 2024-10-01T12:00:00.000000Z host app 42 admin \
+             [location@32473 file=\"a \\\"b\\\" \\\\c\\]\" line=\"1\"] \u{feff}failed"
+        );
+        // 空のフィールドは NILVALUE にする
+        assert_eq!(
+            formatter().format(timestamp, &Level::Info, "", &[], "hello"),
+            "<134>1 2024-10-01T12:00:00.000000Z host app 42 - - \u{feff}hello"
+        );
+    }
+
+    #[test]
+    fn test_severity() {
+        assert_eq!(severity(&Level::Trace), 7);
+        assert_eq!(severity(&Level::Info), 6);
+        assert_eq!(severity(&Level::Warn), 4);
+        assert_eq!(severity(&Level::Error), 3);
+        assert_eq!(header("a b\tc", 2), "ab");
+    }
+
+    #[test]
+    fn test_parse_transport() {
+        assert_eq!("unix".parse(), Ok(Transport::Unix("/dev/log".into())));
+        assert_eq!(
+            "unix:/var/run/syslog".parse(),
+            Ok(Transport::Unix("/var/run/syslog".into()))
+        );
+        assert_eq!(
+            "udp://collector".parse(),
+            Ok(Transport::Udp("collector:514".to_string()))
+        );
+        assert_eq!(
+            "tcp://127.0.0.1:6514".parse(),
+            Ok(Transport::Tcp("127.0.0.1:6514".to_string()))
+        );
+        assert!("http://collector".parse::<Transport>().is_err());
+    }
+
+    #[test]
+    fn test_udp() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        listener.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let settings = SyslogSettings {
+            transport: Transport::Udp(listener.local_addr().unwrap().to_string()),
+            facility: Facility::LOG_USER,
+            hostname: Some("host".to_string()),
+        };
+        let client = Client::open(settings, "ddd_tx_tut", 42);
+
+        client
+            .handle_notification(Level::Warn, "admin", "slow", location!())
+            .unwrap();
+        let mut buf = [0; 1024];
+        let n = listener.recv(&mut buf).unwrap();
+        let message = std::str::from_utf8(&buf[..n]).unwrap();
+        // user(1) * 8 + warning(4)
+        assert!(message.starts_with("<12>1 "), "{}", message);
+        assert!(message.contains(" host ddd_tx_tut 42 admin [location@32473 file=\""));
+        assert!(message.ends_with("] \u{feff}slow"));
+    }
+
+    #[test]
+    fn test_unreachable() {
+        let path = std::env::temp_dir().join(format!("syslog-{}", Uuid::now_v7()));
+        let settings = SyslogSettings {
+            transport: Transport::Unix(path),
+            ..Default::default()
+        };
+        // 起動時には繋がなくてよい
+        let client = Client::open(settings, "ddd_tx_tut", 42);
+        assert!(client
+            .handle_notification(Level::Warn, "admin", "slow", location!())
+            .is_err());
+        assert!(client.writer.borrow().is_none());
+    }
+
+    #[test]
+    fn test_error_data() {
+        let error = ErrorDetail {
+            kind: "ServiceError::TransactionFailed".to_string(),
+            message: "transaction failed: collect person failed: select error: timeout".to_string(),
+            sources: vec![
+                ErrorSource {
+                    kind: "UsecaseError::CollectPersonFailed".to_string(),
+                    message: "collect person failed: select error: timeout".to_string(),
+                },
+                ErrorSource {
+                    kind: "DaoError::SelectError".to_string(),
+                    message: "select error: timeout".to_string(),
+                },
+            ],
+            backend: Some("postgres".to_string()),
+            retryable: true,
+        };
+        let timestamp = Utc.with_ymd_and_hms(2024, 10, 1, 12, 0, 0).unwrap();
+        let message = formatter().format(
+            timestamp,
+            &Level::Error,
+            "admin",
+            &[error_data(&error)],
+            "cannot list all persons",
+        );
+
+        // 原因は source を繰り返して外側から並べる
+        assert!(message.contains(
+            "[error@32473 kind=\"ServiceError::TransactionFailed\" \
+             message=\"transaction failed: collect person failed: select error: timeout\" \
+             source=\"UsecaseError::CollectPersonFailed: collect person failed: select error: timeout\" \
+             source=\"DaoError::SelectError: select error: timeout\" \
+             backend=\"postgres\" retryable=\"true\"]"
+        ));
+    }
+
+    #[test]
+    fn test_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let settings = SyslogSettings {
+            transport: Transport::Tcp(listener.local_addr().unwrap().to_string()),
+            ..Default::default()
+        };
+        let client = Client::open(settings, "ddd_tx_tut", 42);
+
+        let id = Uuid::now_v7();
+        let envelope = EventEnvelope::new(PersonEvent::Unregistered { person_id: id }, Some(3));
+        client.handle_event(&envelope, location!()).unwrap();
+        drop(client);
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut received = String::new();
+        stream.read_to_string(&mut received).unwrap();
+        // octet counting で区切る
+        let (len, message) = received.split_once(' ').unwrap();
+        assert_eq!(len.parse::<usize>().unwrap(), message.len());
+        assert!(message.starts_with("<14>1 "), "{}", message);
+        assert!(message.contains(&format!(
+            " - ddd_tx_tut 42 unregister_person [event@32473 id=\"{}\" type=\"unregistered\" person_id=\"{}\" schema=\"1\" revision=\"3\"][location@32473 ",
+            envelope.id, id
+        )));
+        assert!(message.ends_with(&format!("\u{feff}unregistered person_id: {}", id)));
     }
 }
@@ -0,0 +1,288 @@
+use log::{trace, warn};
+use std::marker::PhantomData;
+use std::time::Duration;
+use tx_rs::Tx;
+
+use crate::cache::{CaoError, LockToken, PersonCao};
+use crate::domain::PersonId;
+use crate::dto::PersonDto;
+
+/// Two level cache hierarchy.
+/// L1 is used through the given context, L2 obtains its own connection per operation.
+/// L1 is always updated first so that it never outlives L2 on `unload`.
+pub struct TieredPersonCao<L1, L2, Conn2> {
+    l1: L1,
+    l2: L2,
+    _conn2: PhantomData<fn() -> Conn2>,
+}
+impl<L1, L2, Conn2> TieredPersonCao<L1, L2, Conn2> {
+    pub fn new(l1: L1, l2: L2) -> Self {
+        Self {
+            l1,
+            l2,
+            _conn2: PhantomData,
+        }
+    }
+}
+impl<L1: Clone, L2: Clone, Conn2> Clone for TieredPersonCao<L1, L2, Conn2> {
+    fn clone(&self) -> Self {
+        Self::new(self.l1.clone(), self.l2.clone())
+    }
+}
+
+impl<Ctx, Conn2, L1, L2> PersonCao<Ctx> for TieredPersonCao<L1, L2, Conn2>
+where
+    L1: PersonCao<Ctx>,
+    L2: PersonCao<Conn2>,
+{
+    fn get_conn(&self) -> Result<Ctx, CaoError> {
+        self.l1.get_conn()
+    }
+
+    fn run_tx<T, F>(&self, f: F) -> Result<T, CaoError>
+    where
+        F: tx_rs::Tx<Ctx, Item = T, Err = CaoError>,
+    {
+        self.l1.run_tx(f)
+    }
+
+    fn find(&self, id: PersonId) -> impl tx_rs::Tx<Ctx, Item = Option<PersonDto>, Err = CaoError> {
+        trace!("find person in tiered cache: {}", id);
+        tx_rs::with_tx(move |ctx: &mut Ctx| {
+            if let Some(p) = self.l1.find(id).run(ctx)? {
+                trace!("L1 hit: {}", id);
+                return Ok(Some(p));
+            }
+            trace!("L1 miss: {}", id);
+
+            let p = self.l2.run_tx(self.l2.find(id))?;
+            if let Some(person) = &p {
+                trace!("L2 hit: {}", id);
+                self.l1.load(id, person).run(ctx)?;
+            }
+            Ok(p)
+        })
+    }
+    fn load(
+        &self,
+        id: PersonId,
+        person: &PersonDto,
+    ) -> impl tx_rs::Tx<Ctx, Item = (), Err = CaoError> {
+        trace!("load person into tiered cache: {}", id);
+        tx_rs::with_tx(move |ctx: &mut Ctx| {
+            self.l1.load(id, person).run(ctx)?;
+            self.l2.run_tx(self.l2.load(id, person))
+        })
+    }
+    fn unload(&self, id: PersonId) -> impl tx_rs::Tx<Ctx, Item = (), Err = CaoError> {
+        trace!("unload person from tiered cache: {}", id);
+        tx_rs::with_tx(move |ctx: &mut Ctx| {
+            // L2 が落ちていても L1 は必ず消しておく
+            let l1 = self.l1.unload(id).run(ctx);
+            let l2 = self.l2.run_tx(self.l2.unload(id));
+            if let Err(e) = &l1 {
+                warn!("failed to unload person from L1: {}", e);
+            }
+            l1.and(l2)
+        })
+    }
+
+    fn ids(&self) -> impl tx_rs::Tx<Ctx, Item = Vec<PersonId>, Err = CaoError> {
+        trace!("list persons in tiered cache");
+        tx_rs::with_tx(move |ctx: &mut Ctx| {
+            let mut ids = self.l1.ids().run(ctx)?;
+            ids.extend(self.l2.run_tx(self.l2.ids())?);
+            ids.sort();
+            ids.dedup();
+            Ok(ids)
+        })
+    }
+
+    // the lock has to be shared with the other instances
+    fn lock(
+        &self,
+        id: PersonId,
+        ttl: Duration,
+    ) -> impl tx_rs::Tx<Ctx, Item = Option<LockToken>, Err = CaoError> {
+        tx_rs::with_tx(move |_: &mut Ctx| self.l2.run_tx(self.l2.lock(id, ttl)))
+    }
+    fn unlock(
+        &self,
+        id: PersonId,
+        token: LockToken,
+    ) -> impl tx_rs::Tx<Ctx, Item = (), Err = CaoError> {
+        tx_rs::with_tx(move |_: &mut Ctx| self.l2.run_tx(self.l2.unlock(id, token)))
+    }
+
+    // so is the snapshot, since the generation is bumped by the others too
+    fn generation(&self) -> impl tx_rs::Tx<Ctx, Item = u64, Err = CaoError> {
+        tx_rs::with_tx(move |_: &mut Ctx| self.l2.run_tx(self.l2.generation()))
+    }
+    fn bump_generation(&self) -> impl tx_rs::Tx<Ctx, Item = (), Err = CaoError> {
+        tx_rs::with_tx(move |_: &mut Ctx| self.l2.run_tx(self.l2.bump_generation()))
+    }
+    fn find_all(
+        &self,
+        generation: u64,
+    ) -> impl tx_rs::Tx<Ctx, Item = Option<Vec<(PersonId, PersonDto)>>, Err = CaoError> {
+        tx_rs::with_tx(move |_: &mut Ctx| self.l2.run_tx(self.l2.find_all(generation)))
+    }
+    fn load_all(
+        &self,
+        generation: u64,
+        persons: &[(PersonId, PersonDto)],
+    ) -> impl tx_rs::Tx<Ctx, Item = (), Err = CaoError> {
+        tx_rs::with_tx(move |_: &mut Ctx| self.l2.run_tx(self.l2.load_all(generation, persons)))
+    }
+    fn unload_all(&self, generation: u64) -> impl tx_rs::Tx<Ctx, Item = (), Err = CaoError> {
+        tx_rs::with_tx(move |_: &mut Ctx| self.l2.run_tx(self.l2.unload_all(generation)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::num::NonZeroUsize;
+    use std::rc::Rc;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::domain::date;
+    use crate::lru_cache::LruPersonCao;
+
+    #[derive(Debug, Clone)]
+    struct FakePersonCao {
+        cache: Rc<RefCell<HashMap<PersonId, PersonDto>>>,
+        available: bool,
+    }
+    impl FakePersonCao {
+        fn new(available: bool) -> Self {
+            Self {
+                cache: Rc::new(RefCell::new(HashMap::new())),
+                available,
+            }
+        }
+    }
+    impl PersonCao<()> for FakePersonCao {
+        fn get_conn(&self) -> Result<(), CaoError> {
+            if self.available {
+                Ok(())
+            } else {
+                Err(CaoError::Unavailable("fake cao".to_string()))
+            }
+        }
+        fn run_tx<T, F>(&self, f: F) -> Result<T, CaoError>
+        where
+            F: tx_rs::Tx<(), Item = T, Err = CaoError>,
+        {
+            self.get_conn()?;
+            f.run(&mut ())
+        }
+        fn find(
+            &self,
+            id: PersonId,
+        ) -> impl tx_rs::Tx<(), Item = Option<PersonDto>, Err = CaoError> {
+            tx_rs::with_tx(move |&mut ()| Ok(self.cache.borrow().get(&id).cloned()))
+        }
+        fn load(
+            &self,
+            id: PersonId,
+            person: &PersonDto,
+        ) -> impl tx_rs::Tx<(), Item = (), Err = CaoError> {
+            tx_rs::with_tx(move |&mut ()| {
+                self.cache.borrow_mut().insert(id, person.clone());
+                Ok(())
+            })
+        }
+        fn unload(&self, id: PersonId) -> impl tx_rs::Tx<(), Item = (), Err = CaoError> {
+            tx_rs::with_tx(move |&mut ()| {
+                self.cache.borrow_mut().remove(&id);
+                Ok(())
+            })
+        }
+        fn ids(&self) -> impl tx_rs::Tx<(), Item = Vec<PersonId>, Err = CaoError> {
+            tx_rs::with_tx(move |&mut ()| Ok(self.cache.borrow().keys().cloned().collect()))
+        }
+    }
+
+    fn person(name: &str) -> PersonDto {
+        PersonDto::new(name, date(2000, 1, 1), None, Some(name), 0)
+    }
+    fn tiered(
+        l2: FakePersonCao,
+    ) -> (
+        LruPersonCao,
+        TieredPersonCao<LruPersonCao, FakePersonCao, ()>,
+    ) {
+        let l1 = LruPersonCao::new(NonZeroUsize::new(8).unwrap());
+        (l1.clone(), TieredPersonCao::new(l1, l2))
+    }
+
+    #[test]
+    fn test_find_fills_l1_from_l2() {
+        let l2 = FakePersonCao::new(true);
+        let (l1, cao) = tiered(l2.clone());
+        let id = Uuid::now_v7();
+        l2.cache.borrow_mut().insert(id, person("Alice"));
+
+        assert_eq!(cao.run_tx(cao.find(id)), Ok(Some(person("Alice"))));
+        assert_eq!(l1.run_tx(l1.find(id)), Ok(Some(person("Alice"))));
+
+        // L1 にあれば L2 は参照しない
+        l2.cache.borrow_mut().clear();
+        assert_eq!(cao.run_tx(cao.find(id)), Ok(Some(person("Alice"))));
+    }
+
+    #[test]
+    fn test_load_writes_both_tiers() {
+        let l2 = FakePersonCao::new(true);
+        let (l1, cao) = tiered(l2.clone());
+        let id = Uuid::now_v7();
+
+        assert_eq!(cao.run_tx(cao.load(id, &person("Alice"))), Ok(()));
+        assert_eq!(l1.run_tx(l1.find(id)), Ok(Some(person("Alice"))));
+        assert_eq!(l2.cache.borrow().get(&id), Some(&person("Alice")));
+    }
+
+    #[test]
+    fn test_unload_clears_both_tiers() {
+        let l2 = FakePersonCao::new(true);
+        let (l1, cao) = tiered(l2.clone());
+        let id = Uuid::now_v7();
+        cao.run_tx(cao.load(id, &person("Alice"))).unwrap();
+
+        assert_eq!(cao.run_tx(cao.unload(id)), Ok(()));
+        assert_eq!(l1.run_tx(l1.find(id)), Ok(None));
+        assert_eq!(l2.cache.borrow().get(&id), None);
+    }
+
+    #[test]
+    fn test_ids_from_both_tiers() {
+        let l2 = FakePersonCao::new(true);
+        let (l1, cao) = tiered(l2.clone());
+        let (id1, id2, id3) = (Uuid::now_v7(), Uuid::now_v7(), Uuid::now_v7());
+        l1.run_tx(l1.load(id1, &person("Alice"))).unwrap();
+        cao.run_tx(cao.load(id2, &person("Bob"))).unwrap();
+        l2.cache.borrow_mut().insert(id3, person("Charlie"));
+
+        assert_eq!(cao.run_tx(cao.ids()), Ok(vec![id1, id2, id3]));
+    }
+
+    #[test]
+    fn test_l2_unavailable() {
+        let l2 = FakePersonCao::new(false);
+        let (l1, cao) = tiered(l2);
+        let id = Uuid::now_v7();
+
+        assert!(cao.run_tx(cao.find(id)).is_err());
+
+        // L2 が使えなくても L1 には載る
+        assert!(cao.run_tx(cao.load(id, &person("Alice"))).is_err());
+        assert_eq!(cao.run_tx(cao.find(id)), Ok(Some(person("Alice"))));
+
+        // L2 が使えなくても L1 からは消える
+        assert!(cao.run_tx(cao.unload(id)).is_err());
+        assert_eq!(l1.run_tx(l1.find(id)), Ok(None));
+    }
+}
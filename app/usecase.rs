@@ -3,9 +3,11 @@ use log::{trace, warn};
 use thiserror::Error;
 use tx_rs::Tx;
 
-use crate::dao::{DaoError, HavePersonDao, PersonDao};
-use crate::domain::{Person, PersonDomainError, PersonId};
+use crate::dao::{DaoError, HavePersonDao, OutboxEvent, PersonDao};
+use crate::domain::{Person, PersonDomainError, PersonId, Revision};
 use crate::dto::PersonDto;
+use crate::error_detail::Diagnose;
+use crate::event::EventId;
 
 #[derive(Error, Debug, Clone, PartialEq, Eq)]
 pub enum UsecaseError {
@@ -23,6 +25,40 @@ pub enum UsecaseError {
     RemovePersonFailed(DaoError),
     #[error("remove person failed: {0}")]
     DomainObjectChangeFailed(PersonDomainError),
+    #[error("record event failed: {0}")]
+    RecordEventFailed(DaoError),
+    #[error("relay event failed: {0}")]
+    RelayEventFailed(DaoError),
+}
+impl Diagnose for UsecaseError {
+    fn kind(&self) -> &'static str {
+        match self {
+            UsecaseError::EntryPersonFailed(_) => "UsecaseError::EntryPersonFailed",
+            UsecaseError::FindPersonFailed(_) => "UsecaseError::FindPersonFailed",
+            UsecaseError::EntryAndVerifyPersonFailed(_) => {
+                "UsecaseError::EntryAndVerifyPersonFailed"
+            }
+            UsecaseError::CollectPersonFailed(_) => "UsecaseError::CollectPersonFailed",
+            UsecaseError::SavePersonFailed(_) => "UsecaseError::SavePersonFailed",
+            UsecaseError::RemovePersonFailed(_) => "UsecaseError::RemovePersonFailed",
+            UsecaseError::DomainObjectChangeFailed(_) => "UsecaseError::DomainObjectChangeFailed",
+            UsecaseError::RecordEventFailed(_) => "UsecaseError::RecordEventFailed",
+            UsecaseError::RelayEventFailed(_) => "UsecaseError::RelayEventFailed",
+        }
+    }
+    fn cause(&self) -> Option<&dyn Diagnose> {
+        match self {
+            UsecaseError::EntryPersonFailed(e)
+            | UsecaseError::FindPersonFailed(e)
+            | UsecaseError::EntryAndVerifyPersonFailed(e)
+            | UsecaseError::CollectPersonFailed(e)
+            | UsecaseError::SavePersonFailed(e)
+            | UsecaseError::RemovePersonFailed(e)
+            | UsecaseError::RecordEventFailed(e)
+            | UsecaseError::RelayEventFailed(e) => Some(e),
+            UsecaseError::DomainObjectChangeFailed(e) => Some(e),
+        }
+    }
 }
 pub trait PersonUsecase<Ctx>: HavePersonDao<Ctx> {
     fn entry<'a>(
@@ -79,11 +115,12 @@ pub trait PersonUsecase<Ctx>: HavePersonDao<Ctx> {
         trace!("collect all persons");
         dao.select().map_err(UsecaseError::CollectPersonFailed)
     }
+    // the revision saved
     fn death<'a>(
         &'a mut self,
         id: PersonId,
         date: NaiveDate,
-    ) -> impl tx_rs::Tx<Ctx, Item = (), Err = UsecaseError>
+    ) -> impl tx_rs::Tx<Ctx, Item = Revision, Err = UsecaseError>
     where
         Ctx: 'a,
     {
@@ -111,17 +148,64 @@ pub trait PersonUsecase<Ctx>: HavePersonDao<Ctx> {
                 // 最新版の管理はユースケースの責務
                 let orig_revision = p.revision;
                 p.revision += 1;
+                let revision = p.revision;
                 dao.save(id, orig_revision, p)
+                    .map(move |_| revision)
                     .map_err(UsecaseError::SavePersonFailed)
             })
     }
-    fn remove<'a>(&'a mut self, id: PersonId) -> impl tx_rs::Tx<Ctx, Item = (), Err = UsecaseError>
+    // the revision of the person removed, None if there was no such person
+    fn remove<'a>(
+        &'a mut self,
+        id: PersonId,
+    ) -> impl tx_rs::Tx<Ctx, Item = Option<Revision>, Err = UsecaseError>
     where
         Ctx: 'a,
     {
         let dao = self.get_dao();
         trace!("remove person_id: {:?}", id);
-        dao.delete(id).map_err(UsecaseError::RemovePersonFailed)
+        dao.fetch(id)
+            .map_err(UsecaseError::FindPersonFailed)
+            .and_then(move |p| {
+                let revision = p.map(|p| p.revision);
+                dao.delete(id)
+                    .map(move |_| revision)
+                    .map_err(UsecaseError::RemovePersonFailed)
+            })
+    }
+    fn record_event<'a>(
+        &'a mut self,
+        event: OutboxEvent,
+    ) -> impl tx_rs::Tx<Ctx, Item = (), Err = UsecaseError>
+    where
+        Ctx: 'a,
+    {
+        let dao = self.get_dao();
+        trace!("record event: {:?}", event);
+        dao.enqueue(event).map_err(UsecaseError::RecordEventFailed)
+    }
+    fn pending_events<'a>(
+        &'a mut self,
+        limit: usize,
+    ) -> impl tx_rs::Tx<Ctx, Item = Vec<OutboxEvent>, Err = UsecaseError>
+    where
+        Ctx: 'a,
+    {
+        let dao = self.get_dao();
+        trace!("pending events: limit={}", limit);
+        dao.pending(limit).map_err(UsecaseError::RelayEventFailed)
+    }
+    fn mark_delivered<'a>(
+        &'a mut self,
+        ids: Vec<EventId>,
+    ) -> impl tx_rs::Tx<Ctx, Item = (), Err = UsecaseError>
+    where
+        Ctx: 'a,
+    {
+        let dao = self.get_dao();
+        trace!("mark delivered: {:?}", ids);
+        dao.mark_delivered(ids)
+            .map_err(UsecaseError::RelayEventFailed)
     }
 }
 
@@ -222,6 +306,21 @@ mod fake_tests {
 
             tx_rs::with_tx(move |()| Ok(()))
         }
+        fn enqueue(&self, _event: OutboxEvent) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
+            tx_rs::with_tx(move |()| Ok(()))
+        }
+        fn pending(
+            &self,
+            _limit: usize,
+        ) -> impl tx_rs::Tx<(), Item = Vec<OutboxEvent>, Err = DaoError> {
+            tx_rs::with_tx(move |()| Ok(vec![]))
+        }
+        fn mark_delivered(
+            &self,
+            _ids: Vec<EventId>,
+        ) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
+            tx_rs::with_tx(move |()| Ok(()))
+        }
     }
 
     struct TargetPersonUsecase {
@@ -388,7 +487,7 @@ mod fake_tests {
                 1,
             ),
         )];
-        assert_eq!(result, Ok(()));
+        assert_eq!(result, Ok(1));
         assert_eq!(*usecase.dao.data.borrow(), expected);
     }
     #[test]
@@ -440,7 +539,7 @@ mod fake_tests {
                 ),
             ),
         ];
-        assert_eq!(result, Ok(()));
+        assert_eq!(result, Ok(Some(1)));
         assert_eq!(*usecase.dao.data.borrow(), expected);
     }
 }
@@ -550,6 +649,21 @@ mod spy_tests {
             // 返り値には意味なし
             tx_rs::with_tx(|()| Ok(()))
         }
+        fn enqueue(&self, _event: OutboxEvent) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
+            tx_rs::with_tx(move |()| Ok(()))
+        }
+        fn pending(
+            &self,
+            _limit: usize,
+        ) -> impl tx_rs::Tx<(), Item = Vec<OutboxEvent>, Err = DaoError> {
+            tx_rs::with_tx(move |()| Ok(vec![]))
+        }
+        fn mark_delivered(
+            &self,
+            _ids: Vec<EventId>,
+        ) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
+            tx_rs::with_tx(move |()| Ok(()))
+        }
     }
 
     struct TargetPersonUsecase {
@@ -737,13 +851,15 @@ mod spy_tests {
         let _ = usecase.remove(id).run(&mut ());
 
         // DAO のメソッドの呼び出し記録の検証
+        // イベントに付ける revision を読むため fetch する
         assert_eq!(usecase.dao.insert.borrow().len(), 0);
-        assert_eq!(usecase.dao.fetch.borrow().len(), 0);
+        assert_eq!(usecase.dao.fetch.borrow().len(), 1);
         assert_eq!(*usecase.dao.select.borrow(), 0);
         assert_eq!(usecase.dao.save.borrow().len(), 0);
         assert_eq!(usecase.dao.delete.borrow().len(), 1);
 
         // Usecase の引数が DAO にそのまま渡されていることを確認
+        assert_eq!(usecase.dao.fetch.borrow()[0], expected);
         assert_eq!(usecase.dao.delete.borrow()[0], expected);
     }
 }
@@ -825,6 +941,21 @@ mod error_stub_tests {
         fn delete(&self, _id: PersonId) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
             tx_rs::with_tx(move |()| self.delete_result.clone())
         }
+        fn enqueue(&self, _event: OutboxEvent) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
+            tx_rs::with_tx(move |()| Ok(()))
+        }
+        fn pending(
+            &self,
+            _limit: usize,
+        ) -> impl tx_rs::Tx<(), Item = Vec<OutboxEvent>, Err = DaoError> {
+            tx_rs::with_tx(move |()| Ok(vec![]))
+        }
+        fn mark_delivered(
+            &self,
+            _ids: Vec<EventId>,
+        ) -> impl tx_rs::Tx<(), Item = (), Err = DaoError> {
+            tx_rs::with_tx(move |()| Ok(()))
+        }
     }
 
     struct TargetPersonUsecase {
@@ -989,8 +1120,8 @@ mod error_stub_tests {
     fn test_remove() {
         let id = Uuid::now_v7();
         let dao = StubPersonDao {
-            insert_result: Ok(id),     // 使わない
-            fetch_result: Ok(None),    // 使わない
+            insert_result: Ok(id), // 使わない
+            fetch_result: Ok(None),
             select_result: Ok(vec![]), // 使わない
             save_result: Ok(()),       // 使わない
             delete_result: Err(DaoError::DeleteError("valid dao".to_string())),
@@ -0,0 +1,448 @@
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use http_body_util::Full;
+use hyper::{Request, StatusCode};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::{connect::HttpConnector, Client as HttpClient};
+use hyper_util::rt::TokioExecutor;
+use log::{error, trace, warn};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::rc::Rc;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::{
+    error_detail::ErrorDetail,
+    location::Location,
+    reporter::{self, Level, Observer},
+};
+
+// the hex HMAC-SHA256 of the body, keyed by the secret of the target
+pub const SIGNATURE_HEADER: &str = "x-webhook-signature";
+// the same for all the attempts and the targets of a report, so that the receiver can drop the duplicates
+pub const ID_HEADER: &str = "x-webhook-id";
+
+/// A URL the reports are posted to, and which of them.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Target {
+    pub url: String,
+    // empty accepts all the levels
+    #[serde(default)]
+    pub levels: Vec<Level>,
+    // empty accepts all the destinations
+    #[serde(default)]
+    pub to: Vec<String>,
+    // None posts unsigned
+    #[serde(default)]
+    pub secret: Option<String>,
+}
+impl Target {
+    fn accepts(&self, level: &Level, to: &str) -> bool {
+        (self.levels.is_empty() || self.levels.contains(level))
+            && (self.to.is_empty() || self.to.iter().any(|t| t == to))
+    }
+}
+
+/// Where the reports are posted, and how patiently.
+#[derive(Debug, Clone)]
+pub struct WebhookSettings {
+    pub targets: Vec<Target>,
+    // for each attempt
+    pub timeout: Duration,
+    // attempts after the first one
+    pub retries: u32,
+    // doubled on each retry
+    pub backoff: Duration,
+}
+
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    timestamp: DateTime<Utc>,
+    level: &'a Level,
+    to: &'a str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a ErrorDetail>,
+    location: Location<'a>,
+}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("any key length");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+enum PostError {
+    // timeouts, connection failures, 429 and 5xx
+    Retryable(String),
+    // the other statuses, which won't change by retrying
+    Rejected(String),
+}
+
+/// An observer which posts the reports to the webhooks as JSON.
+pub struct Client {
+    async_runtime: Rc<tokio::runtime::Runtime>,
+    http: HttpClient<HttpsConnector<HttpConnector>, Full<Bytes>>,
+    settings: WebhookSettings,
+}
+impl Client {
+    pub fn open(
+        runtime: Rc<tokio::runtime::Runtime>,
+        settings: WebhookSettings,
+    ) -> Result<Self, reporter::ReporterError> {
+        // both of the crypto providers are linked, so one has to be chosen
+        let https = HttpsConnectorBuilder::new()
+            .with_provider_and_native_roots(rustls::crypto::aws_lc_rs::default_provider())
+            .map_err(|e| {
+                error!("cannot load root certificates: {}", e);
+                reporter::ReporterError::Unavailable(e.to_string())
+            })?
+            .https_or_http()
+            .enable_http1()
+            .build();
+        let http = HttpClient::builder(TokioExecutor::new()).build(https);
+        trace!("webhook targets: {:?}", settings.targets);
+
+        Ok(Self {
+            async_runtime: runtime,
+            http,
+            settings,
+        })
+    }
+
+    async fn try_post(&self, target: &Target, id: &str, body: &Bytes) -> Result<(), PostError> {
+        let mut req = Request::post(&target.url)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .header(ID_HEADER, id);
+        if let Some(secret) = &target.secret {
+            req = req.header(SIGNATURE_HEADER, format!("sha256={}", sign(secret, body)));
+        }
+        let req = req
+            .body(Full::new(body.clone()))
+            .map_err(|e| PostError::Rejected(e.to_string()))?;
+
+        let res = tokio::time::timeout(self.settings.timeout, self.http.request(req))
+            .await
+            .map_err(|_| PostError::Retryable("timed out".to_string()))?
+            .map_err(|e| PostError::Retryable(e.to_string()))?;
+        match res.status() {
+            s if s.is_success() => Ok(()),
+            s if s.is_server_error() || s == StatusCode::TOO_MANY_REQUESTS => {
+                Err(PostError::Retryable(s.to_string()))
+            }
+            s => Err(PostError::Rejected(s.to_string())),
+        }
+    }
+
+    async fn post(
+        &self,
+        target: &Target,
+        id: &str,
+        body: &Bytes,
+    ) -> Result<(), reporter::ReporterError> {
+        let mut backoff = self.settings.backoff;
+        for attempt in 0..=self.settings.retries {
+            match self.try_post(target, id, body).await {
+                Ok(()) => {
+                    trace!("posted to webhook: {}", target.url);
+                    return Ok(());
+                }
+                Err(PostError::Retryable(e)) if attempt < self.settings.retries => {
+                    warn!("retry webhook {} in {:?}: {}", target.url, backoff, e);
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(PostError::Retryable(e)) => {
+                    error!("cannot post to webhook {}: {}", target.url, e);
+                    return Err(reporter::ReporterError::Unavailable(format!(
+                        "{}: {}",
+                        target.url, e
+                    )));
+                }
+                // the target is alive, so the breaker shared by the targets stays closed
+                Err(PostError::Rejected(e)) => {
+                    error!("webhook {} rejected: {}", target.url, e);
+                    return Err(reporter::ReporterError::Rejected(format!(
+                        "{}: {}",
+                        target.url, e
+                    )));
+                }
+            }
+        }
+        unreachable!("the last attempt returns")
+    }
+
+    fn notify(
+        &self,
+        level: Level,
+        to: &str,
+        message: &str,
+        error: Option<&ErrorDetail>,
+        loc: Location,
+    ) -> Result<(), reporter::ReporterError> {
+        let targets: Vec<&Target> = self
+            .settings
+            .targets
+            .iter()
+            .filter(|t| t.accepts(&level, to))
+            .collect();
+        if targets.is_empty() {
+            return Ok(());
+        }
+
+        let body = serde_json::to_vec(&Payload {
+            timestamp: Utc::now(),
+            level: &level,
+            to,
+            message,
+            error,
+            location: loc,
+        })
+        .expect("serialize report");
+        let body = Bytes::from(body);
+        let id = Uuid::now_v7().to_string();
+
+        // all the targets are tried, and the report is delivered if any of them accepted it,
+        // so that it's not posted again to the ones which have accepted it.
+        // if none did, an unavailable one is reported over the rejecting ones.
+        self.async_runtime.block_on(async {
+            let mut res = Ok(());
+            let mut accepted = false;
+            for target in targets {
+                match self.post(target, &id, &body).await {
+                    Ok(()) => accepted = true,
+                    // logged by post
+                    Err(e) => {
+                        if !matches!(res, Err(reporter::ReporterError::Unavailable(_))) {
+                            res = Err(e);
+                        }
+                    }
+                }
+            }
+            if accepted {
+                return Ok(());
+            }
+            res
+        })
+    }
+}
+
+impl Observer for Client {
+    fn handle_notification(
+        &self,
+        level: Level,
+        to: &str,
+        message: &str,
+        loc: Location,
+    ) -> Result<(), reporter::ReporterError> {
+        self.notify(level, to, message, None, loc)
+    }
+    fn handle_error(
+        &self,
+        level: Level,
+        to: &str,
+        message: &str,
+        error: &ErrorDetail,
+        loc: Location,
+    ) -> Result<(), reporter::ReporterError> {
+        self.notify(level, to, message, Some(error), loc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::*;
+
+    struct Received {
+        headers: Vec<(String, String)>,
+        body: String,
+    }
+    impl Received {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        }
+    }
+
+    // answers the requests with the statuses in order, and passes what it received
+    fn mock_server(statuses: Vec<u16>) -> (String, mpsc::Receiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for status in statuses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut headers = vec![];
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                loop {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+                    match line.trim_end().split_once(": ") {
+                        Some((n, v)) => headers.push((n.to_ascii_lowercase(), v.to_string())),
+                        None => break,
+                    }
+                }
+                let len = headers
+                    .iter()
+                    .find(|(n, _)| n == "content-length")
+                    .map_or(0, |(_, v)| v.parse().unwrap());
+                let mut body = vec![0; len];
+                reader.read_exact(&mut body).unwrap();
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 {} X\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+                tx.send(Received {
+                    headers,
+                    body: String::from_utf8(body).unwrap(),
+                })
+                .unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    fn client(targets: Vec<Target>, retries: u32) -> Client {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let settings = WebhookSettings {
+            targets,
+            timeout: Duration::from_secs(2),
+            retries,
+            backoff: Duration::from_millis(10),
+        };
+        Client::open(Rc::new(runtime), settings).unwrap()
+    }
+
+    fn target(url: &str) -> Target {
+        Target {
+            url: url.to_string(),
+            levels: vec![],
+            to: vec![],
+            secret: None,
+        }
+    }
+
+    #[test]
+    fn test_post_signed() {
+        let (url, rx) = mock_server(vec![200]);
+        let client = client(
+            vec![Target {
+                secret: Some("s3cret".to_string()),
+                ..target(&url)
+            }],
+            0,
+        );
+
+        client
+            .handle_notification(Level::Error, "admin", "failed", location!())
+            .unwrap();
+        let received = rx.recv().unwrap();
+        let body: serde_json::Value = serde_json::from_str(&received.body).unwrap();
+        assert_eq!(body["level"], "Error");
+        assert_eq!(body["to"], "admin");
+        assert_eq!(body["message"], "failed");
+        // 受け手は同じ鍵で本文を検証できる
+        assert_eq!(
+            received.header(SIGNATURE_HEADER),
+            Some(format!("sha256={}", sign("s3cret", received.body.as_bytes())).as_str())
+        );
+        assert!(received.header(ID_HEADER).is_some());
+    }
+
+    #[test]
+    fn test_filter() {
+        let (url, rx) = mock_server(vec![200]);
+        let client = client(
+            vec![Target {
+                levels: vec![Level::Warn, Level::Error],
+                to: vec!["admin".to_string()],
+                ..target(&url)
+            }],
+            0,
+        );
+
+        // 対象外のものは送らない
+        client
+            .handle_notification(Level::Info, "admin", "info", location!())
+            .unwrap();
+        client
+            .handle_notification(Level::Error, "entry_person", "other", location!())
+            .unwrap();
+        client
+            .handle_notification(Level::Warn, "admin", "warn", location!())
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_str(&rx.recv().unwrap().body).unwrap();
+        assert_eq!(body["message"], "warn");
+    }
+
+    #[test]
+    fn test_retry() {
+        let (url, rx) = mock_server(vec![503, 500, 200]);
+        let client = client(vec![target(&url)], 2);
+
+        client
+            .handle_notification(Level::Info, "admin", "retried", location!())
+            .unwrap();
+        let ids: Vec<String> = rx
+            .iter()
+            .take(3)
+            .map(|r| r.header(ID_HEADER).unwrap().to_string())
+            .collect();
+        // 再送でも同じ id を使う
+        assert!(ids.iter().all(|id| *id == ids[0]));
+    }
+
+    #[test]
+    fn test_partial_failure() {
+        let (down, down_rx) = mock_server(vec![503]);
+        let (up, up_rx) = mock_server(vec![200]);
+        let partial = client(vec![target(&down), target(&up)], 0);
+
+        // どれかが受け取れば届いたものとし、受け取ったところへ送り直させない
+        partial
+            .handle_notification(Level::Info, "admin", "partial", location!())
+            .unwrap();
+        let (down, up) = (down_rx.recv().unwrap(), up_rx.recv().unwrap());
+        // 宛先が違っても同じ報告は同じ id
+        assert_eq!(down.header(ID_HEADER), up.header(ID_HEADER));
+
+        // どれも受け取らなければ失敗
+        let (down, _rx) = mock_server(vec![503]);
+        let (bad, _rx) = mock_server(vec![400]);
+        let lost = client(vec![target(&down), target(&bad)], 0);
+        assert!(matches!(
+            lost.handle_notification(Level::Info, "admin", "lost", location!()),
+            Err(reporter::ReporterError::Unavailable(_))
+        ));
+    }
+
+    #[test]
+    fn test_rejected() {
+        let (url, rx) = mock_server(vec![400, 200]);
+        let client = client(vec![target(&url)], 2);
+
+        // 4xx は再送しない
+        assert!(matches!(
+            client.handle_notification(Level::Info, "admin", "bad", location!()),
+            Err(reporter::ReporterError::Rejected(_))
+        ));
+        assert!(rx.recv().is_ok());
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+}
@@ -0,0 +1,50 @@
+// cargo bench --bench codecs
+use std::hint::black_box;
+use std::time::Instant;
+
+use ddd::cache_codec::{CacheCodec, Codec};
+use ddd::domain::date;
+use ddd::dto::PersonDto;
+
+const N: u32 = 10_000;
+
+fn person(data: &str) -> PersonDto {
+    PersonDto::new("Alice", date(2000, 1, 1), None, Some(data), 3)
+}
+
+fn main() {
+    for (label, p) in [
+        ("small", person("Alice is here")),
+        ("large", person(&"Alice is here. ".repeat(1000))),
+    ] {
+        for codec in [Codec::Json, Codec::MessagePack, Codec::Bincode] {
+            for codec in [
+                CacheCodec::new(codec, None),
+                CacheCodec::new(codec, Some(64)),
+            ] {
+                let s = codec.encode(&p).unwrap();
+
+                let start = Instant::now();
+                for _ in 0..N {
+                    black_box(codec.encode(black_box(&p)).unwrap());
+                }
+                let encode = start.elapsed() / N;
+
+                let start = Instant::now();
+                for _ in 0..N {
+                    black_box(codec.decode(black_box(&s)).unwrap());
+                }
+                let decode = start.elapsed() / N;
+
+                println!(
+                    "{:5} {:<65} size: {:6} bytes, encode: {:?}, decode: {:?}",
+                    label,
+                    format!("{:?}", codec),
+                    s.len(),
+                    encode,
+                    decode
+                );
+            }
+        }
+    }
+}
@@ -9,4 +9,12 @@ CREATE TABLE person (
 
   revision     INT NOT NULL
 );
+CREATE TABLE outbox (
+  id           UUID PRIMARY KEY,
+  queue        TEXT NOT NULL,
+  message      TEXT NOT NULL,
+  delivered_at TIMESTAMPTZ
+);
+CREATE INDEX outbox_pending ON outbox (id) WHERE delivered_at IS NULL;
+CREATE INDEX outbox_delivered ON outbox (delivered_at) WHERE delivered_at IS NOT NULL;
 EOSQL
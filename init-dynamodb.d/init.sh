@@ -25,11 +25,40 @@ create_table_if_not_exists() {
     --attribute-definitions \
       AttributeName=PK,AttributeType=S \
       AttributeName=SK,AttributeType=S \
+      AttributeName=pending,AttributeType=S \
+      AttributeName=id,AttributeType=S \
     --key-schema \
       AttributeName=PK,KeyType=HASH \
       AttributeName=SK,KeyType=RANGE \
+    --global-secondary-indexes "$PENDING_INDEX" \
     --billing-mode PAY_PER_REQUEST
   echo "Table '$table' created."
 }
 
+# the undelivered outbox events, only they have the pending attribute
+PENDING_INDEX='[{"IndexName":"pending","KeySchema":[{"AttributeName":"pending","KeyType":"HASH"},{"AttributeName":"id","KeyType":"RANGE"}],"Projection":{"ProjectionType":"ALL"}}]'
+
+# for the tables created before the index
+create_pending_index_if_not_exists() {
+  local table=$1
+  if aws dynamodb --region us-west-1 --endpoint-url http://dynamodb:8000 \
+      describe-table --table-name "$table" \
+      --query "Table.GlobalSecondaryIndexes[?IndexName=='pending'].IndexName" \
+      --output text | grep -q pending; then
+    echo "Index 'pending' of '$table' already exists, skipping."
+    return 0
+  fi
+  aws dynamodb \
+    --region us-west-1 \
+    --endpoint-url http://dynamodb:8000 \
+    update-table \
+    --table-name "$table" \
+    --attribute-definitions \
+      AttributeName=pending,AttributeType=S \
+      AttributeName=id,AttributeType=S \
+    --global-secondary-index-updates '[{"Create":{"IndexName":"pending","KeySchema":[{"AttributeName":"pending","KeyType":"HASH"},{"AttributeName":"id","KeyType":"RANGE"}],"Projection":{"ProjectionType":"ALL"}}}]'
+  echo "Index 'pending' of '$table' created."
+}
+
 create_table_if_not_exists person
+create_pending_index_if_not_exists person